@@ -1,22 +1,45 @@
-use core::ptr;
+use core::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    fmt::Write,
+    mem,
+};
 
+use heapless::{binary_heap::Min, BinaryHeap};
 use spin::Once;
-use x86::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
+use x86::{
+    msr::{rdmsr, wrmsr, IA32_APIC_BASE, IA32_TSC_DEADLINE},
+    time::rdtsc,
+};
 
 use crate::{
-    apic::registers::{DivideConfiguration, Timer, LVT_TIMER_REG, TIMER_DIVIDE_CONF_REG},
-    cpu::cpuid,
-    linker,
+    apic::registers::{Timer, LVT_TIMER_REG},
+    boot::serial_console::{self, Color},
+    cpu::{self, cpuid, CpuVendor},
+    defer::{self, Event},
+    idt::{self, handler::Frame},
+    interrupt_handler, linker,
+    mmio::Mmio,
+    percpu, pm_timer,
 };
 
 use self::registers::{
-    DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Divisor, Error,
-    ErrorStatus, Icr, IcrHigh, IcrLow, Level, TimerMode, TriggerMode, EOI_REG, ERROR_STATUS_REG,
-    ICR_HIGH_REG, ICR_LOW_REG, LOCAL_APIC_ID_REG, LVT_ERROR_REG, TIMER_INIT_COUNT_REG,
-    X2APIC_MSR_BASE,
+    reg_index, x2apic_msr, ApicBase, Divisor, ErrorStatus, SpuriousInterrupt, TimerMode,
+    SPURIOUS_INT_VECTOR_REG,
 };
 
-pub mod registers;
+pub use libapic::{local as registers, x2apic_logical_id, ApicAccess, ApicMode, ICR_SEND_TIMEOUT};
+
+/// The vector used to signal local APIC errors.
+pub const ERROR_VECTOR: u8 = 0xfe;
+
+/// The vector used for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The vector used to stop other CPUs when panicking (see [panic::stop_handler]).
+///
+/// [panic::stop_handler]: crate::panic::stop_handler
+pub const STOP_VECTOR: u8 = 0xfd;
 
 /// Local APIC.
 ///
@@ -29,7 +52,7 @@ pub enum LocalApic {
     /// Backed by xAPIC.
     ///
     /// We're using an MMIO to interface with the APIC registers.
-    XApic(*mut [u32; 0x400]),
+    XApic(Mmio<0x1000>),
 
     /// Backed by x2APIC.
     ///
@@ -45,254 +68,481 @@ unsafe impl Send for LocalApic {}
 
 impl LocalApic {
     /// Enable the local APIC.
-    pub fn enable(&self) {
+    ///
+    /// This sets the global enable bit (and x2APIC mode if applicable), and
+    /// software enables the APIC through the spurious interrupt vector
+    /// register, using [SPURIOUS_VECTOR]. Returns true if the APIC was already
+    /// enabled.
+    pub fn enable(&self) -> bool {
         unsafe {
-            let mut base = rdmsr(IA32_APIC_BASE);
+            let mut base = self.read_base();
+            let mut was_enabled = base.is_enabled();
 
             // set the global 'EN' (or 'AE' on AMD) bit.
-            base |= 1 << 11;
-            wrmsr(IA32_APIC_BASE, base);
+            base.enable();
+            self.write_base(base);
 
             // From AMD64 Architecture Programmer's Manual Vol. 2, 16.9:
             // 'the local APIC is placed into x2APIC mode by setting bit 10 in the
             // Local APIC base register. Before entering x2APIC mode, the local APIC
             // must first be enabled. System software can then place the local APIC
             // into x2APIC mode by executing a WRMSR with both AE=1 and EXTD=1.'
-            if matches!(self, LocalApic::X2Apic) {
-                base |= 1 << 10;
-                wrmsr(IA32_APIC_BASE, base);
+            if self.is_x2apic() {
+                was_enabled &= base.is_x2apic();
+                base.enable_x2apic();
+                self.write_base(base);
             }
+
+            // The APIC comes out of a global enable software disabled, so the
+            // SVR can only be trusted from here on.
+            let mut svr =
+                SpuriousInterrupt::from_bits_unchecked(self.read(SPURIOUS_INT_VECTOR_REG) as u32);
+            was_enabled &= svr.is_enabled();
+
+            svr.set_vector(SPURIOUS_VECTOR);
+            svr.set_enabled(true);
+            self.write(SPURIOUS_INT_VECTOR_REG, svr.bits() as u64);
+
+            was_enabled
         }
     }
 
-    /// Disable the local APIC.
-    pub fn disable(&self) {
+    /// Setup the APIC timer in TSC-deadline mode.
+    ///
+    /// The timer is armed by writing a TSC value to `IA32_TSC_DEADLINE` (see
+    /// [set_tsc_deadline](LocalApic::set_tsc_deadline)). The divide
+    /// configuration and initial count registers are not used in this mode.
+    pub fn setup_tsc_deadline(&self, vector: u8, masked: bool) {
+        assert!(cpuid().features.has_tsc_deadline());
+
+        let mut timer = Timer::new(vector, masked);
+        timer.set_timer_mode(TimerMode::TscDeadline);
+
         unsafe {
-            let mut base = rdmsr(IA32_APIC_BASE);
-            base &= !(1 << 11 & 1 << 10);
-            wrmsr(IA32_APIC_BASE, base);
+            self.write(LVT_TIMER_REG, timer.bits() as u64);
         }
     }
 
-    /// Returns true is the current CPU is the Boot Strap Processor.
-    pub fn is_bsp(&self) -> bool {
-        unsafe { (rdmsr(IA32_APIC_BASE) & (1 << 8)) == (1 << 8) }
+    /// Arm the TSC-deadline timer.
+    ///
+    /// The timer fires once the TSC reaches `deadline`, or immediately if it
+    /// already has. Writing 0 disarms the timer.
+    pub fn set_tsc_deadline(&self, deadline: u64) {
+        unsafe {
+            wrmsr(IA32_TSC_DEADLINE, deadline);
+        }
     }
 
-    /// Returns the APIC ID of the current CPU.
-    pub fn id(&self) -> u32 {
-        let mut raw = unsafe { self.read(LOCAL_APIC_ID_REG) as u32 };
+    /// Perform an unchecked write to the given register.
+    ///
+    /// In xAPIC mode `reg` is the byte offset of the register, in x2APIC mode
+    /// it is the MSR.
+    pub unsafe fn unchecked_write(&self, reg: u32, val: u64) {
+        match self {
+            LocalApic::XApic(mmio) => {
+                mmio.write(reg_index(reg) * mem::size_of::<u32>(), val as u32)
+            }
+            LocalApic::X2Apic => {
+                wrmsr(reg, val);
+            }
+        }
+    }
+
+    /// Perform an unchecked read from the given register.
+    ///
+    /// See [unchecked_write](LocalApic::unchecked_write).
+    pub unsafe fn unchecked_read(&self, reg: u32) -> u64 {
+        match self {
+            LocalApic::XApic(mmio) => {
+                mmio.read::<u32>(reg_index(reg) * mem::size_of::<u32>()) as u64
+            }
+            LocalApic::X2Apic => rdmsr(reg),
+        }
+    }
+}
 
-        if matches!(self, LocalApic::XApic(_)) {
-            raw >>= 24;
+impl ApicAccess for LocalApic {
+    /// Return the mode the local APIC operates in.
+    fn mode(&self) -> ApicMode {
+        match self {
+            LocalApic::XApic(_) => ApicMode::XApic,
+            LocalApic::X2Apic => ApicMode::X2Apic,
         }
+    }
 
-        raw
+    unsafe fn read(&self, reg: u32) -> u64 {
+        match self {
+            LocalApic::XApic(_) => self.unchecked_read(reg),
+            LocalApic::X2Apic => self.unchecked_read(x2apic_msr(reg)),
+        }
     }
 
-    /// Setup the APIC Error LVT entry.
-    pub fn setup_error(&self, vector: u8) {
-        unsafe {
-            self.write(LVT_ERROR_REG, Error::new(vector, false).bits() as u64);
-            self.write(ERROR_STATUS_REG, 0);
+    unsafe fn write(&self, reg: u32, val: u64) {
+        match self {
+            LocalApic::XApic(_) => self.unchecked_write(reg, val),
+            LocalApic::X2Apic => self.unchecked_write(x2apic_msr(reg), val),
         }
     }
 
-    /// Setup the APIC timer.
-    pub fn setup_timer(&self, vector: u8, masked: bool, mode: TimerMode, divisor: Divisor) {
-        assert!(matches!(mode, TimerMode::OneShot) || matches!(mode, TimerMode::Periodic));
+    fn read_base(&self) -> ApicBase {
+        unsafe { ApicBase::from_bits_unchecked(rdmsr(IA32_APIC_BASE)) }
+    }
 
-        let mut timer = Timer::new(vector, masked);
-        timer.set_timer_mode(mode);
+    unsafe fn write_base(&self, base: ApicBase) {
+        wrmsr(IA32_APIC_BASE, base.bits());
+    }
 
-        let divisor = DivideConfiguration::new(divisor);
+    /// Defer the errors, see [defer_errors].
+    fn report_errors(&self, status: ErrorStatus) {
+        defer_errors(status);
+    }
+}
 
-        unsafe {
-            self.write(LVT_TIMER_REG, timer.bits() as u64);
-            self.write(TIMER_DIVIDE_CONF_REG, divisor.bits() as u64);
-        }
+interrupt_handler! {
+    /// Clear the errors recorded by the local APIC, and defer logging them.
+    pub fn error_handler(_frame: Frame) {
+        let apic = local();
+        defer_errors(apic.clear_errors());
+        apic.eoi();
     }
+}
 
-    /// Start the APIC timer.
+/// Queue the errors recorded in the given error status, if any, as an event
+/// for [ERROR_VECTOR].
+///
+/// The errors can be reported while the console is held, e.g. from an
+/// interrupt that arrived in the middle of a `print!`, or on an IPI timeout
+/// while panicking. So they are logged later (see [log_errors]), instead of
+/// taking the console here.
+fn defer_errors(status: ErrorStatus) {
+    if !status.is_empty() {
+        defer::push(Event {
+            vector: ERROR_VECTOR,
+            data: status.bits() as u64,
+        });
+    }
+}
+
+/// Log the errors of a deferred [ERROR_VECTOR] event.
+///
+/// Takes the console, so this must not be called from interrupt context.
+pub fn log_errors(event: Event) {
+    debug_assert_eq!(event.vector, ERROR_VECTOR);
+    let status = unsafe { ErrorStatus::from_bits_unchecked(event.data as u32) };
+
+    // The checksum and redirectable IPI bits are reserved on AMD.
+    let intel = cpu::vendor() != CpuVendor::Amd;
+    let errors = [
+        (intel && status.send_checksum_error(), "send checksum"),
+        (intel && status.receive_checksum_error(), "receive checksum"),
+        (status.send_accept_error(), "send accept"),
+        (status.receive_accept_error(), "receive accept"),
+        (intel && status.redirectable_ipi(), "redirectable IPI"),
+        (status.send_illegal_vector(), "send illegal vector"),
+        (status.receive_illegal_vector(), "receive illegal vector"),
+        (status.illegal_register_access(), "illegal register access"),
+    ];
+
+    // Hold the console, so the report isn't interleaved with other output.
+    let apic_id = cpu::current_apic_id();
+    let mut console = serial_console::CONSOLE.lock();
+    console.set_color(Color::Red);
+    let _ = write!(console, "APIC error on ");
+    console.set_color(Color::for_cpu(apic_id));
+    let _ = write!(console, "CPU {}", apic_id);
+    console.set_color(Color::Red);
+    let _ = writeln!(console, " ({:#04x}):", status.bits());
+    for (_, name) in errors.iter().filter(|(set, _)| *set) {
+        let _ = writeln!(console, "  - {}", name);
+    }
+    console.reset_color();
+}
+
+interrupt_handler! {
+    /// Ignore a spurious interrupt.
     ///
-    /// To avoid race conditions, this function should not be called before
-    /// [`setup_timer`](LocalApic::start_timer) has been called.
-    pub fn start_timer(&self, init: u32) {
-        unsafe {
-            self.write(TIMER_INIT_COUNT_REG, init as u64);
+    /// Spurious interrupts are not accepted by the APIC, so no EOI is sent.
+    pub fn spurious_handler(_frame: Frame) {}
+}
+
+/// The callback invoked on every periodic timer tick.
+static TICK: Once<fn()> = Once::new();
+
+percpu! {
+    /// Whether the periodic timer runs on this CPU.
+    static TICKING: Cell<bool> = Cell::new(false);
+}
+
+interrupt_handler! {
+    /// Acknowledge the timer interrupt and run the tick callback.
+    ///
+    /// The EOI is sent before the callback runs, so a panicking callback can't
+    /// leave the timer blocked.
+    pub fn timer_handler(_frame: Frame) {
+        local().eoi();
+
+        if let Some(tick) = TICK.get() {
+            tick();
         }
     }
+}
 
-    /// Stop the APIC timer.
-    pub fn stop_timer(&self) {
-        unsafe {
-            self.write(TIMER_INIT_COUNT_REG, 0x0);
-        }
+/// Return the frequency of the local APIC timer in Hz, with a divisor of 1.
+///
+/// The timer is calibrated against the PM timer on the current CPU, with its
+/// LVT entry masked, which takes 10ms. The PM timer must have been initialised
+/// (see [pm_timer::init]), else this returns [None]. Once known, the frequency
+/// is cached, all CPUs are assumed to run their timers at the same rate.
+pub fn timer_frequency() -> Option<u32> {
+    static FREQUENCY: Once<u32> = Once::new();
+    if let Some(&freq) = FREQUENCY.get() {
+        return Some(freq);
     }
 
-    /// Send an INIT IPI to the target APIC.
-    ///
-    /// This will reset the target into the INIT state and await a STARTUP IPI.
-    pub fn ipi_init(&self, apic_id: u32) {
-        let low = IcrLow::new(
-            0,
-            DeliveryMode::INIT,
-            DestinationMode::Physical,
-            Level::Assert,
-            TriggerMode::Edge,
-            DestinationShorthand::NoShorthand,
-        );
-
-        let high = if matches!(self, LocalApic::XApic(_)) {
-            IcrHigh::new_xapic_destination(apic_id as u8)
-        } else {
-            IcrHigh::new_x2apic_destination(apic_id)
-        };
+    let apic = local();
+    let (waited, remaining) = defer::without_interrupts(|| {
+        apic.setup_timer(SPURIOUS_VECTOR, true, TimerMode::OneShot, Divisor::By1);
+        apic.start_timer(u32::MAX);
+        let waited = pm_timer::udelay(10_000);
+        let remaining = apic.timer_count();
+        apic.stop_timer();
+        (waited, remaining)
+    });
+    waited.ok()?;
+
+    let freq = u32::try_from((u32::MAX - remaining) as u64 * 100).ok()?;
+    Some(*FREQUENCY.call_once(|| freq))
+}
 
-        self.ipi(Icr::new(low, high));
+/// Start a periodic timer on the current CPU, firing `hz` times per second.
+///
+/// `tick` is called from the interrupt handler on every tick, with interrupts
+/// disabled. The callback is shared by all CPUs, so starting the timer with a
+/// different one than before fails.
+pub fn start_periodic(vector: u8, hz: u32, tick: fn()) -> Result<(), &'static str> {
+    assert!(hz != 0);
+
+    let freq = timer_frequency().ok_or("APIC timer frequency unknown")?;
+    let init = freq / hz;
+    if init == 0 {
+        return Err("APIC timer frequency too low");
     }
 
-    /// Send a synchronization message to all local APICs in the system to set
-    /// their arbitration IDs to the values of their APIC IDs.
-    pub fn ipi_init_deassert(&self) {
-        let low = IcrLow::new(
-            0,
-            DeliveryMode::INIT,
-            DestinationMode::Physical, // destination mode doesn't matter.
-            Level::Deassert,
-            TriggerMode::Level,
-            DestinationShorthand::AllIncludingSelf,
-        );
-
-        let high = IcrHigh::new();
-
-        self.ipi(Icr::new(low, high));
+    if *TICK.call_once(|| tick) as usize != tick as usize {
+        return Err("A different tick callback is already set");
     }
+    idt::set_handler(vector, &timer_handler);
 
-    /// Send a STARTUP IPI to the target APIC.
-    ///
-    /// After receiving the STARTUP, the target will begin executing the bootstrap
-    /// routine located at `bootstrap * 4096`.
-    pub fn ipi_startup(&self, apic_id: u32, bootstrap: u8) {
-        let low = IcrLow::new(
-            bootstrap,
-            DeliveryMode::StartUp,
-            DestinationMode::Physical,
-            Level::Assert,
-            TriggerMode::Edge,
-            DestinationShorthand::NoShorthand,
-        );
-        let high = if matches!(self, LocalApic::XApic(_)) {
-            IcrHigh::new_xapic_destination(apic_id as u8)
-        } else {
-            IcrHigh::new_x2apic_destination(apic_id)
-        };
+    let apic = local();
+    apic.setup_timer(vector, false, TimerMode::Periodic, Divisor::By1);
+    apic.start_timer(init);
+    TICKING.with(|ticking| ticking.set(true));
+
+    Ok(())
+}
+
+/// Returns true if the periodic timer runs on the current CPU (see
+/// [start_periodic]), so an interrupt arrives at least once per tick.
+pub fn is_ticking() -> bool {
+    TICKING.with(Cell::get)
+}
+
+/// The maximum number of pending deadlines per CPU.
+pub const MAX_DEADLINES: usize = 32;
+
+/// A pending deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Deadline {
+    /// The TSC value at which the deadline expires.
+    at: u64,
+
+    /// Passed on as the data of the deferred [Event].
+    callback_id: u64,
+}
 
-        self.ipi(Icr::new(low, high));
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    /// Send an IPI using the supplied ICR.
-    ///
-    /// Caller must make sure ICR is properly formatted.
-    pub fn ipi(&self, icr: Icr) {
-        match self {
-            LocalApic::XApic(_) => {
-                self.await_icr_send();
-                self.write_icr(icr);
-                self.await_icr_send();
-            }
-            LocalApic::X2Apic => self.write_icr(icr),
-        }
+impl Ord for Deadline {
+    /// Deadlines are ordered by expiry, and by callback ID if they expire at
+    /// the same time, which keeps the order consistent with [Eq].
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.callback_id).cmp(&(other.at, other.callback_id))
     }
+}
 
-    /// Issue an end-of-interrupt.
-    pub fn eoi(&self) {
-        unsafe {
-            self.write(EOI_REG, 0x0);
+/// How the timer is armed for the next deadline.
+#[derive(Debug, Clone, Copy)]
+enum TimerSource {
+    /// The TSC-deadline timer, deadlines are programmed as-is.
+    TscDeadline,
+
+    /// The one-shot timer. The remaining TSC ticks are scaled to timer ticks
+    /// by `denominator / numerator`, the ratio of the TSC and the core crystal
+    /// clock (CPUID leaf 0x15).
+    OneShot { numerator: u32, denominator: u32 },
+}
+
+/// The pending deadlines of a CPU, used to run the timer tickless.
+///
+/// Only the nearest deadline is programmed into the timer. When it fires, all
+/// expired deadlines are pushed onto the [defer] queue as an [Event] with the
+/// timer vector and the callback ID as data, and the timer is reprogrammed
+/// to the next deadline.
+#[derive(Debug)]
+pub struct TimerWheel {
+    deadlines: BinaryHeap<Deadline, Min, MAX_DEADLINES>,
+
+    /// The deadline the timer is currently armed for.
+    armed: Option<u64>,
+
+    vector: u8,
+    source: Option<TimerSource>,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        TimerWheel {
+            deadlines: BinaryHeap::new(),
+            armed: None,
+            vector: 0,
+            source: None,
         }
     }
 
-    /// Read the error status register.
-    pub fn esr(&self) -> ErrorStatus {
-        unsafe {
-            self.write(ERROR_STATUS_REG, 0);
-            ErrorStatus::from_bits_unchecked(self.read(ERROR_STATUS_REG) as u32)
+    /// Add a deadline, reprogramming the timer if it's earlier than the one
+    /// currently armed.
+    fn schedule(&mut self, deadline: Deadline) -> Result<(), &'static str> {
+        if self.source.is_none() {
+            return Err("tickless timer not started");
         }
-    }
 
-    /// Block while the ICR is in the 'Send Pending' status.
-    fn await_icr_send(&self) {
-        while self.read_icr().low.delivery_status() == DeliveryStatus::SendPending {
-            core::hint::spin_loop();
+        self.deadlines
+            .push(deadline)
+            .map_err(|_| "too many pending deadlines")?;
+
+        if self.armed.map_or(true, |armed| deadline.at < armed) {
+            self.arm(deadline.at);
         }
+
+        Ok(())
     }
 
-    /// Perform a raw write to the ICR register.
-    fn write_icr(&self, icr: Icr) {
-        match self {
-            LocalApic::XApic(_) => unsafe {
-                self.write(ICR_HIGH_REG, icr.high.bits() as u64);
-                self.write(ICR_LOW_REG, icr.low.bits() as u64);
-            },
-            LocalApic::X2Apic => unsafe {
-                self.write(ICR_LOW_REG, icr.bits());
-            },
+    /// Defer every deadline that expired at `now`, and arm the timer for the
+    /// next one.
+    fn expire(&mut self, now: u64) {
+        self.armed = None;
+
+        while let Some(deadline) = self.deadlines.peek().copied() {
+            if deadline.at > now {
+                self.arm(deadline.at);
+                break;
+            }
+
+            self.deadlines.pop();
+            defer::push(Event {
+                vector: self.vector,
+                data: deadline.callback_id,
+            });
         }
     }
 
-    /// Read the ICR register.
-    pub fn read_icr(&self) -> Icr {
-        match self {
-            LocalApic::XApic(_) => unsafe {
-                let low = self.read(ICR_LOW_REG) as u32;
-                let high = self.read(ICR_HIGH_REG) as u32;
-                Icr::from_bits_unchecked(low, high)
-            },
-            LocalApic::X2Apic => unsafe { Icr::from_bits64_unchecked(self.read(ICR_LOW_REG)) },
+    fn arm(&mut self, at: u64) {
+        let source = self.source.expect("tickless timer not started");
+        let apic = local();
+
+        match source {
+            // 0 disarms the timer, so never write it.
+            TimerSource::TscDeadline => apic.set_tsc_deadline(at.max(1)),
+            TimerSource::OneShot {
+                numerator,
+                denominator,
+            } => {
+                let remaining = at.saturating_sub(unsafe { rdtsc() }) as u128;
+                let ticks = remaining * denominator as u128 / numerator as u128;
+
+                // A deadline too far away fires early, and is simply rearmed.
+                apic.start_timer(ticks.clamp(1, u32::MAX as u128) as u32);
+            }
         }
+
+        self.armed = Some(at);
     }
+}
 
-    /// Write to the given register, automatically translating it to x2APIC if
-    /// necessary.
-    unsafe fn write(&self, reg: u32, val: u64) {
-        match self {
-            LocalApic::XApic(_) => self.unchecked_write(reg, val),
-            LocalApic::X2Apic => self.unchecked_write(X2APIC_MSR_BASE + (reg >> 4), val),
-        }
+percpu! {
+    static WHEEL: RefCell<TimerWheel> = RefCell::new(TimerWheel::new());
+}
+
+interrupt_handler! {
+    /// Acknowledge the timer interrupt and defer the expired deadlines.
+    pub fn deadline_handler(_frame: Frame) {
+        local().eoi();
+
+        let now = unsafe { rdtsc() };
+        WHEEL.with_borrow_mut(|wheel| wheel.expire(now));
     }
+}
 
-    /// Read the given register, automatically translating it to x2APIC if
-    /// necessary.
-    unsafe fn read(&self, reg: u32) -> u64 {
-        match self {
-            LocalApic::XApic(_) => self.unchecked_read(reg),
-            LocalApic::X2Apic => self.unchecked_read(X2APIC_MSR_BASE + (reg >> 4)),
+/// Run the timer of the current CPU tickless, on `vector`.
+///
+/// The TSC-deadline timer is used when available, the one-shot timer
+/// otherwise. The timer only fires for deadlines added through [schedule_at],
+/// so idle CPUs aren't woken up needlessly.
+pub fn start_tickless(vector: u8) -> Result<(), &'static str> {
+    let source = if cpuid().features.has_tsc_deadline() {
+        TimerSource::TscDeadline
+    } else {
+        let info = x86::cpuid::CpuId::new()
+            .get_tsc_info()
+            .ok_or("TSC frequency unknown")?;
+        if info.numerator() == 0 || info.denominator() == 0 {
+            return Err("TSC frequency unknown");
         }
-    }
 
-    /// Perform an unchecked write to the given register.
-    pub unsafe fn unchecked_write(&self, reg: u32, val: u64) {
-        match self {
-            LocalApic::XApic(mmio) => {
-                let ptr = (*mmio as *const u32).add(reg as usize) as *mut u32;
-                ptr::write_volatile(ptr, val as u32)
-            }
-            LocalApic::X2Apic => {
-                wrmsr(reg, val);
-            }
+        TimerSource::OneShot {
+            numerator: info.numerator(),
+            denominator: info.denominator(),
         }
-    }
+    };
 
-    /// Perform an unchecked read from the given register.
-    pub unsafe fn unchecked_read(&self, reg: u32) -> u64 {
-        match self {
-            LocalApic::XApic(mmio) => {
-                let ptr = (*mmio as *const u32).add(reg as usize) as *const u32;
-                ptr::read_volatile(ptr) as u64
-            }
-            LocalApic::X2Apic => rdmsr(reg),
+    idt::set_handler(vector, &deadline_handler);
+
+    let apic = local();
+    match source {
+        TimerSource::TscDeadline => apic.setup_tsc_deadline(vector, false),
+        TimerSource::OneShot { .. } => {
+            apic.setup_timer(vector, false, TimerMode::OneShot, Divisor::By1)
         }
     }
+
+    defer::without_interrupts(|| {
+        WHEEL.with_borrow_mut(|wheel| {
+            wheel.vector = vector;
+            wheel.source = Some(source);
+        })
+    });
+
+    Ok(())
+}
+
+/// Schedule a deadline on the current CPU.
+///
+/// Once the TSC reaches `deadline`, an [Event] with the timer vector and
+/// `callback_id` as data is pushed onto the [defer] queue. Deadlines in the
+/// past fire right away. [start_tickless] must have been called first.
+pub fn schedule_at(deadline: u64, callback_id: u64) -> Result<(), &'static str> {
+    defer::without_interrupts(|| {
+        WHEEL.with_borrow_mut(|wheel| {
+            wheel.schedule(Deadline {
+                at: deadline,
+                callback_id,
+            })
+        })
+    })
 }
 
 /// Retrieve a reference to the local APIC.
@@ -305,7 +555,7 @@ pub fn local() -> &'static LocalApic {
         if cpuid().features.has_x2apic() {
             LocalApic::X2Apic
         } else {
-            LocalApic::XApic(linker::LOCAL_APIC_ADDRESS as *mut [u32; 0x400])
+            LocalApic::XApic(unsafe { Mmio::new(linker::LOCAL_APIC_ADDRESS as *mut u8) })
         }
     })
 }
@@ -1,26 +1,35 @@
 use core::{
-    panic,
+    mem, panic,
     sync::atomic::{compiler_fence, AtomicBool, AtomicU32, Ordering},
 };
 
 use acpi::{
-    madt::{ApicStructureKind, LocalApicFlags},
-    AcpiTables, TableKind,
+    facs::Facs,
+    fadt::Fadt,
+    madt::{ApicStructureKind, LocalApicFlags, MpsIntiFlags, MultiProcessorWakeupMailbox},
+    sdt::SdtHeader,
+    AcpiTable, AcpiTables, TableIndex,
 };
-use heapless::Vec;
-use multiboot2::{MemoryAreaType, MemoryMapTag};
+use heapless::{String, Vec};
+use multiboot2::{BootInformation, MemoryAreaType, MemoryMapTag};
 use spin::Once;
 
 use crate::{
-    idt, include_asm, linker,
+    hpet, idt, include_asm,
+    ioapic::registers::{InterruptPinPolarity, TriggerMode},
+    linker,
     mm::{
         self,
         desc::{MemoryDescriptor, Region},
+        paging,
     },
-    pic, println, smp,
+    pic, pm_timer, power, println, smp,
+    stacks::StackTop,
+    sync::{self, Backoff},
+    time,
 };
 
-mod early;
+pub mod early;
 pub mod serial_console;
 
 include_asm! {
@@ -34,18 +43,157 @@ const AP_BOOTCODE: u8 = (0x8000 >> 12) as u8;
 
 static CPU_INFO: Once<Vec<CpuInfo, { linker::MAX_CPUS }>> = Once::new();
 
+/// The interrupt controllers found in the MADT, kept around after boot.
+static APIC_INFO: Once<ApicInfo> = Once::new();
+
+/// The maximum number of interrupt source overrides we keep track of.
+const MAX_INT_OVERRIDES: usize = 16;
+
+/// Physical address of the multiprocessor wakeup mailbox, if present.
+static MP_WAKEUP_MAILBOX: Once<u64> = Once::new();
+
+/// The maximum number of boot modules we keep track of.
+pub const MAX_MODULES: usize = 4;
+
+/// The maximum length of a module command line, longer ones are truncated.
+pub const MAX_MODULE_CMDLINE: usize = 64;
+
+static MODULES: Once<Vec<Module, MAX_MODULES>> = Once::new();
+
 #[derive(Debug)]
 pub struct CpuInfo {
     pub apic_id: u32,
-    pub stack: u64,
+    pub stack: StackTop,
     pub percpu_offset: u64,
 }
 
+/// An interrupt source override.
+///
+/// Describes how an ISA interrupt source maps onto a global system interrupt.
+/// See ACPI v6.4 section 5.2.12.5
+#[derive(Debug, Clone, Copy)]
+pub struct IntSourceOverride {
+    pub source: u8,
+    pub global_system_interrupt: u32,
+    pub polarity: InterruptPinPolarity,
+    pub trigger_mode: TriggerMode,
+}
+
+impl IntSourceOverride {
+    /// Decode an override from its MADT flags.
+    ///
+    /// Conforming (and reserved) values fall back to the ISA defaults, which are
+    /// active high and edge triggered.
+    fn new(source: u8, global_system_interrupt: u32, flags: MpsIntiFlags) -> Self {
+        let polarity = match flags.bits() & 0b11 {
+            0b11 => InterruptPinPolarity::LowActive,
+            _ => InterruptPinPolarity::HighActive,
+        };
+
+        let trigger_mode = match (flags.bits() >> 2) & 0b11 {
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        };
+
+        Self {
+            source,
+            global_system_interrupt,
+            polarity,
+            trigger_mode,
+        }
+    }
+}
+
+/// An IOAPIC, as described by the MADT.
+///
+/// See ACPI v6.4 section 5.2.12.3
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+
+    /// Physical address of the MMIO registers.
+    pub address: u32,
+
+    /// The first global system interrupt handled by this IOAPIC. Its pins map
+    /// onto consecutive interrupts starting here.
+    pub gsi_base: u32,
+}
+
+/// A module loaded by the bootloader, like an initrd.
+#[derive(Debug, Clone)]
+pub struct Module {
+    /// Physical start address.
+    pub start: u64,
+
+    /// Physical end address (exclusive).
+    pub end: u64,
+
+    pub cmdline: String<MAX_MODULE_CMDLINE>,
+}
+
+impl Module {
+    /// The physical memory occupied by the module.
+    pub fn region(&self) -> Region {
+        Region {
+            base: self.start,
+            length: (self.end - self.start) as usize,
+        }
+    }
+
+    /// Return the contents of the module.
+    ///
+    /// The module is read through the physical memory window, which keeps it
+    /// read-only. The window is extended to cover modules during boot, this
+    /// fails for the ones that couldn't be covered.
+    pub fn data(&self) -> Result<&'static [u8], &'static str> {
+        let region = self.region();
+        if !mm::phys_window_contains(region.base, region.length) {
+            return Err("module not covered by the physical memory window");
+        }
+
+        unsafe {
+            Ok(core::slice::from_raw_parts(
+                (linker::PHYS_OFFSET + region.base) as *const u8,
+                region.length,
+            ))
+        }
+    }
+}
+
+/// Return the modules loaded by the bootloader.
+pub fn modules() -> &'static [Module] {
+    MODULES.get().map_or(&[], |modules| modules.as_slice())
+}
+
+/// Return the offset of the per-CPU region of the CPU with the given APIC ID.
+pub fn percpu_offset(apic_id: u32) -> Option<u64> {
+    CPU_INFO
+        .get()?
+        .iter()
+        .find(|cpu| cpu.apic_id == apic_id)
+        .map(|cpu| cpu.percpu_offset)
+}
+
+/// Return the interrupt controllers found in the MADT.
+///
+/// Panics if called before the ACPI tables were parsed in `pre_boot`.
+pub fn apic_info() -> &'static ApicInfo {
+    APIC_INFO.get().expect("APIC info not present!")
+}
+
 #[derive(Debug)]
 pub struct ApicInfo {
     pub local_apic_address: u64,
     pub apic_ids: Vec<u32, { linker::MAX_CPUS }>,
-    pub io_apics: Vec<u32, { linker::MAX_IOAPICS }>,
+
+    /// The IOAPICs, sorted by GSI base. They are mapped in this order, starting
+    /// at [linker::IO_APIC_OFFSET].
+    pub io_apics: Vec<IoApicInfo, { linker::MAX_IOAPICS }>,
+
+    pub mp_wakeup_mailbox: Option<u64>,
+
+    /// Interrupt source overrides, sorted by source.
+    pub overrides: Vec<IntSourceOverride, MAX_INT_OVERRIDES>,
 }
 
 impl ApicInfo {
@@ -57,52 +205,150 @@ impl ApicInfo {
     pub fn num_cpus(&self) -> usize {
         self.apic_ids.len()
     }
+
+    /// Find the IOAPIC handling the given global system interrupt.
+    ///
+    /// Returns the index of the IOAPIC in [io_apics](ApicInfo::io_apics), and
+    /// the pin the interrupt is connected to. Whether the IOAPIC actually has
+    /// that many pins isn't checked, this requires reading its version register.
+    pub fn find_io_apic(&self, gsi: u32) -> Option<(usize, u32)> {
+        let idx = self
+            .io_apics
+            .partition_point(|io_apic| io_apic.gsi_base <= gsi)
+            .checked_sub(1)?;
+        Some((idx, gsi - self.io_apics[idx].gsi_base))
+    }
+
+    /// Find the override for the given ISA interrupt source.
+    pub fn find_override(&self, source: u8) -> Option<&IntSourceOverride> {
+        self.overrides
+            .binary_search_by_key(&source, |o| o.source)
+            .ok()
+            .map(|idx| &self.overrides[idx])
+    }
+}
+
+/// Return the end of the highest memory area in the memory map, of any kind.
+fn max_phys_address(mmap: &MemoryMapTag) -> u64 {
+    mmap.all_memory_areas()
+        .map(|area| area.end_address())
+        .max()
+        .unwrap_or(0)
 }
 
 /// Parse the memory map provided by multiboot2 into our own descriptors.
+///
+/// Adjacent or overlapping areas of the same kind are merged while parsing, to
+/// make fragmented maps fit in [crate::MAX_MEM_REGIONS] descriptors. Anything
+/// that still doesn't fit is dropped.
 fn parse_memory_map(mmap: &MemoryMapTag) -> Vec<MemoryDescriptor, { crate::MAX_MEM_REGIONS }> {
-    Vec::from_iter(mmap.all_memory_areas().map(|area| MemoryDescriptor {
-        kind: match area.typ() {
-            MemoryAreaType::Available => mm::desc::MemoryKind::Usable,
-            MemoryAreaType::Reserved => mm::desc::MemoryKind::Reserved,
-            MemoryAreaType::AcpiAvailable => mm::desc::MemoryKind::AcpiReclaimable,
-            MemoryAreaType::ReservedHibernate => mm::desc::MemoryKind::AcpiNvs,
-            MemoryAreaType::Defective => mm::desc::MemoryKind::Defective,
-        },
-        region: Region {
-            base: area.start_address(),
-            length: area.size() as usize,
-        },
-    }))
+    let mut descriptors: Vec<MemoryDescriptor, { crate::MAX_MEM_REGIONS }> = Vec::new();
+
+    for area in mmap.all_memory_areas() {
+        let desc = MemoryDescriptor {
+            kind: match area.typ() {
+                MemoryAreaType::Available => mm::desc::MemoryKind::Usable,
+                MemoryAreaType::Reserved => mm::desc::MemoryKind::Reserved,
+                MemoryAreaType::AcpiAvailable => mm::desc::MemoryKind::AcpiReclaimable,
+                MemoryAreaType::ReservedHibernate => mm::desc::MemoryKind::AcpiNvs,
+                MemoryAreaType::Defective => mm::desc::MemoryKind::Defective,
+            },
+            region: Region {
+                base: area.start_address(),
+                length: area.size() as usize,
+            },
+        };
+
+        if let Some(last) = descriptors.last_mut() {
+            if last.kind == desc.kind {
+                if let Some(region) = Region::merge(&last.region, &desc.region) {
+                    last.region = region;
+                    continue;
+                }
+            }
+        }
+
+        if descriptors.push(desc).is_err() {
+            println!("Too many memory regions, ignoring {:?}", desc);
+        }
+    }
+
+    descriptors
 }
 
-/// Parse the ACPI tables (at least the ones we use).
-fn parse_acpi(acpi_tables: &AcpiTables) -> ApicInfo {
-    let madt = acpi_tables
-        .iter()
-        .find_map(|table| {
-            if let TableKind::Madt(madt) = table {
-                Some(madt)
-            } else {
-                None
+/// Returns true if the kernel command line contains `flag`, with or without a
+/// leading `--`.
+fn has_cmdline_flag(boot_info: &BootInformation, flag: &str) -> bool {
+    boot_info.command_line_tag().map_or(false, |tag| {
+        tag.command_line()
+            .trim_end_matches('\0')
+            .split_whitespace()
+            .any(|arg| arg.trim_start_matches("--") == flag)
+    })
+}
+
+/// Parse the module tags provided by multiboot2.
+///
+/// Only up to [MAX_MODULES] modules are kept, any others are reported and
+/// ignored.
+fn parse_modules(boot_info: &BootInformation) -> Vec<Module, MAX_MODULES> {
+    let mut modules = Vec::new();
+
+    for tag in boot_info.module_tags() {
+        let mut cmdline = String::new();
+        for c in tag.cmdline().chars() {
+            if cmdline.push(c).is_err() {
+                println!("Module command line too long, truncated to {:?}", cmdline);
+                break;
             }
-        })
-        .expect("MADT not present!");
+        }
 
+        let module = Module {
+            start: tag.start_address() as u64,
+            end: tag.end_address() as u64,
+            cmdline,
+        };
+
+        if let Err(module) = modules.push(module) {
+            println!("Too many modules, ignoring {:?}", module);
+        }
+    }
+
+    modules
+}
+
+/// Parse the ACPI tables (at least the ones we use).
+///
+/// Only up to [linker::MAX_CPUS] CPUs and [linker::MAX_IOAPICS] IOAPICs are
+/// kept, any others are reported and ignored.
+fn parse_acpi(acpi_tables: &TableIndex) -> ApicInfo {
+    let madt = acpi_tables.madt().expect("MADT not present!");
+
+    // The structures are still parsed, but anything past the first malformed
+    // one is ignored.
+    if let Err(err) = madt.validate() {
+        println!("Malformed MADT: {}", err);
+    }
+
+    // CPUs and IOAPICs beyond what we support are ignored, but counted so we
+    // can tell how many we're missing out on.
     let mut cpu_ids: Vec<u32, { linker::MAX_CPUS }> = Vec::new();
+    let mut num_cpus = 0;
     let mut add_cpu = |flags: LocalApicFlags, apic_id| {
-        if !cpu_ids.is_full() && flags.bits() == LocalApicFlags::ENABLED.bits() {
-            cpu_ids.push(apic_id).unwrap();
+        if flags.bits() == LocalApicFlags::ENABLED.bits() {
+            num_cpus += 1;
+            let _ = cpu_ids.push(apic_id);
         }
     };
 
     let mut local_apic_address = madt.local_apic_address as u64;
 
-    let mut io_apics = Vec::new();
+    let mut mp_wakeup_mailbox = None;
 
     // ACPI spec dictates the BSP is the first entry in the table. Additionally,
     // the lists contains the first logical processor of each of the possible
-    // individual multithreaded processors.
+    // individual multithreaded processors. Local APIC and x2APIC entries can be
+    // mixed, so they are walked together to keep that order.
     for ics in madt.iter() {
         match ics {
             // If an override address is present, we MUST use that instead.
@@ -111,20 +357,58 @@ fn parse_acpi(acpi_tables: &AcpiTables) -> ApicInfo {
             }
             ApicStructureKind::ProcessorLocalApic(apic) => add_cpu(apic.flags, apic.apic_id as u32),
             ApicStructureKind::ProcessorLocalX2Apic(xapic) => add_cpu(xapic.flags, xapic.x2apic_id),
-            ApicStructureKind::IoApic(ioapic) => {
-                // TODO: Check if we're exceeding max, and just continue with however many we can support.
-                io_apics
-                    .push(ioapic.io_apic_address)
-                    .expect("Failed to push ioapic");
+            // If present, APs have to be started through the mailbox.
+            ApicStructureKind::MultiprocessorWakeup(wakeup) => {
+                mp_wakeup_mailbox = Some(wakeup.mailbox_address);
             }
             _ => {}
         }
     }
 
+    let mut io_apics: Vec<IoApicInfo, { linker::MAX_IOAPICS }> = Vec::new();
+    let mut num_io_apics = 0;
+    for ioapic in madt.io_apics() {
+        num_io_apics += 1;
+        let _ = io_apics.push(IoApicInfo {
+            id: ioapic.io_apic_id,
+            address: ioapic.io_apic_address,
+            gsi_base: ioapic.global_system_interrupt_base,
+        });
+    }
+
+    let mut overrides: Vec<IntSourceOverride, MAX_INT_OVERRIDES> = Vec::new();
+    for iso in madt.interrupt_overrides() {
+        let iso = IntSourceOverride::new(iso.source, iso.global_system_interrupt, iso.flags);
+        if overrides.push(iso).is_err() {
+            println!("Too many interrupt source overrides, ignoring {:?}", iso);
+        }
+    }
+
+    if num_cpus > cpu_ids.len() {
+        println!(
+            "Found {} CPUs, only using {} (MAX_CPUS)",
+            num_cpus,
+            cpu_ids.len()
+        );
+    }
+
+    if num_io_apics > io_apics.len() {
+        println!(
+            "Found {} IOAPICs, only using {} (MAX_IOAPICS)",
+            num_io_apics,
+            io_apics.len()
+        );
+    }
+
+    io_apics.sort_unstable_by_key(|io_apic| io_apic.gsi_base);
+    overrides.sort_unstable_by_key(|o| o.source);
+
     ApicInfo {
         local_apic_address,
         apic_ids: cpu_ids,
         io_apics,
+        mp_wakeup_mailbox,
+        overrides,
     }
 }
 
@@ -148,7 +432,7 @@ extern "C" fn boot_ap(stack: u64, percpu_offset: u64) {
     );
 
     // Init the CPU.
-    crate::init(stack, percpu_offset);
+    crate::init(StackTop::new(stack), percpu_offset);
 
     // Ready to start doing work.
     crate::start();
@@ -156,7 +440,7 @@ extern "C" fn boot_ap(stack: u64, percpu_offset: u64) {
 
 /// Boot the BSP.
 extern "C" fn boot_bsp(stack: u64, percpu_offset: u64) -> ! {
-    crate::init(stack, percpu_offset);
+    crate::init(StackTop::new(stack), percpu_offset);
 
     let aps = CPU_INFO
         .get()
@@ -172,26 +456,103 @@ extern "C" fn boot_bsp(stack: u64, percpu_offset: u64) -> ! {
         )
     };
 
+    let mailbox = MP_WAKEUP_MAILBOX
+        .get()
+        .map(|address| (linker::PHYS_OFFSET + address) as *mut MultiProcessorWakeupMailbox);
+
     // It's up to us to bring up the APs.
     for ap in aps {
         AP_BUSY.store(true, Ordering::SeqCst);
 
-        unsafe { bootstrap.try_start_ap(ap.apic_id, ap.stack, ap.percpu_offset) }
+        let started = unsafe {
+            if let Some(mailbox) = mailbox {
+                bootstrap.try_wakeup_ap(mailbox, ap.apic_id, ap.stack.get(), ap.percpu_offset)
+            } else {
+                bootstrap.try_start_ap(ap.apic_id, ap.stack.get(), ap.percpu_offset)
+            }
+        };
+
+        // Skip cores that don't respond. They no longer count towards the
+        // number of CPUs, or the others would wait for them at the barrier.
+        if let Err(err) = started {
+            println!("Failed to start CPU {}: {}", ap.apic_id, err);
+            crate::NUM_CPUS.fetch_sub(1, Ordering::SeqCst);
+            AP_BUSY.store(false, Ordering::SeqCst);
+            continue;
+        }
 
         // Wait until the AP is done setting up.
+        let backoff = Backoff::new();
         while AP_BUSY.load(Ordering::SeqCst) {
-            core::hint::spin_loop();
+            backoff.snooze(sync::nap);
         }
     }
 
     crate::start();
 }
 
+/// Make sure the firmware structure of `len` bytes at physical address `phys`
+/// is reachable through the physical memory window, before it's dereferenced.
+fn map_firmware(phys: u64, len: usize, what: &str) {
+    if !mm::phys_window_contains(phys, len) {
+        if let Err(err) = mm::extend_phys_window(phys, len) {
+            panic!("Failed to map the {} at {:#x}: {}", what, phys, err);
+        }
+    }
+}
+
+/// Make sure the table at physical address `phys` is reachable through the
+/// physical memory window, and return its length.
+///
+/// # Safety
+/// `phys` must point to an ACPI table.
+unsafe fn map_acpi_table(phys: u64) -> usize {
+    map_firmware(phys, mem::size_of::<SdtHeader>(), "ACPI table header");
+    let header = &*((phys + linker::PHYS_OFFSET) as *const SdtHeader);
+    let length = header.length as usize;
+    map_firmware(phys, length, "ACPI table");
+    length
+}
+
+/// Make sure the RSDT (or XSDT if `extended`) at physical address `phys`, every
+/// table it lists, and the DSDT and FACS referenced by the FADT are reachable
+/// through the physical memory window.
+///
+/// # Safety
+/// `phys` must point to an RSDT (or XSDT).
+unsafe fn map_acpi_tables(phys: u64, extended: bool) {
+    let length = map_acpi_table(phys);
+    let entry_size = if extended { 8 } else { 4 };
+    let entries = (phys + linker::PHYS_OFFSET) as usize + mem::size_of::<SdtHeader>();
+    let num_entries = length.saturating_sub(mem::size_of::<SdtHeader>()) / entry_size;
+
+    for x in 0..num_entries {
+        let entry = entries + x * entry_size;
+        let table = if extended {
+            (entry as *const u64).read_unaligned()
+        } else {
+            (entry as *const u32).read_unaligned() as u64
+        };
+        map_acpi_table(table);
+
+        let header = &*((table + linker::PHYS_OFFSET) as *const SdtHeader);
+        if header.signature == Fadt::SIGNATURE {
+            let fadt = &*(header as *const SdtHeader as *const Fadt);
+            if let Some(dsdt) = fadt.dsdt_address() {
+                map_acpi_table(dsdt as u64);
+            }
+            if let Some(facs) = fadt.facs_address() {
+                map_firmware(facs as u64, mem::size_of::<Facs>(), "FACS");
+            }
+        }
+    }
+}
+
 /// Switch stack and jump into [`boot_bsp`].
 ///
 /// # Safety
 /// This function updates the current stack pointer.
-unsafe fn switch_stack_and_boot(new_stack: u64, percpu_offset: u64) -> ! {
+unsafe fn switch_stack_and_boot(new_stack: StackTop, percpu_offset: u64) -> ! {
     core::arch::asm!(
         "
         movq    {stack}, %rdi
@@ -202,7 +563,7 @@ unsafe fn switch_stack_and_boot(new_stack: u64, percpu_offset: u64) -> ! {
         pushq   %rax
         retq
         ",
-        stack = in(reg) new_stack,
+        stack = in(reg) new_stack.get(),
         percpu_offset = in(reg) percpu_offset,
         boot = sym boot_bsp,
         options(att_syntax, noreturn)
@@ -226,9 +587,25 @@ unsafe extern "C" fn pre_boot(multiboot_info_ptr: u64) {
     // Setup some form of output ASAP.
     serial_console::init();
 
+    // The physical memory window is sized after the memory map, so that has to
+    // be read first. The boot info is placed below 4G, which is still identity
+    // mapped at this point. The memory descriptors are kept for later, so the
+    // map is only parsed once.
+    let (mem_descriptors, max_phys) = unsafe {
+        let boot_info =
+            multiboot2::load(multiboot_info_ptr as usize).expect("Failed to read multiboot2 info!");
+        let mmap = boot_info
+            .memory_map_tag()
+            .expect("Memory map not provided by bootloader!");
+
+        // Translate the memory descriptors provided by the bootloader into a
+        // format we understand.
+        (parse_memory_map(mmap), max_phys_address(mmap))
+    };
+
     // Prepare for switching to proper page tables, and allocating per-cpu
     // structures.
-    mm::init();
+    mm::init(max_phys);
 
     // We're using x(2)APIC, so disable the PIC before we enable APIC.
     pic::remap(0x20, 0x28);
@@ -245,32 +622,95 @@ unsafe extern "C" fn pre_boot(multiboot_info_ptr: u64) {
 
     // Physical memory should be availabl at [linker::PHYS_OFFSET] now, so we
     // can safely
-    // load the boot info.
+    // load the boot info. The first 8 bytes hold its total size.
+    map_firmware(multiboot_info_ptr, 8, "multiboot2 info");
+    let total_size = unsafe { *((multiboot_info_ptr + linker::PHYS_OFFSET) as *const u32) };
+    map_firmware(multiboot_info_ptr, total_size as usize, "multiboot2 info");
+
     let boot_info = unsafe {
         multiboot2::load_with_offset(multiboot_info_ptr as usize, linker::PHYS_OFFSET as usize)
             .expect("Failed to read multiboot2 info!")
     };
 
+    if has_cmdline_flag(&boot_info, "color") {
+        serial_console::enable_color();
+    }
+
     // Now the ACPI tables are available as well. We access them through the
     // physical memory window.
-    let acpi_address = if let Some(xsdt) = boot_info.rsdp_v2_tag() {
-        xsdt.xsdt_address()
+    let (acpi_address, extended) = if let Some(xsdt) = boot_info.rsdp_v2_tag() {
+        (xsdt.xsdt_address(), true)
     } else if let Some(rsdp) = boot_info.rsdp_v1_tag() {
-        rsdp.rsdt_address()
+        (rsdp.rsdt_address(), false)
     } else {
         panic!("No ACPI info!")
     };
 
+    // Firmware may place the tables beyond the physical memory window.
+    unsafe { map_acpi_tables(acpi_address as u64, extended) };
+
     let acpi_tables = unsafe {
         AcpiTables::from_address(acpi_address, linker::PHYS_OFFSET as usize)
             .expect("Failed to read the ACPI tables!")
     };
 
+    for (address, err) in acpi_tables.iter_invalid() {
+        println!("Skipping ACPI table at {:#x}: {}", address, err);
+    }
+
+    // Look up every table once, instead of walking the RSDT/XSDT every time.
+    let index = acpi_tables.index();
+
+    match index.fadt() {
+        Some(fadt) => {
+            println!("Platform: {:?}", fadt.summary());
+            if let Err(err) = pm_timer::init(fadt) {
+                println!("PM timer unavailable: {}", err);
+            }
+            match acpi::power::pm1_status(fadt, &mut power::Access) {
+                Ok(status) => println!("PM1 status: {:#06x}", status),
+                Err(err) => println!("PM1 status unavailable: {}", err),
+            }
+        }
+        None => println!("No FADT, assuming a legacy PC"),
+    }
+
+    if has_cmdline_flag(&boot_info, "dump-acpi") {
+        let mut console = serial_console::CONSOLE.lock();
+        if let Some(madt) = index.madt() {
+            let _ = madt.summarize(&mut *console);
+        }
+        if let Some(fadt) = index.fadt() {
+            let _ = fadt.summarize(&mut *console);
+        }
+    }
+
+    if let Some(table) = index.hpet() {
+        match hpet::init(table) {
+            Ok(()) => {
+                let start = hpet::counter();
+                println!("HPET period: {}fs", hpet::period_fs());
+                let elapsed = (hpet::counter() - start) * hpet::period_fs() as u64 / 1_000_000;
+                println!("  printing took {}ns", elapsed);
+            }
+            Err(err) => println!("HPET unavailable: {}", err),
+        }
+    }
+
+    match time::init() {
+        Ok(source) => println!("Clock source: {:?}", source),
+        Err(err) => println!("No monotonic clock: {}", err),
+    }
+
     // Find the APIC info. We need it to find out how many cores are available.
-    let apic_info = parse_acpi(&acpi_tables);
+    let apic_info = parse_acpi(&index);
+
+    // The index borrows the tables, which are moved into ACPI_TABLES below.
+    drop(index);
 
     // There must be at least 1 CPU. If there isn't, something is wrong.
     assert!(apic_info.num_cpus() >= 1);
+    crate::NUM_CPUS.store(apic_info.num_cpus() as u32, Ordering::Relaxed);
 
     if apic_info.num_cpus() > 1 {
         // Setup the AP bootcode in case there's more than one processor.
@@ -281,38 +721,93 @@ unsafe extern "C" fn pre_boot(multiboot_info_ptr: u64) {
     // is mapped either way.
     mm::map_apic(apic_info.local_apic_address, &apic_info.io_apics);
 
-    // Translate the memory descriptors provided by the bootloader into a
-    // format we understand.
-    let mem_descriptors = parse_memory_map(
-        boot_info
-            .memory_map_tag()
-            .expect("Memory map not provided by bootloader!"),
-    );
-
     // Setup available memory for per-CPU data.
     mm::init_memory(&mem_descriptors);
 
+    // Everything below is one of the boot reservations, so the allocator has
+    // room to keep all of them out of the way (see `mm::reserve_memory`).
+    let reserve = |region: Region, what: &str| {
+        if let Err(err) = mm::reserve_memory(region) {
+            panic!("Failed to reserve the {} at {:?}: {:?}", what, region, err);
+        }
+    };
+
+    // Keep the real mode IVT and BIOS data area intact.
+    reserve(
+        Region {
+            base: 0,
+            length: paging::BASE_PAGE,
+        },
+        "IVT",
+    );
+
+    if apic_info.num_cpus() > 1 {
+        reserve(
+            Region {
+                base: (AP_BOOTCODE as u64) << 12,
+                length: mm::AP_BOOTCODE_SIZE,
+            },
+            "AP bootcode",
+        );
+    }
+
+    if let Some(framebuffer) = boot_info.framebuffer_tag() {
+        reserve(
+            Region {
+                base: framebuffer.address,
+                length: framebuffer.pitch as usize * framebuffer.height as usize,
+            },
+            "framebuffer",
+        );
+    }
+
+    // The modules must be out of the way before anything is allocated.
+    let modules = parse_modules(&boot_info);
+    for module in modules.iter() {
+        let region = module.region();
+        reserve(region, "module");
+
+        if !mm::phys_window_contains(region.base, region.length) {
+            if let Err(err) = mm::extend_phys_window(region.base, region.length) {
+                println!("Failed to map module {:?}: {}", module.cmdline, err);
+            }
+        }
+    }
+    MODULES.call_once(|| modules);
+
+    // Give back whatever ACPI memory isn't occupied by tables we still use.
+    mm::reclaim_acpi(&acpi_tables, &mem_descriptors);
+
     // Allocate memory for every core.
     let per_cpus = mm::allocate_percpus(apic_info.num_cpus());
 
+    // Only the CPUs we could allocate for are started.
+    crate::NUM_CPUS.store(per_cpus.len() as u32, Ordering::Relaxed);
+
     CPU_INFO.call_once(|| {
         apic_info
             .apic_ids
-            .into_iter()
+            .iter()
+            .copied()
             .zip(per_cpus.into_iter())
             .map(|(apic_id, percpu)| CpuInfo {
                 apic_id,
-                stack: percpu.stack.as_ptr() as u64 + percpu.stack.len() as u64,
+                stack: StackTop::new(percpu.stack.as_ptr() as u64 + percpu.stack.len() as u64),
                 percpu_offset: percpu.storage,
             })
             .collect::<Vec<CpuInfo, { linker::MAX_CPUS }>>()
     });
 
+    if let Some(mailbox) = apic_info.mp_wakeup_mailbox {
+        MP_WAKEUP_MAILBOX.call_once(|| mailbox);
+    }
+
     // Make ACPI tables available to everyone.
     crate::ACPI_TABLES.call_once(|| acpi_tables);
+    APIC_INFO.call_once(|| apic_info);
 
     unsafe {
-        let bsp = CPU_INFO.get_unchecked().first().unwrap();
+        let bsp = CPU_INFO.get_unchecked().first().expect("No BSP info!");
         switch_stack_and_boot(bsp.stack, bsp.percpu_offset)
     }
 }
@@ -38,7 +38,7 @@ pub static mut BOOT_STACK: Stack = Stack([0u8; linker::STACK_SIZE]);
 /// space.
 #[used]
 #[no_mangle]
-static BOOT_PML4: PML4 = PML4::zero();
+pub static mut BOOT_PML4: PML4 = PML4::zero();
 
 /// The level 3 page table, initialised in `head.S`. It contains six entries.
 /// The first four entries point to each of the level 2 page directories in
@@ -46,7 +46,7 @@ static BOOT_PML4: PML4 = PML4::zero();
 /// first two page directories in [BOOT_PDS].
 #[used]
 #[no_mangle]
-static BOOT_PDPT: PDPT = PDPT::zero();
+pub static mut BOOT_PDPT: PDPT = PDPT::zero();
 
 /// The level 2 page directories, initialised in `head.S`. Each of the four
 /// directories maps 1G of memory using 2M pages. The full 32bit address
@@ -1,26 +1,208 @@
+use core::{
+    fmt::{self, Write},
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use acpi::sdt::SdtHeader;
+use heapless::HistoryBuffer;
 use spin::Mutex;
 use uart_16550::SerialPort;
 
 pub const DEFAULT_PORT: u16 = 0x3f8;
 
-pub static SERIAL_PORT: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(DEFAULT_PORT) });
+/// The number of bytes of output kept around for [replay].
+pub const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+pub static CONSOLE: Mutex<Console> = Mutex::new(unsafe { Console::new(DEFAULT_PORT) });
+
+/// Whether the colors set with [set_color] are sent to the serial port.
+static COLOR: AtomicBool = AtomicBool::new(cfg!(feature = "color"));
+
+/// A foreground color, the value is its ANSI SGR parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Color {
+    Red = 31,
+    Green = 32,
+    Yellow = 33,
+    Blue = 34,
+    Magenta = 35,
+    Cyan = 36,
+}
+
+impl Color {
+    /// Return the color used for output of the CPU with the given APIC ID.
+    ///
+    /// Red and yellow are left for errors and warnings.
+    pub const fn for_cpu(apic_id: u32) -> Self {
+        const COLORS: [Color; 4] = [Color::Green, Color::Blue, Color::Magenta, Color::Cyan];
+        COLORS[apic_id as usize % COLORS.len()]
+    }
+}
+
+/// The serial port, along with a copy of the most recent output.
+///
+/// Everything is written to the serial port first. The copy only exists so the
+/// early boot log can be shown on consoles that are set up later on (see
+/// [replay]). Once the buffer is full, the oldest output is dropped.
+pub struct Console {
+    port: SerialPort,
+    log: HistoryBuffer<u8, LOG_BUFFER_SIZE>,
+}
+
+impl Console {
+    /// # Safety
+    /// `base` must be the I/O port of a serial port.
+    const unsafe fn new(base: u16) -> Self {
+        Self {
+            port: SerialPort::new(base),
+            log: HistoryBuffer::new(),
+        }
+    }
+
+    /// Send a single byte.
+    pub fn send(&mut self, byte: u8) {
+        self.port.send(byte);
+        self.log.write(byte);
+    }
+
+    /// Print anything that follows in the given color.
+    ///
+    /// Does nothing unless colors are enabled (see [enable_color]). The escape
+    /// sequence isn't kept for [replay], since other consoles may not
+    /// understand it.
+    pub fn set_color(&mut self, color: Color) {
+        if COLOR.load(Ordering::Relaxed) {
+            let tens = color as u8 / 10;
+            for byte in [0x1b, b'[', b'0' + tens, b'0' + color as u8 % 10, b'm'] {
+                self.port.send(byte);
+            }
+        }
+    }
+
+    /// Go back to the default color of the terminal.
+    pub fn reset_color(&mut self) {
+        if COLOR.load(Ordering::Relaxed) {
+            for &byte in b"\x1b[0m" {
+                self.port.send(byte);
+            }
+        }
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
 
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {{
         use core::fmt::Write;
-        let mut port = $crate::boot::serial_console::SERIAL_PORT.lock();
-        port.write_fmt(format_args!($($arg)*)).unwrap();
+        let mut console = $crate::boot::serial_console::CONSOLE.lock();
+        console.write_fmt(format_args!($($arg)*)).unwrap();
     }}
 }
 
 #[macro_export]
 macro_rules! println {
+    () => ($crate::print!("\n"));
     ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Turn on the colors set with [set_color], even without the `color` feature.
+///
+/// Terminals that don't support ANSI escape sequences show them as garbage,
+/// so this is opt-in.
+pub fn enable_color() {
+    COLOR.store(true, Ordering::Relaxed);
+}
+
+/// Print anything that follows in the given color (see [Console::set_color]).
+pub fn set_color(color: Color) {
+    CONSOLE.lock().set_color(color);
+}
+
+/// Go back to the default color of the terminal.
+pub fn reset_color() {
+    CONSOLE.lock().reset_color();
+}
+
+/// Forcefully release the serial port lock.
+///
+/// # Safety
+/// Only to be used when the current holder of the lock will never release it,
+/// for example when panicking.
+pub unsafe fn force_unlock() {
+    CONSOLE.force_unlock();
+}
+
+/// Write the buffered output to `writer`, oldest first.
+///
+/// This is meant to bring a newly initialised console up to date. Only the last
+/// [LOG_BUFFER_SIZE] bytes are kept, and anything that isn't ASCII (e.g. a
+/// character cut in half when the buffer wrapped) is replaced with `?`. The
+/// console stays locked meanwhile, so `writer` must not print.
+pub fn replay(writer: &mut impl Write) -> fmt::Result {
+    let console = CONSOLE.lock();
+    for &byte in console.log.oldest_ordered() {
+        writer.write_char(if byte.is_ascii() { byte as char } else { '?' })?;
+    }
+    Ok(())
+}
+
+/// Print a hex dump of the given bytes.
+///
+/// Every line shows the address (starting at `base`), 16 bytes in hex, and the
+/// same bytes as ASCII, with non-printable characters shown as dots. The port
+/// is locked for the whole dump, so it isn't interleaved with other output.
+pub fn hexdump(bytes: &[u8], base: u64) {
+    let mut port = CONSOLE.lock();
+
+    for (idx, line) in bytes.chunks(16).enumerate() {
+        write!(port, "{:016x}: ", base + idx as u64 * 16).unwrap();
+
+        for col in 0..16 {
+            match line.get(col) {
+                Some(byte) => write!(port, "{:02x} ", byte),
+                None => write!(port, "   "),
+            }
+            .unwrap();
+
+            if col == 7 {
+                port.send(b' ');
+            }
+        }
+
+        port.send(b'|');
+        for &byte in line {
+            port.send(if byte.is_ascii_graphic() || byte == b' ' {
+                byte
+            } else {
+                b'.'
+            });
+        }
+        writeln!(port, "|").unwrap();
+    }
+}
+
+/// Print a hex dump of an ACPI table, including its header.
+///
+/// # Safety
+/// The table must be mapped for `header.length` bytes.
+pub unsafe fn dump_table(header: &SdtHeader) {
+    let length = header.length as usize;
+    let bytes = slice::from_raw_parts(header as *const SdtHeader as *const u8, length);
+    hexdump(bytes, header as *const SdtHeader as u64);
+}
+
 /// Initialise the serial console.
 pub fn init() {
-    SERIAL_PORT.lock().init();
+    CONSOLE.lock().port.init();
 }
@@ -1,14 +1,164 @@
+use core::{
+    arch::{asm, x86_64::__cpuid_count},
+    cell::OnceCell,
+    ptr,
+};
+
+use heapless::Vec;
 use spin::Once;
 use x86::{
+    bits64::segmentation::{rdfsbase, rdgsbase, wrfsbase, wrgsbase},
     controlregs::{cr0, cr0_write, cr4, cr4_write, Cr0, Cr4},
     cpuid::{
         self, ExtendedFeatures, ExtendedProcessorFeatureIdentifiers, ExtendedStateInfo,
-        FeatureInfo, ProcessorBrandString, VendorInfo,
+        FeatureInfo, ProcessorBrandString, TopologyType, VendorInfo,
+    },
+    irq,
+    msr::{
+        self, rdmsr, wrmsr, IA32_EFER, IA32_FS_BASE, IA32_GS_BASE, IA32_KERNEL_GSBASE, IA32_TSC_AUX,
     },
-    msr::{self, rdmsr, wrmsr, IA32_EFER},
+    time,
+};
+
+use crate::{
+    apic::{self, ApicAccess},
+    idt::handler::Frame,
+    percpu, pm_timer, println, stacks,
 };
 
-use crate::println;
+percpu! {
+    /// The APIC ID of the current CPU.
+    static APIC_ID: OnceCell<u32> = OnceCell::new();
+
+    /// The topology of the current CPU.
+    static TOPOLOGY: OnceCell<Topology> = OnceCell::new();
+}
+
+/// The maximum number of cache levels we keep track of.
+pub const MAX_CACHE_LEVELS: usize = 4;
+
+/// Describes which logical CPUs share a cache.
+///
+/// Two logical CPUs share the cache if their x2APIC IDs are equal after
+/// shifting them right by `shift`.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSharing {
+    pub level: u8,
+    pub shift: u32,
+}
+
+/// The position of a logical CPU in the system.
+///
+/// The x2APIC ID is made up of the SMT ID in the lowest bits, followed by the
+/// core ID, followed by the package ID.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    pub x2apic_id: u32,
+
+    /// Shift to go from an x2APIC ID to a core.
+    pub smt_shift: u32,
+
+    /// Shift to go from an x2APIC ID to a package.
+    pub package_shift: u32,
+
+    pub smt_id: u32,
+    pub core_id: u32,
+    pub package_id: u32,
+
+    /// Cache sharing, for every cache level that's reported.
+    pub caches: Vec<CacheSharing, MAX_CACHE_LEVELS>,
+}
+
+impl Topology {
+    /// Decode the topology of the current CPU.
+    ///
+    /// The APIC ID layout comes from leaf 0xB. When that leaf is unavailable,
+    /// we fall back to leaf 0x1, which lacks SMT information. The cache sharing
+    /// comes from leaf 0x4 on Intel and leaf 0x8000001D on AMD.
+    pub fn read() -> Self {
+        let (x2apic_id, smt_shift, package_shift) = match cpuid::CpuId::new()
+            .get_extended_topology_info()
+        {
+            Some(levels) => {
+                let mut x2apic_id = 0;
+                let mut smt_shift = 0;
+                let mut package_shift = 0;
+
+                for level in levels {
+                    x2apic_id = level.x2apic_id();
+                    match level.level_type() {
+                        TopologyType::SMT => smt_shift = level.shift_right_for_next_apic_id(),
+                        TopologyType::Invalid => break,
+                        // Treat any level above SMT as part of the core ID.
+                        _ => package_shift = level.shift_right_for_next_apic_id(),
+                    }
+                }
+
+                (x2apic_id, smt_shift, package_shift.max(smt_shift))
+            }
+            None => {
+                let features = &cpuid().features;
+                let count = (features.max_logical_processor_ids() as u32).max(1);
+                (
+                    features.initial_local_apic_id() as u32,
+                    0,
+                    count.next_power_of_two().trailing_zeros(),
+                )
+            }
+        };
+
+        let leaf = if vendor() == CpuVendor::Amd {
+            0x8000_001d
+        } else {
+            0x4
+        };
+
+        let mut caches = Vec::new();
+        for subleaf in 0.. {
+            let eax = unsafe { __cpuid_count(leaf, subleaf).eax };
+
+            // A cache type of 0 means there are no more caches.
+            if eax & 0x1f == 0 {
+                break;
+            }
+
+            let sharing = ((eax >> 14) & 0xfff) + 1;
+            let cache = CacheSharing {
+                level: ((eax >> 5) & 0x7) as u8,
+                shift: sharing.next_power_of_two().trailing_zeros(),
+            };
+
+            if caches.push(cache).is_err() {
+                break;
+            }
+        }
+
+        Self {
+            x2apic_id,
+            smt_shift,
+            package_shift,
+            smt_id: x2apic_id & ((1 << smt_shift) - 1),
+            core_id: (x2apic_id & ((1 << package_shift) - 1)) >> smt_shift,
+            package_id: x2apic_id >> package_shift,
+            caches,
+        }
+    }
+
+    /// Return whether the given x2APIC ID shares the cache at the given level.
+    pub fn shares_cache(&self, level: u8, x2apic_id: u32) -> bool {
+        self.caches
+            .iter()
+            .filter(|cache| cache.level == level)
+            .any(|cache| self.x2apic_id >> cache.shift == x2apic_id >> cache.shift)
+    }
+}
+
+/// Return the topology of the current CPU.
+///
+/// Per-CPU storage must be available.
+pub fn topology() -> Topology {
+    TOPOLOGY.with_or_init(Topology::read, Topology::clone)
+}
 
 /// A wrapper over the CpuId type provided by the x86 crate.
 #[derive(Debug)]
@@ -52,6 +202,264 @@ pub fn cpuid() -> &'static CpuId {
     CPUID.call_once(CpuId::read)
 }
 
+/// The manufacturer of the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuVendor {
+    Intel,
+    Amd,
+    Other,
+}
+
+/// Return the manufacturer of the CPU, from the vendor string in CPUID leaf 0.
+///
+/// Hygon CPUs are derived from AMD's Zen, and follow AMD's CPUID leaves and
+/// APIC behaviour, so they are reported as [CpuVendor::Amd].
+pub fn vendor() -> CpuVendor {
+    static VENDOR: Once<CpuVendor> = Once::new();
+    *VENDOR.call_once(|| match cpuid().vendor_info.as_str() {
+        "GenuineIntel" => CpuVendor::Intel,
+        "AuthenticAMD" | "HygonGenuine" => CpuVendor::Amd,
+        _ => CpuVendor::Other,
+    })
+}
+
+/// Return the APIC ID of the current CPU.
+///
+/// Unlike [`apic::ApicAccess::id`], this doesn't touch the APIC registers; the ID
+/// is read from per-CPU storage instead. Only valid after [init_apic_id].
+#[inline]
+pub fn current_apic_id() -> u32 {
+    APIC_ID.with(|id| *id.get().expect("APIC ID not initialised"))
+}
+
+/// Cache the APIC ID of the current CPU.
+///
+/// The ID is also stored in IA32_TSC_AUX, so [rdtscp] can return it. The local
+/// APIC must be enabled, and per-CPU storage must be available.
+pub fn init_apic_id() {
+    let id = apic::local().id();
+    let _ = APIC_ID.set(id);
+
+    if cpuid().ext_proc_feature_ids.has_rdtscp() {
+        unsafe { wrmsr(IA32_TSC_AUX, id as u64) };
+    }
+}
+
+/// Read the time-stamp counter.
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { time::rdtsc() }
+}
+
+/// Read the time-stamp counter, along with the APIC ID of the CPU it was read
+/// on (see [init_apic_id]).
+///
+/// Unlike [rdtsc], this waits for all previous instructions to execute. The
+/// CPU must support `rdtscp`.
+#[inline]
+pub fn rdtscp() -> (u64, u32) {
+    unsafe { time::rdtscp() }
+}
+
+/// Returns true if the TSC runs at a constant rate in all ACPI P-, C- and
+/// T-states.
+///
+/// Without an invariant TSC, the rate changes with the power state of the CPU,
+/// and the TSC may even stop in deep sleep states. It can still be used to
+/// measure short intervals, but not to keep time.
+pub fn has_invariant_tsc() -> bool {
+    cpuid::CpuId::new()
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |info| info.has_invariant_tsc())
+}
+
+/// Return the frequency of the TSC in Hz.
+///
+/// The frequency is taken from CPUID leaf 0x15 if the processor reports it.
+/// Otherwise the TSC is calibrated against the PM timer, which takes 10ms, so
+/// the PM timer must have been initialised (see [pm_timer::init]). Returns
+/// [None] if neither is available. Once known, the frequency is cached.
+///
+/// See [has_invariant_tsc] for whether the frequency is meaningful over time.
+pub fn tsc_frequency() -> Option<u64> {
+    static FREQUENCY: Once<u64> = Once::new();
+    if let Some(&freq) = FREQUENCY.get() {
+        return Some(freq);
+    }
+
+    let freq = cpuid::CpuId::new()
+        .get_tsc_info()
+        .and_then(|info| info.tsc_frequency())
+        .or_else(|| {
+            let start = rdtsc();
+            pm_timer::udelay(10_000).ok()?;
+            Some((rdtsc() - start) * 100)
+        })?;
+
+    Some(*FREQUENCY.call_once(|| freq))
+}
+
+/// Returns true if the CPU supports MONITOR/MWAIT.
+pub fn has_mwait() -> bool {
+    cpuid().features.has_monitor_mwait()
+}
+
+/// Returns true if MWAIT can be woken by an interrupt while interrupts are
+/// masked (ECX bit 0 of MWAIT, enumerated by CPUID leaf 0x5).
+fn has_mwait_interrupt_break() -> bool {
+    static BREAK: Once<bool> = Once::new();
+    *BREAK.call_once(|| {
+        cpuid::CpuId::new()
+            .get_monitor_mwait_info()
+            .map_or(false, |info| {
+                info.extensions_supported() && info.interrupts_as_break_event()
+            })
+    })
+}
+
+/// Return the MWAIT hint for the deepest C-state the CPU supports.
+///
+/// The local APIC timer stops in C-states deeper than C1, unless it's always
+/// running (ARAT). Without ARAT, or without C-state enumeration in CPUID leaf
+/// 0x5, this is C1 (hint 0).
+pub fn mwait_deepest_hint() -> u32 {
+    let cpuid = cpuid::CpuId::new();
+    let arat = cpuid
+        .get_thermal_power_info()
+        .map_or(false, |info| info.has_arat());
+    let Some(info) = cpuid.get_monitor_mwait_info().filter(|_| arat) else {
+        return 0;
+    };
+
+    let substates = [
+        info.supported_c1_states(),
+        info.supported_c2_states(),
+        info.supported_c3_states(),
+        info.supported_c4_states(),
+        info.supported_c5_states(),
+        info.supported_c6_states(),
+        info.supported_c7_states(),
+    ];
+
+    // Bits 7:4 of the hint select the C-state (minus one), bits 3:0 the
+    // sub-state.
+    substates
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, &count)| count != 0)
+        .map_or(0, |(state, &count)| {
+            (state as u32) << 4 | (count as u32 - 1)
+        })
+}
+
+/// Idle until the cache line at `monitor_addr` is written to, or an interrupt
+/// arrives.
+///
+/// MONITOR is armed on the line, and MWAIT is entered with the given C-state
+/// `hint` (see [mwait_deepest_hint]). If the byte at `monitor_addr` is already
+/// non-zero, the wakeup happened before the monitor was armed and this returns
+/// right away.
+///
+/// If the CPU supports it, MWAIT treats interrupts as break events while they
+/// are still masked, and interrupts are enabled afterwards so the pending one
+/// is taken. Otherwise `sti; mwait` enables them atomically, like `sti; hlt`.
+///
+/// # Safety
+/// The CPU must support MONITOR/MWAIT (see [has_mwait]) and `monitor_addr`
+/// must be valid for reads. Interrupts must be disabled, and are enabled on
+/// return.
+pub unsafe fn mwait_idle(monitor_addr: *const u8, hint: u32) {
+    asm!(
+        "monitor",
+        in("rax") monitor_addr,
+        in("ecx") 0,
+        in("edx") 0,
+        options(att_syntax, nostack, preserves_flags)
+    );
+
+    if ptr::read_volatile(monitor_addr) != 0 {
+        irq::enable();
+    } else if has_mwait_interrupt_break() {
+        asm!(
+            "mwait",
+            in("eax") hint,
+            in("ecx") 1,
+            options(att_syntax, nostack, preserves_flags)
+        );
+        irq::enable();
+    } else {
+        asm!(
+            "sti",
+            "mwait",
+            in("eax") hint,
+            in("ecx") 0,
+            options(att_syntax, nostack)
+        );
+    }
+}
+
+/// Print a stack trace by walking the saved frame pointers.
+///
+/// If a frame is given, the trace starts at the interrupted code, otherwise it
+/// starts at the caller. This relies on the kernel being built with frame
+/// pointers (see `frame-pointer` in the target specification). The walk stops
+/// at the first frame pointer that is misaligned, or that doesn't point further
+/// up the stack the walk started on (see [stacks::containing]).
+pub fn backtrace(frame: Option<&Frame>) {
+    const MAX_DEPTH: usize = 32;
+
+    let mut rbp = match frame {
+        Some(frame) => {
+            println!("Backtrace:");
+            println!("  {:#018x}", frame.rip());
+            frame.rbp()
+        }
+        None => {
+            let rbp: u64;
+            unsafe {
+                core::arch::asm!(
+                    "mov    %rbp, {}",
+                    out(reg) rbp,
+                    options(att_syntax, nomem, nostack, preserves_flags)
+                );
+            }
+            println!("Backtrace:");
+            rbp
+        }
+    };
+
+    // Frames never leave the stack they started on.
+    let stack = match stacks::containing(rbp) {
+        Some(stack) => stack,
+        None => return,
+    };
+
+    for _ in 0..MAX_DEPTH {
+        // Every frame starts with the caller's rbp, followed by the return address.
+        if rbp % 8 != 0 || !stack.contains(&rbp) || !stack.contains(&(rbp + 15)) {
+            break;
+        }
+
+        let (next, rip) = unsafe {
+            let ptr = rbp as *const u64;
+            (ptr.read(), ptr.add(1).read())
+        };
+
+        if rip == 0 {
+            break;
+        }
+
+        println!("  {:#018x}", rip);
+
+        // Callers sit higher up the stack, anything else is garbage.
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
 /// Enable essential CPU features.
 ///
 /// These are the bare minimum features required. They should be enabled before
@@ -102,6 +510,145 @@ pub fn pre_mm_init() -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Check that the protections enabled during boot actually took effect.
+///
+/// Some CPUs (mostly virtual ones) advertise a feature through CPUID but ignore
+/// the bit that enables it. Every protection that is supported, but found
+/// disabled, is reported. Returns true if all of them are active.
+pub fn verify_protections() -> bool {
+    const NXE: u64 = 1 << 11;
+
+    let features = cpuid();
+    let (cr0, cr4, efer) = unsafe { (cr0(), cr4(), rdmsr(IA32_EFER)) };
+
+    // Every entry is: name, supported according to CPUID, active.
+    //
+    // SMEP and SMAP are left out on purpose. They only stop the kernel from
+    // executing or accessing user pages, there is no user space yet, and
+    // nothing enables them (see `init_cpu`), so every CPU that supports them
+    // would be reported. Add them here once they are turned on.
+    let protections = [
+        // Always supported, and never cleared.
+        ("WP", true, cr0.contains(Cr0::CR0_WRITE_PROTECT)),
+        (
+            "NXE",
+            features.ext_proc_feature_ids.has_execute_disable(),
+            (efer & NXE) != 0,
+        ),
+        (
+            "PGE",
+            features.features.has_pge(),
+            cr4.contains(Cr4::CR4_ENABLE_GLOBAL_PAGES),
+        ),
+    ];
+
+    let mut ok = true;
+    for (name, _, _) in protections
+        .iter()
+        .filter(|(_, supported, active)| *supported && !*active)
+    {
+        println!(
+            "Warning: {} is supported, but not active on CPU {}",
+            name,
+            current_apic_id()
+        );
+        ok = false;
+    }
+
+    ok
+}
+
+/// Enable the RDGSBASE/WRGSBASE family of instructions on the current CPU.
+///
+/// Returns false if they aren't supported, in which case the GS base is only
+/// accessible through the MSRs.
+///
+/// Don't call this yet: once WRGSBASE is available, the GS base can be set to
+/// anything without going through the kernel, and the paranoid entry path can
+/// no longer tell from its sign whether the kernel GS is loaded. That path
+/// needs to save and restore the GS base first.
+pub fn enable_fsgsbase() -> bool {
+    if !cpuid().ext_features.has_fsgsbase() {
+        return false;
+    }
+
+    unsafe {
+        cr4_write(cr4() | Cr4::CR4_ENABLE_FSGSBASE);
+    }
+
+    true
+}
+
+/// Returns true if the FSGSBASE instructions are enabled on the current CPU.
+///
+/// CR4 is checked rather than a per-CPU flag, since this is needed to set up
+/// per-CPU storage in the first place.
+fn has_fsgsbase() -> bool {
+    unsafe { cr4().contains(Cr4::CR4_ENABLE_FSGSBASE) }
+}
+
+/// Set the base of the active GS segment.
+///
+/// # Safety
+/// Per-CPU storage is accessed through GS, so the base must point at the
+/// per-CPU region of the current CPU while running in the kernel.
+pub unsafe fn set_gs_base(base: u64) {
+    if has_fsgsbase() {
+        wrgsbase(base);
+    } else {
+        wrmsr(IA32_GS_BASE, base);
+    }
+}
+
+/// Return the base of the active GS segment.
+pub fn gs_base() -> u64 {
+    unsafe {
+        if has_fsgsbase() {
+            rdgsbase()
+        } else {
+            rdmsr(IA32_GS_BASE)
+        }
+    }
+}
+
+/// Set the base of the FS segment.
+///
+/// # Safety
+/// Thread-local storage is accessed through FS, so the base must point at the
+/// TLS block of the running thread (see [tls](crate::tls)).
+pub unsafe fn set_fs_base(base: u64) {
+    if has_fsgsbase() {
+        wrfsbase(base);
+    } else {
+        wrmsr(IA32_FS_BASE, base);
+    }
+}
+
+/// Return the base of the FS segment.
+pub fn fs_base() -> u64 {
+    unsafe {
+        if has_fsgsbase() {
+            rdfsbase()
+        } else {
+            rdmsr(IA32_FS_BASE)
+        }
+    }
+}
+
+/// Set the GS base that is swapped in by the next `swapgs`.
+///
+/// # Safety
+/// See [set_gs_base]. There is no instruction for this, so the MSR is always
+/// written.
+pub unsafe fn set_kernel_gs_base(base: u64) {
+    wrmsr(IA32_KERNEL_GSBASE, base);
+}
+
+/// Return the GS base that is swapped in by the next `swapgs`.
+pub fn kernel_gs_base() -> u64 {
+    unsafe { rdmsr(IA32_KERNEL_GSBASE) }
+}
+
 /// Initialise the CPU.
 pub unsafe fn init_cpu() {
     let features = CpuId::read();
@@ -0,0 +1,104 @@
+//! Deferred interrupt work.
+//!
+//! Interrupt handlers should be short. Anything that takes longer is pushed
+//! onto a per-CPU queue as an [Event], and handled later from the idle loop.
+//!
+//! Since the queue is per-CPU, the only contention is between an interrupt
+//! handler and the code it interrupted. Handlers run with interrupts disabled,
+//! and [drain] disables them while dequeuing, so no lock is needed.
+
+use core::cell::RefCell;
+
+use heapless::spsc::Queue;
+use x86::{
+    bits64::rflags::{self, RFlags},
+    irq,
+};
+
+use crate::percpu;
+
+/// The size of the per-CPU queue. One slot is always kept free, so the queue
+/// can hold one event less.
+pub const QUEUE_SIZE: usize = 64;
+
+/// A unit of deferred work.
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    /// The vector of the interrupt that raised the event.
+    pub vector: u8,
+
+    /// Event specific data.
+    pub data: u64,
+}
+
+struct Deferred {
+    queue: Queue<Event, QUEUE_SIZE>,
+
+    /// The number of events dropped because the queue was full.
+    dropped: u64,
+}
+
+percpu! {
+    static DEFERRED: RefCell<Deferred> = RefCell::new(Deferred {
+        queue: Queue::new(),
+        dropped: 0,
+    });
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state afterwards.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let enabled = rflags::read().contains(RFlags::FLAGS_IF);
+    if enabled {
+        unsafe { irq::disable() };
+    }
+
+    let ret = f();
+
+    if enabled {
+        unsafe { irq::enable() };
+    }
+
+    ret
+}
+
+/// Queue an event on the current CPU.
+///
+/// This never blocks or allocates. If the queue is full, the event is dropped
+/// and counted (see [dropped]).
+pub fn push(event: Event) {
+    without_interrupts(|| {
+        DEFERRED.with_borrow_mut(|deferred| {
+            if deferred.queue.enqueue(event).is_err() {
+                deferred.dropped += 1;
+            }
+        })
+    })
+}
+
+/// Handle all queued events on the current CPU.
+///
+/// Interrupts are only disabled while taking an event off the queue, `f` runs
+/// with interrupts in whatever state the caller had them.
+pub fn drain<F>(mut f: F)
+where
+    F: FnMut(Event),
+{
+    while let Some(event) =
+        without_interrupts(|| DEFERRED.with_borrow_mut(|deferred| deferred.queue.dequeue()))
+    {
+        f(event);
+    }
+}
+
+/// Returns true if there are no events queued on the current CPU.
+pub fn is_empty() -> bool {
+    without_interrupts(|| DEFERRED.with_borrow(|deferred| deferred.queue.is_empty()))
+}
+
+/// Return the number of events dropped on the current CPU.
+pub fn dropped() -> u64 {
+    without_interrupts(|| DEFERRED.with_borrow(|deferred| deferred.dropped))
+}
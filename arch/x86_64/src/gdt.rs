@@ -15,6 +15,10 @@ use crate::{
     percpu,
 };
 
+/// The IST entries used by the NMI, double fault and machine check handlers.
+///
+/// [init] stores the matching stacks in these TSS entries, and the IDT gates
+/// for those vectors refer to the same index (see [idt::set_ist](crate::idt::set_ist)).
 pub const NMI_IST_INDEX: u8 = 1;
 pub const DF_IST_INDEX: u8 = 2;
 pub const MC_IST_INDEX: u8 = 3;
@@ -122,15 +126,20 @@ impl KernelGdt {
 
 /// Set the given RSP in the TSS to `stack`.
 fn set_tss_rsp(rsp: u8, stack: u64) {
-    KERNEL_TSS.with_borrow_mut(|tss| tss.tss.rsp[rsp as usize] = stack);
+    KERNEL_TSS.with_borrow_mut(|tss| tss.tss.set_rsp(rsp as usize, stack));
 }
 
 /// Set the given IST in the TSS to `stack`.
 fn set_tss_ist(ist: u8, stack: u64) {
-    KERNEL_TSS.with_borrow_mut(|tss| tss.tss.ist[ist as usize - 1] = stack);
+    KERNEL_TSS.with_borrow_mut(|tss| tss.tss.set_ist(ist as usize, stack));
 }
 
 /// Setup the GDT and TSS structures.
+///
+/// The TSS descriptor limit covers the TSS *and* the I/O permission bitmap that
+/// follows it, up to and including the trailing `0xff` byte. A TSS without a
+/// bitmap (see [Tss::without_io_bitmap]) must use a limit of
+/// `size_of::<Tss>() - 1` instead, so its bitmap base lies beyond the limit.
 pub unsafe fn init(kstack: u64, nmi_stack: u64, df_stack: u64, mc_stack: u64) {
     set_tss_rsp(0, kstack);
 
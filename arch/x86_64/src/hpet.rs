@@ -0,0 +1,114 @@
+//! High Precision Event Timer.
+//!
+//! Only the main counter is used, as a clock source (see [time](crate::time)).
+//! The comparators are left alone. See the IA-PC HPET specification v1.0a.
+
+use acpi::{address::AddressSpace, hpet::Hpet};
+use spin::Once;
+
+use crate::{linker, mm, mmio::Mmio, time::ExtendedCounter};
+
+/// General capabilities and ID register.
+const GENERAL_CAPS_REG: usize = 0x000;
+
+/// General configuration register.
+const GENERAL_CONF_REG: usize = 0x010;
+
+/// Main counter value register.
+const MAIN_COUNTER_REG: usize = 0x0f0;
+
+/// Bit in [GENERAL_CAPS_REG] set if the main counter is 64 bits wide.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// Bit in [GENERAL_CONF_REG] that starts the main counter.
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// The maximum period of the main counter, in femtoseconds (100ns).
+const MAX_PERIOD: u64 = 100_000_000;
+
+/// The HPET register block.
+#[derive(Debug)]
+struct HpetRegs {
+    mmio: Mmio<0x400>,
+
+    /// The period of the main counter in femtoseconds.
+    period: u32,
+
+    /// The bits implemented by the main counter.
+    mask: u64,
+
+    /// Extends a 32-bit main counter to 64 bits.
+    counter: ExtendedCounter,
+}
+
+unsafe impl Send for HpetRegs {}
+unsafe impl Sync for HpetRegs {}
+
+static HPET: Once<HpetRegs> = Once::new();
+
+/// Map the HPET described by the given table, and start its main counter.
+///
+/// The counter isn't reset, so firmware that already started it keeps its
+/// value. Only HPETs in system memory are supported.
+pub fn init(table: &Hpet) -> Result<(), &'static str> {
+    let base = table.base_address;
+    let address = base.address;
+    if base.address_space() != AddressSpace::SystemMemory || address == 0 {
+        return Err("Unsupported HPET address");
+    }
+
+    mm::map_hpet(address);
+    let mmio = unsafe { Mmio::new(linker::HPET_ADDRESS as *mut u8) };
+
+    let caps = unsafe { mmio.read_reg::<GENERAL_CAPS_REG, u64>() };
+    let period = (caps >> 32) as u32;
+    if period == 0 || period as u64 > MAX_PERIOD {
+        return Err("Invalid HPET period");
+    }
+
+    unsafe {
+        let conf = mmio.read_reg::<GENERAL_CONF_REG, u64>();
+        mmio.write_reg::<GENERAL_CONF_REG, u64>(conf | ENABLE_CNF);
+    }
+
+    HPET.call_once(|| HpetRegs {
+        mmio,
+        period,
+        mask: if caps & COUNT_SIZE_CAP != 0 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        },
+        counter: ExtendedCounter::new(),
+    });
+    Ok(())
+}
+
+/// Returns true if the HPET has been initialised.
+pub fn is_present() -> bool {
+    HPET.get().is_some()
+}
+
+/// Return the frequency of the main counter in Hz, or [None] if there is no
+/// HPET.
+pub fn frequency() -> Option<u64> {
+    HPET.get()
+        .map(|hpet| 1_000_000_000_000_000 / hpet.period as u64)
+}
+
+/// Return the period of the main counter in femtoseconds.
+///
+/// Panics if the HPET hasn't been initialised.
+pub fn period_fs() -> u32 {
+    HPET.get().expect("HPET not initialised").period
+}
+
+/// Read the main counter.
+///
+/// A 32-bit counter is extended to 64 bits (see [ExtendedCounter]), so the
+/// value never wraps. Panics if the HPET hasn't been initialised.
+pub fn counter() -> u64 {
+    let hpet = HPET.get().expect("HPET not initialised");
+    let raw = unsafe { hpet.mmio.read_reg::<MAIN_COUNTER_REG, u64>() } & hpet.mask;
+    hpet.counter.extend(raw, hpet.mask)
+}
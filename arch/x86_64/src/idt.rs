@@ -15,7 +15,7 @@
 //!  - AMD Architecture Programmer's Manual Vol. 2, 8.1
 //!  - Intel Software Developer Manual Vol. 3, 6.1
 
-use core::mem;
+use core::{marker::PhantomData, mem};
 
 use spin::Once;
 use x86::{
@@ -25,8 +25,14 @@ use x86::{
 
 use crate::desc::{Access, GateDescriptor, GateDescriptorType};
 
+use self::{
+    handler::{ErrorCodeKind, Handler},
+    vector::Vector,
+};
+
 pub mod handler;
 pub mod traps;
+pub mod vector;
 
 /// The early descriptor table.
 static mut EARLY_IDT: [GateDescriptor; 256] = [GateDescriptor::NULL; 256];
@@ -35,7 +41,7 @@ pub fn load() {
     unsafe {
         let ptr: DescriptorTablePointer<GateDescriptor> = DescriptorTablePointer {
             base: &EARLY_IDT as *const _,
-            limit: ((32 * mem::size_of::<GateDescriptor>()) - 1) as u16,
+            limit: ((256 * mem::size_of::<GateDescriptor>()) - 1) as u16,
         };
 
         lidt(&ptr);
@@ -49,44 +55,110 @@ pub fn set_ist(vector: u8, ist: u8) {
     }
 }
 
+/// Checks at compile time that the handler for exception `VECTOR` takes an
+/// error code iff the CPU pushes one.
+struct ExceptionCheck<const VECTOR: u8, E>(PhantomData<E>);
+
+impl<const VECTOR: u8, E: ErrorCodeKind> ExceptionCheck<VECTOR, E> {
+    const OK: () = match Vector::from_u8(VECTOR) {
+        Some(vector) => assert!(
+            vector.pushes_error_code() == E::PUSHED,
+            "Exception handler has the wrong error code signature"
+        ),
+        None => panic!("Not an exception vector"),
+    };
+}
+
+/// Install the handler for an exception.
+///
+/// The vector is a const parameter, e.g.
+/// `set_exception_handler::<{ Vector::PageFault as u8 }, _>(&handler)`, so a
+/// handler that disagrees with [Vector::pushes_error_code] fails to build
+/// rather than corrupting the stack at runtime. Exceptions are installed as
+/// DPL 0 trap gates, so userspace can't raise them with `int`.
+pub fn set_exception_handler<const VECTOR: u8, E: ErrorCodeKind>(handler: &'static Handler<E>) {
+    #[allow(clippy::let_unit_value)]
+    let () = ExceptionCheck::<VECTOR, E>::OK;
+
+    unsafe {
+        EARLY_IDT[VECTOR as usize] = GateDescriptor::new(
+            handler.as_ptr() as u64,
+            cs(),
+            GateDescriptorType::Trap,
+            Access::DPL_0 | Access::P,
+            0,
+        );
+    }
+}
+
+/// Install an interrupt handler for the given vector.
+///
+/// The handler is installed as an interrupt gate, so interrupts are disabled
+/// while it runs. Vectors 0-31 are reserved for exceptions.
+pub fn set_handler(vector: u8, handler: &Handler) {
+    assert!(vector >= 32);
+
+    unsafe {
+        EARLY_IDT[vector as usize] = GateDescriptor::new(
+            handler.as_ptr() as u64,
+            cs(),
+            GateDescriptorType::Interrupt,
+            Access::DPL_0 | Access::P,
+            0,
+        );
+    }
+}
+
+/// Install a handler for the given vector that userspace can invoke with `int`.
+///
+/// The handler is installed as a DPL 3 interrupt gate (see
+/// [GateDescriptor::interrupt_ring3]). Only use this for the vector that is
+/// meant to be called from userspace, never for exceptions or IRQs.
+pub fn set_user_handler(vector: u8, handler: &Handler) {
+    assert!(vector >= 32);
+
+    unsafe {
+        EARLY_IDT[vector as usize] =
+            GateDescriptor::interrupt_ring3(handler.as_ptr() as u64, cs(), 0);
+    }
+}
+
 /// Initialise the early interrupt descriptor table.
 pub fn init() {
     static INIT: Once<()> = Once::new();
     INIT.call_once(|| {
-        unsafe fn set_gate(vector: u8, isr: handler::InterruptHandlerFn) {
-            EARLY_IDT[vector as usize] = GateDescriptor::new(
-                isr as u64,
-                cs(),
-                GateDescriptorType::Trap,
-                Access::DPL_0 | Access::P,
-                0,
-            );
-        }
-
-        unsafe {
-            set_gate(0, traps::divide_by_zero.as_ptr());
-            set_gate(1, traps::debug.as_ptr());
-            set_gate(2, traps::nmi.as_ptr());
-            set_gate(3, traps::breakpoint.as_ptr());
-            set_gate(4, traps::overflow.as_ptr());
-            set_gate(5, traps::bound_range.as_ptr());
-            set_gate(6, traps::invalid_opcode.as_ptr());
-            set_gate(7, traps::device_not_available.as_ptr());
-            set_gate(8, traps::double_fault.as_ptr());
-            set_gate(10, traps::invalid_tss.as_ptr());
-            set_gate(11, traps::segment_not_present.as_ptr());
-            set_gate(12, traps::stack.as_ptr());
-            set_gate(13, traps::general_protection.as_ptr());
-            set_gate(14, traps::page_fault.as_ptr());
-            set_gate(16, traps::x87_floating_point_ex.as_ptr());
-            set_gate(17, traps::alignment_check.as_ptr());
-            set_gate(18, traps::machine_check.as_ptr());
-            set_gate(19, traps::simd_floating_point.as_ptr());
-            set_gate(21, traps::control_protection.as_ptr());
-            set_gate(28, traps::hypervisor_injection.as_ptr());
-            set_gate(29, traps::vmm_communication.as_ptr());
-            set_gate(30, traps::security.as_ptr());
-        }
+        set_exception_handler::<{ Vector::DivideByZero as u8 }, _>(&traps::divide_by_zero);
+        set_exception_handler::<{ Vector::Debug as u8 }, _>(&traps::debug);
+        set_exception_handler::<{ Vector::Nmi as u8 }, _>(&traps::nmi);
+        set_exception_handler::<{ Vector::Breakpoint as u8 }, _>(&traps::breakpoint);
+        set_exception_handler::<{ Vector::Overflow as u8 }, _>(&traps::overflow);
+        set_exception_handler::<{ Vector::BoundRange as u8 }, _>(&traps::bound_range);
+        set_exception_handler::<{ Vector::InvalidOpcode as u8 }, _>(&traps::invalid_opcode);
+        set_exception_handler::<{ Vector::DeviceNotAvailable as u8 }, _>(
+            &traps::device_not_available,
+        );
+        set_exception_handler::<{ Vector::DoubleFault as u8 }, _>(&traps::double_fault);
+        set_exception_handler::<{ Vector::InvalidTss as u8 }, _>(&traps::invalid_tss);
+        set_exception_handler::<{ Vector::SegmentNotPresent as u8 }, _>(
+            &traps::segment_not_present,
+        );
+        set_exception_handler::<{ Vector::Stack as u8 }, _>(&traps::stack);
+        set_exception_handler::<{ Vector::GeneralProtection as u8 }, _>(&traps::general_protection);
+        set_exception_handler::<{ Vector::PageFault as u8 }, _>(&traps::page_fault);
+        set_exception_handler::<{ Vector::X87FloatingPoint as u8 }, _>(
+            &traps::x87_floating_point_ex,
+        );
+        set_exception_handler::<{ Vector::AlignmentCheck as u8 }, _>(&traps::alignment_check);
+        set_exception_handler::<{ Vector::MachineCheck as u8 }, _>(&traps::machine_check);
+        set_exception_handler::<{ Vector::SimdFloatingPoint as u8 }, _>(
+            &traps::simd_floating_point,
+        );
+        set_exception_handler::<{ Vector::ControlProtection as u8 }, _>(&traps::control_protection);
+        set_exception_handler::<{ Vector::HypervisorInjection as u8 }, _>(
+            &traps::hypervisor_injection,
+        );
+        set_exception_handler::<{ Vector::VmmCommunication as u8 }, _>(&traps::vmm_communication);
+        set_exception_handler::<{ Vector::Security as u8 }, _>(&traps::security);
 
         load();
     });
@@ -1,9 +1,11 @@
+use core::marker::PhantomData;
+
 pub type InterruptHandlerFn = *const unsafe extern "C" fn();
 
 /// Register values saved on entering kernel through an interrupt. They will be
 /// restored upon returning to userspace (or caller).
 #[derive(Debug, Clone, Copy)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct Regs {
     // Preserved registers
     pub r15: u64,
@@ -29,7 +31,7 @@ pub struct Regs {
 /// values on the stack. After the interrupt is handled, the processor will
 /// resume to rip.
 #[derive(Debug, Clone, Copy)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct IRetStack {
     /// The return instruction pointer.
     pub rip: u64,
@@ -45,27 +47,136 @@ pub struct IRetStack {
 
 /// Interrupt frame, every interrupt handler has access to these values.
 #[derive(Debug, Clone, Copy)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct Frame {
     pub regs: Regs,
     pub iret: IRetStack,
 }
 
+/// The frame is packed, so its fields can't be borrowed. These accessors copy
+/// them instead, which also works from a `&Frame` or `&mut Frame`.
+impl Frame {
+    /// The instruction pointer execution resumes at.
+    pub fn rip(&self) -> u64 {
+        self.iret.rip
+    }
+
+    /// Resume execution at `rip`.
+    pub fn set_rip(&mut self, rip: u64) {
+        self.iret.rip = rip;
+    }
+
+    /// The stack pointer of the interrupted context.
+    pub fn rsp(&self) -> u64 {
+        self.iret.rsp
+    }
+
+    /// Resume with the given stack pointer.
+    pub fn set_rsp(&mut self, rsp: u64) {
+        self.iret.rsp = rsp;
+    }
+
+    /// The RFLAGS of the interrupted context.
+    pub fn rflags(&self) -> u64 {
+        self.iret.rflags
+    }
+
+    /// Resume with the given RFLAGS.
+    pub fn set_rflags(&mut self, rflags: u64) {
+        self.iret.rflags = rflags;
+    }
+
+    /// The frame pointer of the interrupted context.
+    pub fn rbp(&self) -> u64 {
+        self.regs.rbp
+    }
+
+    /// The privilege level (ring) the interrupt was raised in.
+    ///
+    /// This is the RPL of the saved CS, which always equals the CPL of the
+    /// interrupted code.
+    pub fn cpl(&self) -> u8 {
+        (self.iret.cs & 0b11) as u8
+    }
+
+    /// Returns true if the interrupt was raised in user mode (CPL 3).
+    ///
+    /// The entry code makes the same check on the saved CS to decide whether
+    /// to `swapgs`.
+    pub fn came_from_user(&self) -> bool {
+        self.cpl() == 3
+    }
+}
+
+/// The frame argument of an interrupt handler.
+///
+/// Handlers either take a copy of the [Frame], or a `&mut Frame` pointing at
+/// the frame saved on the stack.
+pub trait FrameArg<'a> {
+    fn from_frame(frame: &'a mut Frame) -> Self;
+}
+
+impl<'a> FrameArg<'a> for Frame {
+    fn from_frame(frame: &'a mut Frame) -> Self {
+        *frame
+    }
+}
+
+impl<'a> FrameArg<'a> for &'a mut Frame {
+    fn from_frame(frame: &'a mut Frame) -> Self {
+        frame
+    }
+}
+
+/// Whether the CPU pushes an error code before entering a handler.
+///
+/// Implemented by the [ErrorCode] and [NoErrorCode] markers, which the
+/// [`interrupt_handler`] macro picks based on the handler's signature.
+pub trait ErrorCodeKind {
+    const PUSHED: bool;
+}
+
+/// Marker for handlers that take an error code.
+#[derive(Debug)]
+pub struct ErrorCode;
+
+impl ErrorCodeKind for ErrorCode {
+    const PUSHED: bool = true;
+}
+
+/// Marker for handlers without an error code.
+#[derive(Debug)]
+pub struct NoErrorCode;
+
+impl ErrorCodeKind for NoErrorCode {
+    const PUSHED: bool = false;
+}
+
 /// An interrupt handler.
+///
+/// The type parameter records whether the handler expects an error code, so
+/// it can't be installed for a vector that disagrees (see
+/// [set_exception_handler](crate::idt::set_exception_handler)). IRQ and IPI
+/// handlers never take one.
 #[derive(Debug)]
-pub struct Handler {
+pub struct Handler<E: ErrorCodeKind = NoErrorCode> {
     /// The actual, inner, interrupt handler. This is the code executed by the CPU
     /// when an interrupt is triggered.
     inner: unsafe extern "C" fn(),
+
+    _kind: PhantomData<E>,
 }
 
-impl Handler {
+impl<E: ErrorCodeKind> Handler<E> {
     /// Wrap a raw interrupt handler.
     ///
     /// This function is used by the [`interrupt_handler`] macro to provide
     /// safe access to interrupt handlers.
     pub const unsafe fn new(inner: unsafe extern "C" fn()) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            _kind: PhantomData,
+        }
     }
 
     /// Return a pointer to the handler.
@@ -74,6 +185,11 @@ impl Handler {
     pub const fn as_ptr(&self) -> InterruptHandlerFn {
         self.inner as *const _
     }
+
+    /// Returns true if the handler takes an error code.
+    pub const fn has_error(&self) -> bool {
+        E::PUSHED
+    }
 }
 
 #[doc(hidden)]
@@ -82,13 +198,18 @@ macro_rules! __interrupt_handler_internal {
     (
         @paranoid=$paranoid:literal
         @has_error=$has_error:literal
+        @kind=$kind:ident
         $(#[$($attrs:tt)*])*
         $vis:vis fn $name:ident($frame:ident: $frame_ty:ty $(, $error_code:ident: u64)?) $code:block
     ) => {
         $(#[$($attrs)*])*
         #[allow(non_upper_case_globals)]
-        $vis static $name: $crate::idt::handler::Handler = {
-            fn rust($frame: $frame_ty $(, $error_code: u64)?) {
+        $vis static $name: $crate::idt::handler::Handler<$crate::idt::handler::$kind> = {
+            // The entry code passes a pointer to the saved frame in rdi, and
+            // the error code (or 0) in rsi.
+            extern "C" fn rust(frame: &mut $crate::idt::handler::Frame, _error_code: u64) {
+                let $frame: $frame_ty = $crate::idt::handler::FrameArg::from_frame(frame);
+                $(let $error_code: u64 = _error_code;)?
                 $code
             }
 
@@ -147,9 +268,22 @@ macro_rules! __interrupt_handler_internal {
                         mov     %rsp, %rdi
 
                     .if {paranoid}
-                        // TODO
+                        // A paranoid interrupt can arrive anywhere, even right
+                        // after entering the kernel but before its swapgs, so
+                        // the privilege level of CS doesn't tell us which GS is
+                        // loaded. Kernel GS bases are kernel addresses, so look
+                        // at the sign of IA32_GS_BASE instead. ebx is callee
+                        // saved, and remembers whether to swap back.
+                        movl    ${gs_base}, %ecx
+                        rdmsr
+                        xorl    %ebx, %ebx
+                        testl   %edx, %edx
+                        js      1f
+                        swapgs
+                        movl    $1, %ebx
                     .else
-                        // Did we come from userspace?
+                        // Did we come from userspace? The saved CS follows
+                        // the 15 registers and RIP (see Frame::came_from_user).
                         testb   $0b11, (16*8)(%rsp)
                         jz      1f
                         swapgs
@@ -158,15 +292,20 @@ macro_rules! __interrupt_handler_internal {
                         // Call rust
                         call    {rust}
 
-                        // Check if we are returning to userspace.
+                        // Check if we are returning to userspace. The frame may
+                        // have been changed by the handler, so this looks at
+                        // the CS we're about to return to.
                     .if {paranoid}
+                        testl   %ebx, %ebx
+                        jz      1f
+                        swapgs
                     .else
                         testb   $0b11, (16*8)(%rsp)
                         jz      1f
                         swapgs
                     .endif
                     1:
-                        // Restore registers
+                        // Restore registers, from the (possibly changed) frame.
                         pop     %r15
                         pop     %r14
                         pop     %r13
@@ -181,13 +320,14 @@ macro_rules! __interrupt_handler_internal {
                         pop     %rcx
                         pop     %rdx
                         pop     %rdi
-                      pop     %rsi
+                        pop     %rsi
         
                         // Return to caller.
                         iretq
                     ",
                     rust = sym rust,
                     paranoid = const($paranoid),
+                    gs_base = const(x86::msr::IA32_GS_BASE),
                     has_error = const($has_error),
                     options(att_syntax, noreturn)
                 );
@@ -201,6 +341,10 @@ macro_rules! __interrupt_handler_internal {
 }
 
 /// Define a paranoid interrupt handler.
+///
+/// Paranoid handlers are used for exceptions that can interrupt the kernel at
+/// any point (NMI, #MC, #DB, #DF), and decide whether to `swapgs` based on the
+/// current GS base rather than on the interrupted CS. See [interrupt_handler].
 #[macro_export(local_inner_macros)]
 macro_rules! paranoid_interrupt_handler {
     (
@@ -210,8 +354,9 @@ macro_rules! paranoid_interrupt_handler {
         __interrupt_handler_internal! {
             @paranoid=1
             @has_error=1
+            @kind=ErrorCode
             $(#[$($attrs)*])*
-            $vis fn $name($frame: $frame_ty $(, $error_code: u64)?) $code
+            $vis fn $name($frame: $frame_ty, $error_code: u64) $code
         }
     };
 
@@ -222,6 +367,7 @@ macro_rules! paranoid_interrupt_handler {
         __interrupt_handler_internal! {
             @paranoid=1
             @has_error=0
+            @kind=NoErrorCode
             $(#[$($attrs)*])*
             $vis fn $name($frame: $frame_ty) $code
         }
@@ -229,6 +375,18 @@ macro_rules! paranoid_interrupt_handler {
 }
 
 /// Define an interrupt handler.
+///
+/// The handler takes the interrupt [Frame](crate::idt::handler::Frame), either
+/// by value or as `&mut Frame`, followed by the error code for exceptions that
+/// push one.
+///
+/// All registers and the `iretq` frame are restored from the saved frame after
+/// the handler returns. Changing `frame.regs` and `frame.iret` through a
+/// `&mut Frame` is therefore the supported way to resume a different context,
+/// e.g. switching threads from the timer interrupt. The new context must have
+/// a valid `cs`/`ss` pair, and `rsp` must point at a stack it owns. Since the
+/// frame is packed, use the accessors like
+/// [set_rip](crate::idt::handler::Frame::set_rip) rather than borrowing fields.
 #[macro_export(local_inner_macros)]
 macro_rules! interrupt_handler {
     (
@@ -238,6 +396,7 @@ macro_rules! interrupt_handler {
         __interrupt_handler_internal! {
             @paranoid=0
             @has_error=1
+            @kind=ErrorCode
             $(#[$($attrs)*])*
             $vis fn $name($frame: $frame_ty, $error_code: u64) $code
         }
@@ -250,6 +409,7 @@ macro_rules! interrupt_handler {
         __interrupt_handler_internal! {
             @paranoid=0
             @has_error=0
+            @kind=NoErrorCode
             $(#[$($attrs)*])*
             $vis fn $name($frame: $frame_ty) $code
         }
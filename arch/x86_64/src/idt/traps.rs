@@ -1,6 +1,8 @@
 use x86::controlregs::cr2;
 
-use crate::{idt::handler::Frame, interrupt_handler, paranoid_interrupt_handler, println};
+use crate::{
+    cpu, idt::handler::Frame, interrupt_handler, panic, paranoid_interrupt_handler, println,
+};
 
 interrupt_handler! {
     pub fn divide_by_zero(frame: Frame) {
@@ -16,6 +18,11 @@ paranoid_interrupt_handler! {
 
 paranoid_interrupt_handler! {
     pub fn nmi(frame: Frame) {
+        // Another CPU panicked and wants us to stop.
+        if panic::is_panicking() {
+            panic::park();
+        }
+
         println!("NMI: {:?}", frame);
     }
 }
@@ -50,9 +57,10 @@ interrupt_handler! {
     }
 }
 
-interrupt_handler! {
+paranoid_interrupt_handler! {
     pub fn double_fault(frame: Frame, error: u64) {
         println!("Double fault: {:?}, error: {:#04x}", frame, error);
+        cpu::backtrace(Some(&frame));
     }
 }
 
@@ -77,6 +85,7 @@ interrupt_handler! {
 interrupt_handler! {
     pub fn general_protection(frame: Frame, error: u64) {
         println!("General protection: {:?}, error: {:#04x}", frame, error);
+        cpu::backtrace(Some(&frame));
     }
 }
 
@@ -86,6 +95,7 @@ interrupt_handler! {
             cr2()
         };
         println!("Page fault: {:?}, error: {:#04b}, addr: {:#018x}", frame, error, addr);
+        cpu::backtrace(Some(&frame));
     }
 }
 
@@ -126,13 +136,13 @@ interrupt_handler! {
 }
 
 paranoid_interrupt_handler! {
-    pub fn vmm_communication(frame: Frame) {
-        println!("VMM communication: {:?}", frame);
+    pub fn vmm_communication(frame: Frame, error: u64) {
+        println!("VMM communication: {:?}, error: {:#04x}", frame, error);
     }
 }
 
 paranoid_interrupt_handler! {
-    pub fn security(frame: Frame) {
-        println!("Security: {:?}", frame);
+    pub fn security(frame: Frame, error: u64) {
+        println!("Security: {:?}, error: {:#04x}", frame, error);
     }
 }
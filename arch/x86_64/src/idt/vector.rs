@@ -0,0 +1,184 @@
+//! The exception vectors.
+//!
+//! Vectors 0-31 are reserved for exceptions. The ones not listed in [Vector]
+//! are reserved by the architecture and never raised. See the Intel SDM Vol. 3,
+//! 6.15 and the AMD APM Vol. 2, 8.2.
+
+use core::fmt;
+
+/// An exception vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Vector {
+    DivideByZero = 0,
+    Debug = 1,
+    Nmi = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRange = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    /// Raised by the 386 when an FPU operand crossed a segment limit. Later
+    /// CPUs raise #GP instead.
+    CoprocessorSegmentOverrun = 9,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    Stack = 12,
+    GeneralProtection = 13,
+    PageFault = 14,
+    /// Reserved by Intel, never raised.
+    Reserved = 15,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    ControlProtection = 21,
+    HypervisorInjection = 28,
+    VmmCommunication = 29,
+    Security = 30,
+}
+
+impl Vector {
+    /// All exception vectors, in order.
+    pub const ALL: [Vector; 25] = [
+        Vector::DivideByZero,
+        Vector::Debug,
+        Vector::Nmi,
+        Vector::Breakpoint,
+        Vector::Overflow,
+        Vector::BoundRange,
+        Vector::InvalidOpcode,
+        Vector::DeviceNotAvailable,
+        Vector::DoubleFault,
+        Vector::CoprocessorSegmentOverrun,
+        Vector::InvalidTss,
+        Vector::SegmentNotPresent,
+        Vector::Stack,
+        Vector::GeneralProtection,
+        Vector::PageFault,
+        Vector::Reserved,
+        Vector::X87FloatingPoint,
+        Vector::AlignmentCheck,
+        Vector::MachineCheck,
+        Vector::SimdFloatingPoint,
+        Vector::Virtualization,
+        Vector::ControlProtection,
+        Vector::HypervisorInjection,
+        Vector::VmmCommunication,
+        Vector::Security,
+    ];
+
+    /// Look up the exception with the given vector number.
+    pub const fn from_u8(vector: u8) -> Option<Self> {
+        let mut i = 0;
+        while i < Self::ALL.len() {
+            if Self::ALL[i] as u8 == vector {
+                return Some(Self::ALL[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Look up an exception by its mnemonic (e.g. `#PF`) or its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|v| v.mnemonic() == Some(name) || v.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The human-readable name of the exception.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Vector::DivideByZero => "Divide-by-zero",
+            Vector::Debug => "Debug",
+            Vector::Nmi => "Non-maskable interrupt",
+            Vector::Breakpoint => "Breakpoint",
+            Vector::Overflow => "Overflow",
+            Vector::BoundRange => "Bound-range",
+            Vector::InvalidOpcode => "Invalid opcode",
+            Vector::DeviceNotAvailable => "Device not available",
+            Vector::DoubleFault => "Double fault",
+            Vector::CoprocessorSegmentOverrun => "Coprocessor segment overrun",
+            Vector::InvalidTss => "Invalid TSS",
+            Vector::SegmentNotPresent => "Segment not present",
+            Vector::Stack => "Stack",
+            Vector::GeneralProtection => "General protection",
+            Vector::PageFault => "Page fault",
+            Vector::Reserved => "Reserved",
+            Vector::X87FloatingPoint => "x87 floating point",
+            Vector::AlignmentCheck => "Alignment check",
+            Vector::MachineCheck => "Machine check",
+            Vector::SimdFloatingPoint => "SIMD floating point",
+            Vector::Virtualization => "Virtualization",
+            Vector::ControlProtection => "Control protection",
+            Vector::HypervisorInjection => "Hypervisor injection",
+            Vector::VmmCommunication => "VMM communication",
+            Vector::Security => "Security",
+        }
+    }
+
+    /// The mnemonic of the exception, e.g. `#PF`.
+    ///
+    /// The legacy and reserved vectors don't have one.
+    pub const fn mnemonic(&self) -> Option<&'static str> {
+        let mnemonic = match self {
+            Vector::DivideByZero => "#DE",
+            Vector::Debug => "#DB",
+            Vector::Nmi => "NMI",
+            Vector::Breakpoint => "#BP",
+            Vector::Overflow => "#OF",
+            Vector::BoundRange => "#BR",
+            Vector::InvalidOpcode => "#UD",
+            Vector::DeviceNotAvailable => "#NM",
+            Vector::DoubleFault => "#DF",
+            Vector::CoprocessorSegmentOverrun | Vector::Reserved => return None,
+            Vector::InvalidTss => "#TS",
+            Vector::SegmentNotPresent => "#NP",
+            Vector::Stack => "#SS",
+            Vector::GeneralProtection => "#GP",
+            Vector::PageFault => "#PF",
+            Vector::X87FloatingPoint => "#MF",
+            Vector::AlignmentCheck => "#AC",
+            Vector::MachineCheck => "#MC",
+            Vector::SimdFloatingPoint => "#XM",
+            Vector::Virtualization => "#VE",
+            Vector::ControlProtection => "#CP",
+            Vector::HypervisorInjection => "#HV",
+            Vector::VmmCommunication => "#VC",
+            Vector::Security => "#SX",
+        };
+        Some(mnemonic)
+    }
+
+    /// Returns true if the CPU pushes an error code for this exception.
+    ///
+    /// Besides the Intel ones (8, 10-14, 17, 21), AMD's #VC and #SX push an
+    /// error code as well.
+    pub const fn pushes_error_code(&self) -> bool {
+        matches!(
+            self,
+            Vector::DoubleFault
+                | Vector::InvalidTss
+                | Vector::SegmentNotPresent
+                | Vector::Stack
+                | Vector::GeneralProtection
+                | Vector::PageFault
+                | Vector::AlignmentCheck
+                | Vector::ControlProtection
+                | Vector::VmmCommunication
+                | Vector::Security
+        )
+    }
+}
+
+impl fmt::Display for Vector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mnemonic() {
+            Some(mnemonic) => write!(f, "{} ({})", self.name(), mnemonic),
+            None => f.write_str(self.name()),
+        }
+    }
+}
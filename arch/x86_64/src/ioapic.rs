@@ -3,13 +3,17 @@ use self::registers::{
     IO_APIC_RED_TBL_0, IO_APIC_REG_SEL, IO_APIC_REG_WIN, IO_APIC_VERSION_REG,
 };
 
-pub mod registers;
+use crate::mmio::Mmio;
+
+pub use libapic::io as registers;
 
 /// Access to the IOAPIC.
+///
+/// The IOAPIC registers are accessed indirectly, by writing the register
+/// index to the select register, and then accessing the window register.
 #[derive(Debug)]
 pub struct IoApic {
-    sel: *mut u32,
-    win: *mut u32,
+    mmio: Mmio<0x20>,
 }
 
 impl IoApic {
@@ -20,8 +24,7 @@ impl IoApic {
     /// IOAPIC.
     pub const unsafe fn new(base: *mut u32) -> Self {
         Self {
-            sel: base.byte_add(IO_APIC_REG_SEL as usize),
-            win: base.byte_add(IO_APIC_REG_WIN as usize),
+            mmio: Mmio::new(base as *mut u8),
         }
     }
 
@@ -106,8 +109,8 @@ impl IoApic {
     /// # Safety
     /// It is up to the caller to make sure `reg` is a valid IOAPIC register.
     pub unsafe fn unchecked_read(&mut self, reg: u32) -> u32 {
-        self.sel.write_volatile(reg);
-        self.win.read_volatile()
+        self.mmio.write_reg::<{ IO_APIC_REG_SEL as usize }, u32>(reg);
+        self.mmio.read_reg::<{ IO_APIC_REG_WIN as usize }, u32>()
     }
 
     /// Perform an unchecked write to the IOAPIC.
@@ -115,7 +118,7 @@ impl IoApic {
     /// # Safety
     /// It is up to the caller that `reg` and `val` are legal values.
     pub unsafe fn unchecked_write(&mut self, reg: u32, val: u32) {
-        self.sel.write_volatile(reg);
-        self.win.write_volatile(val);
+        self.mmio.write_reg::<{ IO_APIC_REG_SEL as usize }, u32>(reg);
+        self.mmio.write_reg::<{ IO_APIC_REG_WIN as usize }, u32>(val);
     }
 }
@@ -47,6 +47,23 @@ pub const MAX_IOAPICS: usize = 1;
 /// Offset of the IOAPICs.
 pub const IO_APIC_OFFSET: u64 = LOCAL_APIC_ADDRESS + paging::BASE_PAGE as u64;
 
+/// Virtual address where the HPET mmio will be mapped.
+pub const HPET_ADDRESS: u64 = IO_APIC_OFFSET + (MAX_IOAPICS * paging::BASE_PAGE) as u64;
+
+/// Virtual address of the window DMA buffers are mapped into (see
+/// [mm::DmaBuffer](crate::mm::DmaBuffer)).
+pub const DMA_OFFSET: u64 = HPET_ADDRESS + paging::BASE_PAGE as u64;
+
+/// The number of pages in the DMA window. It takes up the rest of the 2M of
+/// the kernel device window.
+pub const DMA_PAGES: usize =
+    ((KDEV_OFFSET + paging::MEGA_PAGE as u64 - DMA_OFFSET) / paging::BASE_PAGE as u64) as usize;
+
+/// Virtual address of the page frames are temporarily mapped at to be written
+/// (see [mm::zero_frame](crate::mm::zero_frame)). It sits in the 2M following
+/// the kernel device window.
+pub const SCRATCH_ADDRESS: u64 = KDEV_OFFSET + paging::MEGA_PAGE as u64;
+
 /// The virtual offset of where the physical memory will be mapped to.
 pub const PHYS_OFFSET: u64 = 0xffff800000000000;
 
@@ -56,6 +73,11 @@ pub const PHYS_OFFSET: u64 = 0xffff800000000000;
 /// need at least 1 table (which can contain 512 entries, each entry able to map
 /// 1G of memory), it makes sense to make this value a multiple of 512G.
 ///
+/// The tables are reserved statically for the full amount, whatever the size
+/// of the memory map. Only the mapping shrinks to the end of the memory map
+/// (see [mm::init](crate::mm::init)), so lowering this value is still the way
+/// to save memory.
+///
 /// Note that this value is not allowed to exceed 64TB!
 pub const MAX_PHYS_MEMORY: usize = 512 * paging::GIGABYTE;
 
@@ -92,6 +114,11 @@ __linker_fn!(
     This function depends on `_eboot16` in `kernel-x86_64.lds`."]
     _eboot16() -> u64;
 
+    #[doc = "Return the physical address of the long mode entry of the boot16 code.
+    # Safety
+    This function depends on `_start64` in `boot/start16.S`."]
+    _start64() -> u64;
+
     #[doc = "Return the virtual start address of the text section.
     # Safety
     This function depends on `_text` in `kernel-x86_64.lds`."]
@@ -8,6 +8,7 @@
 #![feature(alloc_layout_extra)]
 #![feature(const_maybe_uninit_zeroed)]
 #![feature(offset_of)]
+#![feature(panic_info_message)]
 #![feature(pointer_byte_offsets)]
 #![feature(const_pointer_byte_offsets)]
 #![no_main]
@@ -19,24 +20,37 @@ use libacpi::AcpiTables;
 use spin::Once;
 use x86::irq;
 
+use crate::{apic::ApicAccess, idt::vector::Vector, stacks::StackTop};
+
 extern crate acpi as libacpi;
+extern crate apic as libapic;
+extern crate desc as libdesc;
 
 pub mod apic;
 pub mod asm;
 pub mod boot;
 pub mod cpu;
-pub mod desc;
+pub mod defer;
 pub mod gdt;
+pub mod hpet;
 pub mod idt;
 pub mod ioapic;
 pub mod linker;
 pub mod mm;
+pub mod mmio;
 pub mod panic;
 pub mod percpu;
 pub mod pic;
+pub mod pm_timer;
+pub mod power;
 pub mod smp;
 pub mod stacks;
+pub mod sync;
 pub mod thread;
+pub mod time;
+pub mod tls;
+
+pub use libdesc::segment as desc;
 
 /// The maximum number of memory region descriptors. Changing this value will change
 /// the kernel memory footprint.
@@ -46,20 +60,30 @@ pub const MAX_MEM_REGIONS: usize = 32;
 static BSP_APIC_ID: AtomicU32 = AtomicU32::new(u32::MAX);
 
 /// The number of CPUs in the system.
+///
+/// Set once from the ACPI tables, before any AP is started.
 static NUM_CPUS: AtomicU32 = AtomicU32::new(1);
 
 /// Global ACPI tables.
 static ACPI_TABLES: Once<AcpiTables> = Once::new();
 
-pub fn init(stack: u64, percpu_offset: u64) {
+pub fn init(stack: StackTop, percpu_offset: u64) {
     unsafe {
         percpu::init(percpu_offset);
+
+        // Enable PCIDs before the first install, while the PCID in CR3 is
+        // still 0.
+        mm::pcid::enable();
+
+        // We are already running on the kernel pages, but make sure this CPU
+        // keeps track of them.
+        mm::install(mm::kernel_top());
     }
 
     // Setup GDT
     unsafe {
         gdt::init(
-            stack + linker::STACK_SIZE as u64,
+            stack.get(),
             stacks::nmi_stack_top(),
             stacks::df_stack_top(),
             stacks::mc_stack_top(),
@@ -70,28 +94,61 @@ pub fn init(stack: u64, percpu_offset: u64) {
 
     // Enable the local APIC for this node.
     apic::local().enable();
+    cpu::init_apic_id();
 
     // If we are the BSP, we are responsible for setting up the IDT stacks.
     if apic::local().is_bsp() {
-        idt::set_ist(2, gdt::NMI_IST_INDEX);
-        idt::set_ist(8, gdt::DF_IST_INDEX);
-        idt::set_ist(18, gdt::MC_IST_INDEX);
+        idt::set_ist(Vector::Nmi as u8, gdt::NMI_IST_INDEX);
+        idt::set_ist(Vector::DoubleFault as u8, gdt::DF_IST_INDEX);
+        idt::set_ist(Vector::MachineCheck as u8, gdt::MC_IST_INDEX);
+
+        idt::set_handler(apic::ERROR_VECTOR, &apic::error_handler);
+        idt::set_handler(apic::SPURIOUS_VECTOR, &apic::spurious_handler);
+        idt::set_handler(apic::STOP_VECTOR, &panic::stop_handler);
 
-        BSP_APIC_ID.store(apic::local().id(), Ordering::Relaxed);
+        BSP_APIC_ID.store(cpu::current_apic_id(), Ordering::Relaxed);
+
+        #[cfg(feature = "self-test")]
+        mm::verify_dma();
     }
 
+    // Make sure APIC errors don't go unnoticed.
+    apic::local().setup_error(apic::ERROR_VECTOR);
+
     // TODO: smep/smap, syscalls, fpu, ...
+    cpu::verify_protections();
+    tls::verify();
 
     // Everything done, we're ready to handle interrupts.
     unsafe {
         irq::enable();
     }
+}
 
-    NUM_CPUS.fetch_add(1, Ordering::Relaxed);
+/// Returns the number of CPUs in the system.
+pub fn num_cpus() -> u32 {
+    NUM_CPUS.load(Ordering::Relaxed)
 }
 
 /// Start the current node.
 pub fn start() -> ! {
+    // Don't start doing real work before every CPU is fully initialized.
+    smp::barrier();
+
     println!("Running!");
-    loop {}
+    loop {
+        defer::drain(|event| match event.vector {
+            apic::ERROR_VECTOR => apic::log_errors(event),
+            _ => println!("Unhandled event: {:?}", event),
+        });
+
+        unsafe {
+            irq::disable();
+            if defer::is_empty() {
+                smp::idle();
+            } else {
+                irq::enable();
+            }
+        }
+    }
 }
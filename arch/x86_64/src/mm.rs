@@ -1,28 +1,46 @@
 //! Kernel memory management.
 
 mod consts;
-pub mod desc;
 pub mod map;
 pub mod memory;
 pub mod paging;
+pub mod pcid;
+pub mod recursive;
+
+pub use libdesc::memory as desc;
+
+use core::{
+    cell::{Cell, OnceCell},
+    marker::PhantomData,
+    mem,
+    ops::{Range, RangeInclusive},
+    ptr, slice,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
 
-use core::{cell::OnceCell, ops::Range, slice};
-
+use acpi::{facs::Facs, AcpiError, AcpiTables};
 use heapless::Vec;
 use spin::{Mutex, Once};
-use x86::controlregs::cr3_write;
+use x86::{controlregs::cr3_write, cpuid::CpuId, tlb};
 
-use crate::linker;
+use crate::{
+    boot::{
+        early::{BOOT_PDPT, BOOT_PML4},
+        IoApicInfo,
+    },
+    defer, linker, percpu, println,
+};
 
 use self::{
-    consts::{NUM_PERCPU_PDS, NUM_PERCPU_PTS, NUM_PHYS_PDPTS},
-    desc::MemoryDescriptor,
-    map::{Flags, Mapper, PdMapper, PdptMapper},
-    memory::Memory,
+    consts::{NUM_EXTRA_PHYS_PDPTS, NUM_PERCPU_PDS, NUM_PHYS_PDPTS},
+    desc::{MemoryDescriptor, MemoryKind, Region},
+    map::{Flags, Mapper, PagingError, PdMapper, PdptMapper, PtAlloc, PtMapper},
+    memory::{Memory, MemoryError, Zone},
     paging::{
         num_tables, pd_index, pdpt_index, pml4_index, pt_index, PDEFlags, PDPTEFlags, PML4EFlags,
-        PTEFlags, PD, PDPT, PML4, PT,
+        PTEFlags, PD, PDPT, PML4, PT, PTE,
     },
+    recursive::Level,
 };
 
 /// Top level 4 table. All other tables live in here.
@@ -38,31 +56,63 @@ static mut KERNEL_PDPT: PDPT = PDPT::zero();
 /// pages for more finegrained control using [KERN_PT].
 static mut KERN_PD: PD = PD::zero();
 
-/// Level 1 kernel table. Makes finegrained mappings possible.
+/// Level 1 kernel tables. Make finegrained mappings possible. Handed out by
+/// [KernPts].
 static mut KERN_PT: [PT; 256] = [PT::zero(); 256];
 
+/// The number of tables in [KERN_PT] in use.
+static KERN_PTS_USED: AtomicUsize = AtomicUsize::new(0);
+
 /// Level 2 table for kernel devices.
 static mut KDEV_PD: PD = PD::zero();
 
 /// Level 1 table for kernel devices.
 static mut KDEV_PT: PT = PT::zero();
 
+/// Level 1 table for [linker::SCRATCH_ADDRESS]. Only touched with [SCRATCH] held.
+static mut SCRATCH_PT: PT = PT::zero();
+
+/// Serialises the use of [linker::SCRATCH_ADDRESS].
+static SCRATCH: Mutex<()> = Mutex::new(());
+
 /// Level 2 table for per-cpu data.
 static mut PERCPU_PDS: [PD; NUM_PERCPU_PDS] = [PD::zero(); NUM_PERCPU_PDS];
 
-/// Level 1 table for per-cpu data. All per-cpu data is mapped using 4K pages, even if
-/// we could map using 2M pages. The reason is that per-cpu data is allocated at runtime,
-/// and just mapping a 4K page to a 4K frame makes it a little bit easier.
-static mut PERCPU_PTS: [PT; NUM_PERCPU_PTS] = [PT::zero(); NUM_PERCPU_PTS];
-
 /// A huge table used to map all physical memory to a predefined offset. To map all
 /// physical memory, we use 1G pages. This has the advantage that we don't actually
 /// occupy more memory between 0 and 512G of physical memory.
 static mut PHYS_PDPTS: [PDPT; NUM_PHYS_PDPTS] = [PDPT::zero(); NUM_PHYS_PDPTS];
 
+/// The end of the physical memory mapped using [PHYS_PDPTS]. Always a multiple
+/// of 1G, and at most [linker::MAX_PHYS_MEMORY]. Only grows, while holding the
+/// [EXTRA_PHYS_SLICES] lock.
+static PHYS_WINDOW_END: AtomicU64 = AtomicU64::new(0);
+
+/// The physical window always covers the first 4G, where firmware and most
+/// devices live, even if the memory map ends below.
+const MIN_PHYS_WINDOW: u64 = 4 * paging::GIGABYTE as u64;
+
+/// Spare tables to map physical memory beyond [linker::MAX_PHYS_MEMORY] (see
+/// [extend_phys_window]).
+static mut EXTRA_PHYS_PDPTS: [PDPT; NUM_EXTRA_PHYS_PDPTS] = [PDPT::zero(); NUM_EXTRA_PHYS_PDPTS];
+
+/// The 512G slices mapped using [EXTRA_PHYS_PDPTS], in order.
+static EXTRA_PHYS_SLICES: Mutex<Vec<usize, NUM_EXTRA_PHYS_PDPTS>> = Mutex::new(Vec::new());
+
+percpu! {
+    /// Physical address of the top table installed on the current CPU.
+    static CURRENT_TOP: Cell<u64> = Cell::new(0);
+}
+
+/// The size of the low memory region used by the AP bootcode and its data.
+pub const AP_BOOTCODE_SIZE: usize = 0x3000;
+
 /// Keep track of free frames.
 static MEMORY: Mutex<OnceCell<Memory<{ crate::MAX_MEM_REGIONS }>>> = Mutex::new(OnceCell::new());
 
+/// The pages of the DMA window in use (see [DmaBuffer]).
+static DMA_WINDOW: Mutex<[bool; linker::DMA_PAGES]> = Mutex::new([false; linker::DMA_PAGES]);
+
 /// Container to keep track of per-cpu data.
 #[derive(Debug)]
 pub struct PerCpuInfo {
@@ -73,6 +123,146 @@ pub struct PerCpuInfo {
     pub stack: &'static mut [u8; linker::STACK_SIZE],
 }
 
+/// Return the mask of physical address bits supported by the CPU.
+///
+/// [paging::ADDRESS_MASK] assumes the architectural maximum of
+/// [paging::MAXPHYADDRESS] bits, but most CPUs support fewer (see CPUID leaf
+/// 0x80000008). Setting any bit above the supported width in a paging entry
+/// causes a reserved-bit page fault.
+pub fn phys_addr_mask() -> u64 {
+    static MASK: Once<u64> = Once::new();
+    *MASK.call_once(|| {
+        let bits = CpuId::new()
+            .get_processor_capacity_feature_info()
+            .map_or(paging::MAXPHYADDRESS, |info| {
+                info.physical_address_bits() as u64
+            });
+
+        (1 << bits) - 1
+    })
+}
+
+/// A temporary identity mapping in the boot page tables.
+///
+/// See [early_map_identity]. The mapping is removed when the guard is dropped.
+#[derive(Debug)]
+pub struct EarlyIdentityMap {
+    /// The boot PDPT entries used by the mapping.
+    entries: Range<usize>,
+}
+
+impl Drop for EarlyIdentityMap {
+    fn drop(&mut self) {
+        unsafe {
+            let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> = Mapper::new(&mut BOOT_PML4);
+            let mut pdpt = mapper
+                .pdpt(0, &mut BOOT_PDPT, Flags::Preserve)
+                .expect("Failed to map boot PDPT");
+
+            for idx in self.entries.clone() {
+                pdpt.map(idx, 0, Flags::Set(PDPTEFlags::empty()))
+                    .expect("Failed to unmap early identity map");
+                tlb::flush(idx * paging::GIGA_PAGE);
+            }
+        }
+    }
+}
+
+/// Identity map the given physical range in the boot page tables.
+///
+/// The boot tables only identity map the first 4G. This can be used to reach
+/// devices above that during early bring-up. The range is mapped using 1G pages,
+/// and must lie below 510G, since the last two boot PDPT entries are shared with
+/// the kernel (-2G) mapping. Ranges in the first 4G are already mapped, so
+/// nothing is done for those; a range straddling 4G only maps the part above.
+///
+/// # Safety
+/// This function may only be used before [switch_to_kernel] is called, as it
+/// modifies the boot tables. The returned guard must be dropped before then as well.
+pub unsafe fn early_map_identity(phys: u64, len: usize, flags: PTEFlags) -> EarlyIdentityMap {
+    const FIRST_FREE: usize = 4;
+    const LAST_FREE: usize = 510;
+
+    assert!(len > 0);
+
+    // The PAT bit overlaps with PS for 1G pages.
+    assert!(!flags.contains(PTEFlags::PAT));
+
+    // Don't touch the entries of the boot identity map.
+    let start = pdpt_index(phys).max(FIRST_FREE);
+    let end = pdpt_index(phys + len as u64 - 1) + 1;
+
+    assert!(phys + (len as u64) <= (LAST_FREE * paging::GIGA_PAGE) as u64);
+
+    if end <= FIRST_FREE {
+        return EarlyIdentityMap { entries: 0..0 };
+    }
+
+    // Don't silently override somebody else's mapping.
+    assert!(BOOT_PDPT.table[start..end]
+        .iter()
+        .all(|entry| entry.flags().is_empty()));
+
+    let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> = Mapper::new(&mut BOOT_PML4);
+    let mut pdpt = mapper
+        .pdpt(0, &mut BOOT_PDPT, Flags::Preserve)
+        .expect("Failed to map boot PDPT");
+    let pdpt_flags = PDPTEFlags::from_bits_truncate(flags.bits()) | PDPTEFlags::P | PDPTEFlags::PS;
+
+    for idx in start..end {
+        pdpt.map(
+            idx,
+            (idx * paging::GIGA_PAGE) as u64,
+            Flags::Set(pdpt_flags),
+        )
+        .expect("Failed to map early identity map");
+    }
+
+    EarlyIdentityMap {
+        entries: start..end,
+    }
+}
+
+/// Hands out the tables in [KERN_PT] to map the kernel window with.
+///
+/// They work before the kernel tables are active, but there's only a fixed
+/// number of them. Only the BSP maps the kernel window, during boot.
+struct KernPts;
+
+impl PtAlloc for KernPts {
+    fn alloc(&mut self) -> Option<u64> {
+        let idx = KERN_PTS_USED.fetch_add(1, Ordering::Relaxed);
+        (idx < 256).then(|| {
+            let pt = unsafe { ptr::addr_of_mut!(KERN_PT).cast::<PT>().add(idx) };
+            pt as u64 - linker::VIRT_OFFSET
+        })
+    }
+
+    unsafe fn pt(&mut self, phys: u64, _virt: u64) -> &mut PT {
+        &mut *((phys + linker::VIRT_OFFSET) as *mut PT)
+    }
+}
+
+/// Allocates tables from [Memory], and reaches them through the recursive
+/// mapping.
+///
+/// Only the active tables can be reached that way, so this may only be used to
+/// map into the PDs of the kernel tables, once they are active.
+struct MemoryPts<'m>(&'m mut Memory<{ crate::MAX_MEM_REGIONS }>);
+
+impl PtAlloc for MemoryPts<'_> {
+    fn alloc(&mut self) -> Option<u64> {
+        unsafe { self.0.alloc_zeroed() }.ok()
+    }
+
+    unsafe fn pt(&mut self, _phys: u64, virt: u64) -> &mut PT {
+        // The PD entry may have changed, drop whatever was cached for it.
+        let pt = recursive::table_addr(Level::Pt, virt);
+        tlb::flush(pt as usize);
+        &mut *(pt as *mut PT)
+    }
+}
+
 /// Map the kernel window.
 ///
 /// This function maps the kernel with the correct permisisons in the page tables. For it
@@ -94,90 +284,204 @@ unsafe fn map_kernel_window<const LINK_OFFSET: usize>(mapper: &mut PdptMapper<LI
         assert!(range.start >= LINK_OFFSET as u64 && range.start <= range.end);
         assert!(range.end <= (LINK_OFFSET + linker::KERNEL_SIZE) as u64);
 
-        let pd_flags = {
-            let mut flags = PDEFlags::P | PDEFlags::PS;
-            flags.set(PDEFlags::RW, rw);
-            flags.set(PDEFlags::XD, xd);
-            flags
-        };
+        if range.start == range.end {
+            return;
+        }
 
-        let pt_flags = {
-            let mut flags = PTEFlags::P;
+        // The kernel is mapped in every address space, so keep it in the TLB across
+        // CR3 reloads. `cpu::pre_mm_init` refuses to continue without CR4.PGE.
+        let flags = {
+            let mut flags = PTEFlags::P | PTEFlags::G;
             flags.set(PTEFlags::RW, rw);
             flags.set(PTEFlags::XD, xd);
             flags
         };
 
-        // The virtual address offset.
-        let mut virt = range.start;
-
-        loop {
-            if virt >= range.end {
-                break;
-            }
-
-            if (range.end - virt) as usize >= paging::MEGA_PAGE {
-                mapper.map(
-                    pd_index(virt),
-                    virt - LINK_OFFSET as u64,
-                    Flags::Enable(pd_flags),
-                );
-                virt += paging::MEGA_PAGE as u64;
-            } else {
-                mapper
-                    .pt(
-                        pd_index(virt),
-                        &mut KERN_PT[pd_index(virt) - pd_index(linker::VIRT_OFFSET)],
-                        Flags::Enable(PDEFlags::P | PDEFlags::RW),
-                    )
-                    .map(
-                        pt_index(virt),
-                        virt - LINK_OFFSET as u64,
-                        Flags::Enable(pt_flags),
-                    );
-                virt += paging::BASE_PAGE as u64;
-            }
-        }
+        mapper
+            .map_region(
+                range.start,
+                Region {
+                    base: range.start - LINK_OFFSET as u64,
+                    length: (range.end - range.start) as usize,
+                },
+                flags,
+                &mut KernPts,
+            )
+            .expect("Failed to map kernel window");
     }
 
     let text = linker::_text()..linker::_etext();
     let rodata = linker::_rodata()..linker::_erodata();
     let data = linker::_data()..linker::_ebss();
 
-    let mut pd = mapper.pd(
-        pdpt_index(LINK_OFFSET as u64),
-        &mut KERN_PD,
-        Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
-    );
+    let mut pd = mapper
+        .pd(
+            pdpt_index(LINK_OFFSET as u64),
+            &mut KERN_PD,
+            Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
+        )
+        .expect("Failed to map kernel PD");
 
     map_range(&mut pd, text, false, false);
     map_range(&mut pd, rodata, false, true);
     map_range(&mut pd, data, true, true);
 }
 
-/// Map [linker::MAX_PHYS_MEMORY] using 1G pages at [linker::PHYS_OFFSET].
+/// Map the physical memory in `range` using 1G pages at [linker::PHYS_OFFSET].
 ///
-/// The mapped window has the execute-disable bit set, and is read-only.
-unsafe fn map_phys_window<const LINK_OFFSET: usize>(mapper: &mut Mapper<LINK_OFFSET>) {
-    let num_frames = linker::MAX_PHYS_MEMORY / paging::GIGA_PAGE;
-    let num_pdpts = num_frames / 512;
+/// Both ends must be 1G aligned, and the range may not exceed
+/// [linker::MAX_PHYS_MEMORY]. The mapped window has the execute-disable bit set,
+/// and is read-only. Like the kernel, the pages are global.
+unsafe fn map_phys_window<const LINK_OFFSET: usize>(
+    mapper: &mut Mapper<LINK_OFFSET>,
+    range: Range<u64>,
+) {
+    assert!(range.end <= linker::MAX_PHYS_MEMORY as u64);
+
+    let pages = (range.start / paging::GIGA_PAGE as u64) as usize
+        ..(range.end / paging::GIGA_PAGE as u64) as usize;
+    for x in 0..NUM_PHYS_PDPTS {
+        let first = pages.start.clamp(x * 512, (x + 1) * 512);
+        let last = pages.end.clamp(x * 512, (x + 1) * 512);
+        if first < last {
+            map_phys_slice(
+                mapper,
+                x,
+                &mut PHYS_PDPTS[x],
+                first - x * 512..last - x * 512,
+            );
+        }
+    }
+}
 
-    for x in 0..num_pdpts {
-        let delta = (x * paging::GIGA_PAGE) as u64;
-        let phys_pdpt = &mut PHYS_PDPTS[x];
-        let mut pdpt = mapper.pdpt(
+/// Map the 1G pages `entries` of the 512G slice `slice` of physical memory into
+/// the physical memory window, using the given PDPT.
+unsafe fn map_phys_slice<const LINK_OFFSET: usize>(
+    mapper: &mut Mapper<LINK_OFFSET>,
+    slice: usize,
+    phys_pdpt: &mut PDPT,
+    entries: Range<usize>,
+) {
+    let delta = (slice * paging::PDPT_COVERAGE) as u64;
+    let mut pdpt = mapper
+        .pdpt(
             pml4_index(linker::PHYS_OFFSET + delta),
             phys_pdpt,
             Flags::Enable(PML4EFlags::P),
-        );
-        for y in 0..512 {
-            pdpt.map(
-                y,
-                (y * paging::GIGA_PAGE) as u64 + delta,
-                Flags::Enable(PDPTEFlags::P | PDPTEFlags::PS | PDPTEFlags::XD),
-            );
+        )
+        .expect("Failed to map physical window PDPT");
+    for y in entries {
+        pdpt.map(
+            y,
+            (y * paging::GIGA_PAGE) as u64 + delta,
+            Flags::Enable(PDPTEFlags::P | PDPTEFlags::PS | PDPTEFlags::G | PDPTEFlags::XD),
+        )
+        .expect("Failed to map physical window");
+    }
+}
+
+/// Zero a 4K frame.
+///
+/// The physical memory window is read-only, so the frame is mapped writable at
+/// [linker::SCRATCH_ADDRESS] for the duration instead. Only the current CPU
+/// uses that mapping, and its translation is flushed before and after, so no
+/// other CPU has to be told.
+///
+/// # Safety
+/// The kernel tables must be active, and the frame must be unused.
+pub unsafe fn zero_frame(frame: u64) {
+    let virt = linker::SCRATCH_ADDRESS;
+    let entry = ptr::addr_of_mut!(SCRATCH_PT.table[pt_index(virt)]);
+
+    defer::without_interrupts(|| {
+        let _scratch = SCRATCH.lock();
+
+        entry.write(PTE::new(frame, PTEFlags::P | PTEFlags::RW | PTEFlags::XD));
+        tlb::flush(virt as usize);
+        ptr::write_bytes(virt as *mut u8, 0, paging::BASE_PAGE);
+
+        entry.write(PTE::ZERO);
+        tlb::flush(virt as usize);
+    });
+}
+
+/// Return the range of 512G slices covering `len` bytes at physical address `phys`.
+fn phys_slices(phys: u64, len: usize) -> Option<RangeInclusive<usize>> {
+    let end = phys.checked_add(len.max(1) as u64)?;
+    let coverage = paging::PDPT_COVERAGE as u64;
+    Some((phys / coverage) as usize..=((end - 1) / coverage) as usize)
+}
+
+/// Returns true if `len` bytes at physical address `phys` can be accessed through
+/// the physical memory window at [linker::PHYS_OFFSET].
+///
+/// This holds for everything below the end of the memory map passed to [init],
+/// and for memory mapped with [extend_phys_window].
+pub fn phys_window_contains(phys: u64, len: usize) -> bool {
+    let Some(slices) = phys_slices(phys, len) else {
+        return false;
+    };
+
+    let extra = EXTRA_PHYS_SLICES.lock();
+    let end = PHYS_WINDOW_END.load(Ordering::Relaxed);
+    if phys + len as u64 <= end {
+        return true;
+    }
+
+    slices
+        .into_iter()
+        .all(|slice| ((slice + 1) * paging::PDPT_COVERAGE) as u64 <= end || extra.contains(&slice))
+}
+
+/// Make sure `len` bytes at physical address `phys` can be accessed through the
+/// physical memory window.
+///
+/// Firmware may place its structures (e.g. the ACPI tables) outside of the memory
+/// map, above the end of the window. Those are mapped on demand, a 512G slice at
+/// a time. Below [linker::MAX_PHYS_MEMORY], the window simply grows up to the end
+/// of the slice. Above, one of [NUM_EXTRA_PHYS_PDPTS] spare tables is used. Like
+/// the rest of the window, the slices are global, read-only and not executable.
+/// Nothing is flushed, since the slices weren't present before.
+///
+/// Fails if the range doesn't fit below the recursive entry, or if we ran out of
+/// spare tables.
+pub fn extend_phys_window(phys: u64, len: usize) -> Result<(), &'static str> {
+    let slices = phys_slices(phys, len).ok_or("Physical range overflows")?;
+    let max_slices = recursive::RECURSIVE_INDEX - pml4_index(linker::PHYS_OFFSET);
+    if *slices.end() >= max_slices {
+        return Err("Physical range doesn't fit in the physical window");
+    }
+
+    let mut extra = EXTRA_PHYS_SLICES.lock();
+    for slice in slices {
+        if slice < NUM_PHYS_PDPTS {
+            let end = PHYS_WINDOW_END.load(Ordering::Relaxed);
+            let slice_end = ((slice + 1) * paging::PDPT_COVERAGE) as u64;
+            if end < slice_end {
+                unsafe {
+                    let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> =
+                        Mapper::new(&mut TOP);
+                    map_phys_window(&mut mapper, end..slice_end);
+                }
+                PHYS_WINDOW_END.store(slice_end, Ordering::Relaxed);
+            }
+            continue;
+        }
+
+        if extra.contains(&slice) {
+            continue;
+        }
+
+        let index = extra.len();
+        extra.push(slice).or(Err("Out of physical window tables"))?;
+
+        unsafe {
+            let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> = Mapper::new(&mut TOP);
+            map_phys_slice(&mut mapper, slice, &mut EXTRA_PHYS_PDPTS[index], 0..512);
         }
     }
+
+    Ok(())
 }
 
 /// Allocate and map the per-CPU structures.
@@ -186,29 +490,37 @@ unsafe fn allocate_per_cpus<const LINK_OFFSET: usize>(
     memory: &mut Memory<{ crate::MAX_MEM_REGIONS }>,
     num: usize,
 ) -> Vec<PerCpuInfo, { linker::MAX_CPUS }> {
-    fn map<const LINK_OFFSET: usize>(
+    /// Map `frame` at `virt`. Missing PTs are allocated from `memory`, so
+    /// only running out of memory is reported.
+    unsafe fn map<const LINK_OFFSET: usize>(
         mapper: &mut PdptMapper<LINK_OFFSET>,
-        pds: &mut [PD; NUM_PERCPU_PDS],
-        pts: &mut [PT; NUM_PERCPU_PTS],
+        memory: &mut Memory<{ crate::MAX_MEM_REGIONS }>,
         virt: u64,
         frame: u64,
-    ) {
-        mapper
+    ) -> memory::Result<()> {
+        let mapped = mapper
             .pd(
                 pdpt_index(virt),
-                &mut pds[pdpt_index(virt) - pdpt_index(linker::PERCPU_OFFSET)],
+                &mut PERCPU_PDS[pdpt_index(virt) - pdpt_index(linker::PERCPU_OFFSET)],
                 Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
             )
-            .pt(
-                pd_index(virt),
-                &mut pts[pd_index(virt) - pd_index(linker::PERCPU_OFFSET)],
-                Flags::Enable(PDEFlags::P | PDEFlags::RW),
-            )
-            .map(
-                pt_index(virt),
-                frame,
-                Flags::Enable(PTEFlags::P | PTEFlags::RW | PTEFlags::XD),
-            );
+            .and_then(|mut pd| {
+                pd.map_region(
+                    virt,
+                    Region {
+                        base: frame,
+                        length: paging::BASE_PAGE,
+                    },
+                    PTEFlags::P | PTEFlags::RW | PTEFlags::XD,
+                    &mut MemoryPts(memory),
+                )
+            });
+
+        match mapped {
+            Ok(()) => Ok(()),
+            Err(PagingError::OutOfFrames) => Err(MemoryError::Oom),
+            Err(err) => panic!("Failed to map per-cpu frame: {:?}", err),
+        }
     }
 
     // Make sure we aren't allocating more than we can handle.
@@ -221,13 +533,20 @@ unsafe fn allocate_per_cpus<const LINK_OFFSET: usize>(
     let frames_per_block = num_tables::<{ paging::BASE_PAGE }>(block_size);
     let frames_per_stack = num_tables::<{ paging::BASE_PAGE }>(linker::STACK_SIZE);
 
+    // If we run out of memory, continue with the CPUs we have so far. Frames
+    // already mapped for the CPU that didn't fit are lost.
     let mut virt = linker::PERCPU_OFFSET;
-    for _ in 0..num {
+    'cpus: for cpu in 0..num {
         // Map the contiguous per-cpu storage block first
         let storage = virt;
         for _ in 0..frames_per_block {
-            let frame = memory.next().expect("Failed to retrieve frame");
-            map(mapper, &mut PERCPU_PDS, &mut PERCPU_PTS, virt, frame);
+            let mapped = memory
+                .next()
+                .and_then(|frame| map(mapper, memory, virt, frame));
+            if let Err(err) = mapped {
+                println!("Out of memory ({:?}), using {} CPUs", err, cpu);
+                break 'cpus;
+            }
             virt += paging::BASE_PAGE as u64;
         }
 
@@ -235,11 +554,17 @@ unsafe fn allocate_per_cpus<const LINK_OFFSET: usize>(
         virt += linker::STACK_GUARD_SIZE as u64;
 
         // Map stack. The System V ABI dictates that the stack should be aligned on a 16 byte boundary.
-        // Since ours sits on a page bounary, this is always the case.
+        // Since ours sits on a page bounary, this is always the case. `stacks::StackTop` checks
+        // this when the stack is handed out.
         let stack = virt;
         for _ in 0..frames_per_stack {
-            let frame = memory.next().expect("Failed to retrieve frame");
-            map(mapper, &mut PERCPU_PDS, &mut PERCPU_PTS, virt, frame);
+            let mapped = memory
+                .alloc_zeroed()
+                .and_then(|frame| map(mapper, memory, virt, frame));
+            if let Err(err) = mapped {
+                println!("Out of memory ({:?}), using {} CPUs", err, cpu);
+                break 'cpus;
+            }
             virt += paging::BASE_PAGE as u64;
         }
 
@@ -248,11 +573,16 @@ unsafe fn allocate_per_cpus<const LINK_OFFSET: usize>(
 
         info.push(PerCpuInfo {
             storage,
-            stack: (stack as *mut [u8; linker::STACK_SIZE]).as_mut().unwrap(),
+            stack: (stack as *mut [u8; linker::STACK_SIZE])
+                .as_mut()
+                .expect("Per-cpu stack is null"),
         })
-        .unwrap();
+        .expect("More per-cpu blocks than MAX_CPUS");
     }
 
+    // There's no point in continuing without the BSP.
+    assert!(!info.is_empty(), "Failed to allocate the BSP");
+
     // Next copy over the per-cpu data for each cpu, we use the phys window here since the per-cpu region
     // isn't actually mapped.
     let data = unsafe {
@@ -266,9 +596,6 @@ unsafe fn allocate_per_cpus<const LINK_OFFSET: usize>(
         // copy over the per-cpu data.
         let block = unsafe { slice::from_raw_parts_mut(percpu.storage as *mut u8, block_size) };
         block.copy_from_slice(&data);
-
-        // zero the stack.
-        percpu.stack.fill(0u8);
     }
 
     info
@@ -278,22 +605,64 @@ unsafe fn allocate_per_cpus<const LINK_OFFSET: usize>(
 ///
 /// This function maps the kernel and physical memory windows. It does not
 /// activate the kernel pages! This function should only be called once.
-pub fn init() {
+///
+/// The physical memory window is sized to `max_phys`, the highest physical
+/// address in the memory map, rounded up to 1G. It covers at least the first 4G
+/// and at most [linker::MAX_PHYS_MEMORY]. This means the memory map has to be
+/// read before calling this function, through the identity mapping of the boot
+/// page tables. Anything outside of the window can be mapped later on using
+/// [extend_phys_window].
+pub fn init(max_phys: u64) {
     static INIT: Once<()> = Once::new();
     INIT.call_once(|| unsafe {
         let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> = Mapper::new(&mut TOP);
 
-        let mut pdpt = mapper.pdpt(
-            pml4_index(linker::KERNEL_START),
-            &mut KERNEL_PDPT,
-            Flags::Set(PML4EFlags::P | PML4EFlags::RW),
-        );
+        let mut pdpt = mapper
+            .pdpt(
+                pml4_index(linker::KERNEL_START),
+                &mut KERNEL_PDPT,
+                Flags::Set(PML4EFlags::P | PML4EFlags::RW),
+            )
+            .expect("Failed to map kernel PDPT");
 
         // Map the kernel.
         map_kernel_window(&mut pdpt);
 
+        // Link the table of the scratch page (see [zero_frame]) once, so using it
+        // only takes writing a single entry.
+        debug_assert_eq!(
+            pml4_index(linker::SCRATCH_ADDRESS),
+            pml4_index(linker::KERNEL_START)
+        );
+        pdpt.pd(
+            pdpt_index(linker::SCRATCH_ADDRESS),
+            &mut KDEV_PD,
+            Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
+        )
+        .and_then(|mut pd| {
+            pd.pt(
+                pd_index(linker::SCRATCH_ADDRESS),
+                &mut SCRATCH_PT,
+                Flags::Enable(PDEFlags::P | PDEFlags::RW),
+            )
+        })
+        .expect("Failed to map scratch PT");
+
         // Map the physical memory window.
-        map_phys_window(&mut mapper);
+        let end = paging::align_up::<{ paging::GIGA_PAGE }>(max_phys)
+            .clamp(MIN_PHYS_WINDOW, linker::MAX_PHYS_MEMORY as u64);
+        map_phys_window(&mut mapper, 0..end);
+        PHYS_WINDOW_END.store(end, Ordering::Relaxed);
+
+        if max_phys > end {
+            println!(
+                "Only the first {}G of physical memory is mapped",
+                end / paging::GIGA_PAGE as u64
+            );
+        }
+
+        // Make the tables reachable without the physical memory window.
+        recursive::install(&mut TOP, TOP.table.as_ptr() as u64 - linker::VIRT_OFFSET);
     });
 }
 
@@ -307,6 +676,43 @@ pub unsafe fn switch_to_kernel() {
     cr3_write(ptr as u64 - linker::VIRT_OFFSET);
 }
 
+/// Install the given top table on the current CPU.
+///
+/// This loads CR3 and records the table so it can be retrieved with
+/// [current_top]. Without PCIDs, the load flushes all non-global TLB entries.
+/// With PCIDs, the translations of a table installed recently on this CPU are
+/// kept (see [pcid]).
+///
+/// Since the kernel and the physical window are mapped global, a CR3 reload
+/// (and thus `tlb::flush_all`) leaves their translations cached. Changes to
+/// those mappings must be invalidated with `tlb::flush` (INVLPG), or by
+/// toggling CR4.PGE.
+///
+/// # Safety
+/// `top` must be the physical address of a valid PML4 that maps the kernel.
+/// Per-CPU storage must be available.
+pub unsafe fn install(top: u64) {
+    cr3_write(pcid::cr3(top));
+    CURRENT_TOP.with(|current| current.set(top));
+}
+
+/// Invalidate the translation of `addr` in the given top table on the current
+/// CPU, whether it's installed or not.
+///
+/// See [pcid::invalidate].
+pub fn invalidate(top: u64, addr: u64) {
+    pcid::invalidate(top, addr, current_top());
+}
+
+/// Return the physical address of the top table installed on the current CPU.
+///
+/// Only valid after [install] has been called on this CPU.
+pub fn current_top() -> u64 {
+    let top = CURRENT_TOP.with(Cell::get);
+    assert!(top != 0, "No page tables installed");
+    top
+}
+
 /// Return the physical address of the kernel top table.
 pub fn kernel_top() -> u64 {
     unsafe { TOP.table.as_ptr() as u64 - linker::VIRT_OFFSET }
@@ -315,8 +721,15 @@ pub fn kernel_top() -> u64 {
 /// Setup the bootcode for the APs at the given vector.
 ///
 /// This function depends on the physical window being available, so it should
-/// only be called after the kernel pages are active.
+/// only be called after the kernel pages are active. Panics if the bootcode was
+/// already set up.
 pub fn setup_ap_bootcode(vector: u8) {
+    static DONE: AtomicBool = AtomicBool::new(false);
+    assert!(
+        !DONE.swap(true, Ordering::AcqRel),
+        "AP bootcode already set up"
+    );
+
     unsafe fn map_bootcode(phys: u64, virt: u64, len: usize) {
         // This memory range should be free for us to use.
         const LOW_MEM_START: u64 = 0x500;
@@ -333,26 +746,26 @@ pub fn setup_ap_bootcode(vector: u8) {
                 &mut KERNEL_PDPT,
                 Flags::Enable(PML4EFlags::P | PML4EFlags::RW),
             )
-            .pd(
-                pdpt_index(virt),
-                &mut KERN_PD,
-                Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
-            );
+            .and_then(|mut pdpt| {
+                pdpt.pd(
+                    pdpt_index(virt),
+                    &mut KERN_PD,
+                    Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
+                )
+            })
+            .expect("Failed to map kernel PD");
 
         let num_frames = num_tables::<{ paging::BASE_PAGE }>(len);
-        for i in 0..num_frames {
-            let delta = (i * paging::BASE_PAGE) as u64;
-            pd.pt(
-                pd_index(virt + delta),
-                &mut KERN_PT[pd_index(virt + delta) - pd_index(linker::VIRT_OFFSET)],
-                Flags::Enable(PDEFlags::P | PDEFlags::RW),
-            )
-            .map(
-                pt_index(virt + delta),
-                phys + delta,
-                Flags::Set(PTEFlags::P | PTEFlags::RW),
-            );
-        }
+        pd.map_region(
+            virt,
+            Region {
+                base: phys,
+                length: num_frames * paging::BASE_PAGE,
+            },
+            PTEFlags::P | PTEFlags::RW,
+            &mut KernPts,
+        )
+        .expect("Failed to map AP bootcode");
     }
 
     // The physical address at which we'll map the bootcode.
@@ -368,11 +781,14 @@ pub fn setup_ap_bootcode(vector: u8) {
     unsafe {
         // Make the lower-memory bootcode region available.
         // TODO: map extra pages for the data we pass to it.
-        map_bootcode(phys, virt, 0x3000);
+        map_bootcode(phys, virt, AP_BOOTCODE_SIZE);
 
         // zero
-        slice::from_raw_parts_mut((linker::VIRT_OFFSET + linker::_boot16()) as *mut u8, 0x3000)
-            .fill(0);
+        slice::from_raw_parts_mut(
+            (linker::VIRT_OFFSET + linker::_boot16()) as *mut u8,
+            AP_BOOTCODE_SIZE,
+        )
+        .fill(0);
 
         let src =
             slice::from_raw_parts((linker::PHYS_OFFSET + linker::_boot16()) as *const u8, len);
@@ -382,59 +798,95 @@ pub fn setup_ap_bootcode(vector: u8) {
     }
 }
 
+/// Return a mapper for the kernel device window at [linker::KDEV_OFFSET].
+///
+/// # Safety
+/// The mapper may not be used concurrently.
+unsafe fn kdev_pt() -> PtMapper<'static, { linker::VIRT_OFFSET as usize }> {
+    let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> = Mapper::new(&mut TOP);
+    mapper
+        .pdpt(
+            pml4_index(linker::KDEV_OFFSET),
+            &mut KERNEL_PDPT,
+            Flags::Enable(PML4EFlags::P | PML4EFlags::RW),
+        )
+        .and_then(|mut pdpt| {
+            pdpt.pd(
+                pdpt_index(linker::KDEV_OFFSET),
+                &mut KDEV_PD,
+                Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
+            )
+        })
+        .and_then(|mut pd| {
+            pd.pt(
+                pd_index(linker::KDEV_OFFSET),
+                &mut KDEV_PT,
+                Flags::Enable(PDEFlags::P | PDEFlags::RW),
+            )
+        })
+        .expect("Failed to map kernel device PT")
+}
+
 /// Map the given local APIC MMIO address to [linker::KDEV_OFFSET].
 ///
 /// The kernel tables do not have to be active for this operation to succeed. Because
-/// the MMIO region is relative to each CPU, this function should only be called once.
-pub fn map_apic(local_apic_address: u64, io_apics: &Vec<u32, { linker::MAX_IOAPICS }>) {
+/// the MMIO region is relative to each CPU, this function should only be called once,
+/// and panics otherwise.
+pub fn map_apic(local_apic_address: u64, io_apics: &Vec<IoApicInfo, { linker::MAX_IOAPICS }>) {
+    static MAPPED: AtomicBool = AtomicBool::new(false);
+    assert!(!MAPPED.swap(true, Ordering::AcqRel), "APICs already mapped");
+
     assert!(linker::LOCAL_APIC_ADDRESS >= linker::KDEV_OFFSET);
     assert!(
         linker::LOCAL_APIC_ADDRESS + paging::BASE_PAGE as u64
             <= linker::KDEV_OFFSET + (paging::GIGA_PAGE as u64 - 1)
     );
 
-    // TODO: Clean up
     unsafe {
-        let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> = Mapper::new(&mut TOP);
-        let mut pt = mapper
-            .pdpt(
-                pml4_index(linker::LOCAL_APIC_ADDRESS),
-                &mut KERNEL_PDPT,
-                Flags::Enable(PML4EFlags::P | PML4EFlags::RW),
-            )
-            .pd(
-                pdpt_index(linker::LOCAL_APIC_ADDRESS),
-                &mut KDEV_PD,
-                Flags::Enable(PDPTEFlags::P | PDPTEFlags::RW),
-            )
-            .pt(
-                pd_index(linker::LOCAL_APIC_ADDRESS),
-                &mut KDEV_PT,
-                Flags::Enable(PDEFlags::P | PDEFlags::RW),
-            );
-
+        let mut pt = kdev_pt();
         pt.map(
             pt_index(linker::LOCAL_APIC_ADDRESS),
             local_apic_address,
             Flags::Enable(PTEFlags::P | PTEFlags::PCD | PTEFlags::PWT | PTEFlags::RW),
-        );
+        )
+        .expect("Failed to map local APIC");
 
         let mut virt = linker::IO_APIC_OFFSET;
         for io_apic in io_apics {
             pt.map(
                 pt_index(virt),
-                *io_apic as u64,
+                io_apic.address as u64,
                 Flags::Enable(PTEFlags::P | PTEFlags::PCD | PTEFlags::PWT | PTEFlags::RW),
-            );
+            )
+            .expect("Failed to map IOAPIC");
             virt += 0x1000;
         }
     }
 }
 
+/// Map the given HPET MMIO address to [linker::HPET_ADDRESS].
+///
+/// Like [map_apic], this should only be called once, and panics otherwise.
+pub fn map_hpet(address: u64) {
+    static MAPPED: AtomicBool = AtomicBool::new(false);
+    assert!(!MAPPED.swap(true, Ordering::AcqRel), "HPET already mapped");
+
+    unsafe {
+        kdev_pt()
+            .map(
+                pt_index(linker::HPET_ADDRESS),
+                address,
+                Flags::Enable(PTEFlags::P | PTEFlags::PCD | PTEFlags::PWT | PTEFlags::RW),
+            )
+            .expect("Failed to map HPET");
+    }
+}
+
 /// Initialise the available physical memory.
 ///
-/// Any regions below 1M are filtered out, since we use that region to bootstrap
-/// APs.
+/// Only the kernel itself is excluded. Anything else that is still in use, like
+/// the AP bootcode, has to be excluded with [reserve_memory] before any frames
+/// are allocated.
 pub fn init_memory(mem: &Vec<MemoryDescriptor, { crate::MAX_MEM_REGIONS }>) {
     MEMORY
         .lock()
@@ -442,8 +894,306 @@ pub fn init_memory(mem: &Vec<MemoryDescriptor, { crate::MAX_MEM_REGIONS }>) {
         .expect("Memory already set!");
 }
 
+/// Exclude the given physical region from the available memory.
+///
+/// Fails with [MemoryError::TooManyRegions] if the region splits a free one
+/// and there's no room left to track both halves. The allocator has room for
+/// the [BOOT_RESERVATIONS](memory::BOOT_RESERVATIONS), as long as they are
+/// made before anything is allocated.
+///
+/// [init_memory] must have been called first.
+pub fn reserve_memory(region: Region) -> memory::Result<()> {
+    MEMORY
+        .lock()
+        .get_mut()
+        .expect("Memory not initialised")
+        .reserve(region)
+}
+
+/// Allocate a 4K frame in the given zone (see [Memory::alloc_zone]).
+///
+/// [init_memory] must have been called first.
+pub fn alloc_frame(zone: Zone) -> memory::Result<u64> {
+    MEMORY
+        .lock()
+        .get_mut()
+        .expect("Memory not initialised")
+        .alloc_zone(zone)
+}
+
+/// A physically contiguous buffer for device DMA.
+///
+/// The physical window is read-only, so the buffer is mapped into the DMA
+/// window at [linker::DMA_OFFSET] instead. It's mapped uncached, so the CPU and
+/// the device always agree on its contents. The buffer is zeroed on
+/// allocation, and unmapped and freed when dropped.
+///
+/// The buffer can't leave the CPU that allocated it, so only that CPU ever
+/// caches its translation, and flushing it locally on drop is enough.
+///
+/// TODO: make it [Send] once we have TLB shootdowns.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    /// The physical address of the buffer.
+    phys: u64,
+
+    /// The first page of the buffer in the DMA window.
+    page: usize,
+
+    /// The number of 4K pages in the buffer.
+    pages: usize,
+
+    /// Keeps the buffer on the CPU it was mapped on.
+    _local: PhantomData<*const u8>,
+}
+
+impl DmaBuffer {
+    /// Allocate a buffer of at least `size` bytes, aligned to `align` bytes and
+    /// in the given zone (see [Memory::alloc_contiguous]).
+    ///
+    /// [init_memory] must have been called first, and the kernel tables must be
+    /// active.
+    pub fn new(size: usize, align: usize, zone: Zone) -> Result<Self, &'static str> {
+        let pages = num_tables::<{ paging::BASE_PAGE }>(size.max(1));
+        if pages > linker::DMA_PAGES {
+            return Err("DMA buffer too large");
+        }
+
+        let mut window = DMA_WINDOW.lock();
+        let page = (0..=linker::DMA_PAGES - pages)
+            .find(|&page| window[page..page + pages].iter().all(|used| !used))
+            .ok_or("DMA window is full")?;
+
+        let phys = MEMORY
+            .lock()
+            .get_mut()
+            .expect("Memory not initialised")
+            .alloc_contiguous(pages, align, zone)
+            .map_err(|_| "Out of contiguous memory")?;
+
+        window[page..page + pages].fill(true);
+        let buffer = DmaBuffer {
+            phys,
+            page,
+            pages,
+            _local: PhantomData,
+        };
+
+        unsafe {
+            // The window lock is held, so nobody else uses the mapper.
+            let mut pt = kdev_pt();
+            for i in 0..pages {
+                let virt = buffer.virt() + (i * paging::BASE_PAGE) as u64;
+                pt.map(
+                    pt_index(virt),
+                    phys + (i * paging::BASE_PAGE) as u64,
+                    Flags::Set(
+                        PTEFlags::P | PTEFlags::PCD | PTEFlags::PWT | PTEFlags::RW | PTEFlags::XD,
+                    ),
+                )
+                .expect("Failed to map DMA buffer");
+            }
+
+            ptr::write_bytes(buffer.virt() as *mut u8, 0, buffer.size());
+        }
+
+        Ok(buffer)
+    }
+
+    /// The physical address of the buffer, to hand to the device.
+    pub fn phys(&self) -> u64 {
+        self.phys
+    }
+
+    /// The virtual address of the buffer.
+    pub fn virt(&self) -> u64 {
+        linker::DMA_OFFSET + (self.page * paging::BASE_PAGE) as u64
+    }
+
+    /// The size of the buffer in bytes, a multiple of 4K.
+    pub fn size(&self) -> usize {
+        self.pages * paging::BASE_PAGE
+    }
+
+    /// Access the buffer from the CPU.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt() as *const u8, self.size()) }
+    }
+
+    /// Access the buffer from the CPU.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt() as *mut u8, self.size()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut window = DMA_WINDOW.lock();
+
+        unsafe {
+            let mut pt = kdev_pt();
+            for i in 0..self.pages {
+                let virt = self.virt() + (i * paging::BASE_PAGE) as u64;
+                pt.map(pt_index(virt), 0, Flags::Set(PTEFlags::empty()))
+                    .expect("Failed to unmap DMA buffer");
+                tlb::flush(virt as usize);
+            }
+        }
+
+        let region = Region {
+            base: self.phys,
+            length: self.size(),
+        };
+        if let Err(err) = MEMORY
+            .lock()
+            .get_mut()
+            .expect("Memory not initialised")
+            .free(region)
+        {
+            println!("Failed to free DMA buffer {:?} ({:?})", region, err);
+        }
+
+        window[self.page..self.page + self.pages].fill(false);
+    }
+}
+
+/// Check that a [DmaBuffer] is aligned and physically contiguous.
+///
+/// A 16K buffer is allocated with 16K alignment, and every page of it must be
+/// mapped to the next frame after the previous one. The buffer is freed again
+/// afterwards, and its window pages must be unmapped.
+#[cfg(feature = "self-test")]
+pub fn verify_dma() {
+    const SIZE: usize = 4 * paging::BASE_PAGE;
+
+    let buffer = DmaBuffer::new(SIZE, SIZE, Zone::Dma32).expect("Failed to allocate DMA buffer");
+    assert_eq!(buffer.phys() % SIZE as u64, 0, "DMA buffer isn't aligned");
+    assert_eq!(buffer.size(), SIZE, "DMA buffer has the wrong size");
+    assert!(
+        buffer.as_slice().iter().all(|&b| b == 0),
+        "DMA buffer isn't zeroed"
+    );
+
+    {
+        // Hold the window lock, so nobody changes the mappings meanwhile.
+        let _window = DMA_WINDOW.lock();
+        let pt = unsafe { &*ptr::addr_of!(KDEV_PT) };
+        for i in 0..SIZE / paging::BASE_PAGE {
+            let virt = buffer.virt() + (i * paging::BASE_PAGE) as u64;
+            assert_eq!(
+                pt.table[pt_index(virt)].frame(),
+                buffer.phys() + (i * paging::BASE_PAGE) as u64,
+                "DMA buffer isn't contiguous"
+            );
+        }
+    }
+
+    let virt = buffer.virt();
+    drop(buffer);
+
+    let _window = DMA_WINDOW.lock();
+    let pt = unsafe { &*ptr::addr_of!(KDEV_PT) };
+    for i in 0..SIZE / paging::BASE_PAGE {
+        let virt = virt + (i * paging::BASE_PAGE) as u64;
+        assert!(
+            !pt.table[pt_index(virt)].flags().contains(PTEFlags::P),
+            "DMA buffer is still mapped"
+        );
+    }
+}
+
+/// Return ACPI reclaimable memory that is no longer in use to the allocator.
+///
+/// Regions are only reclaimed when they don't overlap any of the tables we
+/// still reference: the RSDT/XSDT, every table it points to, the DSDT and the
+/// FACS. A region containing any of those is kept as a whole.
+///
+/// [init_memory] must have been called first.
+pub fn reclaim_acpi(tables: &AcpiTables, descriptors: &[MemoryDescriptor]) {
+    let table_region = |phys: usize, length: u32| Region {
+        base: phys as u64,
+        length: length as usize,
+    };
+
+    let is_referenced = |region: &Region| {
+        let root = tables.version().header();
+        if Region::are_overlapping(
+            region,
+            &table_region(tables.physical_address(root), root.length),
+        ) {
+            return true;
+        }
+
+        // The DSDT isn't listed in the root table, it's referenced by the FADT.
+        // Even an invalid DSDT is kept, so don't use `tables.dsdt()` here.
+        if let Some(dsdt) = tables.fadt().and_then(|fadt| fadt.dsdt_address()) {
+            let header = unsafe { &*((dsdt + tables.offset()) as *const acpi::sdt::SdtHeader) };
+
+            if Region::are_overlapping(region, &table_region(dsdt, header.length)) {
+                return true;
+            }
+        }
+
+        // Neither is the FACS, which firmware keeps using for the global lock
+        // and the waking vector. Its signature isn't checked for the same reason.
+        if let Some(facs) = tables.fadt().and_then(|fadt| fadt.facs_address()) {
+            let length = unsafe { (*((facs + tables.offset()) as *const Facs)).length };
+            let length = length.max(mem::size_of::<Facs>() as u32);
+
+            if Region::are_overlapping(region, &table_region(facs, length)) {
+                return true;
+            }
+        }
+
+        if tables.iter_with_addr().any(|(phys, table)| {
+            Region::are_overlapping(region, &table_region(phys, table.header().length))
+        }) {
+            return true;
+        }
+
+        // Tables failing validation are skipped by the walk above, but they may
+        // be in use by firmware anyway. Only entries we can't follow are ignored.
+        tables.iter_invalid().any(|(phys, err)| {
+            if matches!(err, AcpiError::InvalidAddress) {
+                return false;
+            }
+
+            let header = unsafe { &*((phys + tables.offset()) as *const acpi::sdt::SdtHeader) };
+            Region::are_overlapping(region, &table_region(phys, header.length))
+        })
+    };
+
+    let mut memory = MEMORY.lock();
+    let memory = memory.get_mut().expect("Memory not initialised");
+
+    for desc in descriptors
+        .iter()
+        .filter(|desc| desc.kind == MemoryKind::AcpiReclaimable)
+        .filter(|desc| !is_referenced(&desc.region))
+    {
+        if memory.free(desc.region).is_err() {
+            println!("Failed to reclaim ACPI memory: {:?}", desc.region);
+        }
+    }
+}
+
+/// Return the bottom of the kernel stack that belongs to the per-cpu block at
+/// `storage` (see [allocate_percpus]).
+///
+/// The stack follows the block after a guard hole, and is [linker::STACK_SIZE]
+/// bytes long.
+pub fn percpu_stack(storage: u64) -> u64 {
+    let block_size = (linker::_epercpu_load() - linker::_percpu_load()) as usize;
+    let frames_per_block = num_tables::<{ paging::BASE_PAGE }>(block_size);
+
+    storage + (frames_per_block * paging::BASE_PAGE + linker::STACK_GUARD_SIZE) as u64
+}
+
 /// Allocate per-cpu data.
 ///
+/// If memory runs out, data is only allocated for the first CPUs, so fewer
+/// than `num` entries can be returned.
+///
 /// # Safety
 /// This function may only be called after the kernel tables are active!
 pub fn allocate_percpus(num: usize) -> Vec<PerCpuInfo, { linker::MAX_CPUS }> {
@@ -451,11 +1201,13 @@ pub fn allocate_percpus(num: usize) -> Vec<PerCpuInfo, { linker::MAX_CPUS }> {
 
     unsafe {
         let mut mapper: Mapper<{ linker::VIRT_OFFSET as usize }> = Mapper::new(&mut TOP);
-        let mut pdpt = mapper.pdpt(
-            pdpt_index(linker::PERCPU_OFFSET),
-            &mut KERNEL_PDPT,
-            Flags::Enable(PML4EFlags::P | PML4EFlags::RW),
-        );
+        let mut pdpt = mapper
+            .pdpt(
+                pdpt_index(linker::PERCPU_OFFSET),
+                &mut KERNEL_PDPT,
+                Flags::Enable(PML4EFlags::P | PML4EFlags::RW),
+            )
+            .expect("Failed to map per-cpu PDPT");
 
         allocate_per_cpus(
             &mut pdpt,
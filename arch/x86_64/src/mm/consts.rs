@@ -17,11 +17,25 @@ use super::paging;
 ///         |    guard page     |
 ///         +-------------------+
 ///                ...
-pub const PERCPU_WINDOW_SIZE: usize =
-    (linker::KERNEL_PHYS_START as usize + linker::STACK_SIZE + (linker::STACK_GUARD_SIZE * 2))
-        * linker::MAX_CPUS;
+pub const PERCPU_WINDOW_SIZE: usize = PERCPU_BLOCK_SIZE * linker::MAX_CPUS;
+
+/// The size of a single per-cpu block in the [PERCPU_WINDOW_SIZE] window.
+pub const PERCPU_BLOCK_SIZE: usize =
+    linker::KERNEL_PHYS_START as usize + linker::STACK_SIZE + (linker::STACK_GUARD_SIZE * 2);
+
+/// Return the number of per-cpu blocks that fit between [linker::PERCPU_OFFSET] and
+/// the kernel image at [linker::VIRT_OFFSET].
+pub const fn max_percpus() -> usize {
+    (linker::VIRT_OFFSET - linker::PERCPU_OFFSET) as usize / PERCPU_BLOCK_SIZE
+}
+
+// Raising `MAX_CPUS` grows the per-cpu window, make sure it doesn't run into the kernel.
+const _: () = assert!(linker::MAX_CPUS <= max_percpus());
 
 pub const NUM_PERCPU_PDS: usize = paging::num_tables::<{ paging::PD_COVERAGE }>(PERCPU_WINDOW_SIZE);
-pub const NUM_PERCPU_PTS: usize = paging::num_tables::<{ paging::PT_COVERAGE }>(PERCPU_WINDOW_SIZE);
 pub const NUM_PHYS_PDPTS: usize =
     paging::num_tables::<{ paging::PDPT_COVERAGE }>(linker::MAX_PHYS_MEMORY);
+
+/// The number of spare tables to map firmware structures above
+/// [linker::MAX_PHYS_MEMORY]. Each one covers 512G.
+pub const NUM_EXTRA_PHYS_PDPTS: usize = 2;
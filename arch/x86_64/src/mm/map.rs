@@ -1,6 +1,10 @@
-use super::paging::{
-    is_aligned, PDEFlags, PDPTEFlags, PML4EFlags, PTEFlags, BASE_PAGE, GIGA_PAGE, MEGA_PAGE, PD,
-    PDE, PDPT, PDPTE, PML4, PML4E, PT, PTE,
+use super::{
+    desc::Region,
+    phys_addr_mask,
+    paging::{
+        is_aligned, pd_index, pdpt_index, pt_index, PDEFlags, PDPTEFlags, PML4EFlags, PTEFlags,
+        BASE_PAGE, GIGA_PAGE, MEGA_PAGE, PD, PDE, PDPT, PDPTE, PML4, PML4E, PT, PTE,
+    },
 };
 
 /// Handle flags on mapped entries.
@@ -23,6 +27,79 @@ pub enum Flags<T> {
     Disable(T),
 }
 
+/// Errors that can occur while mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// The entry is present and already maps a different frame.
+    AlreadyMapped,
+
+    /// No table was available to map the range with.
+    OutOfFrames,
+
+    /// The index or address is out of range.
+    InvalidRange,
+
+    /// The address is not properly aligned.
+    Unaligned,
+}
+
+pub type Result<T> = core::result::Result<T, PagingError>;
+
+/// Provides the PTs [PdMapper::map_region] maps 4K pages in.
+pub trait PtAlloc {
+    /// Return the physical address of a new, zeroed PT, or None if there are
+    /// no frames left.
+    fn alloc(&mut self) -> Option<u64>;
+
+    /// Return the PT at the physical address `phys`.
+    ///
+    /// # Safety
+    /// `phys` must be a PT linked in the PD that translates `virt`, in the
+    /// tables the allocator was made for.
+    unsafe fn pt(&mut self, phys: u64, virt: u64) -> &mut PT;
+}
+
+/// Check the index of an entry in a table.
+#[inline]
+fn check_index(idx: usize) -> Result<()> {
+    if idx < 512 {
+        Ok(())
+    } else {
+        Err(PagingError::InvalidRange)
+    }
+}
+
+/// Check a physical address for the given alignment.
+#[inline]
+fn check_frame<const ALIGNMENT: usize>(frame: u64) -> Result<()> {
+    if !is_aligned::<ALIGNMENT>(frame) {
+        Err(PagingError::Unaligned)
+    } else if !is_valid_phys(frame) {
+        Err(PagingError::InvalidRange)
+    } else {
+        Ok(())
+    }
+}
+
+/// Check if a table is page aligned.
+#[inline]
+fn check_table(table: u64) -> Result<()> {
+    if is_aligned::<{ BASE_PAGE }>(table) {
+        Ok(())
+    } else {
+        Err(PagingError::Unaligned)
+    }
+}
+
+/// Return true if the physical address fits in the width supported by the CPU.
+///
+/// A virtual address accidentally passed as a physical one has its high bits set,
+/// which would otherwise only show up as a reserved-bit page fault.
+#[inline]
+fn is_valid_phys(addr: u64) -> bool {
+    addr & !phys_addr_mask() == 0
+}
+
 macro_rules! flags {
     ($old_flags:expr, $flags:ident) => {{
         let old_flags = $old_flags;
@@ -54,25 +131,38 @@ pub struct Mapper<'a, const LINK_OFFSET: usize> {
 }
 
 impl<'a, const LINK_OFFSET: usize> Mapper<'a, LINK_OFFSET> {
+    /// Create a mapper for the given top table.
+    ///
+    /// The table must be page aligned. The table types take care of that, but
+    /// not a table cast from a raw pointer. Tables handed to the other mappers
+    /// are checked as well, and rejected with [PagingError::Unaligned].
     pub fn new(top: &'a mut PML4) -> Self {
+        debug_assert!(
+            check_table(top.table.as_ptr() as u64).is_ok(),
+            "Top table is not page aligned"
+        );
         Self { top }
     }
 
     /// Map a 512G memory range.
+    ///
+    /// Like the tables handed to the other mappers, `pdpt` must be zeroed
+    /// before its first use (see [Memory::alloc_zeroed](super::memory::Memory::alloc_zeroed)).
     pub fn pdpt<'b>(
         &mut self,
         pml4_idx: usize,
         pdpt: &'b mut PDPT,
         flags: Flags<PML4EFlags>,
-    ) -> PdptMapper<'b, LINK_OFFSET> {
-        assert!(pml4_idx < 512);
-        assert!(is_aligned::<{ BASE_PAGE }>(pdpt.table.as_ptr() as u64));
+    ) -> Result<PdptMapper<'b, LINK_OFFSET>> {
+        check_index(pml4_idx)?;
+        check_table(pdpt.table.as_ptr() as u64)?;
+
         self.top.table[pml4_idx] = PML4E::new(
             pdpt.table.as_ptr() as u64 - LINK_OFFSET as u64,
             flags!(self.top.table[pml4_idx].flags(), flags),
         );
 
-        PdptMapper { pdpt }
+        Ok(PdptMapper { pdpt })
     }
 }
 
@@ -87,12 +177,21 @@ impl<'a, const LINK_OFFSET: usize> PdptMapper<'a, LINK_OFFSET> {
     /// Map a 1G page.
     ///
     /// It is up to the caller to provide the apprioriate flags (P, PS, etc).
-    pub fn map(&mut self, pdpt_idx: usize, frame: u64, flags: Flags<PDPTEFlags>) {
-        assert!(pdpt_idx < 512);
-        assert!(is_aligned::<{ GIGA_PAGE }>(frame));
+    pub fn map(&mut self, pdpt_idx: usize, frame: u64, flags: Flags<PDPTEFlags>) -> Result<()> {
+        check_index(pdpt_idx)?;
+        check_frame::<{ GIGA_PAGE }>(frame)?;
+
+        let old = self.pdpt.table[pdpt_idx];
+        let new = PDPTE::new(frame, flags!(old.flags(), flags));
+        if old.flags().contains(PDPTEFlags::P)
+            && new.flags().contains(PDPTEFlags::P)
+            && old.address() != new.address()
+        {
+            return Err(PagingError::AlreadyMapped);
+        }
 
-        self.pdpt.table[pdpt_idx] =
-            PDPTE::new(frame, flags!(self.pdpt.table[pdpt_idx].flags(), flags));
+        self.pdpt.table[pdpt_idx] = new;
+        Ok(())
     }
 
     /// Map a 1G memory range.
@@ -101,16 +200,16 @@ impl<'a, const LINK_OFFSET: usize> PdptMapper<'a, LINK_OFFSET> {
         pdpt_idx: usize,
         pd: &'b mut PD,
         flags: Flags<PDPTEFlags>,
-    ) -> PdMapper<'b, LINK_OFFSET> {
-        assert!(pdpt_idx < 512);
-        assert!(is_aligned::<{ BASE_PAGE }>(pd.table.as_ptr() as u64));
+    ) -> Result<PdMapper<'b, LINK_OFFSET>> {
+        check_index(pdpt_idx)?;
+        check_table(pd.table.as_ptr() as u64)?;
 
         self.pdpt.table[pdpt_idx] = PDPTE::new(
             pd.table.as_ptr() as u64 - LINK_OFFSET as u64,
             flags!(self.pdpt.table[pdpt_idx].flags(), flags),
         );
 
-        PdMapper { pd }
+        Ok(PdMapper { pd })
     }
 }
 
@@ -125,11 +224,21 @@ impl<'a, const LINK_OFFSET: usize> PdMapper<'a, LINK_OFFSET> {
     /// Map a 2M page.
     ///
     /// It is up to the caller to provide the apprioriate flags (P, PS, etc).
-    pub fn map(&mut self, pd_idx: usize, frame: u64, flags: Flags<PDEFlags>) {
-        assert!(pd_idx < 512);
-        assert!(is_aligned::<{ MEGA_PAGE }>(frame));
+    pub fn map(&mut self, pd_idx: usize, frame: u64, flags: Flags<PDEFlags>) -> Result<()> {
+        check_index(pd_idx)?;
+        check_frame::<{ MEGA_PAGE }>(frame)?;
 
-        self.pd.table[pd_idx] = PDE::new(frame, flags!(self.pd.table[pd_idx].flags(), flags));
+        let old = self.pd.table[pd_idx];
+        let new = PDE::new(frame, flags!(old.flags(), flags));
+        if old.flags().contains(PDEFlags::P)
+            && new.flags().contains(PDEFlags::P)
+            && old.address() != new.address()
+        {
+            return Err(PagingError::AlreadyMapped);
+        }
+
+        self.pd.table[pd_idx] = new;
+        Ok(())
     }
 
     /// Map a 2M memory range.
@@ -138,16 +247,87 @@ impl<'a, const LINK_OFFSET: usize> PdMapper<'a, LINK_OFFSET> {
         pd_idx: usize,
         pt: &'b mut PT,
         flags: Flags<PDEFlags>,
-    ) -> PtMapper<'b, LINK_OFFSET> {
-        assert!(pd_idx < 512);
-        assert!(is_aligned::<{ BASE_PAGE }>(pt.table.as_ptr() as u64));
+    ) -> Result<PtMapper<'b, LINK_OFFSET>> {
+        check_index(pd_idx)?;
+        check_table(pt.table.as_ptr() as u64)?;
 
         self.pd.table[pd_idx] = PDE::new(
             pt.table.as_ptr() as u64 - LINK_OFFSET as u64,
             flags!(self.pd.table[pd_idx].flags(), flags),
         );
 
-        PtMapper { pt }
+        Ok(PtMapper { pt })
+    }
+
+    /// Map the physical `region` at `virt`.
+    ///
+    /// 2M pages are used where both the virtual and physical address are 2M
+    /// aligned and at least 2M of the region remains. Everything else is mapped
+    /// using 4K pages, in the PT already linked for the given virtual address,
+    /// or a new one from `pts`. If `pts` runs out, [PagingError::OutOfFrames]
+    /// is returned. The region must fit in the 1G range covered by this PD.
+    pub fn map_region(
+        &mut self,
+        virt: u64,
+        region: Region,
+        flags: PTEFlags,
+        pts: &mut impl PtAlloc,
+    ) -> Result<()> {
+        if !is_aligned::<{ BASE_PAGE }>(virt) || !is_aligned::<{ BASE_PAGE }>(region.base) {
+            return Err(PagingError::Unaligned);
+        }
+
+        if region.length == 0 || pdpt_index(virt) != pdpt_index(virt + region.length as u64 - 1)
+        {
+            return Err(PagingError::InvalidRange);
+        }
+
+        // The PAT bit lives at a different position for 2M pages, so only use
+        // them when it isn't set.
+        let huge = cfg!(feature = "hugepages") && !flags.contains(PTEFlags::PAT);
+        let pd_flags = PDEFlags::from_bits_truncate(flags.bits()) | PDEFlags::PS;
+        let pt_flags = {
+            let mut pt_flags = PDEFlags::P | PDEFlags::RW;
+            pt_flags.set(PDEFlags::US, flags.contains(PTEFlags::US));
+            pt_flags
+        };
+
+        let mut offset = 0;
+        while offset < region.length {
+            let virt = virt + offset as u64;
+            let frame = region.base + offset as u64;
+
+            if huge
+                && is_aligned::<{ MEGA_PAGE }>(virt)
+                && is_aligned::<{ MEGA_PAGE }>(frame)
+                && region.length - offset >= MEGA_PAGE
+            {
+                self.map(pd_index(virt), frame, Flags::Enable(pd_flags))?;
+                offset += MEGA_PAGE;
+            } else {
+                let pd_idx = pd_index(virt);
+                let pde = self.pd.table[pd_idx];
+                let pt = if !pde.flags().contains(PDEFlags::P) {
+                    let pt = pts.alloc().ok_or(PagingError::OutOfFrames)?;
+                    check_table(pt)?;
+                    pt
+                } else if pde.flags().contains(PDEFlags::PS) {
+                    return Err(PagingError::AlreadyMapped);
+                } else {
+                    pde.address()
+                };
+                self.pd.table[pd_idx] = PDE::new(pt, pde.flags() | pt_flags);
+
+                // The PT was linked in this PD just now.
+                let mut pt = PtMapper::<LINK_OFFSET> {
+                    pt: unsafe { pts.pt(pt, virt) },
+                };
+                pt.map(pt_index(virt), frame, Flags::Enable(flags))?;
+                offset += BASE_PAGE;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -161,10 +341,20 @@ impl<'a, const LINK_OFFSET: usize> PtMapper<'a, LINK_OFFSET> {
     /// Map a 4K page.
     ///
     /// It is up to the caller to provide the apprioriate flags (P, etc).
-    pub fn map(&mut self, pt_idx: usize, frame: u64, flags: Flags<PTEFlags>) {
-        assert!(pt_idx < 512);
-        assert!(is_aligned::<{ BASE_PAGE }>(frame));
+    pub fn map(&mut self, pt_idx: usize, frame: u64, flags: Flags<PTEFlags>) -> Result<()> {
+        check_index(pt_idx)?;
+        check_frame::<{ BASE_PAGE }>(frame)?;
+
+        let old = self.pt.table[pt_idx];
+        let new = PTE::new(frame, flags!(old.flags(), flags));
+        if old.flags().contains(PTEFlags::P)
+            && new.flags().contains(PTEFlags::P)
+            && old.frame() != new.frame()
+        {
+            return Err(PagingError::AlreadyMapped);
+        }
 
-        self.pt.table[pt_idx] = PTE::new(frame, flags!(self.pt.table[pt_idx].flags(), flags));
+        self.pt.table[pt_idx] = new;
+        Ok(())
     }
 }
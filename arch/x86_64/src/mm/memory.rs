@@ -1,4 +1,4 @@
-use core::cmp::Ordering;
+use core::{cmp::Ordering, mem};
 
 use heapless::{binary_heap::Min, BinaryHeap, Vec};
 use itertools::Itertools;
@@ -10,13 +10,62 @@ use super::{
     paging,
 };
 
-const LOWERMEM_END: u64 = paging::MEGABYTE as u64;
+/// The number of ranges reserved while booting: the kernel itself, the real
+/// mode IVT, the AP bootcode, the framebuffer and every module.
+pub const BOOT_RESERVATIONS: usize = 4 + crate::boot::MAX_MODULES;
+
+/// The maximum number of reserved ranges.
+///
+/// Every reservation can split at most a single free region in two, so this
+/// bounds the number of extra regions we need to keep track of. On top of the
+/// [BOOT_RESERVATIONS], this leaves room for buffers allocated later on with
+/// [alloc_contiguous](Memory::alloc_contiguous).
+pub const MAX_RESERVATIONS: usize = BOOT_RESERVATIONS + 8;
 
 pub type Result<T> = core::result::Result<T, MemoryError>;
 
 #[derive(Debug, Clone, Copy)]
 pub enum MemoryError {
     Oom,
+    TooManyRegions,
+}
+
+/// A range of physical memory, for devices that can't address all of it.
+///
+/// Zones nest: a frame in [Zone::Low] is in [Zone::Dma32] as well, and every
+/// frame is in [Zone::Normal].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16M, for legacy (ISA) DMA.
+    Low,
+
+    /// Below 4G, for devices limited to 32-bit addresses.
+    Dma32,
+
+    /// Anywhere.
+    Normal,
+}
+
+impl Zone {
+    /// The end of the zone (exclusive).
+    pub const fn end(&self) -> u64 {
+        match self {
+            Zone::Low => 16 * paging::MEGABYTE as u64,
+            Zone::Dma32 => 4 * paging::GIGABYTE as u64,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    /// Return the zone the given frame belongs to.
+    pub const fn of(frame: u64) -> Self {
+        if frame < Zone::Low.end() {
+            Zone::Low
+        } else if frame < Zone::Dma32.end() {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
 }
 
 /// Keeps track of usable memory.
@@ -24,26 +73,27 @@ pub enum MemoryError {
 pub struct Memory<const NUM_REGIONS: usize> {
     /// An heap of usable memory regions.
     ///
-    /// Note that the heap is `+ 1` in size here. When we parse the region
-    /// containing the kernel, we need to split it in two, which would create
-    /// an extra entry.
-    mem: BinaryHeap<Region, Min, { crate::MAX_MEM_REGIONS + 1 }>,
+    /// Note that the heap is `+ MAX_RESERVATIONS` in size here. Reserving a
+    /// range in the middle of a region splits it in two, which creates an
+    /// extra entry.
+    mem: BinaryHeap<Region, Min, { crate::MAX_MEM_REGIONS + MAX_RESERVATIONS }>,
 }
 
 impl<const NUM_REGIONS: usize> Memory<NUM_REGIONS> {
     /// Initialise the free memory using the given descriptors.
     ///
-    /// Any descriptor for memory within the first 1M will be discarded. Overlapping regions
-    /// will be merged, and every region is 4K aligned. Memory occupied by the kernel will
-    /// not be included, so any frame retrieved with [`next`] is guaranteed to be free.
+    /// Overlapping regions will be merged, and every region is 4K aligned. Any
+    /// part of them covered by a descriptor that isn't usable (reserved, ACPI,
+    /// defective) is excluded afterwards. Memory occupied by the kernel is
+    /// reserved, any other range that is in use has to be excluded with
+    /// [`reserve`](Memory::reserve) before handing out frames.
     pub fn new(descriptors: &Vec<MemoryDescriptor, { crate::MAX_MEM_REGIONS }>) -> Self {
         let kernel_region: Region = Region {
             base: linker::KERNEL_PHYS_START,
             length: (linker::_end() - linker::VIRT_OFFSET) as usize,
         };
-        let kernel_region = kernel_region.align::<{ paging::BASE_PAGE }>().unwrap();
 
-        let mut mem: BinaryHeap<Region, Min, { crate::MAX_MEM_REGIONS + 1 }> = BinaryHeap::new();
+        let mut mem = BinaryHeap::new();
         let mut descriptors = descriptors.clone();
 
         // For the coalescing we need the regions to be sorted.
@@ -60,92 +110,92 @@ impl<const NUM_REGIONS: usize> Memory<NUM_REGIONS> {
             })
             .coalesce(|left, right| {
                 if Region::are_overlapping(&left, &right) {
-                    Ok(Region::merge(&left, &right).unwrap())
+                    Ok(Region::merge(&left, &right).expect("Failed to merge overlapping regions"))
                 } else {
                     Err((left, right))
                 }
             })
             .filter_map(|region| region.align::<{ paging::BASE_PAGE }>())
-            .filter_map(|region| {
-                if region.base < LOWERMEM_END {
-                    if region.end() < LOWERMEM_END {
-                        None
-                    } else {
-                        Some(Region {
-                            base: LOWERMEM_END,
-                            length: (region.end() - LOWERMEM_END) as usize,
-                        })
-                    }
-                } else {
-                    Some(region)
-                }
-            })
             .for_each(|region| {
-                let final_region = {
-                    if Region::are_overlapping(&region, &kernel_region) {
-                        if region.end() <= kernel_region.end() {
-                            if region.base < kernel_region.base {
-                                // Region end lies before kernel end, region base lies before
-                                // kernel base, since we overlap that means the region end falls
-                                // partly inside the kernel region.
-                                Some(Ok(Region {
-                                    base: region.base,
-                                    length: (kernel_region.base - region.base) as usize,
-                                }))
-                            } else {
-                                // The region base falls inside the kernel region, as does the end,
-                                // so we discard it.
-                                None
-                            }
-                        } else {
-                            if region.base < kernel_region.base {
-                                // The region end falls beyond the kernel region end, and region
-                                // base falls before the kernel region base. That means the region
-                                // is split in half by the kernel region.
-                                let before = Region {
-                                    base: region.base,
-                                    length: (kernel_region.base - region.base) as usize,
-                                };
-
-                                let after = Region {
-                                    base: kernel_region.end(),
-                                    length: (region.end() - kernel_region.end()) as usize,
-                                };
-
-                                Some(Err((before, after)))
-                            } else {
-                                Some(Ok(Region {
-                                    base: kernel_region.end(),
-                                    length: (region.end() - kernel_region.end()) as usize,
-                                }))
-                            }
-                        }
-                    } else {
-                        Some(Ok(region))
-                    }
-                };
-
-                if let Some(region) = final_region {
-                    // Safety: we have at most `NUM_REGIONS` to work with. Any overlapping or
-                    // duplicates are merged first. So, there can only be a single occurance
-                    // of a region splitting in two because the kernel lies in the middle of it.
-                    // Since the heap we use is `NUM_REGIONS + 1`, it should not overflow.
-                    match region {
-                        Ok(region) => unsafe { mem.push_unchecked(region) },
-                        Err((before, after)) => unsafe {
-                            mem.push_unchecked(before);
-                            mem.push_unchecked(after);
-                        },
-                    }
-                }
+                // Safety: we have at most `MAX_MEM_REGIONS` descriptors, and
+                // coalescing only reduces that number.
+                unsafe { mem.push_unchecked(region) }
             });
 
-        Memory { mem }
+        let mut memory = Memory { mem };
+
+        // Descriptors may overlap, so a usable region can still contain memory
+        // the firmware marked otherwise. Every descriptor splits at most one
+        // region, so this fits.
+        for desc in descriptors.iter().filter(|desc| !desc.is_usable()) {
+            memory
+                .reserve(desc.region)
+                .expect("Failed to exclude an unusable region");
+        }
+
+        memory
+            .reserve(kernel_region)
+            .expect("Failed to reserve the kernel region");
+
+        memory
+    }
+
+    /// Exclude the given region from the free memory.
+    ///
+    /// The region is extended to 4K boundaries, and any free region overlapping
+    /// it is split around it. This can be called repeatedly, but only before
+    /// frames are handed out, since those are not tracked.
+    pub fn reserve(&mut self, region: Region) -> Result<()> {
+        let base = paging::align_down::<{ paging::BASE_PAGE }>(region.base);
+        let end = paging::align_up::<{ paging::BASE_PAGE }>(region.end());
+        let reserved = Region {
+            base,
+            length: (end - base) as usize,
+        };
+
+        // Free regions don't overlap, so at most one of them can be split.
+        let splits = self
+            .mem
+            .iter()
+            .filter(|free| free.base < reserved.base && free.end() > reserved.end())
+            .count();
+        if self.mem.len() + splits > self.mem.capacity() {
+            return Err(MemoryError::TooManyRegions);
+        }
+
+        let free = mem::replace(&mut self.mem, BinaryHeap::new());
+        for region in free.into_vec() {
+            let (before, after) = region.exclude(&reserved);
+
+            // Safety: checked above.
+            for region in [before, after].into_iter().flatten() {
+                unsafe { self.mem.push_unchecked(region) };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return a region of memory to the allocator.
+    ///
+    /// The region is 4K aligned first, just like in [`new`](Memory::new). The
+    /// caller must make sure the region is actually free, and not already
+    /// tracked.
+    pub fn free(&mut self, region: Region) -> Result<()> {
+        let region = match region.align::<{ paging::BASE_PAGE }>() {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+
+        self.mem
+            .push(region)
+            .map_err(|_| MemoryError::TooManyRegions)
     }
 
     /// Return the maximum length of a contiguous chunk of memory.
     ///
     /// It does *not* return the total remaining memory!
+    #[must_use]
     pub fn max(&self) -> usize {
         self.mem.peek().map_or(0, |r| r.length)
     }
@@ -162,7 +212,139 @@ impl<const NUM_REGIONS: usize> Memory<NUM_REGIONS> {
         }
     }
 
+    /// Return a 4K frame aligned to `align` bytes.
+    ///
+    /// `align` must be a power of two, anything below 4K is treated as 4K. The
+    /// lowest suitable frame is used. Taking a frame from the middle of a region
+    /// splits it, which fails with [MemoryError::TooManyRegions] if there is no
+    /// room for the extra region.
+    #[must_use = "the frame is lost unless it is freed"]
+    pub fn alloc_aligned(&mut self, align: usize) -> Result<u64> {
+        assert!(align.is_power_of_two());
+        let align = align.max(paging::BASE_PAGE) as u64;
+
+        let frame = self
+            .mem
+            .iter()
+            .filter_map(|region| {
+                let base = (region.base + align - 1) & !(align - 1);
+                if base + paging::BASE_PAGE as u64 <= region.end() {
+                    Some(base)
+                } else {
+                    None
+                }
+            })
+            .min()
+            .ok_or(MemoryError::Oom)?;
+
+        self.reserve(Region {
+            base: frame,
+            length: paging::BASE_PAGE,
+        })?;
+
+        Ok(frame)
+    }
+
+    /// Return the highest free 4K frame.
+    ///
+    /// Unlike [next](Memory::next), this leaves low memory to the devices that
+    /// need it. The frame is taken from the end of its region, so this never
+    /// has to split one.
+    #[must_use = "the frame is lost unless it is freed"]
+    pub fn alloc_high(&mut self) -> Result<u64> {
+        self.alloc_zone(Zone::Normal)
+    }
+
+    /// Return the highest free 4K frame in the given zone.
+    ///
+    /// Taking the highest frame keeps the lower zones free for as long as
+    /// possible, so e.g. a [Zone::Dma32] frame only comes from below 16M once
+    /// everything between 16M and 4G is in use. A region crossing the end of
+    /// the zone is split, which fails with [MemoryError::TooManyRegions] if
+    /// there is no room for the extra region.
+    #[must_use = "the frame is lost unless it is freed"]
+    pub fn alloc_zone(&mut self, zone: Zone) -> Result<u64> {
+        let frame = self
+            .mem
+            .iter()
+            .filter(|region| region.base < zone.end())
+            .filter_map(|region| {
+                let end = region.end().min(zone.end());
+                let base = end - paging::BASE_PAGE as u64;
+                if base >= region.base {
+                    Some(base)
+                } else {
+                    None
+                }
+            })
+            .max()
+            .ok_or(MemoryError::Oom)?;
+
+        self.reserve(Region {
+            base: frame,
+            length: paging::BASE_PAGE,
+        })?;
+
+        Ok(frame)
+    }
+
+    /// Return `count` physically contiguous 4K frames in the given zone,
+    /// aligned to `align` bytes.
+    ///
+    /// `align` must be a power of two, anything below 4K is treated as 4K.
+    /// Like [alloc_zone](Memory::alloc_zone), the highest suitable range is
+    /// used, which may split a region.
+    #[must_use = "the frames are lost unless they are freed"]
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize, zone: Zone) -> Result<u64> {
+        assert!(count > 0 && align.is_power_of_two());
+        let align = align.max(paging::BASE_PAGE) as u64;
+        let length = count * paging::BASE_PAGE;
+
+        let base = self
+            .mem
+            .iter()
+            .filter_map(|region| {
+                let end = region.end().min(zone.end());
+                let base = end.checked_sub(length as u64)? & !(align - 1);
+                if base >= region.base {
+                    Some(base)
+                } else {
+                    None
+                }
+            })
+            .max()
+            .ok_or(MemoryError::Oom)?;
+
+        self.reserve(Region { base, length })?;
+
+        Ok(base)
+    }
+
+    /// Return the next 4K block, zeroed.
+    ///
+    /// Frames returned by [next](Memory::next) hold whatever was there before.
+    /// Frames used as page tables or stacks must come from here, since stale
+    /// entries would have their present bit set. The [MEMORY](super::MEMORY)
+    /// lock, if held, is taken before the scratch page lock.
+    ///
+    /// # Safety
+    /// See [zero_frame](super::zero_frame).
+    #[must_use = "the frame is lost unless it is freed"]
+    pub unsafe fn alloc_zeroed(&mut self) -> Result<u64> {
+        let frame = self.next()?;
+        super::zero_frame(frame);
+        Ok(frame)
+    }
+
     /// Return the next 4K block.
+    ///
+    /// This is [alloc_aligned](Memory::alloc_aligned) with the minimum
+    /// alignment, but never has to split a region. The frame isn't cleared,
+    /// see [alloc_zeroed](Memory::alloc_zeroed).
+    ///
+    /// The lowest free frame is returned, use [alloc_high](Memory::alloc_high)
+    /// for frames that don't need to be in low memory.
+    #[must_use = "the frame is lost unless it is freed"]
     pub fn next(&mut self) -> Result<u64> {
         match self.max().cmp(&paging::BASE_PAGE) {
             Ordering::Less => {
@@ -177,7 +359,7 @@ impl<const NUM_REGIONS: usize> Memory<NUM_REGIONS> {
                 .map(|region| region.base)
                 .ok_or(MemoryError::Oom),
             Ordering::Greater => {
-                let mut region = self.mem.peek_mut().unwrap();
+                let mut region = self.mem.peek_mut().expect("Free memory heap is empty");
                 let base = region.base;
                 region.base += paging::BASE_PAGE as u64;
                 region.length -= paging::BASE_PAGE;
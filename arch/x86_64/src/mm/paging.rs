@@ -259,8 +259,9 @@ bitflags! {
         /// Page size; when set this entry references a 1Gbyte page.
         const PS = 1 << 7;
 
-        /// Ignored by hardware.
-        const IGNORED_1 = 1 << 8;
+        /// Global; if CR4.PGE = 1 and this entry maps a 1GByte page, determines whether the
+        /// translation is global. Ignored otherwise.
+        const G = 1 << 8;
 
         /// Available to user.
         const USER_0 = 1 << 9;
@@ -377,8 +378,9 @@ bitflags! {
         /// Page size; when set this entry references a 2MByte page.
         const PS = 1 << 7;
 
-        /// Ignored by hardware.
-        const IGNORED_1 = 1 << 8;
+        /// Global; if CR4.PGE = 1 and this entry maps a 2MByte page, determines whether the
+        /// translation is global. Ignored otherwise.
+        const G = 1 << 8;
 
         /// Available to user.
         const USER_0 = 1 << 9;
@@ -0,0 +1,144 @@
+//! Process-context identifiers.
+//!
+//! With PCIDs enabled, the TLB tags translations with the PCID in bits 11:0 of
+//! CR3, so installing another top table doesn't have to flush them. Every CPU
+//! hands out its own PCIDs to the top tables it installs, and recycles them
+//! round robin. Re-installing a top table that still owns its PCID sets the
+//! no-flush bit, keeping its cached translations.
+//!
+//! Global translations (the G flag) are shared by all PCIDs. Switching top
+//! tables never flushes them, and neither does invalidating a single PCID, so
+//! changes to global mappings still need INVLPG on every CPU, or toggling
+//! CR4.PGE.
+//!
+//! Without PCID support, top tables are installed with a plain CR3 write, which
+//! flushes all non-global translations.
+
+use core::{arch::asm, cell::RefCell};
+
+use x86::{
+    controlregs::{cr4, cr4_write, Cr4},
+    tlb,
+};
+
+use crate::{cpu::cpuid, percpu};
+
+/// The number of PCIDs handed out per CPU. PCID 0 is left for the boot tables.
+pub const NUM_PCIDS: usize = 8;
+
+/// When set in a CR3 write, the translations of the new PCID are kept.
+const NOFLUSH: u64 = 1 << 63;
+
+/// INVPCID type for invalidating a single address in a single PCID.
+const INVPCID_ADDRESS: u64 = 0;
+
+/// INVPCID type for invalidating all non-global translations of a PCID.
+const INVPCID_CONTEXT: u64 = 1;
+
+#[derive(Debug)]
+struct Pcids {
+    /// The top table owning each PCID, or 0 if it's free.
+    tops: [u64; NUM_PCIDS],
+
+    /// The next PCID to recycle.
+    next: usize,
+}
+
+percpu! {
+    static PCIDS: RefCell<Pcids> = RefCell::new(Pcids {
+        tops: [0; NUM_PCIDS],
+        next: 0,
+    });
+}
+
+/// Enable PCIDs on the current CPU, if supported.
+///
+/// This must be done while the PCID in CR3 is 0, which is the case as long as
+/// no top table was installed through [cr3].
+pub fn enable() -> bool {
+    if !cpuid().features.has_pcid() {
+        return false;
+    }
+
+    unsafe {
+        cr4_write(cr4() | Cr4::CR4_ENABLE_PCID);
+    }
+
+    true
+}
+
+/// Returns true if PCIDs are enabled on the current CPU.
+pub fn is_enabled() -> bool {
+    unsafe { cr4().contains(Cr4::CR4_ENABLE_PCID) }
+}
+
+/// Compute the CR3 value to install `top` on the current CPU.
+///
+/// If `top` still owns a PCID, the no-flush bit is set. Otherwise the oldest
+/// PCID is taken over, and its stale translations are flushed by the write.
+pub fn cr3(top: u64) -> u64 {
+    if !is_enabled() {
+        return top;
+    }
+
+    PCIDS.with_borrow_mut(|pcids| {
+        if let Some(idx) = pcids.tops.iter().position(|&owner| owner == top) {
+            top | (idx as u64 + 1) | NOFLUSH
+        } else {
+            let idx = pcids.next;
+            pcids.next = (idx + 1) % NUM_PCIDS;
+            pcids.tops[idx] = top;
+            top | (idx as u64 + 1)
+        }
+    })
+}
+
+/// Invalidate the translation of `addr` in the given top table, on the current
+/// CPU.
+///
+/// If `top` is installed, INVLPG is enough. Otherwise only its PCID is
+/// targeted with INVPCID. If INVPCID isn't supported, `top` loses its PCID so
+/// its next install flushes everything.
+pub fn invalidate(top: u64, addr: u64, current: u64) {
+    if top == current || !is_enabled() {
+        unsafe { tlb::flush(addr as usize) };
+        return;
+    }
+
+    PCIDS.with_borrow_mut(|pcids| {
+        if let Some(idx) = pcids.tops.iter().position(|&owner| owner == top) {
+            if cpuid().ext_features.has_invpcid() {
+                unsafe { invpcid(INVPCID_ADDRESS, idx as u64 + 1, addr) };
+            } else {
+                pcids.tops[idx] = 0;
+            }
+        }
+    })
+}
+
+/// Invalidate all non-global translations of the given top table, on the
+/// current CPU.
+///
+/// `top` must not be installed.
+pub fn invalidate_all(top: u64) {
+    PCIDS.with_borrow_mut(|pcids| {
+        if let Some(idx) = pcids.tops.iter().position(|&owner| owner == top) {
+            if cpuid().ext_features.has_invpcid() {
+                unsafe { invpcid(INVPCID_CONTEXT, idx as u64 + 1, 0) };
+            } else {
+                pcids.tops[idx] = 0;
+            }
+        }
+    })
+}
+
+/// Invalidate translations using INVPCID.
+unsafe fn invpcid(typ: u64, pcid: u64, addr: u64) {
+    let descriptor: [u64; 2] = [pcid, addr];
+    asm!(
+        "invpcid ({}), {}",
+        in(reg) &descriptor,
+        in(reg) typ,
+        options(att_syntax, nostack, preserves_flags)
+    );
+}
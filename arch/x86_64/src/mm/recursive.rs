@@ -0,0 +1,103 @@
+//! Recursive mapping of the page tables.
+//!
+//! Entry [RECURSIVE_INDEX] of the top table points back at the top table. A
+//! walk that goes through it once more than usual ends at a page table instead
+//! of a page, so every table of the active address space can be reached at a
+//! fixed virtual address. Unlike the physical memory window, this works as soon
+//! as the top table is installed.
+//!
+//! The recursive entry is part of the top table, so switching address spaces
+//! switches the tables visible through it as well. Top tables must install it
+//! themselves (see [install]).
+//!
+//! The translations are cached in the TLB like any other. After changing an
+//! entry, the recursive address of the table it points to must be flushed with
+//! INVLPG before that table is accessed through it. Since the top table is
+//! also walked as a lower level table, its entry must not have the G flag (bit
+//! 8) set, else those translations would survive a CR3 switch.
+
+use crate::linker;
+
+use super::paging::{pd_index, pdpt_index, pml4_index, PML4EFlags, PML4, PML4E};
+
+/// The index of the recursive entry in the top table.
+pub const RECURSIVE_INDEX: usize = 510;
+
+// Make sure the recursive entry doesn't take the place of any of our windows.
+const _: () = {
+    assert!(pml4_index(linker::PHYS_OFFSET) != RECURSIVE_INDEX);
+    assert!(pml4_index(linker::PERCPU_OFFSET) != RECURSIVE_INDEX);
+    assert!(pml4_index(linker::KERNEL_START) != RECURSIVE_INDEX);
+    assert!(pml4_index(linker::KDEV_OFFSET) != RECURSIVE_INDEX);
+};
+
+/// The level of a page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Pml4,
+    Pdpt,
+    Pd,
+    Pt,
+}
+
+/// Build a canonical address from four table indices.
+const fn address(pml4: usize, pdpt: usize, pd: usize, pt: usize) -> u64 {
+    let addr = (pml4 as u64) << 39 | (pdpt as u64) << 30 | (pd as u64) << 21 | (pt as u64) << 12;
+
+    // Sign extend bit 47.
+    if addr & (1 << 47) != 0 {
+        addr | 0xffff_0000_0000_0000
+    } else {
+        addr
+    }
+}
+
+/// Return the virtual address of the top table.
+pub const fn pml4_addr() -> u64 {
+    address(
+        RECURSIVE_INDEX,
+        RECURSIVE_INDEX,
+        RECURSIVE_INDEX,
+        RECURSIVE_INDEX,
+    )
+}
+
+/// Return the virtual address of the PDPT referenced by the given top table
+/// entry.
+pub const fn pdpt_addr(pml4: usize) -> u64 {
+    address(RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX, pml4)
+}
+
+/// Return the virtual address of the PD referenced by the given entries.
+pub const fn pd_addr(pml4: usize, pdpt: usize) -> u64 {
+    address(RECURSIVE_INDEX, RECURSIVE_INDEX, pml4, pdpt)
+}
+
+/// Return the virtual address of the PT referenced by the given entries.
+pub const fn pt_addr(pml4: usize, pdpt: usize, pd: usize) -> u64 {
+    address(RECURSIVE_INDEX, pml4, pdpt, pd)
+}
+
+/// Return the virtual address of the table at `level` used to translate `virt`.
+///
+/// The table is only accessible if every table above it is present.
+pub const fn table_addr(level: Level, virt: u64) -> u64 {
+    let pml4 = pml4_index(virt);
+    let pdpt = pdpt_index(virt);
+    let pd = pd_index(virt);
+
+    match level {
+        Level::Pml4 => pml4_addr(),
+        Level::Pdpt => pdpt_addr(pml4),
+        Level::Pd => pd_addr(pml4, pdpt),
+        Level::Pt => pt_addr(pml4, pdpt, pd),
+    }
+}
+
+/// Install the recursive entry in the given top table.
+///
+/// # Safety
+/// `phys` must be the physical address of `top`.
+pub unsafe fn install(top: &mut PML4, phys: u64) {
+    top.table[RECURSIVE_INDEX] = PML4E::new(phys, PML4EFlags::P | PML4EFlags::RW | PML4EFlags::XD);
+}
@@ -0,0 +1,81 @@
+//! Memory mapped I/O.
+
+use core::{marker::PhantomData, mem};
+
+/// A region of memory mapped I/O, `SIZE` bytes long.
+///
+/// All offsets are in *bytes*, and every access is volatile. Registers are
+/// typically read and written as `u32` (or one of the `repr(transparent)`
+/// register types wrapping one).
+#[derive(Debug)]
+pub struct Mmio<const SIZE: usize> {
+    base: *mut u8,
+}
+
+/// Compile-time bounds check for accesses at a constant offset.
+struct Bounds<const SIZE: usize, const OFFSET: usize, T>(PhantomData<T>);
+
+impl<const SIZE: usize, const OFFSET: usize, T> Bounds<SIZE, OFFSET, T> {
+    const CHECK: () = assert!(
+        OFFSET + mem::size_of::<T>() <= SIZE && OFFSET % mem::align_of::<T>() == 0,
+        "MMIO access out of bounds or misaligned"
+    );
+}
+
+impl<const SIZE: usize> Mmio<SIZE> {
+    /// Wrap the MMIO region at the given virtual address.
+    ///
+    /// # Safety
+    /// `base` must point to `SIZE` bytes of mapped (uncached) MMIO.
+    pub const unsafe fn new(base: *mut u8) -> Self {
+        Self { base }
+    }
+
+    /// Return the base address of the region.
+    pub const fn as_ptr(&self) -> *mut u8 {
+        self.base
+    }
+
+    /// Read a value at the given byte offset.
+    ///
+    /// # Safety
+    /// Reading the register must not have side effects the caller isn't
+    /// prepared for.
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + mem::size_of::<T>() <= SIZE && offset % mem::align_of::<T>() == 0);
+        (self.base.add(offset) as *const T).read_volatile()
+    }
+
+    /// Write a value at the given byte offset.
+    ///
+    /// # Safety
+    /// The caller must make sure the value is legal for the register.
+    pub unsafe fn write<T: Copy>(&self, offset: usize, val: T) {
+        assert!(offset + mem::size_of::<T>() <= SIZE && offset % mem::align_of::<T>() == 0);
+        (self.base.add(offset) as *mut T).write_volatile(val)
+    }
+
+    /// Read a register at a constant byte offset.
+    ///
+    /// Out of bounds offsets are rejected at compile time.
+    ///
+    /// # Safety
+    /// See [read](Mmio::read).
+    pub unsafe fn read_reg<const OFFSET: usize, R: Copy>(&self) -> R {
+        #[allow(clippy::let_unit_value)]
+        let () = Bounds::<SIZE, OFFSET, R>::CHECK;
+        (self.base.add(OFFSET) as *const R).read_volatile()
+    }
+
+    /// Write a register at a constant byte offset.
+    ///
+    /// Out of bounds offsets are rejected at compile time.
+    ///
+    /// # Safety
+    /// See [write](Mmio::write).
+    pub unsafe fn write_reg<const OFFSET: usize, R: Copy>(&self, val: R) {
+        #[allow(clippy::let_unit_value)]
+        let () = Bounds::<SIZE, OFFSET, R>::CHECK;
+        (self.base.add(OFFSET) as *mut R).write_volatile(val)
+    }
+}
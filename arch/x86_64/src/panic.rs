@@ -1,6 +1,81 @@
-use core::panic::PanicInfo;
+use core::{
+    cell::Cell,
+    hint,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
 
-use crate::println;
+use x86::{halt as hlt, irq};
+
+use crate::{
+    apic::{self, ApicAccess},
+    boot::serial_console::{self, Color},
+    cpu,
+    idt::handler::Frame,
+    interrupt_handler, percpu, pm_timer, print, println,
+};
+
+/// How long the panicking CPU waits for the others to park, in microseconds.
+const PARK_TIMEOUT_US: u64 = 100_000;
+
+/// Set once any CPU panics.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// The number of CPUs parked on behalf of the panicking CPU.
+static PARKED: AtomicU32 = AtomicU32::new(0);
+
+percpu! {
+    /// Whether the current CPU is counted in [PARKED].
+    static IS_PARKED: Cell<bool> = Cell::new(false);
+}
+
+/// Return whether a CPU is panicking.
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Acquire)
+}
+
+/// Stop the current CPU.
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            irq::disable();
+            hlt();
+        }
+    }
+}
+
+/// Stop the current CPU on behalf of the panicking CPU, and let it know.
+///
+/// A CPU can get here more than once, e.g. an NMI arriving after the stop IPI,
+/// but it is only counted once.
+pub fn park() -> ! {
+    if !IS_PARKED.with(|parked| parked.replace(true)) {
+        PARKED.fetch_add(1, Ordering::AcqRel);
+    }
+    halt();
+}
+
+/// Wait until every other CPU is parked, or until [PARK_TIMEOUT_US] passed.
+///
+/// Without a PM timer, the wait is bounded by a number of spins instead.
+fn await_parked() {
+    let others = crate::num_cpus().saturating_sub(1);
+    for _ in 0..PARK_TIMEOUT_US / 10 {
+        if PARKED.load(Ordering::Acquire) >= others {
+            return;
+        }
+        if pm_timer::udelay(10).is_err() {
+            hint::spin_loop();
+        }
+    }
+}
+
+interrupt_handler! {
+    /// Stop the CPU on behalf of another CPU that panicked.
+    pub fn stop_handler(_frame: Frame) {
+        park();
+    }
+}
 
 #[lang = "eh_personality"]
 #[no_mangle]
@@ -9,9 +84,46 @@ pub extern "C" fn rust_eh_personality() {}
 #[lang = "panic_impl"]
 #[no_mangle]
 pub extern "C" fn rust_begin_panic(panic_info: &PanicInfo) -> ! {
-    // TODO:
-    // - unwind the stack
-    // - use a proper logger and not println.
-    println!("{:?}", panic_info);
-    loop {}
+    unsafe {
+        irq::disable();
+    }
+
+    // Only the first panic gets to print.
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        park();
+    }
+
+    // Stop the other CPUs so their output doesn't interleave with ours. The
+    // BSP APIC ID is only set once the local APIC is usable. The stop IPI only
+    // reaches CPUs with interrupts enabled, the NMI takes care of the rest (see
+    // `traps::nmi`). If neither is accepted, there's
+    // nothing more we can do about it. APIC errors are deferred, so a timeout
+    // doesn't touch the console (see `apic::defer_errors`).
+    if crate::BSP_APIC_ID.load(Ordering::Relaxed) != u32::MAX {
+        let apic = apic::local();
+        let _ = apic.ipi_others(apic::STOP_VECTOR);
+        let _ = apic.ipi_nmi_others();
+
+        // Don't unlock the console while another CPU may still be writing.
+        await_parked();
+    }
+
+    // Whoever held the serial port isn't going to release it anymore.
+    unsafe {
+        serial_console::force_unlock();
+    }
+
+    // TODO: use a proper logger and not println.
+    serial_console::set_color(Color::Red);
+    print!("Kernel panic");
+    if let Some(location) = panic_info.location() {
+        print!(" at {}", location);
+    }
+    match panic_info.message() {
+        Some(message) => println!(": {}", message),
+        None => println!(),
+    }
+    serial_console::reset_color();
+    cpu::backtrace(None);
+    halt();
 }
@@ -7,7 +7,7 @@ use core::{
     cell::{OnceCell, RefCell},
 };
 
-use x86::msr::{wrmsr, IA32_GS_BASE};
+use crate::{cpu, tls};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Error {
@@ -49,6 +49,14 @@ impl<T: 'static> PerCpu<T> {
     pub fn as_ptr(&'static self) -> *const T {
         unsafe { (self.inner)().expect("Failed to get self as ptr") as *const T }
     }
+
+    /// Provides a raw pointer to the instance of another CPU, given the offset
+    /// of its per-CPU region (see [init]).
+    ///
+    /// The pointer is only valid once that CPU has set up its per-CPU region.
+    pub fn remote_ptr(&'static self, percpu_offset: u64) -> *const T {
+        (self.as_ptr() as u64 - cpu::gs_base() + percpu_offset) as *const T
+    }
 }
 
 impl<T: 'static> PerCpu<OnceCell<T>> {
@@ -130,14 +138,14 @@ macro_rules! __percpu_internal {
 /// # Safety
 /// The given offset must be the start of the per-CPU region for the executing
 /// CPU. Furthermore, this value should be unique for each CPU. When this function
-/// returns, per-CPU storage is available.
+/// returns, per-CPU storage and thread-local storage are available.
 pub unsafe fn init(offset: u64) {
     #[no_mangle]
     #[link_section = ".percpu"]
     static mut PERCPU_OFFSET: u64 = 0;
 
     // Store the offset in `%gs`.
-    wrmsr(IA32_GS_BASE, offset);
+    cpu::set_gs_base(offset);
 
     // Set the PERCPU_OFFSET for this CPU.
     asm!(
@@ -146,4 +154,8 @@ pub unsafe fn init(offset: u64) {
         sym PERCPU_OFFSET,
         options(att_syntax, preserves_flags, nostack)
     );
+
+    // Point `%fs` at the TLS block of whatever runs on this CPU until threads
+    // take over.
+    tls::init();
 }
@@ -0,0 +1,88 @@
+//! The ACPI power management timer.
+//!
+//! A free-running counter at [FREQUENCY], which is either 24 or 32 bits wide.
+//! It's slow to read, but unlike the TSC its frequency is fixed, which makes it
+//! useful to calibrate other clocks. See ACPI v6.4 section 4.8.3.3.
+
+use core::{hint, ptr};
+
+use acpi::{address::AddressSpace, fadt::Fadt};
+use spin::Once;
+use x86::io::inl;
+
+use crate::linker;
+
+/// The frequency of the PM timer in Hz.
+pub const FREQUENCY: u64 = 3_579_545;
+
+#[derive(Debug, Clone, Copy)]
+struct PmTimer {
+    space: AddressSpace,
+    address: u64,
+
+    /// The bits implemented by the counter.
+    mask: u32,
+}
+
+static TIMER: Once<PmTimer> = Once::new();
+
+impl PmTimer {
+    fn read(&self) -> u32 {
+        let value = unsafe {
+            match self.space {
+                AddressSpace::SystemIo => inl(self.address as u16),
+                _ => ptr::read_volatile((linker::PHYS_OFFSET + self.address) as *const u32),
+            }
+        };
+        value & self.mask
+    }
+}
+
+/// Find the PM timer in the FADT.
+///
+/// Only timers in system memory or I/O space are supported. Memory mapped
+/// timers must be covered by the physical memory window.
+pub fn init(fadt: &Fadt) -> Result<(), &'static str> {
+    let (register, extended) = fadt.pm_timer_register().ok_or("No PM timer")?;
+    let space = register.address_space();
+    if !matches!(space, AddressSpace::SystemIo | AddressSpace::SystemMemory) {
+        return Err("Unsupported PM timer address space");
+    }
+
+    TIMER.call_once(|| PmTimer {
+        space,
+        address: register.address,
+        mask: if extended { u32::MAX } else { 0xff_ffff },
+    });
+    Ok(())
+}
+
+/// Return the bits implemented by the PM timer, or [None] if there is none.
+pub fn mask() -> Option<u32> {
+    TIMER.get().map(|timer| timer.mask)
+}
+
+/// Return the current value of the PM timer, or [None] if there is none.
+pub fn read() -> Option<u32> {
+    TIMER.get().map(PmTimer::read)
+}
+
+/// Busy-wait for at least `us` microseconds.
+///
+/// The counter is allowed to wrap while waiting. Fails if there is no PM timer
+/// (see [init]).
+pub fn udelay(us: u64) -> Result<(), &'static str> {
+    let timer = TIMER.get().ok_or("No PM timer")?;
+    let ticks = us * FREQUENCY / 1_000_000;
+
+    let mut last = timer.read();
+    let mut elapsed = 0;
+    while elapsed < ticks {
+        hint::spin_loop();
+        let now = timer.read();
+        elapsed += (now.wrapping_sub(last) & timer.mask) as u64;
+        last = now;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! Rebooting the machine.
+
+use core::ptr;
+
+use acpi::address::{AccessSize, PciAddress, Readable, Writable};
+use x86::{
+    dtables::{lidt, DescriptorTablePointer},
+    io::{inb, inl, inw, outb, outl, outw},
+    irq,
+};
+
+use crate::{linker, println};
+
+/// The 8042 keyboard controller status and command port.
+const KBC_PORT: u16 = 0x64;
+
+/// 8042 command to pulse the CPU reset line.
+const KBC_RESET: u8 = 0xfe;
+
+/// PCI configuration space access mechanism #1.
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+/// Register access for the ACPI crate.
+///
+/// Memory is accessed through the physical memory window. I/O ports and PCI
+/// configuration space are limited to 32-bit accesses, wider values are
+/// truncated.
+#[derive(Debug)]
+pub struct Access;
+
+impl Access {
+    /// Select the dword of PCI configuration space containing `address`.
+    unsafe fn select_pci_config(address: PciAddress) {
+        // Bus 0 of segment 0, see ACPI v6.4 section 5.2.3.2.
+        let config = 1 << 31
+            | (address.device as u32 & 0x1f) << 11
+            | (address.function as u32 & 0x7) << 8
+            | (address.offset as u32 & 0xfc);
+        outl(PCI_CONFIG_ADDRESS, config);
+    }
+}
+
+impl Readable for Access {
+    fn read_memory(&mut self, address: u64, size: AccessSize) -> u64 {
+        let address = linker::PHYS_OFFSET + address;
+        unsafe {
+            match size {
+                AccessSize::Byte => ptr::read_volatile(address as *const u8) as u64,
+                AccessSize::Word => ptr::read_volatile(address as *const u16) as u64,
+                AccessSize::DWord => ptr::read_volatile(address as *const u32) as u64,
+                AccessSize::QWord | AccessSize::Undefined => {
+                    ptr::read_volatile(address as *const u64)
+                }
+            }
+        }
+    }
+
+    fn read_io(&mut self, port: u16, size: AccessSize) -> u64 {
+        unsafe {
+            match size {
+                AccessSize::Byte => inb(port) as u64,
+                AccessSize::Word => inw(port) as u64,
+                _ => inl(port) as u64,
+            }
+        }
+    }
+
+    fn read_pci_config(&mut self, address: PciAddress, size: AccessSize) -> u64 {
+        unsafe {
+            Self::select_pci_config(address);
+            self.read_io(PCI_CONFIG_DATA + (address.offset & 0b11), size)
+        }
+    }
+}
+
+impl Writable for Access {
+    fn write_memory(&mut self, address: u64, size: AccessSize, value: u64) {
+        let address = linker::PHYS_OFFSET + address;
+        unsafe {
+            match size {
+                AccessSize::Byte => ptr::write_volatile(address as *mut u8, value as u8),
+                AccessSize::Word => ptr::write_volatile(address as *mut u16, value as u16),
+                AccessSize::DWord => ptr::write_volatile(address as *mut u32, value as u32),
+                AccessSize::QWord | AccessSize::Undefined => {
+                    ptr::write_volatile(address as *mut u64, value)
+                }
+            }
+        }
+    }
+
+    fn write_io(&mut self, port: u16, size: AccessSize, value: u64) {
+        unsafe {
+            match size {
+                AccessSize::Byte => outb(port, value as u8),
+                AccessSize::Word => outw(port, value as u16),
+                _ => outl(port, value as u32),
+            }
+        }
+    }
+
+    fn write_pci_config(&mut self, address: PciAddress, size: AccessSize, value: u64) {
+        unsafe {
+            Self::select_pci_config(address);
+            self.write_io(PCI_CONFIG_DATA + (address.offset & 0b11), size, value);
+        }
+    }
+}
+
+/// Wait roughly `ms` milliseconds.
+///
+/// There's no timer we can rely on here, but a write to the POST code port
+/// takes about a microsecond.
+fn io_delay(ms: u32) {
+    for _ in 0..ms * 1000 {
+        unsafe { outb(0x80, 0) };
+    }
+}
+
+/// Reboot the machine.
+///
+/// The ACPI reset register is tried first, if the FADT has one. If the machine
+/// is still alive after that, the 8042 is asked to pulse the reset line (unless
+/// the FADT says there is none), and as a last resort a triple fault is forced.
+pub fn reboot() -> ! {
+    unsafe { irq::disable() };
+
+    let fadt = crate::ACPI_TABLES.get().and_then(|tables| tables.fadt());
+    if let Some(fadt) = fadt {
+        match acpi::power::reboot(fadt, &mut Access) {
+            Ok(()) => io_delay(50),
+            Err(err) => println!("ACPI reset failed: {}", err),
+        }
+    }
+
+    unsafe {
+        if fadt.map_or(true, |fadt| fadt.summary().has_8042) {
+            // Wait for the input buffer to drain, then pulse the reset line.
+            for _ in 0..0x10000 {
+                if inb(KBC_PORT) & 0b10 == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            outb(KBC_PORT, KBC_RESET);
+            io_delay(50);
+        }
+
+        // With an empty IDT, the breakpoint escalates to a triple fault.
+        let idt: DescriptorTablePointer<u64> = DescriptorTablePointer {
+            limit: 0,
+            base: ptr::null(),
+        };
+        lidt(&idt);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
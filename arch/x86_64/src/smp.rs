@@ -1,13 +1,23 @@
-use core::mem;
+use core::{
+    arch::asm,
+    hint, mem, ptr,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
 
+use acpi::madt::{MultiProcessorWakeupMailbox, MultiProcessorWakeupMailboxCommand};
+use spin::Once;
 use x86::{dtables::DescriptorTablePointer, fence::mfence};
 
 use crate::{
-    apic,
+    apic::{self, ApicAccess},
+    boot::{self, IoApicInfo},
+    cpu,
     desc::{
         Access, CodeSegmentBits, DataSegmentBits, DescriptorFlags, UserDescriptor,
         UserDescriptorType,
     },
+    linker, percpu, pm_timer,
+    sync::{self, Backoff},
 };
 
 const BOOTSTRAP_DATA_OFFSET: usize = 0x1000;
@@ -17,6 +27,87 @@ const BOOTSTRAP_KERNEL_TOP_OFFSET: usize = 42;
 const BOOTSTRAP_STACK_OFFSET: usize = 50;
 const BOOTSTRAP_PERCPU_OFFSET: usize = 58;
 
+/// How long the firmware gets to pick up a wakeup mailbox command, in
+/// microseconds (see [Bootstrap::try_wakeup_ap]).
+const MAILBOX_TIMEOUT_US: u64 = 100_000;
+
+/// The number of CPUs that have reached the [barrier].
+static ARRIVED: AtomicU32 = AtomicU32::new(0);
+
+/// Wait until every CPU has reached this point.
+///
+/// Each CPU checks in once, and waits until all CPUs that are being booted
+/// have checked in as well (see [Backoff::snooze]). This is a one-shot barrier, it can't be reused.
+/// APs that fail to start are taken out of [num_cpus](crate::num_cpus) while
+/// the others are waiting, so the total is read again on every spin. An AP
+/// that starts but never gets here still blocks everyone forever.
+pub fn barrier() {
+    ARRIVED.fetch_add(1, Ordering::SeqCst);
+    let backoff = Backoff::new();
+    while ARRIVED.load(Ordering::SeqCst) < crate::num_cpus() {
+        backoff.snooze(sync::nap);
+    }
+}
+
+/// Return the IOAPICs, sorted by GSI base (see [boot::ApicInfo::io_apics]).
+pub fn io_apics() -> &'static [IoApicInfo] {
+    &boot::apic_info().io_apics
+}
+
+/// Return the physical address of the local APIC, as reported by the MADT.
+pub fn local_apic_address() -> u64 {
+    boot::apic_info().local_apic_address
+}
+
+/// The cache line an idle CPU monitors. Writing to it wakes the CPU up, see
+/// [idle] and [wake].
+#[derive(Debug)]
+#[repr(C, align(64))]
+pub struct WakeupLine {
+    pending: AtomicU8,
+}
+
+percpu! {
+    static WAKEUP: WakeupLine = WakeupLine {
+        pending: AtomicU8::new(0),
+    };
+}
+
+/// Idle the current CPU until it's woken up by [wake] or an interrupt.
+///
+/// With MONITOR/MWAIT, the CPU waits in the deepest C-state that keeps the
+/// local APIC timer running. Otherwise it halts, and only an interrupt wakes
+/// it. The caller checks for work with interrupts disabled, so nothing slips
+/// in before the CPU goes to sleep. Interrupts are enabled on return.
+///
+/// # Safety
+/// Interrupts must be disabled.
+pub unsafe fn idle() {
+    static HINT: Once<u32> = Once::new();
+
+    if !cpu::has_mwait() {
+        asm!("sti", "hlt", options(att_syntax, nostack));
+        return;
+    }
+
+    let hint = *HINT.call_once(cpu::mwait_deepest_hint);
+    WAKEUP.with(|line| {
+        cpu::mwait_idle(line.pending.as_ptr(), hint);
+        line.pending.store(0, Ordering::Relaxed);
+    });
+}
+
+/// Wake up the CPU with the given APIC ID if it's idle (see [idle]).
+///
+/// This only writes the CPU's [WakeupLine], a CPU that halts because it has no
+/// MWAIT keeps sleeping until its next interrupt.
+pub fn wake(apic_id: u32) -> Result<(), &'static str> {
+    let offset = boot::percpu_offset(apic_id).ok_or("Unknown CPU")?;
+    let line = unsafe { &*WAKEUP.remote_ptr(offset) };
+    line.pending.store(1, Ordering::Release);
+    Ok(())
+}
+
 /// Bootstrap GDT, used to enable 32-bit protected mode.
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -106,17 +197,90 @@ impl Bootstrap {
 
     /// Attempt to start the target AP with the given stack and percpu.
     ///
+    /// Fails if the INIT or STARTUP IPI isn't accepted, in which case the AP
+    /// should be skipped.
+    ///
     /// # Safety
     /// Caller must make sure apic_id, stack, and percpu are valid and unique
     /// for each AP!
-    pub unsafe fn try_start_ap(&self, apic_id: u32, stack: u64, percpu: u64) {
+    pub unsafe fn try_start_ap(
+        &self,
+        apic_id: u32,
+        stack: u64,
+        percpu: u64,
+    ) -> Result<(), &'static str> {
         self.write_data(BOOTSTRAP_STACK_OFFSET, stack);
         self.write_data(BOOTSTRAP_PERCPU_OFFSET, percpu);
 
         // Make sure the memory is synced between all CPUs.
         mfence();
 
-        apic::local().ipi_init(apic_id);
-        apic::local().ipi_startup(apic_id, (self.phys >> 12) as u8);
+        apic::local().ipi_init(apic_id)?;
+        apic::local().ipi_startup(apic_id, (self.phys >> 12) as u8)
     }
+
+    /// Attempt to start the target AP through the multiprocessor wakeup
+    /// mailbox, with the given stack and percpu.
+    ///
+    /// Some hypervisors (and confidential computing guests) don't support
+    /// INIT-SIPI-SIPI. Instead, the firmware parks the APs and wakes them up
+    /// when the mailbox command is set. The AP is handed over in long mode, so
+    /// it enters the bootstrap at `_start64`.
+    /// See ACPI v6.4 section 5.2.12.19
+    ///
+    /// Fails if the firmware doesn't pick up a command within
+    /// [MAILBOX_TIMEOUT_US].
+    ///
+    /// # Safety
+    /// Caller must make sure apic_id, stack, and percpu are valid and unique
+    /// for each AP! `mailbox` should point to the mailbox described by the MADT.
+    pub unsafe fn try_wakeup_ap(
+        &self,
+        mailbox: *mut MultiProcessorWakeupMailbox,
+        apic_id: u32,
+        stack: u64,
+        percpu: u64,
+    ) -> Result<(), &'static str> {
+        self.write_data(BOOTSTRAP_STACK_OFFSET, stack);
+        self.write_data(BOOTSTRAP_PERCPU_OFFSET, percpu);
+
+        let command = ptr::addr_of_mut!((*mailbox).command) as *mut u16;
+
+        // The firmware clears the command once it has picked up the request,
+        // the mailbox can't be used before then.
+        await_mailbox(command).map_err(|_| "Mailbox busy")?;
+
+        let vector = self.phys as u64 + (linker::_start64() - linker::_boot16());
+        ptr::addr_of_mut!((*mailbox).apic_id).write_unaligned(apic_id);
+        ptr::addr_of_mut!((*mailbox).wakeup_vector).write_unaligned(vector);
+
+        // The command must be written last, make sure the other fields are
+        // visible first.
+        mfence();
+
+        command.write_volatile(MultiProcessorWakeupMailboxCommand::Wakeup as u16);
+
+        await_mailbox(command)
+    }
+}
+
+/// Wait until the firmware has cleared the mailbox `command`, or until
+/// [MAILBOX_TIMEOUT_US] passed.
+///
+/// Without a PM timer, the wait is bounded by a number of spins instead.
+///
+/// # Safety
+/// `command` must point to the command of the wakeup mailbox.
+unsafe fn await_mailbox(command: *const u16) -> Result<(), &'static str> {
+    let nop = MultiProcessorWakeupMailboxCommand::Nop as u16;
+    for _ in 0..MAILBOX_TIMEOUT_US / 10 {
+        if command.read_volatile() == nop {
+            return Ok(());
+        }
+        if pm_timer::udelay(10).is_err() {
+            hint::spin_loop();
+        }
+    }
+
+    Err("Mailbox command not picked up")
 }
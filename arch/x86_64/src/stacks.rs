@@ -1,4 +1,6 @@
-use crate::{linker, percpu};
+use core::{ops::Range, ptr};
+
+use crate::{boot::early, cpu, linker, mm, percpu};
 
 percpu! {
     static NMI_STACK: IrqStack = IrqStack::zero();
@@ -19,6 +21,45 @@ impl IrqStack {
     }
 }
 
+/// The required alignment of a stack top, as dictated by the System V ABI.
+pub const STACK_ALIGNMENT: u64 = 16;
+
+// Kernel stacks start on a page boundary, so their top is aligned as long as
+// the size is.
+const _: () = assert!(linker::STACK_SIZE as u64 % STACK_ALIGNMENT == 0);
+
+/// The top of a kernel stack.
+///
+/// Stacks grow down, so this is the address right past the end of the stack.
+/// It's guaranteed to be aligned to [STACK_ALIGNMENT], and to lie within the
+/// per-cpu window, where kernel stacks are mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackTop(u64);
+
+impl StackTop {
+    /// Wrap the given stack top.
+    ///
+    /// Panics if `top` is misaligned or outside the per-cpu window.
+    pub fn new(top: u64) -> Self {
+        assert!(
+            top % STACK_ALIGNMENT == 0,
+            "Misaligned stack top {:#018x}",
+            top
+        );
+        assert!(
+            top > linker::PERCPU_OFFSET && top <= linker::VIRT_OFFSET,
+            "Stack top {:#018x} outside the per-cpu window",
+            top
+        );
+        Self(top)
+    }
+
+    /// Return the address of the stack top.
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
 /// Returns the top of the NMI stack for the current CPU.
 pub fn nmi_stack_top() -> u64 {
     NMI_STACK.with(IrqStack::top)
@@ -33,3 +74,35 @@ pub fn df_stack_top() -> u64 {
 pub fn mc_stack_top() -> u64 {
     MC_STACK.with(IrqStack::top)
 }
+
+/// Return the stack of the current CPU that contains `addr`, if any.
+///
+/// The boot stack, the kernel stack and the interrupt stacks are checked, the
+/// latter two only once per-CPU storage is set up.
+pub fn containing(addr: u64) -> Option<Range<u64>> {
+    let stack = |bottom: u64, size: usize| bottom..bottom + size as u64;
+    let irq_stack = |irq: &IrqStack| stack(irq.0.as_ptr() as u64, linker::INTERRUPT_STACK_SIZE);
+
+    let boot = stack(
+        unsafe { ptr::addr_of!(early::BOOT_STACK) } as u64,
+        linker::STACK_SIZE,
+    );
+    if boot.contains(&addr) {
+        return Some(boot);
+    }
+
+    let storage = cpu::gs_base();
+    if storage == 0 {
+        return None;
+    }
+
+    [
+        Some(stack(mm::percpu_stack(storage), linker::STACK_SIZE)),
+        NMI_STACK.try_with(irq_stack).ok(),
+        DF_STACK.try_with(irq_stack).ok(),
+        MC_STACK.try_with(irq_stack).ok(),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|stack| stack.contains(&addr))
+}
@@ -0,0 +1,25 @@
+//! Synchronisation helpers.
+
+use x86::{
+    bits64::rflags::{self, RFlags},
+    halt,
+};
+
+use crate::apic;
+
+pub use kernel::sync::Backoff;
+
+/// Sleep for [Backoff::snooze], by halting until the next tick of the periodic
+/// timer (see [apic::start_periodic]).
+///
+/// Nothing else guarantees that an interrupt arrives, so this returns false
+/// right away if the timer isn't running on this CPU, or interrupts are
+/// disabled.
+pub fn nap() -> bool {
+    if !rflags::read().contains(RFlags::FLAGS_IF) || !apic::is_ticking() {
+        return false;
+    }
+
+    unsafe { halt() };
+    true
+}
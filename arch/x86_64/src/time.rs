@@ -0,0 +1,186 @@
+//! A monotonic clock.
+//!
+//! The clock is backed by one of the available counters, picked once by [init]:
+//! the HPET if there is one, the TSC if it's invariant, and the PM timer as a
+//! last resort. Counters narrower than 64 bits are extended in software (see
+//! [ExtendedCounter]).
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+
+use crate::{cpu, hpet, pm_timer};
+
+/// The counter backing the clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Hpet,
+    Tsc,
+    PmTimer,
+}
+
+#[derive(Debug)]
+struct Clock {
+    source: ClockSource,
+
+    /// The frequency of the counter in Hz.
+    frequency: u64,
+
+    /// The bits implemented by the counter.
+    mask: u64,
+}
+
+static CLOCK: Once<Clock> = Once::new();
+
+/// Extends the PM timer to 64 bits.
+static PM_TIMER: ExtendedCounter = ExtendedCounter::new();
+
+/// Extends a counter narrower than 64 bits in software.
+///
+/// The counter must be read at least once every half wraparound period, which
+/// is about 2 seconds for a 24-bit PM timer, and several minutes for 32-bit
+/// counters.
+#[derive(Debug)]
+pub struct ExtendedCounter {
+    /// The last value, extended to 64 bits, or [UNSET](ExtendedCounter::UNSET)
+    /// before the first read.
+    last: AtomicU64,
+}
+
+impl ExtendedCounter {
+    const UNSET: u64 = u64::MAX;
+
+    pub const fn new() -> Self {
+        Self {
+            last: AtomicU64::new(Self::UNSET),
+        }
+    }
+
+    /// Extend the raw counter value `raw`, of which only the bits in `mask` are
+    /// implemented. The result never goes backwards.
+    pub fn extend(&self, raw: u64, mask: u64) -> u64 {
+        if mask == u64::MAX {
+            return raw;
+        }
+
+        // Another CPU may have extended the counter past our read already, in
+        // which case the difference is "negative" and the last value is used.
+        let mut last = self.last.load(Ordering::Acquire);
+        loop {
+            // The first read starts counting from the raw value.
+            if last == Self::UNSET {
+                match self.last.compare_exchange_weak(
+                    last,
+                    raw,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return raw,
+                    Err(actual) => {
+                        last = actual;
+                        continue;
+                    }
+                }
+            }
+
+            let delta = raw.wrapping_sub(last) & mask;
+            if delta > mask / 2 {
+                return last;
+            }
+
+            match self.last.compare_exchange_weak(
+                last,
+                last + delta,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return last + delta,
+                Err(actual) => last = actual,
+            }
+        }
+    }
+}
+
+impl Default for ExtendedCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pick the clock source.
+///
+/// Both the HPET and the PM timer must have been initialised already (see
+/// [hpet::init] and [pm_timer::init]), if present. The TSC is only used if it
+/// is invariant, since it isn't suitable for keeping time otherwise.
+pub fn init() -> Result<ClockSource, &'static str> {
+    let clock = if let Some(frequency) = hpet::frequency() {
+        Clock {
+            source: ClockSource::Hpet,
+            frequency,
+            mask: u64::MAX,
+        }
+    } else if let Some(frequency) = cpu::tsc_frequency().filter(|_| cpu::has_invariant_tsc()) {
+        Clock {
+            source: ClockSource::Tsc,
+            frequency,
+            mask: u64::MAX,
+        }
+    } else if let Some(mask) = pm_timer::mask() {
+        Clock {
+            source: ClockSource::PmTimer,
+            frequency: pm_timer::FREQUENCY,
+            mask: mask as u64,
+        }
+    } else {
+        return Err("No clock source");
+    };
+
+    Ok(CLOCK.call_once(|| clock).source)
+}
+
+/// Return the clock source, if the clock has been initialised.
+pub fn source() -> Option<ClockSource> {
+    CLOCK.get().map(|clock| clock.source)
+}
+
+/// Read the counter, extended to 64 bits.
+fn ticks(clock: &Clock) -> u64 {
+    match clock.source {
+        ClockSource::Hpet => hpet::counter(),
+        ClockSource::Tsc => cpu::rdtsc(),
+        ClockSource::PmTimer => {
+            let raw = pm_timer::read().expect("PM timer disappeared");
+            PM_TIMER.extend(raw as u64, clock.mask)
+        }
+    }
+}
+
+/// A point in time, measured by the monotonic clock.
+///
+/// Only meaningful compared to other instants, like `std::time::Instant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Return the current time. Panics if the clock hasn't been initialised.
+    pub fn now() -> Self {
+        let clock = CLOCK.get().expect("Clock not initialised");
+        Self(ticks(clock))
+    }
+
+    /// Return the time passed between `earlier` and this instant, or zero if
+    /// `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        let clock = CLOCK.get().expect("Clock not initialised");
+        let ticks = self.0.saturating_sub(earlier.0) as u128;
+        Duration::from_nanos((ticks * 1_000_000_000 / clock.frequency as u128) as u64)
+    }
+
+    /// Return the time passed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
@@ -0,0 +1,140 @@
+//! Thread-local storage.
+//!
+//! Per-CPU data is reached through the GS base (see [percpu](crate::percpu)).
+//! Every thread has a [TlsBlock], which becomes the current block of the CPU
+//! when switching to the thread (see [switch_to]). Until there are threads,
+//! every CPU runs on its own boot block, installed by
+//! [percpu::init](crate::percpu::init).
+//!
+//! The FS base is pointed at the block as well, and like the x86-64 ABI
+//! requires, the first word of a block points to the block itself. The kernel
+//! never reads through `%fs` though: user code can change the FS base (and
+//! whatever it points to) at will. Instead, the current block is kept in the
+//! per-CPU [CURRENT] pointer, and [ThreadLocal] variables live at a fixed
+//! offset from there.
+
+use core::{cell::Cell, marker::PhantomData, mem};
+
+use crate::{cpu, percpu};
+
+/// The thread-local variables of a single thread.
+#[derive(Debug)]
+#[repr(C)]
+pub struct TlsBlock {
+    /// The address of the block itself. Must be the first field!
+    this: Cell<u64>,
+
+    /// The error code of the last failed operation (see [ERRNO]).
+    errno: Cell<i64>,
+}
+
+impl TlsBlock {
+    pub const fn new() -> Self {
+        Self {
+            this: Cell::new(0),
+            errno: Cell::new(0),
+        }
+    }
+}
+
+impl Default for TlsBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+percpu! {
+    /// The TLS block used by the CPU before it runs any threads.
+    static BOOT_BLOCK: TlsBlock = TlsBlock::new();
+
+    /// The address of the [TlsBlock] of the thread running on the CPU.
+    static CURRENT: Cell<u64> = Cell::new(0);
+}
+
+/// A thread-local variable, stored at a fixed offset in the [TlsBlock] of the
+/// running thread.
+pub struct ThreadLocal<T: Copy> {
+    offset: usize,
+    _phantom: PhantomData<T>,
+}
+
+unsafe impl<T: Copy> Sync for ThreadLocal<T> {}
+
+impl<T: Copy> ThreadLocal<T> {
+    /// # Safety
+    /// `offset` must be the offset of a field of type `Cell<T>` in [TlsBlock].
+    const unsafe fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Read the value for the running thread.
+    #[inline(always)]
+    pub fn get(&'static self) -> T {
+        unsafe { self.as_ptr().read() }
+    }
+
+    /// Write the value for the running thread.
+    #[inline(always)]
+    pub fn set(&'static self, val: T) {
+        unsafe { self.as_ptr().write(val) }
+    }
+
+    #[inline(always)]
+    fn as_ptr(&'static self) -> *mut T {
+        (current() + self.offset as u64) as *mut T
+    }
+}
+
+/// The error code of the last failed operation of the running thread.
+pub static ERRNO: ThreadLocal<i64> = unsafe { ThreadLocal::new(mem::offset_of!(TlsBlock, errno)) };
+
+/// Return the address of the [TlsBlock] of the running thread.
+#[inline(always)]
+pub fn current() -> u64 {
+    CURRENT.with(Cell::get)
+}
+
+/// Make `block` the TLS block of the running thread.
+///
+/// # Safety
+/// The block must stay alive for as long as it's installed, and must not be
+/// installed on another CPU at the same time.
+pub unsafe fn switch_to(block: &TlsBlock) {
+    let address = block as *const TlsBlock as u64;
+    block.this.set(address);
+    CURRENT.with(|current| current.set(address));
+    cpu::set_fs_base(address);
+}
+
+/// Install the boot block of the current CPU.
+///
+/// # Safety
+/// Per-CPU storage must be available.
+pub unsafe fn init() {
+    BOOT_BLOCK.with(|block| switch_to(block));
+}
+
+/// Check that thread-local variables follow the running thread.
+///
+/// A different [ERRNO] is stored while running on the boot block and on a
+/// second block, switching between the two, and both are read back after
+/// switching. The boot block is installed again afterwards. Interrupts must be
+/// disabled, so nothing runs on the second block by accident.
+pub fn verify() {
+    let other = TlsBlock::new();
+    let saved = ERRNO.get();
+
+    ERRNO.set(1);
+    unsafe { switch_to(&other) };
+    assert_eq!(ERRNO.get(), 0, "fresh TLS block isn't empty");
+    ERRNO.set(2);
+
+    unsafe { BOOT_BLOCK.with(|block| switch_to(block)) };
+    assert_eq!(ERRNO.get(), 1, "ERRNO didn't survive a switch");
+    assert_eq!(other.errno.get(), 2, "ERRNO written to the wrong block");
+
+    ERRNO.set(saved);
+}
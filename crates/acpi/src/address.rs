@@ -1,3 +1,7 @@
+use core::fmt;
+
+use crate::{AcpiError, Result};
+
 /// Generic Address Structure.
 ///
 /// Expresses register addresses within tables defined by ACPI.
@@ -11,3 +15,233 @@ pub struct GenericAddress {
     pub access_size: u8,
     pub address: u64,
 }
+
+impl GenericAddress {
+    /// Return the address space the register lives in.
+    pub fn address_space(&self) -> AddressSpace {
+        match self.address_space_id {
+            0x00 => AddressSpace::SystemMemory,
+            0x01 => AddressSpace::SystemIo,
+            0x02 => AddressSpace::PciConfig,
+            id => AddressSpace::Other(id),
+        }
+    }
+
+    /// Read the register.
+    ///
+    /// The value is shifted down by `register_bit_offset` and truncated to
+    /// `register_bit_width`. The access size is picked like for [write].
+    ///
+    /// [write]: GenericAddress::write
+    pub fn read<A: Readable>(&self, access: &mut A) -> Result<u64> {
+        let size = self.effective_access_size();
+        self.validate_access(size)?;
+
+        let address = self.address;
+        let value = match self.address_space() {
+            AddressSpace::SystemMemory => access.read_memory(address, size),
+            AddressSpace::SystemIo => access.read_io(self.port()?, size),
+            AddressSpace::PciConfig => access.read_pci_config(PciAddress::from_gas(address), size),
+            AddressSpace::Other(id) => return Err(AcpiError::UnsupportedAddressSpace { id }),
+        };
+
+        let value = mask(value, size.bits()) >> self.register_bit_offset;
+        Ok(mask(value, self.register_bit_width as u32))
+    }
+
+    /// Write `value` to the register.
+    ///
+    /// The value is shifted into place by `register_bit_offset`. If the
+    /// firmware didn't declare an access size, it is derived from the width of
+    /// the register, defaulting to a byte access.
+    pub fn write<A: Writable>(&self, access: &mut A, value: u64) -> Result<()> {
+        let size = self.effective_access_size();
+        self.validate_access(size)?;
+
+        let address = self.address;
+        let value = value << self.register_bit_offset;
+
+        match self.address_space() {
+            AddressSpace::SystemMemory => access.write_memory(address, size, value),
+            AddressSpace::SystemIo => access.write_io(self.port()?, size, value),
+            AddressSpace::PciConfig => {
+                access.write_pci_config(PciAddress::from_gas(address), size, value)
+            }
+            AddressSpace::Other(id) => return Err(AcpiError::UnsupportedAddressSpace { id }),
+        }
+
+        Ok(())
+    }
+
+    /// Return the access size to use for the register: the declared one, or
+    /// one derived from the width of the register.
+    fn effective_access_size(&self) -> AccessSize {
+        match self.access_size() {
+            AccessSize::Undefined => match self.register_bit_width {
+                16 => AccessSize::Word,
+                32 => AccessSize::DWord,
+                64 => AccessSize::QWord,
+                _ => AccessSize::Byte,
+            },
+            size => size,
+        }
+    }
+
+    /// Return the address as an I/O port.
+    fn port(&self) -> Result<u16> {
+        u16::try_from(self.address).map_err(|_| AcpiError::InvalidAddress)
+    }
+
+    /// Return the access size declared by the firmware.
+    ///
+    /// Unknown values are treated as [AccessSize::Undefined].
+    pub fn access_size(&self) -> AccessSize {
+        match self.access_size {
+            1 => AccessSize::Byte,
+            2 => AccessSize::Word,
+            3 => AccessSize::DWord,
+            4 => AccessSize::QWord,
+            _ => AccessSize::Undefined,
+        }
+    }
+
+    /// Check if the register can be accessed using the given access size.
+    ///
+    /// If the firmware declared an access size, it must match. Otherwise, the
+    /// access can't be wider than the register itself (`register_bit_width`),
+    /// so e.g. a 64-bit access to a 32-bit register is rejected rather than
+    /// silently truncated.
+    pub fn validate_access(&self, size: AccessSize) -> Result<()> {
+        let declared = self.access_size();
+        let width = self.register_bit_width as u32;
+
+        if size == AccessSize::Undefined
+            || (declared != AccessSize::Undefined && declared != size)
+            || (width != 0 && size.bits() > width)
+        {
+            Err(AcpiError::InvalidAccessSize)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for GenericAddress {
+    /// Formats the address space and address, e.g. `I/O port 0x608`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = self.address;
+        match self.address_space() {
+            AddressSpace::SystemMemory => write!(f, "memory {:#x}", address),
+            AddressSpace::SystemIo => write!(f, "I/O port {:#x}", address),
+            AddressSpace::PciConfig => {
+                let pci = PciAddress::from_gas(address);
+                write!(
+                    f,
+                    "PCI 00:{:02x}.{:x} offset {:#x}",
+                    pci.device, pci.function, pci.offset
+                )
+            }
+            AddressSpace::Other(id) => write!(f, "address space {:#04x} {:#x}", id, address),
+        }
+    }
+}
+
+/// Keep the low `bits` of `value`. 0 keeps everything.
+const fn mask(value: u64, bits: u32) -> u64 {
+    match bits {
+        1..=63 => value & ((1 << bits) - 1),
+        _ => value,
+    }
+}
+
+/// Address space of a [GenericAddress].
+///
+/// Only the address spaces we can access are listed, anything else (embedded
+/// controller, SMBus, functional fixed hardware, ...) is [AddressSpace::Other].
+/// See ACPI v6.4 section 5.2.3.2 (table 5.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// A function in PCI configuration space.
+///
+/// Registers in PCI configuration space are confined to segment 0, bus 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub device: u16,
+    pub function: u16,
+    pub offset: u16,
+}
+
+impl PciAddress {
+    /// Decode the address of a [GenericAddress] in [AddressSpace::PciConfig],
+    /// which is laid out as `0x0000_DDDD_FFFF_OOOO`.
+    pub const fn from_gas(address: u64) -> Self {
+        Self {
+            device: (address >> 32) as u16,
+            function: (address >> 16) as u16,
+            offset: address as u16,
+        }
+    }
+}
+
+/// Platform read access to the registers described by a [GenericAddress].
+///
+/// This crate doesn't know how to reach memory, I/O ports or PCI configuration
+/// space, so it's up to the caller to provide that. Only the low bits covered
+/// by the access size are used.
+pub trait Readable {
+    /// Read from the given physical address.
+    fn read_memory(&mut self, address: u64, size: AccessSize) -> u64;
+
+    /// Read from the given I/O port.
+    fn read_io(&mut self, port: u16, size: AccessSize) -> u64;
+
+    /// Read from PCI configuration space.
+    fn read_pci_config(&mut self, address: PciAddress, size: AccessSize) -> u64;
+}
+
+/// Platform write access to the registers described by a [GenericAddress].
+///
+/// See [Readable]. The value is truncated to the access size.
+pub trait Writable {
+    /// Write to the given physical address.
+    fn write_memory(&mut self, address: u64, size: AccessSize, value: u64);
+
+    /// Write to the given I/O port.
+    fn write_io(&mut self, port: u16, size: AccessSize, value: u64);
+
+    /// Write to PCI configuration space.
+    fn write_pci_config(&mut self, address: PciAddress, size: AccessSize, value: u64);
+}
+
+/// Access size of a [GenericAddress].
+///
+/// See ACPI v6.4 section 5.2.3.2 (table 5.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AccessSize {
+    /// Undefined (legacy reasons).
+    Undefined = 0,
+    Byte = 1,
+    Word = 2,
+    DWord = 3,
+    QWord = 4,
+}
+
+impl AccessSize {
+    /// Return the width of the access, in bits.
+    pub const fn bits(&self) -> u32 {
+        match self {
+            AccessSize::Undefined => 0,
+            AccessSize::Byte => 8,
+            AccessSize::Word => 16,
+            AccessSize::DWord => 32,
+            AccessSize::QWord => 64,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Building and patching tables.
+//!
+//! Tables are written into a caller provided buffer, so nothing is allocated.
+//! This is mostly useful to build synthetic tables, or to patch a copy of a
+//! table provided by broken firmware.
+
+use core::{mem, slice};
+
+use crate::{sdt::SdtHeader, AcpiError, AcpiTable, Result};
+
+/// Builds a table in a byte buffer.
+///
+/// The table starts with a header, after which the body is pushed. The length
+/// and checksum in the header are filled in by [finish](TableBuilder::finish).
+#[derive(Debug)]
+pub struct TableBuilder<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> TableBuilder<'b> {
+    /// Start a new table with the given header.
+    pub fn new(buffer: &'b mut [u8], header: SdtHeader) -> Result<Self> {
+        let mut builder = TableBuilder { buffer, len: 0 };
+        // Safety: the header is packed.
+        unsafe { builder.push(&header)? };
+        Ok(builder)
+    }
+
+    /// Start a new table from an existing table structure.
+    ///
+    /// The signature is set to `T::SIGNATURE`.
+    ///
+    /// # Safety
+    /// Like every [AcpiTable] should be, `T` must be a packed structure starting
+    /// with an [SdtHeader] (see [push](TableBuilder::push)).
+    pub unsafe fn from_table<T: AcpiTable>(buffer: &'b mut [u8], table: &T) -> Result<Self> {
+        let mut builder = TableBuilder { buffer, len: 0 };
+        builder.push(table)?;
+        builder.buffer[..4].copy_from_slice(&T::SIGNATURE);
+        Ok(builder)
+    }
+
+    /// Append the raw bytes of `value` to the table.
+    ///
+    /// # Safety
+    /// `T` must not contain any padding, since padding bytes are uninitialised.
+    /// This holds for the packed ACPI structures and for integers.
+    pub unsafe fn push<T>(&mut self, value: &T) -> Result<()> {
+        let bytes = slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>());
+        self.push_bytes(bytes)
+    }
+
+    /// Append the given bytes to the table.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let end = self.len + bytes.len();
+        if end > self.buffer.len() || end > u32::MAX as usize {
+            return Err(AcpiError::BufferTooSmall);
+        }
+
+        self.buffer[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Fill in the length and checksum, and return the table.
+    pub fn finish(self) -> &'b mut [u8] {
+        let table = &mut self.buffer[..self.len];
+        let length = mem::offset_of!(SdtHeader, length);
+        table[length..length + 4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        update_checksum(table);
+        table
+    }
+}
+
+/// Recompute the checksum of the given table, after it has been modified.
+///
+/// The bytes of a valid table add up to 0.
+pub fn update_checksum(table: &mut [u8]) {
+    let checksum = mem::offset_of!(SdtHeader, checksum);
+    table[checksum] = 0;
+
+    let sum = table.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    table[checksum] = 0u8.wrapping_sub(sum);
+}
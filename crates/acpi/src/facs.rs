@@ -0,0 +1,47 @@
+use bitflags::bitflags;
+
+use crate::AcpiTable;
+
+/// Firmware ACPI Control Structure.
+///
+/// The FACS isn't listed in the RSDT/XSDT, it is referenced by the FADT
+/// instead (see [Fadt::facs](crate::fadt::Fadt::facs)). It has no checksum.
+/// See ACPI v6.4 section 5.2.10
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Facs {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub hardware_signature: u32,
+    pub firmware_waking_vector: u32,
+    pub global_lock: u32,
+    pub flags: FcsfFlags,
+    pub x_firmware_waking_vector: u64,
+    pub version: u8,
+    pub _reserved0: [u8; 3],
+    pub ospm_flags: OspmFcsfFlags,
+    pub _reserved1: [u8; 24],
+}
+
+impl AcpiTable for Facs {
+    const SIGNATURE: [u8; 4] = *b"FACS";
+}
+
+bitflags! {
+    /// Firmware Control Structure Feature Flags.
+    ///
+    /// See ACPI v6.4 table 5.14
+    #[derive(Debug, Clone, Copy)]
+    pub struct FcsfFlags: u32 {
+        const S4BIOS = 1 << 0;
+        const BIT64_WAKE_SUPPORTED = 1 << 1;
+    }
+
+    /// OSPM Enabled Firmware Control Structure Feature Flags.
+    ///
+    /// See ACPI v6.4 table 5.15
+    #[derive(Debug, Clone, Copy)]
+    pub struct OspmFcsfFlags: u32 {
+        const BIT64_WAKE = 1 << 0;
+    }
+}
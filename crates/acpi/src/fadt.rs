@@ -1,6 +1,8 @@
+use core::{fmt, ptr};
+
 use bitflags::bitflags;
 
-use crate::{address::GenericAddress, sdt::SdtHeader, AcpiTable};
+use crate::{address::GenericAddress, facs::Facs, sdt::SdtHeader, AcpiTable};
 
 /// Fixed ACPI description table.
 ///
@@ -70,6 +72,371 @@ impl AcpiTable for Fadt {
     const SIGNATURE: [u8; 4] = *b"FACP";
 }
 
+impl Fadt {
+    /// Returns true if the platform implements the hardware-reduced ACPI interface.
+    pub fn is_hardware_reduced(&self) -> bool {
+        let flags = self.flags;
+        flags.contains(FixedFeatureFlags::HW_REDUCED_ACPI)
+    }
+
+    /// Return the system vector the SCI interrupt is wired to in 8259 mode.
+    ///
+    /// Returns [None] on hardware-reduced platforms, which don't have an SCI.
+    pub fn sci_interrupt(&self) -> Option<u16> {
+        let sci_int = self.sci_int;
+        if self.is_hardware_reduced() {
+            None
+        } else {
+            Some(sci_int)
+        }
+    }
+
+    /// Return the I/O port of the SMI command port.
+    ///
+    /// Returns [None] if the port is 0 (meaning the system doesn't support
+    /// SMM), or on hardware-reduced platforms.
+    pub fn smi_command_port(&self) -> Option<u16> {
+        let smi_cmd = self.smi_cmd;
+        if smi_cmd == 0 || self.is_hardware_reduced() {
+            None
+        } else {
+            u16::try_from(smi_cmd).ok()
+        }
+    }
+
+    /// Return the value to write to the SMI command port to enable ACPI.
+    ///
+    /// Returns [None] if there is no SMI command port.
+    pub fn acpi_enable_value(&self) -> Option<u8> {
+        self.smi_command_port().map(|_| self.acpi_enable)
+    }
+
+    /// Return the value to write to the SMI command port to disable ACPI.
+    ///
+    /// Returns [None] if there is no SMI command port.
+    pub fn acpi_disable_value(&self) -> Option<u8> {
+        self.smi_command_port().map(|_| self.acpi_disable)
+    }
+
+    /// Return the physical address of the DSDT.
+    ///
+    /// Like [facs](Fadt::facs), the 64-bit address is preferred, falling back
+    /// to the 32-bit one if it is 0 or not present in this revision of the
+    /// FADT. Returns [None] if neither is set.
+    pub fn dsdt_address(&self) -> Option<usize> {
+        let x_field = ptr::addr_of!(self.x_dsdt) as usize - self as *const _ as usize;
+        let x_dsdt = if self.header.length as usize >= x_field + 8 {
+            self.x_dsdt
+        } else {
+            0
+        };
+
+        match x_dsdt {
+            0 if self.dsdt == 0 => None,
+            0 => Some(self.dsdt as usize),
+            x_dsdt => Some(x_dsdt as usize),
+        }
+    }
+
+    /// Return the reset register, and the value to write to it to reset the
+    /// system.
+    ///
+    /// Returns [None] if the platform doesn't support the reset register, or
+    /// if it's not present in this revision of the FADT.
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        let field = ptr::addr_of!(self.reset_value) as usize - self as *const _ as usize;
+        let flags = self.flags;
+
+        if self.header.length as usize > field && flags.contains(FixedFeatureFlags::RESET_REG_SUP) {
+            Some((self.reset_reg, self.reset_value))
+        } else {
+            None
+        }
+    }
+
+    /// Decode the platform properties the kernel cares about.
+    ///
+    /// The IA-PC boot architecture flags were introduced in revision 3 of the
+    /// FADT. Older revisions are assumed to describe a legacy PC, with every
+    /// device present.
+    pub fn summary(&self) -> FadtSummary {
+        let flags = self.flags;
+        let boot_arch = if self.header.revision >= 3 {
+            self.iapc_boot_arch
+        } else {
+            IaPCBootArchFlags::LEGACY_DEVICES | IaPCBootArchFlags::MC8042
+        };
+
+        FadtSummary {
+            hardware_reduced: self.is_hardware_reduced(),
+            legacy_devices: boot_arch.contains(IaPCBootArchFlags::LEGACY_DEVICES),
+            has_8042: boot_arch.contains(IaPCBootArchFlags::MC8042),
+            has_vga: !boot_arch.contains(IaPCBootArchFlags::VGA_NOT_PRESENT),
+            has_cmos_rtc: !boot_arch.contains(IaPCBootArchFlags::CMOS_RTC_NOT_PRESENT),
+            msi_supported: !boot_arch.contains(IaPCBootArchFlags::MSI_NOT_SUPPORTED),
+            reset_register: flags.contains(FixedFeatureFlags::RESET_REG_SUP),
+        }
+    }
+
+    /// Return the power management profile preferred by the OEM.
+    ///
+    /// Returns [None] for reserved values.
+    pub fn pm_profile(&self) -> Option<PmProfile> {
+        match self.preferred_pm_profile {
+            0 => Some(PmProfile::Unspecified),
+            1 => Some(PmProfile::Desktop),
+            2 => Some(PmProfile::Mobile),
+            3 => Some(PmProfile::Workstation),
+            4 => Some(PmProfile::EnterpriseServer),
+            5 => Some(PmProfile::SOHOServer),
+            6 => Some(PmProfile::AppliancePC),
+            7 => Some(PmProfile::PerformanceServer),
+            8 => Some(PmProfile::Tablet),
+            _ => None,
+        }
+    }
+
+    /// Write a human-readable report of the FADT: the PM profile, the SCI, the
+    /// decoded flags and the fixed hardware registers.
+    pub fn summarize<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        let (revision, minor) = (self.header.revision, self.fadt_minor_version);
+        writeln!(w, "FADT: revision {}.{}", revision, minor)?;
+        match self.pm_profile() {
+            Some(profile) => writeln!(w, "  PM profile: {:?}", profile)?,
+            None => writeln!(w, "  PM profile: reserved ({})", self.preferred_pm_profile)?,
+        }
+
+        match self.sci_interrupt() {
+            Some(sci) => writeln!(w, "  SCI: IRQ {}", sci)?,
+            None => writeln!(w, "  SCI: none")?,
+        }
+        if let Some(port) = self.smi_command_port() {
+            writeln!(w, "  SMI command port: {:#x}", port)?;
+        }
+
+        let flags = self.flags;
+        write!(w, "  Flags: ")?;
+        bitflags::parser::to_writer(&flags, &mut *w)?;
+        writeln!(w)?;
+
+        if revision >= 3 {
+            let boot_arch = self.iapc_boot_arch;
+            write!(w, "  IA-PC boot architecture: ")?;
+            bitflags::parser::to_writer(&boot_arch, &mut *w)?;
+            writeln!(w)?;
+        }
+
+        if let Some((register, extended)) = self.pm_timer_register() {
+            let width = if extended { 32 } else { 24 };
+            writeln!(w, "  PM timer: {}, {} bits", register, width)?;
+        }
+        let (pm1a, pm1b) = self.pm1_control_registers();
+        for (name, register) in [("PM1a", pm1a), ("PM1b", pm1b)] {
+            if let Some(register) = register {
+                writeln!(w, "  {} control: {}", name, register)?;
+            }
+        }
+        if let Some((control, status)) = self.sleep_registers() {
+            writeln!(w, "  Sleep control: {}, status: {}", control, status)?;
+        }
+        if let Some((register, value)) = self.reset_register() {
+            writeln!(w, "  Reset: write {:#04x} to {}", value, register)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the PM1a and PM1b control registers.
+    ///
+    /// The 64-bit registers are preferred, falling back to the 32-bit I/O
+    /// ports. Hardware-reduced platforms don't have them, so [None] is returned
+    /// there, as well as for a block that isn't implemented.
+    pub fn pm1_control_registers(&self) -> (Option<GenericAddress>, Option<GenericAddress>) {
+        if self.is_hardware_reduced() {
+            return (None, None);
+        }
+
+        let x_field = ptr::addr_of!(self.x_pm1a_cnt_blk) as usize - self as *const _ as usize;
+        let has_x_fields = self.header.length as usize >= x_field + 2 * 12;
+        let register = |x_blk: GenericAddress, blk: u32| {
+            let address = x_blk.address;
+            if has_x_fields && address != 0 {
+                Some(x_blk)
+            } else if blk != 0 {
+                Some(GenericAddress {
+                    address_space_id: 0x01,
+                    register_bit_width: self.pm1_cnt_len * 8,
+                    register_bit_offset: 0,
+                    access_size: 0,
+                    address: blk as u64,
+                })
+            } else {
+                None
+            }
+        };
+
+        (
+            register(self.x_pm1a_cnt_blk, self.pm1a_cnt_blk),
+            register(self.x_pm1b_cnt_blk, self.pm1b_cnt_blk),
+        )
+    }
+
+    /// Return the PM1a and PM1b status registers.
+    ///
+    /// These are the first half of the PM1 event blocks, the enable registers
+    /// being the second half. Like the control registers, the 64-bit blocks
+    /// are preferred and [None] is returned on hardware-reduced platforms.
+    pub fn pm1_status_registers(&self) -> (Option<GenericAddress>, Option<GenericAddress>) {
+        if self.is_hardware_reduced() || self.pm1_evt_len < 2 {
+            return (None, None);
+        }
+
+        let x_field = ptr::addr_of!(self.x_pm1a_evt_blk) as usize - self as *const _ as usize;
+        let has_x_fields = self.header.length as usize >= x_field + 2 * 12;
+        let register = |x_blk: GenericAddress, blk: u32| {
+            let x_address = x_blk.address;
+            let (address_space_id, address) = if has_x_fields && x_address != 0 {
+                (x_blk.address_space_id, x_address)
+            } else if blk != 0 {
+                (0x01, blk as u64)
+            } else {
+                return None;
+            };
+
+            // The block's access size covers the whole block, so let it be
+            // derived from the width of the status register instead.
+            Some(GenericAddress {
+                address_space_id,
+                register_bit_width: self.pm1_evt_len / 2 * 8,
+                register_bit_offset: 0,
+                access_size: 0,
+                address,
+            })
+        };
+
+        (
+            register(self.x_pm1a_evt_blk, self.pm1a_evt_blk),
+            register(self.x_pm1b_evt_blk, self.pm1b_evt_blk),
+        )
+    }
+
+    /// Return the power management timer register, and whether the timer is
+    /// 32 bits wide (24 bits otherwise).
+    ///
+    /// Like the PM1 registers, the 64-bit register is preferred. Returns [None]
+    /// on hardware-reduced platforms, and if the timer isn't implemented.
+    pub fn pm_timer_register(&self) -> Option<(GenericAddress, bool)> {
+        if self.is_hardware_reduced() {
+            return None;
+        }
+
+        let flags = self.flags;
+        let extended = flags.contains(FixedFeatureFlags::TMR_VAL_EXT);
+
+        let x_field = ptr::addr_of!(self.x_pm_tmr_blk) as usize - self as *const _ as usize;
+        let x_blk = self.x_pm_tmr_blk;
+        let address = x_blk.address;
+        if self.header.length as usize >= x_field + 12 && address != 0 {
+            Some((x_blk, extended))
+        } else if self.pm_tmr_blk != 0 && self.pm_tmr_len == 4 {
+            let register = GenericAddress {
+                address_space_id: 0x01,
+                register_bit_width: 32,
+                register_bit_offset: 0,
+                access_size: 0,
+                address: self.pm_tmr_blk as u64,
+            };
+            Some((register, extended))
+        } else {
+            None
+        }
+    }
+
+    /// Return the sleep control and status registers.
+    ///
+    /// These replace the PM1 control registers on hardware-reduced platforms.
+    /// Returns [None] if they are not implemented, or not present in this
+    /// revision of the FADT.
+    pub fn sleep_registers(&self) -> Option<(GenericAddress, GenericAddress)> {
+        let field = ptr::addr_of!(self.sleep_status_reg) as usize - self as *const _ as usize;
+        if (self.header.length as usize) < field + 12 {
+            return None;
+        }
+
+        let control = self.sleep_control_reg;
+        let address = control.address;
+        if address == 0 {
+            None
+        } else {
+            Some((control, self.sleep_status_reg))
+        }
+    }
+
+    /// Return the physical address of the FACS.
+    ///
+    /// The 64-bit address is preferred, falling back to the 32-bit one if it
+    /// is 0 or not present in this revision of the FADT. Returns [None] if
+    /// neither is set (e.g. on hardware-reduced platforms).
+    pub fn facs_address(&self) -> Option<usize> {
+        let x_field = ptr::addr_of!(self.x_firmware_ctrl) as usize - self as *const _ as usize;
+        let x_firmware_ctrl = if self.header.length as usize >= x_field + 8 {
+            self.x_firmware_ctrl
+        } else {
+            0
+        };
+
+        match x_firmware_ctrl {
+            0 if self.firmware_ctrl == 0 => None,
+            0 => Some(self.firmware_ctrl as usize),
+            x_firmware_ctrl => Some(x_firmware_ctrl as usize),
+        }
+    }
+
+    /// Return the FACS, mapped at the given offset.
+    ///
+    /// See [facs_address](Fadt::facs_address) for which address is used.
+    /// Returns [None] if there is no FACS, or if the signature doesn't match.
+    ///
+    /// # Safety
+    /// The firmware control address must point to readable memory once the
+    /// offset is added.
+    pub unsafe fn facs(&self, offset: usize) -> Option<&Facs> {
+        let address = self.facs_address()?;
+        let facs = ((address + offset) as *const Facs).as_ref()?;
+        if facs.signature == Facs::SIGNATURE {
+            Some(facs)
+        } else {
+            None
+        }
+    }
+}
+
+/// Platform properties decoded from the FADT (see [Fadt::summary]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FadtSummary {
+    /// The platform implements the hardware-reduced ACPI interface.
+    pub hardware_reduced: bool,
+
+    /// There are legacy devices (e.g. on the LPC bus) that can't be enumerated.
+    pub legacy_devices: bool,
+
+    /// There's an 8042 keyboard controller. Don't probe for PS/2 devices
+    /// without one.
+    pub has_8042: bool,
+
+    /// It's safe to probe for VGA hardware.
+    pub has_vga: bool,
+
+    /// The CMOS RTC is present.
+    pub has_cmos_rtc: bool,
+
+    /// MSI can be enabled on the platform.
+    pub msi_supported: bool,
+
+    /// The system can be reset through the reset register.
+    pub reset_register: bool,
+}
+
 bitflags! {
     /// IA-PC boot architecture flags.
     ///
@@ -127,7 +494,10 @@ bitflags! {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Power management profile preferred by the OEM (see [Fadt::pm_profile]).
+///
+/// See ACPI v6.4 section 5.2.9 (table 5.9)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PmProfile {
     Unspecified = 0,
@@ -0,0 +1,35 @@
+use crate::{address::GenericAddress, sdt::SdtHeader, AcpiTable};
+
+/// High Precision Event Timer description table.
+///
+/// Not part of the ACPI specification itself, see the IA-PC HPET specification
+/// v1.0a section 3.2.4.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Hpet {
+    pub header: SdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl AcpiTable for Hpet {
+    const SIGNATURE: [u8; 4] = *b"HPET";
+}
+
+impl Hpet {
+    /// Returns true if the main counter is 64 bits wide (32 bits otherwise).
+    ///
+    /// This is a copy of the capabilities register of the timer block, so it
+    /// can be checked without touching the hardware.
+    pub fn is_64bit(&self) -> bool {
+        self.event_timer_block_id & (1 << 13) != 0
+    }
+
+    /// Return the number of comparators in the timer block.
+    pub fn num_comparators(&self) -> u8 {
+        ((self.event_timer_block_id >> 8) & 0x1f) as u8 + 1
+    }
+}
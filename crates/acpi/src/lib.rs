@@ -10,27 +10,197 @@
 
 #![no_std]
 
-use core::{mem, result};
+use core::{fmt, mem, result, str};
 
+use heapless::FnvIndexMap;
 use sdt::SdtHeader;
 
 pub mod address;
+pub mod builder;
+pub mod facs;
 pub mod fadt;
+pub mod hpet;
 pub mod madt;
+pub mod power;
 pub mod sdt;
 
+#[cfg(test)]
+mod tests;
+
 pub type Result<T> = result::Result<T, AcpiError>;
 
 #[derive(Debug)]
 pub enum AcpiError {
-    InvalidHeader,
+    /// The header of the table with the given signature is invalid.
+    InvalidHeader { signature: [u8; 4] },
     UnsupportedRevision,
-    ChecksumFailed,
+    /// The requested access size doesn't match the register.
+    InvalidAccessSize,
+    /// The checksum of the table with the given signature does not add up to 0.
+    ChecksumFailed { signature: [u8; 4] },
+    /// The buffer is too small to hold the table.
+    BufferTooSmall,
+    /// The register lives in an address space we can't access.
+    UnsupportedAddressSpace { id: u8 },
+    /// The register address doesn't fit its address space.
+    InvalidAddress,
+    /// The platform doesn't support resetting through the FADT reset register.
+    ResetUnsupported,
+    /// The sleep registers are not available.
+    SleepUnsupported,
+    /// The PM1 event registers are not available.
+    Pm1Unsupported,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcpiError::InvalidHeader { signature } => {
+                write!(
+                    f,
+                    "invalid header for table {}",
+                    Signature::from_bytes(signature)
+                )
+            }
+            AcpiError::UnsupportedRevision => write!(f, "unsupported revision"),
+            AcpiError::InvalidAccessSize => write!(f, "invalid access size"),
+            AcpiError::ChecksumFailed { signature } => {
+                write!(
+                    f,
+                    "checksum failed for table {}",
+                    Signature::from_bytes(signature)
+                )
+            }
+            AcpiError::BufferTooSmall => write!(f, "buffer too small"),
+            AcpiError::UnsupportedAddressSpace { id } => {
+                write!(f, "unsupported address space {:#04x}", id)
+            }
+            AcpiError::InvalidAddress => write!(f, "invalid register address"),
+            AcpiError::ResetUnsupported => write!(f, "reset register not supported"),
+            AcpiError::SleepUnsupported => write!(f, "sleep registers not supported"),
+            AcpiError::Pm1Unsupported => write!(f, "PM1 event registers not supported"),
+        }
+    }
 }
 
 /// An ACPI table type.
 pub trait AcpiTable {
     const SIGNATURE: [u8; 4];
+
+    /// Return the signature as a string, for logging.
+    fn signature_str() -> &'static str {
+        signature_str(&Self::SIGNATURE).expect("table signature is not printable ASCII")
+    }
+}
+
+/// Interpret a table signature as a string.
+///
+/// Returns `None` unless all bytes are printable ASCII, since firmware can
+/// put anything in the signature.
+pub fn signature_str(signature: &[u8; 4]) -> Option<&str> {
+    if signature.iter().all(|b| b.is_ascii_graphic()) {
+        str::from_utf8(signature).ok()
+    } else {
+        None
+    }
+}
+
+/// A table signature.
+///
+/// Lists the tables this crate knows about, any other signature is kept as
+/// [Signature::Unknown]. Formats as a string, falling back to the raw bytes in
+/// hex if it isn't printable (see [signature_str]).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Signature {
+    /// Fixed ACPI description table (`FACP`).
+    Fadt,
+    /// Multiple APIC description table (`APIC`).
+    Madt,
+    /// High precision event timer table (`HPET`).
+    Hpet,
+    /// PCI Express memory mapped configuration table (`MCFG`).
+    Mcfg,
+    /// System resource affinity table (`SRAT`).
+    Srat,
+    /// System locality distance information table (`SLIT`).
+    Slit,
+    /// Differentiated system description table (`DSDT`).
+    Dsdt,
+    /// Secondary system description table (`SSDT`).
+    Ssdt,
+    /// Firmware ACPI control structure (`FACS`).
+    Facs,
+    /// Windows ACPI emulated devices table (`WAET`).
+    Waet,
+    /// Boot graphics resource table (`BGRT`).
+    Bgrt,
+    /// Embedded controller boot resources table (`ECDT`).
+    Ecdt,
+    Unknown([u8; 4]),
+}
+
+impl Signature {
+    /// Classify the given signature.
+    pub const fn from_bytes(bytes: &[u8; 4]) -> Self {
+        match bytes {
+            b"FACP" => Signature::Fadt,
+            b"APIC" => Signature::Madt,
+            b"HPET" => Signature::Hpet,
+            b"MCFG" => Signature::Mcfg,
+            b"SRAT" => Signature::Srat,
+            b"SLIT" => Signature::Slit,
+            b"DSDT" => Signature::Dsdt,
+            b"SSDT" => Signature::Ssdt,
+            b"FACS" => Signature::Facs,
+            b"WAET" => Signature::Waet,
+            b"BGRT" => Signature::Bgrt,
+            b"ECDT" => Signature::Ecdt,
+            _ => Signature::Unknown(*bytes),
+        }
+    }
+
+    /// Return the signature as it appears in the table header.
+    pub const fn as_bytes(&self) -> &[u8; 4] {
+        match self {
+            Signature::Fadt => b"FACP",
+            Signature::Madt => b"APIC",
+            Signature::Hpet => b"HPET",
+            Signature::Mcfg => b"MCFG",
+            Signature::Srat => b"SRAT",
+            Signature::Slit => b"SLIT",
+            Signature::Dsdt => b"DSDT",
+            Signature::Ssdt => b"SSDT",
+            Signature::Facs => b"FACS",
+            Signature::Waet => b"WAET",
+            Signature::Bgrt => b"BGRT",
+            Signature::Ecdt => b"ECDT",
+            Signature::Unknown(bytes) => bytes,
+        }
+    }
+}
+
+impl From<[u8; 4]> for Signature {
+    fn from(bytes: [u8; 4]) -> Self {
+        Signature::from_bytes(&bytes)
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match signature_str(self.as_bytes()) {
+            Some(signature) => write!(f, "{}", signature),
+            None => write!(f, "{:02x?}", self.as_bytes()),
+        }
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match signature_str(self.as_bytes()) {
+            Some(signature) => write!(f, "{:?}", signature),
+            None => write!(f, "{:02x?}", self.as_bytes()),
+        }
+    }
 }
 
 /// ACPI version.
@@ -56,7 +226,9 @@ impl<'a> Version<'a> {
     pub unsafe fn from_address(address: usize) -> Result<Self> {
         let header = (address as *const SdtHeader)
             .as_ref()
-            .ok_or(AcpiError::InvalidHeader)?;
+            .ok_or(AcpiError::InvalidHeader {
+                signature: [0; 4],
+            })?;
 
         // Make sure the header is valid.
         header.validate()?;
@@ -64,7 +236,9 @@ impl<'a> Version<'a> {
         match header.signature() {
             Ok("RSDT") => Ok(Version::Root(header)),
             Ok("XSDT") => Ok(Version::Extended(header)),
-            _ => Err(AcpiError::InvalidHeader),
+            _ => Err(AcpiError::InvalidHeader {
+                signature: header.signature,
+            }),
         }
     }
 
@@ -91,12 +265,56 @@ impl<'a> AcpiTables<'a> {
     /// The given address should point to a valid RSDT. The header is validated
     /// (which means its signature and checksum are checked) before it is
     /// accepted. However, a given address that points to a _valid_ RSDT
-    /// containing bogus data can still cause unexpected behaviour.
+    /// containing bogus data can still cause unexpected behaviour. An address
+    /// that overflows once the offset is added is rejected.
     pub unsafe fn from_address(addr: usize, offset: usize) -> Result<Self> {
-        let version = Version::from_address(addr + offset)?;
+        let address = addr
+            .checked_add(offset)
+            .ok_or(AcpiError::InvalidHeader { signature: [0; 4] })?;
+        let version = Version::from_address(address)?;
         Ok(Self { version, offset })
     }
 
+    /// Rebuild the tables for a copy located at physical address `new_base`, and
+    /// mapped at `new_offset`.
+    ///
+    /// The entries of the RSDT/XSDT still hold the physical addresses of the
+    /// original tables, and are not rewritten. Instead, the offset is adjusted
+    /// by the distance between the copy and the original. This means that all
+    /// tables must be copied as a single contiguous block, starting with the
+    /// RSDT/XSDT and preserving the relative offsets between the tables.
+    ///
+    /// Note that [physical_address](AcpiTables::physical_address) will keep
+    /// returning the addresses of the original tables.
+    ///
+    /// # Safety
+    /// See [from_address](AcpiTables::from_address). Additionally, the copy must
+    /// have been made as described above.
+    pub unsafe fn relocate<'b>(&self, new_base: usize, new_offset: usize) -> Result<AcpiTables<'b>> {
+        let old_base = self.physical_address(self.version.header());
+        let offset = new_offset.wrapping_add(new_base.wrapping_sub(old_base));
+        let version = Version::from_address(old_base.wrapping_add(offset))?;
+        Ok(AcpiTables { version, offset })
+    }
+
+    /// Return the root table (RSDT or XSDT).
+    pub fn version(&self) -> &Version<'a> {
+        &self.version
+    }
+
+    /// Return the offset at which the tables are mapped.
+    ///
+    /// Subtracting the offset from the address of a table yields its physical
+    /// address.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Return the physical address of the given table header.
+    pub fn physical_address(&self, header: &SdtHeader) -> usize {
+        header as *const SdtHeader as usize - self.offset
+    }
+
     /// Compute the number of entries in the table.
     ///
     /// An RSDT contains 32-bit pointers, while an XSDT contains 64-bit pointers.
@@ -119,6 +337,89 @@ impl<'a> AcpiTables<'a> {
             tables: self,
             len: self.len(),
             cur: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Return an iterator over the entries in the table, along with the
+    /// physical address of each table.
+    ///
+    /// The address is the one stored in the RSDT/XSDT, so it is the same as
+    /// [physical_address](AcpiTables::physical_address) of the table header.
+    pub fn iter_with_addr(&self) -> EntriesWithAddress<'_> {
+        EntriesWithAddress(self.iter())
+    }
+
+    /// Return an iterator over the entries whose table failed validation,
+    /// along with the physical address stored in each entry.
+    ///
+    /// [iter](AcpiTables::iter) skips these, so firmware bugs can be reported
+    /// with this.
+    pub fn iter_invalid(&self) -> InvalidEntries<'_> {
+        InvalidEntries(self.iter())
+    }
+
+    /// Return the first table with the given signature.
+    ///
+    /// This walks the RSDT/XSDT, and validates every table it passes. Use
+    /// [index](AcpiTables::index) when looking up many tables.
+    pub fn find(&self, signature: [u8; 4]) -> Option<TableKind<'_>> {
+        self.iter()
+            .find(|table| table.header().signature == signature)
+    }
+
+    /// Build an index of the tables by signature.
+    ///
+    /// The RSDT/XSDT is walked once, after which lookups through the index
+    /// don't have to walk it again. Like [find](AcpiTables::find), only the
+    /// first table of each signature is indexed.
+    pub fn index(&self) -> TableIndex<'_> {
+        let mut index = TableIndex {
+            acpi: self,
+            tables: FnvIndexMap::new(),
+            complete: true,
+        };
+
+        for table in self.iter() {
+            let signature = table.header().signature;
+            if !index.tables.contains_key(&signature)
+                && index.tables.insert(signature, table).is_err()
+            {
+                index.complete = false;
+            }
+        }
+
+        index
+    }
+
+    /// Return the FADT, if present.
+    pub fn fadt(&self) -> Option<&fadt::Fadt> {
+        self.iter().find_map(|table| match table {
+            TableKind::Fadt(fadt) => Some(fadt),
+            _ => None,
+        })
+    }
+
+    /// Return the HPET table, if present.
+    pub fn hpet(&self) -> Option<&hpet::Hpet> {
+        self.iter().find_map(|table| match table {
+            TableKind::Hpet(hpet) => Some(hpet),
+            _ => None,
+        })
+    }
+
+    /// Return the header of the DSDT, if present.
+    ///
+    /// The DSDT isn't listed in the RSDT/XSDT, it's referenced by the FADT. The
+    /// table is only returned if its signature and checksum are valid.
+    pub fn dsdt(&self) -> Option<&SdtHeader> {
+        let address = self.fadt()?.dsdt_address()?;
+        let header = unsafe { ((address + self.offset) as *const SdtHeader).as_ref()? };
+
+        if header.signature == *b"DSDT" && unsafe { header.validate() }.is_ok() {
+            Some(header)
+        } else {
+            None
         }
     }
 
@@ -130,78 +431,230 @@ impl<'a> AcpiTables<'a> {
                 .map(|table| table.header().length as usize)
                 .sum::<usize>()
     }
+
+    /// Compute the size of the root table, and the tables with the given
+    /// signatures.
+    ///
+    /// The FACS and DSDT aren't listed in the RSDT/XSDT, so if the FADT is
+    /// retained, the ones it references are counted as well. Use this to size a
+    /// buffer for copying only the tables the kernel keeps using.
+    pub fn retained_size(&self, signatures: &[Signature]) -> usize {
+        let tables = self
+            .iter()
+            .filter(|table| signatures.contains(&table.signature()))
+            .map(|table| table.header().length as usize)
+            .sum::<usize>();
+
+        let referenced = match self.fadt() {
+            Some(fadt) if signatures.contains(&Signature::Fadt) => {
+                let facs = unsafe { fadt.facs(self.offset) }.map_or(0, |facs| facs.length);
+                let dsdt = self.dsdt().map_or(0, |dsdt| dsdt.length);
+                facs as usize + dsdt as usize
+            }
+            _ => 0,
+        };
+
+        self.version.header().length as usize + tables + referenced
+    }
 }
 
-#[derive(Debug)]
+/// The maximum number of signatures in a [TableIndex]. Must be a power of two.
+pub const MAX_INDEXED_SIGNATURES: usize = 32;
+
+/// The tables of an [AcpiTables], by signature (see [AcpiTables::index]).
+pub struct TableIndex<'a> {
+    acpi: &'a AcpiTables<'a>,
+    tables: FnvIndexMap<[u8; 4], TableKind<'a>, MAX_INDEXED_SIGNATURES>,
+
+    /// False if some signatures didn't fit in the index.
+    complete: bool,
+}
+
+impl<'a> TableIndex<'a> {
+    /// Return the first table with the given signature.
+    ///
+    /// If there were more than [MAX_INDEXED_SIGNATURES] different signatures,
+    /// the ones that didn't fit are looked up the slow way.
+    pub fn find(&self, signature: [u8; 4]) -> Option<TableKind<'a>> {
+        match self.tables.get(&signature) {
+            Some(table) => Some(*table),
+            None if !self.complete => self.acpi.find(signature),
+            None => None,
+        }
+    }
+
+    /// Return the FADT, if present.
+    pub fn fadt(&self) -> Option<&'a fadt::Fadt> {
+        match self.find(fadt::Fadt::SIGNATURE)? {
+            TableKind::Fadt(fadt) => Some(fadt),
+            _ => None,
+        }
+    }
+
+    /// Return the MADT, if present.
+    pub fn madt(&self) -> Option<&'a madt::Madt> {
+        match self.find(madt::Madt::SIGNATURE)? {
+            TableKind::Madt(madt) => Some(madt),
+            _ => None,
+        }
+    }
+
+    /// Return the HPET table, if present.
+    pub fn hpet(&self) -> Option<&'a hpet::Hpet> {
+        match self.find(hpet::Hpet::SIGNATURE)? {
+            TableKind::Hpet(hpet) => Some(hpet),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum TableKind<'a> {
     Fadt(&'a fadt::Fadt),
     Madt(&'a madt::Madt),
+    Hpet(&'a hpet::Hpet),
     Unknown(&'a sdt::SdtHeader),
 }
 
+/// Only the signature of unknown tables is shown, e.g. `Unknown("SRAT")`.
+impl fmt::Debug for TableKind<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableKind::Fadt(fadt) => f.debug_tuple("Fadt").field(fadt).finish(),
+            TableKind::Madt(madt) => f.debug_tuple("Madt").field(madt).finish(),
+            TableKind::Hpet(hpet) => f.debug_tuple("Hpet").field(hpet).finish(),
+            TableKind::Unknown(header) => f
+                .debug_tuple("Unknown")
+                .field(&Signature::from_bytes(&header.signature))
+                .finish(),
+        }
+    }
+}
+
 impl<'a> TableKind<'a> {
     #[inline]
     pub fn header(&self) -> &sdt::SdtHeader {
         match self {
             TableKind::Fadt(fadt) => &fadt.header,
             TableKind::Madt(madt) => &madt.header,
+            TableKind::Hpet(hpet) => &hpet.header,
             TableKind::Unknown(header) => &header,
         }
     }
+
+    /// Return the signature of the table.
+    pub fn signature(&self) -> Signature {
+        Signature::from_bytes(&self.header().signature)
+    }
 }
 
 /// An iterator over RSDT/XSDT header entries.
+///
+/// Entries whose table fails validation are skipped and counted, instead of
+/// ending the iteration.
 pub struct Entries<'a> {
     tables: &'a AcpiTables<'a>,
     len: usize,
     cur: isize,
+    skipped: usize,
 }
 
 impl<'a> Iterator for Entries<'a> {
     type Item = TableKind<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur as usize >= self.len {
-            None
-        } else {
-            let header_address = match self.tables.version {
-                Version::Root(ref rsdt) => unsafe {
-                    let ptr = (*rsdt as *const _ as *const u8)
-                        .offset(mem::size_of::<SdtHeader>() as isize)
-                        .offset(self.cur * 4);
-                    (ptr as *const u32).read_unaligned() as usize
-                },
-                Version::Extended(ref xsdt) => unsafe {
-                    let ptr = (*xsdt as *const _ as *const u8)
-                        .offset(mem::size_of::<SdtHeader>() as isize)
-                        .offset(self.cur * 8);
-                    (ptr as *const u64).read_unaligned() as usize
-                },
-            };
-
-            let header = unsafe {
-                ((header_address + self.tables.offset) as *const SdtHeader)
-                    .as_ref()
-                    .unwrap()
-            };
-
-            self.cur += 1;
-
-            unsafe {
-                if header.validate().is_err() {
-                    None
-                } else {
-                    match header.signature {
-                        fadt::Fadt::SIGNATURE => Some(TableKind::Fadt(
-                            (header as *const _ as *const fadt::Fadt).as_ref().unwrap(),
-                        )),
-                        madt::Madt::SIGNATURE => Some(TableKind::Madt(
-                            (header as *const _ as *const madt::Madt).as_ref().unwrap(),
-                        )),
-                        _ => Some(TableKind::Unknown(&header)),
-                    }
-                }
+        self.next_with_address().map(|(_, table)| table)
+    }
+}
+
+/// An iterator over RSDT/XSDT header entries, along with the physical address
+/// of each table.
+pub struct EntriesWithAddress<'a>(Entries<'a>);
+
+impl<'a> Iterator for EntriesWithAddress<'a> {
+    type Item = (usize, TableKind<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_with_address()
+    }
+}
+
+/// An iterator over the RSDT/XSDT entries that failed validation, along with
+/// the physical address stored in each entry.
+pub struct InvalidEntries<'a>(Entries<'a>);
+
+impl<'a> Iterator for InvalidEntries<'a> {
+    type Item = (usize, AcpiError);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.0.next_entry()? {
+                (address, Err(err)) => return Some((address, err)),
+                (_, Ok(_)) => continue,
             }
         }
     }
 }
+
+impl<'a> Entries<'a> {
+    /// Return the next valid table, and the physical address stored in the
+    /// entry. Invalid tables are skipped, see [skipped](Entries::skipped).
+    fn next_with_address(&mut self) -> Option<(usize, TableKind<'a>)> {
+        loop {
+            match self.next_entry()? {
+                (address, Ok(table)) => return Some((address, table)),
+                (_, Err(_)) => self.skipped += 1,
+            }
+        }
+    }
+
+    /// The number of entries skipped so far because their table failed
+    /// validation. [AcpiTables::iter_invalid] lists them.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Return the next entry, and the physical address stored in it.
+    fn next_entry(&mut self) -> Option<(usize, Result<TableKind<'a>>)> {
+        if self.cur as usize >= self.len {
+            return None;
+        }
+
+        let header_address = match self.tables.version {
+            Version::Root(ref rsdt) => unsafe {
+                let ptr = (*rsdt as *const _ as *const u8)
+                    .offset(mem::size_of::<SdtHeader>() as isize)
+                    .offset(self.cur * 4);
+                (ptr as *const u32).read_unaligned() as usize
+            },
+            Version::Extended(ref xsdt) => unsafe {
+                let ptr = (*xsdt as *const _ as *const u8)
+                    .offset(mem::size_of::<SdtHeader>() as isize)
+                    .offset(self.cur * 8);
+                (ptr as *const u64).read_unaligned() as usize
+            },
+        };
+
+        self.cur += 1;
+
+        // A bogus entry could wrap around to a low address.
+        let header = match header_address.checked_add(self.tables.offset) {
+            Some(address) if address != 0 => unsafe { &*(address as *const SdtHeader) },
+            _ => return Some((header_address, Err(AcpiError::InvalidAddress))),
+        };
+
+        let table = unsafe {
+            header.validate().map(|_| {
+                let ptr = header as *const SdtHeader;
+                match Signature::from_bytes(&header.signature) {
+                    Signature::Fadt => TableKind::Fadt(&*(ptr as *const fadt::Fadt)),
+                    Signature::Madt => TableKind::Madt(&*(ptr as *const madt::Madt)),
+                    Signature::Hpet => TableKind::Hpet(&*(ptr as *const hpet::Hpet)),
+                    _ => TableKind::Unknown(header),
+                }
+            })
+        };
+
+        Some((header_address, table))
+    }
+}
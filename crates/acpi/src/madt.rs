@@ -1,8 +1,8 @@
 pub mod structs;
 
-use crate::{sdt::SdtHeader, AcpiTable};
+use crate::{sdt::SdtHeader, AcpiError, AcpiTable, Result};
 use bitflags::bitflags;
-use core::mem;
+use core::{fmt, mem};
 
 pub use structs::*;
 
@@ -32,14 +32,183 @@ impl AcpiTable for Madt {
 }
 
 impl Madt {
+    /// Return an iterator over the interrupt controller structures.
+    ///
+    /// The structures are variable in length, so the iterator advances using
+    /// the length in each structure header. Iteration stops at the first
+    /// structure that is malformed (a length of 0) or that doesn't fit inside
+    /// the table.
     #[inline]
     pub fn iter(&self) -> Structures {
         Structures {
             madt: self,
-            len: self.header.length as usize - mem::size_of::<Madt>(),
+            len: (self.header.length as usize).saturating_sub(mem::size_of::<Madt>()),
             cur: 0,
         }
     }
+
+    /// Make sure the interrupt controller structures add up to the length in
+    /// the header.
+    ///
+    /// Some firmware pads the table, in which case [iter](Madt::iter) would
+    /// parse whatever follows the last structure. Walks the structures and
+    /// returns [AcpiError::InvalidHeader] if one is malformed, or if they don't
+    /// end exactly at the end of the table.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = || AcpiError::InvalidHeader {
+            signature: self.header.signature,
+        };
+        if (self.header.length as usize) < mem::size_of::<Madt>() {
+            return Err(invalid());
+        }
+        let len = (self.header.length as usize).saturating_sub(mem::size_of::<Madt>());
+
+        let mut cur = 0;
+        while cur < len {
+            if cur + mem::size_of::<ApicStructureHeader>() > len {
+                return Err(invalid());
+            }
+
+            let header = unsafe {
+                &*((self as *const _ as *const u8).add(mem::size_of::<Madt>() + cur)
+                    as *const ApicStructureHeader)
+            };
+
+            let length = header.length as usize;
+            if length == 0 || cur + length > len {
+                return Err(invalid());
+            }
+
+            cur += length;
+        }
+
+        Ok(())
+    }
+}
+
+impl Madt {
+    /// Write a human-readable report of the interrupt controllers: every CPU
+    /// with its APIC ID, the IOAPICs, interrupt overrides and NMIs.
+    ///
+    /// Structures that don't matter on x86 are only listed by type.
+    pub fn summarize<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        let (local_apic_address, flags) = (self.local_apic_address, self.flags);
+        write!(
+            w,
+            "MADT: local APIC at {:#010x}, flags: ",
+            local_apic_address
+        )?;
+        bitflags::parser::to_writer(&flags, &mut *w)?;
+        writeln!(w)?;
+
+        let state = |flags: LocalApicFlags| {
+            if flags.contains(LocalApicFlags::ENABLED) {
+                "enabled"
+            } else if flags.contains(LocalApicFlags::ONLINE_CAPABALE) {
+                "online capable"
+            } else {
+                "disabled"
+            }
+        };
+
+        for structure in self.iter() {
+            match structure {
+                ApicStructureKind::ProcessorLocalApic(apic) => {
+                    let (uid, id) = (apic.acpi_processor_uid, apic.apic_id);
+                    writeln!(w, "  CPU {}: APIC ID {}, {}", uid, id, state(apic.flags))?;
+                }
+                ApicStructureKind::ProcessorLocalX2Apic(apic) => {
+                    let (uid, id) = (apic.acpi_processor_uid, apic.x2apic_id);
+                    writeln!(w, "  CPU {}: x2APIC ID {}, {}", uid, id, state(apic.flags))?;
+                }
+                ApicStructureKind::IoApic(io_apic) => {
+                    let address = io_apic.io_apic_address;
+                    let gsi_base = io_apic.global_system_interrupt_base;
+                    writeln!(
+                        w,
+                        "  IOAPIC {}: address {:#010x}, GSI base {}",
+                        io_apic.io_apic_id, address, gsi_base
+                    )?;
+                }
+                ApicStructureKind::InterruptSourceOverrice(over) => {
+                    let (gsi, flags) = (over.global_system_interrupt, over.flags);
+                    writeln!(w, "  IRQ {} -> GSI {}, {}", over.source, gsi, flags)?;
+                }
+                ApicStructureKind::NmiSource(nmi) => {
+                    let gsi = nmi.global_system_interrupt;
+                    let flags = MpsIntiFlags::from_bits_retain(nmi.flags);
+                    writeln!(w, "  NMI: GSI {}, {}", gsi, flags)?;
+                }
+                ApicStructureKind::LocalApicNmi(nmi) => {
+                    let flags = nmi.flags;
+                    write!(w, "  NMI: LINT{}, {}, ", nmi.local_apic_lint_n, flags)?;
+                    match nmi.acpi_processor_uid {
+                        0xff => writeln!(w, "all CPUs")?,
+                        uid => writeln!(w, "CPU {}", uid)?,
+                    }
+                }
+                ApicStructureKind::LocalX2ApicNmi(nmi) => {
+                    let (uid, flags) = (nmi.acpi_processor_uid, nmi.flags);
+                    write!(w, "  NMI: LINT{}, {}, ", nmi.local_x2apic_lint_n, flags)?;
+                    match uid {
+                        u32::MAX => writeln!(w, "all CPUs")?,
+                        uid => writeln!(w, "CPU {}", uid)?,
+                    }
+                }
+                ApicStructureKind::LocalApicAddressOverride(over) => {
+                    let address = over.local_apic_address;
+                    writeln!(w, "  Local APIC address override: {:#018x}", address)?;
+                }
+                ApicStructureKind::Reserved(header) | ApicStructureKind::Oem(header) => {
+                    writeln!(w, "  Unknown structure type {:#04x}", header.entry_type)?;
+                }
+                other => writeln!(w, "  {:?}", other)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Generate typed iterators over a single kind of interrupt controller
+/// structure. They share [Madt::iter], and just filter on the structure kind.
+macro_rules! typed_iter {
+    ($($(#[$attr:meta])* $name:ident => $kind:ident($ty:ty);)*) => {
+        impl Madt {
+            $(
+                $(#[$attr])*
+                pub fn $name(&self) -> impl Iterator<Item = &$ty> {
+                    self.iter().filter_map(|ics| match ics {
+                        ApicStructureKind::$kind(structure) => Some(structure),
+                        _ => None,
+                    })
+                }
+            )*
+        }
+    };
+}
+
+typed_iter! {
+    /// Return an iterator over the Processor Local APIC structures.
+    local_apics => ProcessorLocalApic(ProcessorLocalApicStructure);
+
+    /// Return an iterator over the Processor Local x2APIC structures.
+    local_x2apics => ProcessorLocalX2Apic(ProcessorLocalX2ApicStructure);
+
+    /// Return an iterator over the IO APIC structures.
+    io_apics => IoApic(IoApicStructure);
+
+    /// Return an iterator over the Interrupt Source Override structures.
+    interrupt_overrides => InterruptSourceOverrice(IntSourceOverrideStructure);
+
+    /// Return an iterator over the NMI Source structures.
+    nmi_sources => NmiSource(NmiSourceStructure);
+
+    /// Return an iterator over the Local APIC NMI structures.
+    local_apic_nmis => LocalApicNmi(LocalApicNmiStructure);
+
+    /// Return an iterator over the Local x2APIC NMI structures.
+    local_x2apic_nmis => LocalX2ApicNmi(LocalX2ApicNmiStructure);
 }
 
 #[derive(Debug)]
@@ -76,7 +245,7 @@ impl<'a> Iterator for Structures<'a> {
     type Item = ApicStructureKind<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur as usize >= self.len {
+        if self.cur as usize + mem::size_of::<ApicStructureHeader>() > self.len {
             None
         } else {
             let header = unsafe {
@@ -86,7 +255,15 @@ impl<'a> Iterator for Structures<'a> {
                 (base as *const ApicStructureHeader).as_ref().unwrap()
             };
 
-            self.cur += header.length as isize;
+            // A zero length would make us loop forever, and a structure running
+            // past the end of the table is just as bogus. Stop either way.
+            let length = header.length as usize;
+            if length == 0 || self.cur as usize + length > self.len {
+                self.cur = self.len as isize;
+                return None;
+            }
+
+            self.cur += length as isize;
 
             macro_rules! match_structs {
                 (
@@ -1,3 +1,5 @@
+use core::fmt;
+
 use bitflags::bitflags;
 
 /// Interrupt Controller Structure Header.
@@ -79,6 +81,25 @@ bitflags! {
     }
 }
 
+impl fmt::Display for MpsIntiFlags {
+    /// Formats the polarity and trigger mode, e.g. `active low, level`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let polarity = match self.bits() & 0b11 {
+            0b00 => "conforming polarity",
+            0b01 => "active high",
+            0b11 => "active low",
+            _ => "reserved polarity",
+        };
+        let trigger_mode = match self.bits() >> 2 & 0b11 {
+            0b00 => "conforming trigger mode",
+            0b01 => "edge",
+            0b11 => "level",
+            _ => "reserved trigger mode",
+        };
+        write!(f, "{}, {}", polarity, trigger_mode)
+    }
+}
+
 /// Non-Maskable Interrupt Source Structure.
 ///
 /// See ACPI v6.4 section 5.2.12.6
@@ -97,8 +118,9 @@ pub struct NmiSourceStructure {
 #[repr(C, packed)]
 pub struct LocalApicNmiStructure {
     pub header: ApicStructureHeader,
-    pub _reserved: [u8; 2],
-    pub local_apic_address: u64,
+    pub acpi_processor_uid: u8,
+    pub flags: MpsIntiFlags,
+    pub local_apic_lint_n: u8,
 }
 
 /// Local APIC Override Structure.
@@ -0,0 +1,109 @@
+//! System power management.
+
+use crate::{
+    address::{Readable, Writable},
+    fadt::Fadt,
+    AcpiError, Result,
+};
+
+/// SLP_EN in the PM1 control register.
+const PM1_SLP_EN: u64 = 1 << 13;
+
+/// Offset of SLP_TYP in the PM1 control register.
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+
+/// SLP_TYP in the PM1 control register.
+const PM1_SLP_TYP: u64 = 0x7 << PM1_SLP_TYP_SHIFT;
+
+/// SLP_EN in the sleep control register.
+const SLEEP_SLP_EN: u64 = 1 << 5;
+
+/// Offset of SLP_TYP in the sleep control register.
+const SLEEP_SLP_TYP_SHIFT: u64 = 2;
+
+/// Reset the system through the reset register of the FADT.
+///
+/// The reset value is written to the reset register, in whatever address space
+/// it lives. If this returns, the write was performed but the system might
+/// take a moment to reset. Callers should wait a bit before falling back to
+/// other means, like the 8042 reset line or a triple fault.
+///
+/// This is the only way to reset hardware-reduced platforms.
+///
+/// Returns [AcpiError::ResetUnsupported] if the platform has no reset register.
+pub fn reboot<A: Writable>(fadt: &Fadt, access: &mut A) -> Result<()> {
+    let (register, value) = fadt.reset_register().ok_or(AcpiError::ResetUnsupported)?;
+    register.write(access, value as u64)
+}
+
+/// Enter a sleep state, e.g. S5 to power off the system.
+///
+/// The sleep type values come from the `\_Sx` object of the DSDT. On
+/// hardware-reduced platforms, `slp_typ_a` is written to the sleep control
+/// register, together with SLP_EN. Otherwise, the values go to the PM1a and
+/// PM1b control registers, which also hold SCI_EN and friends, so they are
+/// read, modified and written back: SLP_TYP first, then SLP_EN (ACPI 7.4.2).
+/// This assumes the system is in ACPI mode already, the SMI command port is
+/// never used.
+///
+/// Returns [AcpiError::SleepUnsupported] if the registers aren't available.
+pub fn sleep<A: Readable + Writable>(
+    fadt: &Fadt,
+    access: &mut A,
+    slp_typ_a: u8,
+    slp_typ_b: u8,
+) -> Result<()> {
+    if fadt.is_hardware_reduced() {
+        let (control, _) = fadt.sleep_registers().ok_or(AcpiError::SleepUnsupported)?;
+        return control.write(
+            access,
+            (slp_typ_a as u64 & 0x7) << SLEEP_SLP_TYP_SHIFT | SLEEP_SLP_EN,
+        );
+    }
+
+    let (pm1a, pm1b) = match fadt.pm1_control_registers() {
+        (Some(pm1a), pm1b) => (pm1a, pm1b),
+        (None, _) => return Err(AcpiError::SleepUnsupported),
+    };
+
+    let pm1a_value = pm1a.read(access)? & !(PM1_SLP_TYP | PM1_SLP_EN)
+        | (slp_typ_a as u64 & 0x7) << PM1_SLP_TYP_SHIFT;
+    let pm1b_value = match pm1b {
+        Some(pm1b) => Some(
+            pm1b.read(access)? & !(PM1_SLP_TYP | PM1_SLP_EN)
+                | (slp_typ_b as u64 & 0x7) << PM1_SLP_TYP_SHIFT,
+        ),
+        None => None,
+    };
+
+    // The sleep type must be in place before SLP_EN is set.
+    pm1a.write(access, pm1a_value)?;
+    if let (Some(pm1b), Some(value)) = (pm1b, pm1b_value) {
+        pm1b.write(access, value)?;
+    }
+
+    pm1a.write(access, pm1a_value | PM1_SLP_EN)?;
+    if let (Some(pm1b), Some(value)) = (pm1b, pm1b_value) {
+        pm1b.write(access, value | PM1_SLP_EN)?;
+    }
+
+    Ok(())
+}
+
+/// Read the PM1 status register.
+///
+/// The PM1a and PM1b status registers are combined, as ACPI requires. Returns
+/// [AcpiError::Pm1Unsupported] if there is no PM1 event block, e.g. on
+/// hardware-reduced platforms.
+pub fn pm1_status<A: Readable>(fadt: &Fadt, access: &mut A) -> Result<u16> {
+    match fadt.pm1_status_registers() {
+        (Some(pm1a), pm1b) => {
+            let mut status = pm1a.read(access)?;
+            if let Some(pm1b) = pm1b {
+                status |= pm1b.read(access)?;
+            }
+            Ok(status as u16)
+        }
+        (None, _) => Err(AcpiError::Pm1Unsupported),
+    }
+}
@@ -25,7 +25,8 @@ impl SdtHeader {
     ///
     /// # Safety
     /// All the bytes in the header (+ `length - size_of::<SdtHeader>()`) are summed.
-    /// When the result is 0, the header is considered valid.
+    /// When the result is 0, the header is considered valid. On failure, the
+    /// returned error carries the signature of the offending table.
     pub unsafe fn validate(&self) -> Result<()> {
         let ptr = self as *const SdtHeader as *const u8;
         let mut acc: u8 = 0;
@@ -36,7 +37,9 @@ impl SdtHeader {
         if acc == 0 {
             Ok(())
         } else {
-            Err(AcpiError::ChecksumFailed)
+            Err(AcpiError::ChecksumFailed {
+                signature: self.signature,
+            })
         }
     }
 
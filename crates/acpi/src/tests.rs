@@ -0,0 +1,866 @@
+//! Host tests for the table parsers.
+//!
+//! The tables are built in a plain byte buffer. Pointers in the RSDT are
+//! offsets into that buffer, and the buffer's address is passed as the
+//! `offset` to [AcpiTables::from_address], mimicking a physical memory window.
+
+extern crate std;
+
+use std::{format, string::String, vec, vec::Vec};
+
+use core::mem;
+
+use crate::{
+    address::{AccessSize, GenericAddress, PciAddress, Readable, Writable},
+    builder::{self, TableBuilder},
+    facs::Facs,
+    fadt::{Fadt, FixedFeatureFlags, IaPCBootArchFlags},
+    hpet::Hpet,
+    madt::*,
+    power,
+    sdt::SdtHeader,
+    signature_str, AcpiError, AcpiTable, AcpiTables, Signature, TableKind, MAX_INDEXED_SIGNATURES,
+};
+
+/// Build a checksummed table with the given signature and body.
+///
+/// The body is everything following the [SdtHeader].
+fn table(signature: [u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0; mem::size_of::<SdtHeader>() + body.len()];
+    let mut builder = TableBuilder::new(&mut buffer, header(signature)).unwrap();
+    builder.push_bytes(body).unwrap();
+    builder.finish();
+    buffer
+}
+
+/// A header with the given signature. The length and checksum are left to
+/// the [TableBuilder].
+fn header(signature: [u8; 4]) -> SdtHeader {
+    SdtHeader {
+        signature,
+        length: 0,
+        revision: 1,
+        checksum: 0,
+        oemid: *b"K_OS  ",
+        oem_table_id: *b"TESTTBL ",
+        oem_revision: 1,
+        creator_id: 0,
+        creator_revision: 0,
+    }
+}
+
+/// Build a buffer containing an RSDT followed by the given tables.
+fn rsdt(tables: &[Vec<u8>]) -> Vec<u8> {
+    let rsdt_len = mem::size_of::<SdtHeader>() + tables.len() * 4;
+
+    let mut pointers = Vec::new();
+    let mut next = rsdt_len;
+    for table in tables {
+        pointers.extend_from_slice(&(next as u32).to_le_bytes());
+        next += table.len();
+    }
+
+    let mut buffer = table(*b"RSDT", &pointers);
+    for table in tables {
+        buffer.extend_from_slice(table);
+    }
+    buffer
+}
+
+fn madt() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes());
+    // Processor Local APIC: type 0, length 8, uid 0, apic id 0, enabled.
+    body.extend_from_slice(&[0, 8, 0, 0]);
+    body.extend_from_slice(&1u32.to_le_bytes());
+    table(Madt::SIGNATURE, &body)
+}
+
+fn fadt() -> Vec<u8> {
+    let body = [0u8; mem::size_of::<Fadt>() - mem::size_of::<SdtHeader>()];
+    table(Fadt::SIGNATURE, &body)
+}
+
+#[test]
+fn parses_rsdt() {
+    let tables = [madt(), fadt()];
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+
+    assert_eq!(acpi.len(), 2);
+    assert_eq!(acpi.size(), buffer.len());
+
+    let mut iter = acpi.iter();
+    assert!(matches!(iter.next(), Some(TableKind::Madt(_))));
+    assert!(matches!(iter.next(), Some(TableKind::Fadt(_))));
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn iter_with_addr() {
+    let tables = [madt(), fadt()];
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+
+    let madt_address = mem::size_of::<SdtHeader>() + 8;
+    let fadt_address = madt_address + tables[0].len();
+
+    let mut iter = acpi.iter_with_addr();
+    match iter.next() {
+        Some((address, TableKind::Madt(madt))) => {
+            assert_eq!(address, madt_address);
+            assert_eq!(acpi.physical_address(&madt.header), address);
+        }
+        other => panic!("unexpected table: {:?}", other),
+    }
+    assert!(matches!(iter.next(), Some((address, TableKind::Fadt(_))) if address == fadt_address));
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn invalid_table_skipped() {
+    let mut bad = table(*b"TEST", &[1, 2, 3, 4]);
+    bad[9] = bad[9].wrapping_add(1);
+    let tables = [madt(), bad, fadt()];
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+
+    let mut iter = acpi.iter();
+    assert!(matches!(iter.next(), Some(TableKind::Madt(_))));
+    assert!(matches!(iter.next(), Some(TableKind::Fadt(_))));
+    assert!(iter.next().is_none());
+    assert_eq!(iter.skipped(), 1);
+
+    let bad_address = mem::size_of::<SdtHeader>() + 12 + tables[0].len();
+    let mut invalid = acpi.iter_invalid();
+    match invalid.next() {
+        Some((address, AcpiError::ChecksumFailed { signature })) => {
+            assert_eq!(address, bad_address);
+            assert_eq!(signature, *b"TEST");
+        }
+        other => panic!("unexpected entry: {:?}", other),
+    }
+    assert!(invalid.next().is_none());
+}
+
+#[test]
+fn unknown_table() {
+    let tables = [table(*b"TEST", &[1, 2, 3, 4])];
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+
+    match acpi.iter().next() {
+        Some(TableKind::Unknown(header)) => assert_eq!(header.signature, *b"TEST"),
+        other => panic!("unexpected table: {:?}", other),
+    }
+}
+
+#[test]
+fn table_index() {
+    let tables = [madt(), table(*b"SSDT", &[1]), fadt(), table(*b"SSDT", &[2])];
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+    let index = acpi.index();
+
+    assert!(index.madt().is_some());
+    assert!(index.fadt().is_some());
+    assert!(index.hpet().is_none());
+
+    // Only the first table of a signature is kept.
+    match index.find(*b"SSDT") {
+        Some(TableKind::Unknown(header)) => {
+            let body = unsafe { *(header as *const SdtHeader).add(1).cast::<u8>() };
+            assert_eq!(body, 1);
+        }
+        other => panic!("unexpected table: {:?}", other),
+    }
+}
+
+#[test]
+fn table_index_overflow() {
+    let tables: Vec<Vec<u8>> = (0..=MAX_INDEXED_SIGNATURES as u8)
+        .map(|idx| table([b'T', b'S', b'T', b'A' + idx], &[]))
+        .collect();
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+    let index = acpi.index();
+
+    // Signatures that didn't fit are still found.
+    for idx in 0..=MAX_INDEXED_SIGNATURES as u8 {
+        let signature = [b'T', b'S', b'T', b'A' + idx];
+        assert!(matches!(index.find(signature), Some(TableKind::Unknown(_))));
+    }
+    assert!(index.find(*b"NONE").is_none());
+}
+
+#[test]
+fn relocate() {
+    let tables = [madt(), fadt()];
+    let buffer = rsdt(&tables);
+
+    // Copy the tables as a single block, to a different "physical" address.
+    // The original tables are gone once the copy is relocated.
+    let copy = buffer.clone();
+    let relocated = {
+        let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+        unsafe { acpi.relocate(0x8000, copy.as_ptr() as usize - 0x8000) }.unwrap()
+    };
+
+    assert_eq!(
+        relocated.version().header() as *const SdtHeader as usize,
+        copy.as_ptr() as usize
+    );
+    assert_eq!(relocated.size(), copy.len());
+
+    for table in relocated.iter() {
+        let address = table.header() as *const SdtHeader as usize;
+        assert!(copy.as_ptr_range().contains(&(address as *const u8)));
+    }
+    assert_eq!(relocated.iter().count(), 2);
+}
+
+/// Build an MADT with the given interrupt controller structures.
+fn madt_with(structures: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes());
+    body.extend_from_slice(structures);
+    table(Madt::SIGNATURE, &body)
+}
+
+fn parse_madt(buffer: &[u8]) -> usize {
+    let madt = unsafe { &*(buffer.as_ptr() as *const Madt) };
+    madt.iter().count()
+}
+
+#[test]
+fn madt_structures() {
+    // A local APIC followed by an I/O APIC.
+    let buffer = madt_with(&[0, 8, 0, 0, 1, 0, 0, 0, 1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+    assert_eq!(parse_madt(&buffer), 2);
+}
+
+#[test]
+fn madt_typed_iterators() {
+    // Local APIC, IO APIC, local APIC NMI, local APIC, interrupt source override.
+    let buffer = madt_with(&[
+        0, 8, 0, 0, 1, 0, 0, 0, //
+        1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0, //
+        4, 6, 0xff, 0, 0, 1, //
+        0, 8, 1, 1, 1, 0, 0, 0, //
+        2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+    ]);
+    let madt = unsafe { &*(buffer.as_ptr() as *const Madt) };
+
+    let ids: Vec<u8> = madt.local_apics().map(|apic| apic.apic_id).collect();
+    assert_eq!(ids, [0, 1]);
+    assert_eq!(madt.io_apics().count(), 1);
+    assert_eq!({ madt.io_apics().next().unwrap().io_apic_address }, 0xfec0_0000);
+
+    let nmi = madt.local_apic_nmis().next().unwrap();
+    assert_eq!(nmi.acpi_processor_uid, 0xff);
+    assert_eq!(nmi.local_apic_lint_n, 1);
+
+    let iso = madt.interrupt_overrides().next().unwrap();
+    assert_eq!({ iso.global_system_interrupt }, 2);
+
+    assert_eq!(madt.nmi_sources().count(), 0);
+    assert_eq!(madt.local_x2apics().count(), 0);
+    assert_eq!(madt.local_x2apic_nmis().count(), 0);
+}
+
+#[test]
+fn madt_summary() {
+    // Local APIC, disabled local APIC, IO APIC, interrupt source override,
+    // local APIC NMI and an OEM structure.
+    let buffer = madt_with(&[
+        0, 8, 0, 0, 1, 0, 0, 0, //
+        0, 8, 1, 1, 0, 0, 0, 0, //
+        1, 12, 2, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0, //
+        2, 10, 0, 9, 9, 0, 0, 0, 0xf, 0, //
+        4, 6, 0xff, 0, 0, 1, //
+        0x80, 2,
+    ]);
+    let madt = unsafe { &*(buffer.as_ptr() as *const Madt) };
+
+    let mut summary = String::new();
+    madt.summarize(&mut summary).unwrap();
+    assert_eq!(
+        summary,
+        "MADT: local APIC at 0xfee00000, flags: PCAT_COMPAT\n\
+         \x20 CPU 0: APIC ID 0, enabled\n\
+         \x20 CPU 1: APIC ID 1, disabled\n\
+         \x20 IOAPIC 2: address 0xfec00000, GSI base 0\n\
+         \x20 IRQ 9 -> GSI 9, active low, level\n\
+         \x20 NMI: LINT1, conforming polarity, conforming trigger mode, all CPUs\n\
+         \x20 Unknown structure type 0x80\n"
+    );
+}
+
+#[test]
+fn madt_zero_length() {
+    let buffer = madt_with(&[0, 8, 0, 0, 1, 0, 0, 0, 0x80, 0, 0, 0]);
+    assert_eq!(parse_madt(&buffer), 1);
+}
+
+#[test]
+fn madt_overrun() {
+    // The second structure claims to be longer than what's left of the table.
+    let buffer = madt_with(&[0, 8, 0, 0, 1, 0, 0, 0, 0x80, 16, 0, 0]);
+    assert_eq!(parse_madt(&buffer), 1);
+}
+
+#[test]
+fn madt_validate() {
+    let madt = |buffer: &[u8]| unsafe { &*(buffer.as_ptr() as *const Madt) }.validate();
+
+    assert!(madt(&madt_with(&[])).is_ok());
+    assert!(madt(&madt_with(&[0, 8, 0, 0, 1, 0, 0, 0])).is_ok());
+
+    // Padding after the last structure.
+    assert!(madt(&madt_with(&[0, 8, 0, 0, 1, 0, 0, 0, 0])).is_err());
+    assert!(madt(&madt_with(&[0, 8, 0, 0, 1, 0, 0, 0, 0x80, 0, 0, 0])).is_err());
+
+    // A structure running past the end of the table.
+    assert!(madt(&madt_with(&[0, 8, 0, 0, 1, 0, 0, 0, 0x80, 16, 0, 0])).is_err());
+}
+
+#[test]
+fn fadt_facs() {
+    // Place the FACS at "physical" address 0x40, and point the 32-bit firmware
+    // control field (the first field after the header) at it.
+    let mut memory = [0u8; 0x40 + mem::size_of::<Facs>()];
+    memory[0x40..0x44].copy_from_slice(&Facs::SIGNATURE);
+    memory[0x48..0x4c].copy_from_slice(&0x1234u32.to_le_bytes());
+
+    let mut fadt = fadt();
+    fadt[mem::size_of::<SdtHeader>()] = 0x40;
+    let table = unsafe { &*(fadt.as_ptr() as *const Fadt) };
+    assert_eq!(table.facs_address(), Some(0x40));
+
+    let facs = unsafe { table.facs(memory.as_ptr() as usize) }.unwrap();
+    assert_eq!({ facs.hardware_signature }, 0x1234);
+
+    // Without a matching signature there is no FACS.
+    memory[0x40] = 0;
+    assert!(unsafe { table.facs(memory.as_ptr() as usize) }.is_none());
+
+    // Neither is there without an address.
+    let fadt = self::fadt();
+    let table = unsafe { &*(fadt.as_ptr() as *const Fadt) };
+    assert!(table.facs_address().is_none());
+    assert!(unsafe { table.facs(memory.as_ptr() as usize) }.is_none());
+}
+
+#[test]
+fn fadt_dsdt() {
+    // The DSDT directly follows the FADT, but isn't listed in the RSDT.
+    let dsdt_address = mem::size_of::<SdtHeader>() + 4 + mem::size_of::<Fadt>();
+    let mut body = [0u8; mem::size_of::<Fadt>() - mem::size_of::<SdtHeader>()];
+    body[4..8].copy_from_slice(&(dsdt_address as u32).to_le_bytes());
+
+    let mut buffer = rsdt(&[table(Fadt::SIGNATURE, &body)]);
+    buffer.extend_from_slice(&table(*b"DSDT", &[0x10, 0x20]));
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+
+    assert!(acpi.fadt().is_some());
+    assert_eq!(acpi.iter().count(), 1);
+
+    let dsdt = acpi.dsdt().unwrap();
+    assert_eq!(acpi.physical_address(dsdt), dsdt_address);
+    assert_eq!({ dsdt.length }, 38);
+
+    // A corrupted DSDT isn't returned.
+    let mut corrupted = buffer.clone();
+    corrupted[dsdt_address + 37] ^= 0xff;
+    let acpi = unsafe { AcpiTables::from_address(0, corrupted.as_ptr() as usize) }.unwrap();
+    assert!(acpi.dsdt().is_none());
+
+    // Neither is one that isn't referenced.
+    let buffer = rsdt(&[fadt()]);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+    assert!(acpi.dsdt().is_none());
+}
+
+#[test]
+fn address_overflow() {
+    assert!(matches!(
+        unsafe { AcpiTables::from_address(usize::MAX, 2) },
+        Err(AcpiError::InvalidHeader { signature }) if signature == [0; 4]
+    ));
+}
+
+#[test]
+fn retained_size() {
+    // The DSDT follows the FADT and MADT, but isn't listed in the RSDT.
+    let madt = madt();
+    let dsdt_address = mem::size_of::<SdtHeader>() + 8 + mem::size_of::<Fadt>() + madt.len();
+    let mut body = [0u8; mem::size_of::<Fadt>() - mem::size_of::<SdtHeader>()];
+    body[4..8].copy_from_slice(&(dsdt_address as u32).to_le_bytes());
+
+    let mut buffer = rsdt(&[table(Fadt::SIGNATURE, &body), madt.clone()]);
+    buffer.extend_from_slice(&table(*b"DSDT", &[0x10, 0x20]));
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+
+    let root = mem::size_of::<SdtHeader>() + 8;
+    assert_eq!(acpi.retained_size(&[]), root);
+    assert_eq!(acpi.retained_size(&[Signature::Madt]), root + madt.len());
+    assert_eq!(
+        acpi.retained_size(&[Signature::Madt, Signature::Fadt]),
+        buffer.len()
+    );
+    assert!(acpi.size() < buffer.len());
+}
+
+/// Records every register write, and answers reads from `values`.
+#[derive(Debug, Default)]
+struct Recorder {
+    memory: Vec<(u64, AccessSize, u64)>,
+    io: Vec<(u16, AccessSize, u64)>,
+    pci: Vec<(PciAddress, AccessSize, u64)>,
+    /// Memory addresses and I/O ports with their value. Anything else reads as
+    /// all ones.
+    values: Vec<(u64, u64)>,
+    reads: Vec<(u64, AccessSize)>,
+}
+
+impl Recorder {
+    fn read(&mut self, address: u64, size: AccessSize) -> u64 {
+        self.reads.push((address, size));
+        self.values
+            .iter()
+            .find(|(a, _)| *a == address)
+            .map_or(u64::MAX, |(_, value)| *value)
+    }
+}
+
+impl Readable for Recorder {
+    fn read_memory(&mut self, address: u64, size: AccessSize) -> u64 {
+        self.read(address, size)
+    }
+
+    fn read_io(&mut self, port: u16, size: AccessSize) -> u64 {
+        self.read(port as u64, size)
+    }
+
+    fn read_pci_config(&mut self, _address: PciAddress, size: AccessSize) -> u64 {
+        self.read(u64::MAX, size)
+    }
+}
+
+impl Writable for Recorder {
+    fn write_memory(&mut self, address: u64, size: AccessSize, value: u64) {
+        self.memory.push((address, size, value));
+    }
+
+    fn write_io(&mut self, port: u16, size: AccessSize, value: u64) {
+        self.io.push((port, size, value));
+    }
+
+    fn write_pci_config(&mut self, address: PciAddress, size: AccessSize, value: u64) {
+        self.pci.push((address, size, value));
+    }
+}
+
+fn gas(address_space_id: u8, access_size: u8, address: u64) -> GenericAddress {
+    GenericAddress {
+        address_space_id,
+        register_bit_width: 8,
+        register_bit_offset: 0,
+        access_size,
+        address,
+    }
+}
+
+#[test]
+fn gas_write() {
+    let mut recorder = Recorder::default();
+
+    gas(0, 0, 0xfed0_0000).write(&mut recorder, 1).unwrap();
+    gas(1, 1, 0xcf9).write(&mut recorder, 6).unwrap();
+    gas(2, 1, 0x0000_001f_0000_0044)
+        .write(&mut recorder, 2)
+        .unwrap();
+
+    assert_eq!(recorder.memory, [(0xfed0_0000, AccessSize::Byte, 1)]);
+    assert_eq!(recorder.io, [(0xcf9, AccessSize::Byte, 6)]);
+    assert_eq!(
+        recorder.pci,
+        [(
+            PciAddress {
+                device: 0x1f,
+                function: 0,
+                offset: 0x44
+            },
+            AccessSize::Byte,
+            2
+        )]
+    );
+
+    // Embedded controller.
+    assert!(matches!(
+        gas(3, 1, 0x62).write(&mut recorder, 0),
+        Err(AcpiError::UnsupportedAddressSpace { id: 3 })
+    ));
+    assert!(matches!(
+        gas(1, 1, 0x10000).write(&mut recorder, 0),
+        Err(AcpiError::InvalidAddress)
+    ));
+    assert!(matches!(
+        gas(1, 2, 0xcf9).write(&mut recorder, 0),
+        Err(AcpiError::InvalidAccessSize)
+    ));
+}
+
+#[test]
+fn gas_read() {
+    let mut recorder = Recorder {
+        values: vec![(0xfed0_0000, 0xabcd), (0x400, 0x8001)],
+        ..Default::default()
+    };
+
+    assert_eq!(gas(0, 0, 0xfed0_0000).read(&mut recorder).unwrap(), 0xcd);
+    let mut register = gas(1, 0, 0x400);
+    register.register_bit_width = 16;
+    assert_eq!(register.read(&mut recorder).unwrap(), 0x8001);
+
+    // The value is shifted down by the bit offset.
+    let mut register = gas(0, 0, 0xfed0_0000);
+    register.register_bit_offset = 4;
+    assert_eq!(register.read(&mut recorder).unwrap(), 0xc);
+
+    assert_eq!(
+        recorder.reads,
+        [
+            (0xfed0_0000, AccessSize::Byte),
+            (0x400, AccessSize::Word),
+            (0xfed0_0000, AccessSize::Byte),
+        ]
+    );
+
+    assert!(matches!(
+        gas(3, 1, 0x62).read(&mut recorder),
+        Err(AcpiError::UnsupportedAddressSpace { id: 3 })
+    ));
+}
+
+#[test]
+fn reboot() {
+    let buffer = fadt();
+    let mut fadt = unsafe { (buffer.as_ptr() as *const Fadt).read_unaligned() };
+    fadt.reset_reg = gas(1, 1, 0xcf9);
+    fadt.reset_value = 6;
+
+    let mut recorder = Recorder::default();
+    assert!(matches!(
+        power::reboot(&fadt, &mut recorder),
+        Err(AcpiError::ResetUnsupported)
+    ));
+    assert!(recorder.io.is_empty());
+
+    fadt.flags = FixedFeatureFlags::RESET_REG_SUP;
+    power::reboot(&fadt, &mut recorder).unwrap();
+    assert_eq!(recorder.io, [(0xcf9, AccessSize::Byte, 6)]);
+}
+
+#[test]
+fn fadt_summary() {
+    let buffer = fadt();
+    let mut fadt = unsafe { (buffer.as_ptr() as *const Fadt).read_unaligned() };
+
+    // Before revision 3, there are no boot architecture flags.
+    let summary = fadt.summary();
+    assert!(summary.legacy_devices && summary.has_8042);
+    assert!(summary.has_vga && summary.has_cmos_rtc && summary.msi_supported);
+    assert!(!summary.hardware_reduced && !summary.reset_register);
+
+    fadt.header.revision = 6;
+    fadt.iapc_boot_arch = IaPCBootArchFlags::MSI_NOT_SUPPORTED;
+    fadt.flags = FixedFeatureFlags::HW_REDUCED_ACPI | FixedFeatureFlags::RESET_REG_SUP;
+
+    let summary = fadt.summary();
+    assert!(!summary.legacy_devices && !summary.has_8042 && !summary.msi_supported);
+    assert!(summary.has_vga && summary.has_cmos_rtc);
+    assert!(summary.hardware_reduced && summary.reset_register);
+}
+
+#[test]
+fn sleep() {
+    let buffer = fadt();
+    let mut fadt = unsafe { (buffer.as_ptr() as *const Fadt).read_unaligned() };
+
+    let mut recorder = Recorder::default();
+    assert!(matches!(
+        power::sleep(&fadt, &mut recorder, 5, 5),
+        Err(AcpiError::SleepUnsupported)
+    ));
+
+    // Legacy PM1a control block at port 0x404, with SCI_EN set and a stale
+    // sleep type. Only SLP_TYP and SLP_EN may change, in that order.
+    fadt.pm1a_cnt_blk = 0x404;
+    fadt.pm1_cnt_len = 2;
+    fadt.sleep_control_reg = gas(1, 1, 0x500);
+    recorder.values.push((0x404, 1 | 7 << 10));
+    power::sleep(&fadt, &mut recorder, 5, 5).unwrap();
+    assert_eq!(
+        recorder.io,
+        [
+            (0x404, AccessSize::Word, 1 | 5 << 10),
+            (0x404, AccessSize::Word, 1 | 5 << 10 | 1 << 13)
+        ]
+    );
+
+    // Hardware-reduced platforms must not touch the PM1 control block.
+    fadt.flags = FixedFeatureFlags::HW_REDUCED_ACPI;
+    recorder.io.clear();
+    power::sleep(&fadt, &mut recorder, 5, 5).unwrap();
+    assert_eq!(recorder.io, [(0x500, AccessSize::Byte, 5 << 2 | 1 << 5)]);
+
+    fadt.sleep_control_reg = gas(1, 1, 0);
+    assert!(matches!(
+        power::sleep(&fadt, &mut recorder, 5, 5),
+        Err(AcpiError::SleepUnsupported)
+    ));
+}
+
+#[test]
+fn fadt_summarize() {
+    let buffer = fadt();
+    let mut fadt = unsafe { (buffer.as_ptr() as *const Fadt).read_unaligned() };
+    fadt.preferred_pm_profile = 1;
+    fadt.sci_int = 9;
+    fadt.flags = FixedFeatureFlags::WBINVD | FixedFeatureFlags::TMR_VAL_EXT;
+    fadt.pm_tmr_blk = 0x608;
+    fadt.pm_tmr_len = 4;
+
+    let mut summary = String::new();
+    fadt.summarize(&mut summary).unwrap();
+    assert_eq!(
+        summary,
+        "FADT: revision 1.0\n\
+         \x20 PM profile: Desktop\n\
+         \x20 SCI: IRQ 9\n\
+         \x20 Flags: WBINVD | TMR_VAL_EXT\n\
+         \x20 PM timer: I/O port 0x608, 32 bits\n"
+    );
+}
+
+#[test]
+fn pm1_status() {
+    let buffer = fadt();
+    let mut fadt = unsafe { (buffer.as_ptr() as *const Fadt).read_unaligned() };
+
+    let mut recorder = Recorder {
+        values: vec![(0x400, 0x8001), (0x500, 0x0100)],
+        ..Default::default()
+    };
+    assert!(matches!(
+        power::pm1_status(&fadt, &mut recorder),
+        Err(AcpiError::Pm1Unsupported)
+    ));
+
+    // Legacy PM1a event block at port 0x400, with the status register in the
+    // first half.
+    fadt.pm1a_evt_blk = 0x400;
+    fadt.pm1_evt_len = 4;
+    assert_eq!(power::pm1_status(&fadt, &mut recorder).unwrap(), 0x8001);
+    assert_eq!(recorder.reads, [(0x400, AccessSize::Word)]);
+
+    // The 64-bit PM1b block is preferred, and combined with PM1a.
+    fadt.pm1b_evt_blk = 0x600;
+    fadt.x_pm1b_evt_blk = gas(1, 3, 0x500);
+    assert_eq!(power::pm1_status(&fadt, &mut recorder).unwrap(), 0x8101);
+
+    fadt.flags = FixedFeatureFlags::HW_REDUCED_ACPI;
+    let (pm1a, pm1b) = fadt.pm1_status_registers();
+    assert!(pm1a.is_none() && pm1b.is_none());
+}
+
+#[test]
+fn pm_timer_register() {
+    let buffer = fadt();
+    let mut fadt = unsafe { (buffer.as_ptr() as *const Fadt).read_unaligned() };
+    assert!(fadt.pm_timer_register().is_none());
+
+    // The legacy block must be 4 bytes long.
+    fadt.pm_tmr_blk = 0x408;
+    assert!(fadt.pm_timer_register().is_none());
+
+    fadt.pm_tmr_len = 4;
+    let (register, extended) = fadt.pm_timer_register().unwrap();
+    assert_eq!({ register.address }, 0x408);
+    assert_eq!(register.address_space_id, 0x01);
+    assert!(!extended);
+
+    fadt.x_pm_tmr_blk = gas(1, 3, 0x608);
+    fadt.flags = FixedFeatureFlags::TMR_VAL_EXT;
+    let (register, extended) = fadt.pm_timer_register().unwrap();
+    assert_eq!({ register.address }, 0x608);
+    assert!(extended);
+
+    fadt.flags = FixedFeatureFlags::TMR_VAL_EXT | FixedFeatureFlags::HW_REDUCED_ACPI;
+    assert!(fadt.pm_timer_register().is_none());
+}
+
+#[test]
+fn builder_from_table() {
+    let fadt = fadt();
+    let mut original = unsafe { (fadt.as_ptr() as *const Fadt).read_unaligned() };
+    original.sci_int = 9;
+
+    // Build a patched copy of the FADT.
+    let mut buffer = [0u8; 512];
+    let table = unsafe { TableBuilder::from_table(&mut buffer, &original) }
+        .unwrap()
+        .finish();
+    assert_eq!(table.len(), mem::size_of::<Fadt>());
+
+    let patched = unsafe { &*(table.as_ptr() as *const Fadt) };
+    assert!(unsafe { patched.header.validate() }.is_ok());
+    assert_eq!(patched.sci_interrupt(), Some(9));
+
+    // Modifying it in place requires the checksum to be updated.
+    table[46] = 10;
+    assert!(unsafe { (*(table.as_ptr() as *const SdtHeader)).validate() }.is_err());
+    builder::update_checksum(table);
+    assert!(unsafe { (*(table.as_ptr() as *const SdtHeader)).validate() }.is_ok());
+}
+
+#[test]
+fn builder_too_small() {
+    let mut buffer = [0u8; 40];
+    let mut builder = TableBuilder::new(&mut buffer, header(*b"TEST")).unwrap();
+    unsafe { builder.push(&1u32) }.unwrap();
+    assert!(matches!(
+        unsafe { builder.push(&1u8) },
+        Err(AcpiError::BufferTooSmall)
+    ));
+
+    let mut buffer = [0u8; 8];
+    assert!(matches!(
+        TableBuilder::new(&mut buffer, header(*b"TEST")),
+        Err(AcpiError::BufferTooSmall)
+    ));
+}
+
+#[test]
+fn signatures() {
+    assert_eq!(Madt::signature_str(), "APIC");
+    assert_eq!(signature_str(b"HPET"), Some("HPET"));
+    assert_eq!(signature_str(b"HP\0T"), None);
+
+    assert_eq!(
+        format!("{}", Signature::from_bytes(b"\x01\x02AB")),
+        "[01, 02, 41, 42]"
+    );
+    assert_eq!(Signature::from_bytes(b"APIC"), Signature::Madt);
+    assert_eq!(Signature::from(Fadt::SIGNATURE).as_bytes(), b"FACP");
+    assert_eq!(Signature::from_bytes(b"XYZW").as_bytes(), b"XYZW");
+    assert_eq!(format!("{:?}", Signature::Ssdt), "\"SSDT\"");
+
+    let tables = [table(*b"SRAT", &[])];
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+    let table = acpi.iter().next().unwrap();
+    assert_eq!(format!("{:?}", table), "Unknown(\"SRAT\")");
+}
+
+#[test]
+fn hpet() {
+    let mut body = [0u8; mem::size_of::<Hpet>() - mem::size_of::<SdtHeader>()];
+    // 64-bit counter, 3 comparators.
+    body[0..4].copy_from_slice(&(1u32 << 13 | 2 << 8).to_le_bytes());
+    body[8..16].copy_from_slice(&0xfed0_0000u64.to_le_bytes());
+
+    let tables = [table(Hpet::SIGNATURE, &body)];
+    let buffer = rsdt(&tables);
+    let acpi = unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) }.unwrap();
+    let hpet = acpi.hpet().unwrap();
+    assert_eq!({ hpet.base_address.address }, 0xfed0_0000);
+    assert!(hpet.is_64bit());
+    assert_eq!(hpet.num_comparators(), 3);
+}
+
+#[test]
+fn bad_checksum() {
+    let mut buffer = rsdt(&[]);
+    buffer[9] = buffer[9].wrapping_add(1);
+
+    match unsafe { AcpiTables::from_address(0, buffer.as_ptr() as usize) } {
+        Err(AcpiError::ChecksumFailed { signature }) => assert_eq!(signature, *b"RSDT"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+/// Assert the size of a table structure and the offsets of some of its fields.
+///
+/// These are checked at compile time, so a layout mistake fails the build of
+/// the test crate instead of showing up as garbage when parsing firmware
+/// tables.
+macro_rules! assert_layout {
+    ($ty:ty, $size:expr $(, $field:ident @ $offset:expr)* $(,)?) => {
+        const _: () = {
+            assert!(mem::size_of::<$ty>() == $size);
+            $(assert!(mem::offset_of!($ty, $field) == $offset);)*
+        };
+    };
+}
+
+// See ACPI v6.4 sections 5.2.3.2, 5.2.6, 5.2.9 and 5.2.10.
+assert_layout!(GenericAddress, 12, access_size @ 3, address @ 4);
+assert_layout!(
+    SdtHeader, 36,
+    length @ 4, revision @ 8, checksum @ 9, oemid @ 10, oem_table_id @ 16,
+    oem_revision @ 24, creator_id @ 28, creator_revision @ 32,
+);
+assert_layout!(
+    Fadt, 276,
+    firmware_ctrl @ 36, dsdt @ 40, sci_int @ 46, smi_cmd @ 48, pm1a_evt_blk @ 56,
+    pm_tmr_blk @ 76, iapc_boot_arch @ 109, flags @ 112, reset_reg @ 116,
+    reset_value @ 128, arm_boot_arch @ 129, fadt_minor_version @ 131,
+    x_firmware_ctrl @ 132, x_dsdt @ 140, x_pm1a_evt_blk @ 148,
+    sleep_control_reg @ 244, sleep_status_reg @ 256, hypervisor_identity_reg @ 268,
+);
+assert_layout!(
+    Facs, 64,
+    hardware_signature @ 8, firmware_waking_vector @ 12, global_lock @ 16, flags @ 20,
+    x_firmware_waking_vector @ 24, version @ 32, ospm_flags @ 36,
+);
+
+// See the IA-PC HPET specification v1.0a section 3.2.4.
+assert_layout!(Hpet, 56, base_address @ 40, hpet_number @ 52, minimum_tick @ 53, page_protection @ 55);
+
+// See ACPI v6.4 section 5.2.12.
+assert_layout!(Madt, 44, local_apic_address @ 36, flags @ 40);
+assert_layout!(ApicStructureHeader, 2, length @ 1);
+assert_layout!(ProcessorLocalApicStructure, 8, acpi_processor_uid @ 2, apic_id @ 3, flags @ 4);
+assert_layout!(IoApicStructure, 12, io_apic_address @ 4, global_system_interrupt_base @ 8);
+assert_layout!(IntSourceOverrideStructure, 10, source @ 3, global_system_interrupt @ 4, flags @ 8);
+assert_layout!(NmiSourceStructure, 8, flags @ 2, global_system_interrupt @ 4);
+assert_layout!(LocalApicNmiStructure, 6, acpi_processor_uid @ 2, flags @ 3, local_apic_lint_n @ 5);
+assert_layout!(LocalApicAdressOverrideStructure, 12, local_apic_address @ 4);
+assert_layout!(IoSapicStructure, 16, global_system_interrupt_base @ 4, io_sapic_address @ 8);
+assert_layout!(LocalSapicStructure, 17, flags @ 8, acpi_processor_uid_value @ 12, acpi_processor_uid_string @ 16);
+assert_layout!(
+    PlatformInterruptSourceStructure, 16,
+    flags @ 2, interrupt_type @ 4, io_sapic_vector @ 7, global_system_interrupt @ 8,
+    platform_interrupt_source_flags @ 12,
+);
+assert_layout!(ProcessorLocalX2ApicStructure, 16, x2apic_id @ 4, flags @ 8, acpi_processor_uid @ 12);
+assert_layout!(LocalX2ApicNmiStructure, 12, flags @ 2, acpi_processor_uid @ 4, local_x2apic_lint_n @ 8);
+assert_layout!(
+    GiccStructure, 80,
+    cpu_interface_number @ 4, acpi_processor_uid @ 8, flags @ 12, parked_address @ 24,
+    physical_base_address @ 32, gicr_base_address @ 60, mpidr @ 68,
+    processor_power_efficiency_class @ 76, spe_overflow_interrupt @ 78,
+);
+assert_layout!(GicdStructure, 24, gic_id @ 4, physical_base_address @ 8, system_vector_base @ 16, gic_version @ 20);
+assert_layout!(GicMsiFrameStructure, 24, physical_base_address @ 8, flags @ 16, spi_count @ 20, spi_base @ 22);
+assert_layout!(GicrStructure, 16, discovery_range_base_address @ 4, discovery_range_length @ 12);
+assert_layout!(GicItsStructure, 20, gic_its_id @ 4, physical_base_address @ 8);
+assert_layout!(MultiProcessorWakeupStructure, 16, mailbox_version @ 2, mailbox_address @ 8);
+assert_layout!(
+    MultiProcessorWakeupMailbox, 4096,
+    apic_id @ 4, wakeup_vector @ 8, reserved_for_os @ 16, reserved_for_firmware @ 2048,
+);
@@ -2,6 +2,8 @@
 //!
 //! These are very similar to the x(2)APIC registers, but not entirely the same.
 
+use core::fmt;
+
 pub const IO_APIC_REG_SEL: u32 = 0x00;
 pub const IO_APIC_REG_WIN: u32 = 0x10;
 
@@ -108,7 +110,7 @@ impl Arbitration {
 /// Interrupt delivery mode.
 ///
 /// Specifies the type of interrupt to be sent to the processor. While these
-/// are very similar to the ones specified in [crate::apic::registers::DeliveryMode],
+/// are very similar to the ones specified in [crate::local::DeliveryMode],
 /// they are not exactly the same or completely interchangeable. Therefore
 /// we're redefining them here.
 #[derive(Debug, Clone, Copy)]
@@ -209,7 +211,7 @@ pub enum TriggerMode {
     Level = 1,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct RedirectionTableEntryLow {
     bits: u32,
@@ -336,6 +338,21 @@ impl RedirectionTableEntryLow {
     }
 }
 
+impl fmt::Debug for RedirectionTableEntryLow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedirectionTableEntryLow")
+            .field("vector", &self.vector())
+            .field("delivery_mode", &self.delivery_mode())
+            .field("destination_mode", &self.destination_mode())
+            .field("delivery_status", &self.delivery_status())
+            .field("int_pin_polarity", &self.int_pin_polarity())
+            .field("remote_irr", &self.remote_irr())
+            .field("trigger_mode", &self.trigger_mode())
+            .field("masked", &self.masked())
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum LogicalDestination {
@@ -401,3 +418,54 @@ impl RedirectionTableEntry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirection_entry_new() {
+        let entry = RedirectionTableEntryLow::new(
+            0x31,
+            DeliveryMode::LowestPriority,
+            DestinationMode::Logical,
+            InterruptPinPolarity::LowActive,
+            TriggerMode::Level,
+            true,
+        );
+
+        assert_eq!(entry.vector(), 0x31);
+        assert!(matches!(entry.delivery_mode(), DeliveryMode::LowestPriority));
+        assert!(matches!(entry.destination_mode(), DestinationMode::Logical));
+        assert!(matches!(entry.delivery_status(), DeliveryStatus::Idle));
+        assert!(matches!(
+            entry.int_pin_polarity(),
+            InterruptPinPolarity::LowActive
+        ));
+        assert!(!entry.remote_irr());
+        assert!(matches!(entry.trigger_mode(), TriggerMode::Level));
+        assert!(entry.masked());
+        assert_eq!(entry.bits(), 0x0001_a931);
+    }
+
+    #[test]
+    fn redirection_entry_setters() {
+        let mut entry = unsafe { RedirectionTableEntryLow::from_bits_unchecked(0) };
+
+        entry.set_vector(0xff);
+        entry.set_delivery_mode(DeliveryMode::ExtINT);
+        entry.set_destination_mode(DestinationMode::Logical);
+        entry.set_int_pin_polarity(InterruptPinPolarity::LowActive);
+        entry.set_trigger_mode(TriggerMode::Level);
+        entry.set_masked(true);
+        assert_eq!(entry.bits(), 0x0001_afff);
+
+        entry.set_vector(0);
+        entry.set_delivery_mode(DeliveryMode::Fixed);
+        entry.set_destination_mode(DestinationMode::Physical);
+        entry.set_int_pin_polarity(InterruptPinPolarity::HighActive);
+        entry.set_trigger_mode(TriggerMode::Edge);
+        entry.set_masked(false);
+        assert_eq!(entry.bits(), 0);
+    }
+}
@@ -0,0 +1,434 @@
+//! Local APIC and IOAPIC support.
+//!
+//! This crate provides the register layouts of the x(2)APIC ([local]) and the
+//! IOAPIC ([io]), and the operations built on top of the local APIC registers
+//! ([ApicAccess]). How the registers are reached (MMIO, MSRs) is up to the
+//! implementor, which keeps everything here testable on the host.
+
+#![no_std]
+
+use kernel::sync::Backoff;
+use local::{
+    ApicBase, DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand,
+    DivideConfiguration, Divisor, Error, ErrorStatus, Icr, IcrHigh, IcrLow, Level, Timer,
+    TimerMode, TriggerMode, EOI_REG, ERROR_STATUS_REG, ICR_HIGH_REG, ICR_LOW_REG,
+    LOCAL_APIC_ID_REG, LOGICAL_DEST_FMT_REG, LOGICAL_DEST_REG, LVT_ERROR_REG, LVT_TIMER_REG,
+    TIMER_CURR_COUNT_REG, TIMER_DIVIDE_CONF_REG, TIMER_INIT_COUNT_REG,
+};
+
+pub mod io;
+pub mod local;
+
+#[cfg(test)]
+mod tests;
+
+/// The number of times the ICR is polled before an IPI is considered lost.
+pub const ICR_SEND_TIMEOUT: u32 = 1_000_000;
+
+/// The mode the local APIC operates in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// Registers are accessed through MMIO, using 8-bit APIC IDs.
+    XApic,
+
+    /// Registers are accessed through MSRs, using 32-bit APIC IDs.
+    X2Apic,
+}
+
+/// Access to the registers of a local APIC.
+///
+/// The higher-level operations (timer, IPIs, error status...) are provided on
+/// top of [read](ApicAccess::read) and [write](ApicAccess::write), so they can
+/// be exercised against a mock in tests. Registers are named by their xAPIC
+/// offset, implementations translate them to MSRs in x2APIC mode.
+pub trait ApicAccess {
+    /// Return the mode the local APIC operates in.
+    fn mode(&self) -> ApicMode;
+
+    /// Read the given register, automatically translating it to x2APIC if
+    /// necessary.
+    ///
+    /// # Safety
+    /// Reading some registers has side effects.
+    unsafe fn read(&self, reg: u32) -> u64;
+
+    /// Write to the given register, automatically translating it to x2APIC if
+    /// necessary.
+    ///
+    /// # Safety
+    /// The caller must make sure the value is valid for the register.
+    unsafe fn write(&self, reg: u32, val: u64);
+
+    /// Read the `IA32_APIC_BASE` MSR.
+    fn read_base(&self) -> ApicBase;
+
+    /// Write the `IA32_APIC_BASE` MSR.
+    ///
+    /// # Safety
+    /// Changing the mode or base address affects every register access after
+    /// it.
+    unsafe fn write_base(&self, base: ApicBase);
+
+    /// Returns true if the local APIC operates in x2APIC mode.
+    fn is_x2apic(&self) -> bool {
+        self.mode() == ApicMode::X2Apic
+    }
+
+    /// Returns true is the current CPU is the Boot Strap Processor.
+    fn is_bsp(&self) -> bool {
+        self.read_base().is_bsp()
+    }
+
+    /// Disable the local APIC.
+    ///
+    /// This clears both the global enable and x2APIC mode bits, leaving the
+    /// rest of `IA32_APIC_BASE` untouched.
+    fn disable(&self) {
+        let mut base = self.read_base();
+        base.disable();
+        unsafe {
+            self.write_base(base);
+        }
+    }
+
+    /// Returns the APIC ID of the current CPU.
+    fn id(&self) -> u32 {
+        let mut raw = unsafe { self.read(LOCAL_APIC_ID_REG) as u32 };
+
+        if !self.is_x2apic() {
+            raw >>= 24;
+        }
+
+        raw
+    }
+
+    /// Returns the logical APIC ID of the current CPU.
+    fn logical_destination(&self) -> u32 {
+        let mut raw = unsafe { self.read(LOGICAL_DEST_REG) as u32 };
+
+        if !self.is_x2apic() {
+            raw >>= 24;
+        }
+
+        raw
+    }
+
+    /// Set the logical APIC ID of the current CPU.
+    ///
+    /// In xAPIC mode the logical destination register is writable, and the
+    /// flat model is used, so `id` is a bitmask of up to 8 CPUs. In x2APIC mode
+    /// the register is read-only and derived from the APIC ID (see
+    /// [x2apic_logical_id]), so nothing is written. The given ID should match
+    /// the derived one.
+    fn set_logical_destination(&self, id: u32) {
+        match self.mode() {
+            ApicMode::XApic => {
+                assert!(id <= u8::MAX as u32);
+                self.set_destination_format(true).unwrap();
+                self.set_logical_id(id as u8).unwrap();
+            }
+            ApicMode::X2Apic => debug_assert_eq!(self.logical_destination(), id),
+        }
+    }
+
+    /// Write the logical APIC ID (bits 31:24 of the logical destination
+    /// register). xAPIC only!
+    ///
+    /// How the ID is interpreted depends on the model set with
+    /// [set_destination_format](ApicAccess::set_destination_format). In x2APIC
+    /// mode the logical ID is fixed by hardware, derived from the APIC ID (see
+    /// [x2apic_logical_id]), so an error is returned.
+    fn set_logical_id(&self, id: u8) -> Result<(), &'static str> {
+        match self.mode() {
+            ApicMode::XApic => unsafe {
+                self.write(LOGICAL_DEST_REG, (id as u64) << 24);
+                Ok(())
+            },
+            ApicMode::X2Apic => Err("logical ID is read-only in x2APIC mode"),
+        }
+    }
+
+    /// Select the logical destination model. xAPIC only!
+    ///
+    /// In the flat model, the logical ID is a bitmask selecting up to 8 CPUs.
+    /// In the cluster model, the upper 4 bits of the logical ID select the
+    /// cluster, and the lower 4 bits the CPUs within it. All local APICs must
+    /// use the same model. x2APIC always uses the cluster model, and has no
+    /// destination format register, so an error is returned.
+    fn set_destination_format(&self, flat: bool) -> Result<(), &'static str> {
+        // Bits 31:28 select the model, the rest is reserved and must be 1.
+        let model: u64 = if flat { 0xf } else { 0x0 };
+
+        match self.mode() {
+            ApicMode::XApic => unsafe {
+                self.write(LOGICAL_DEST_FMT_REG, model << 28 | 0x0fff_ffff);
+                Ok(())
+            },
+            ApicMode::X2Apic => Err("no destination format register in x2APIC mode"),
+        }
+    }
+
+    /// Setup the APIC Error LVT entry.
+    fn setup_error(&self, vector: u8) {
+        unsafe {
+            self.write(LVT_ERROR_REG, Error::new(vector, false).bits() as u64);
+            self.write(ERROR_STATUS_REG, 0);
+        }
+    }
+
+    /// Setup the APIC timer.
+    fn setup_timer(&self, vector: u8, masked: bool, mode: TimerMode, divisor: Divisor) {
+        assert!(matches!(mode, TimerMode::OneShot) || matches!(mode, TimerMode::Periodic));
+
+        let mut timer = Timer::new(vector, masked);
+        timer.set_timer_mode(mode);
+
+        let divisor = DivideConfiguration::new(divisor);
+
+        unsafe {
+            self.write(LVT_TIMER_REG, timer.bits() as u64);
+            self.write(TIMER_DIVIDE_CONF_REG, divisor.bits() as u64);
+        }
+    }
+
+    /// Start the APIC timer.
+    ///
+    /// To avoid race conditions, this function should not be called before
+    /// [`setup_timer`](ApicAccess::start_timer) has been called.
+    fn start_timer(&self, init: u32) {
+        unsafe {
+            self.write(TIMER_INIT_COUNT_REG, init as u64);
+        }
+    }
+
+    /// Stop the APIC timer.
+    fn stop_timer(&self) {
+        unsafe {
+            self.write(TIMER_INIT_COUNT_REG, 0x0);
+        }
+    }
+
+    /// Read the current count of the APIC timer, which counts down from the
+    /// initial count to 0.
+    fn timer_count(&self) -> u32 {
+        unsafe { self.read(TIMER_CURR_COUNT_REG) as u32 }
+    }
+
+    /// Send an INIT IPI to the target APIC.
+    ///
+    /// This will reset the target into the INIT state and await a STARTUP IPI.
+    fn ipi_init(&self, apic_id: u32) -> Result<(), &'static str> {
+        let low = IcrLow::new(
+            0,
+            DeliveryMode::INIT,
+            DestinationMode::Physical,
+            Level::Assert,
+            TriggerMode::Edge,
+            DestinationShorthand::NoShorthand,
+        );
+
+        let high = if self.is_x2apic() {
+            IcrHigh::new_x2apic_destination(apic_id)
+        } else {
+            IcrHigh::new_xapic_destination(apic_id as u8)
+        };
+
+        self.ipi(Icr::new(low, high))
+    }
+
+    /// Send a synchronization message to all local APICs in the system to set
+    /// their arbitration IDs to the values of their APIC IDs.
+    fn ipi_init_deassert(&self) -> Result<(), &'static str> {
+        let low = IcrLow::new(
+            0,
+            DeliveryMode::INIT,
+            DestinationMode::Physical, // destination mode doesn't matter.
+            Level::Deassert,
+            TriggerMode::Level,
+            DestinationShorthand::AllIncludingSelf,
+        );
+
+        let high = IcrHigh::new();
+
+        self.ipi(Icr::new(low, high))
+    }
+
+    /// Send a STARTUP IPI to the target APIC.
+    ///
+    /// After receiving the STARTUP, the target will begin executing the bootstrap
+    /// routine located at `bootstrap * 4096`.
+    fn ipi_startup(&self, apic_id: u32, bootstrap: u8) -> Result<(), &'static str> {
+        let low = IcrLow::new(
+            bootstrap,
+            DeliveryMode::StartUp,
+            DestinationMode::Physical,
+            Level::Assert,
+            TriggerMode::Edge,
+            DestinationShorthand::NoShorthand,
+        );
+        let high = if self.is_x2apic() {
+            IcrHigh::new_x2apic_destination(apic_id)
+        } else {
+            IcrHigh::new_xapic_destination(apic_id as u8)
+        };
+
+        self.ipi(Icr::new(low, high))
+    }
+
+    /// Send an IPI using the supplied ICR.
+    ///
+    /// Caller must make sure ICR is properly formatted. Fails if the IPI isn't
+    /// accepted within [ICR_SEND_TIMEOUT] polls (see
+    /// [await_icr_send](ApicAccess::await_icr_send)).
+    fn ipi(&self, icr: Icr) -> Result<(), &'static str> {
+        match self.mode() {
+            ApicMode::XApic => {
+                self.await_icr_send(ICR_SEND_TIMEOUT)?;
+                self.write_icr(icr);
+                self.await_icr_send(ICR_SEND_TIMEOUT)
+            }
+            ApicMode::X2Apic => {
+                self.write_icr(icr);
+                Ok(())
+            }
+        }
+    }
+
+    /// Send an IPI to a set of processors within an x2APIC cluster.
+    ///
+    /// Every bit set in `mask` selects the CPU with that index in the cluster
+    /// (see [x2apic_logical_id]). Only available in x2APIC mode.
+    fn ipi_logical_cluster(&self, cluster: u16, mask: u16, vector: u8) -> Result<(), &'static str> {
+        assert!(self.is_x2apic());
+
+        let low = IcrLow::new(
+            vector,
+            DeliveryMode::Fixed,
+            DestinationMode::Logical,
+            Level::Assert,
+            TriggerMode::Edge,
+            DestinationShorthand::NoShorthand,
+        );
+        let high = IcrHigh::new_x2apic_destination((cluster as u32) << 16 | mask as u32);
+
+        self.ipi(Icr::new(low, high))
+    }
+
+    /// Send a fixed IPI with the given vector to all other processors.
+    fn ipi_others(&self, vector: u8) -> Result<(), &'static str> {
+        let low = IcrLow::new(
+            vector,
+            DeliveryMode::Fixed,
+            DestinationMode::Physical,
+            Level::Assert,
+            TriggerMode::Edge,
+            DestinationShorthand::AllExludingSelf,
+        );
+
+        self.ipi(Icr::new(low, IcrHigh::new()))
+    }
+
+    /// Send an NMI to all other processors.
+    fn ipi_nmi_others(&self) -> Result<(), &'static str> {
+        let low = IcrLow::new(
+            0,
+            DeliveryMode::NMI,
+            DestinationMode::Physical,
+            Level::Assert,
+            TriggerMode::Edge,
+            DestinationShorthand::AllExludingSelf,
+        );
+
+        self.ipi(Icr::new(low, IcrHigh::new()))
+    }
+
+    /// Issue an end-of-interrupt.
+    fn eoi(&self) {
+        unsafe {
+            self.write(EOI_REG, 0x0);
+        }
+    }
+
+    /// Read the error status register.
+    fn esr(&self) -> ErrorStatus {
+        unsafe {
+            self.write(ERROR_STATUS_REG, 0);
+            ErrorStatus::from_bits_unchecked(self.read(ERROR_STATUS_REG) as u32)
+        }
+    }
+
+    /// Read and clear the error status register.
+    ///
+    /// [`esr`](ApicAccess::esr) only returns the errors recorded since the last
+    /// write, so the register is written once more to reset it.
+    fn clear_errors(&self) -> ErrorStatus {
+        let status = self.esr();
+        unsafe {
+            self.write(ERROR_STATUS_REG, 0);
+        }
+        status
+    }
+
+    /// Called with the errors recorded by the APIC when an IPI isn't accepted
+    /// in time (see [await_icr_send](ApicAccess::await_icr_send)). Does nothing
+    /// by default.
+    fn report_errors(&self, _status: ErrorStatus) {}
+
+    /// Block while the ICR is in the 'Send Pending' status, polling it at most
+    /// `spins` times. Polls are spaced out with a [Backoff].
+    ///
+    /// On timeout, the errors recorded by the APIC are cleared and passed to
+    /// [report_errors](ApicAccess::report_errors). The x2APIC doesn't have a
+    /// delivery status, so this never blocks there.
+    fn await_icr_send(&self, spins: u32) -> Result<(), &'static str> {
+        if self.is_x2apic() {
+            return Ok(());
+        }
+
+        let backoff = Backoff::new();
+        for _ in 0..spins {
+            if self.read_icr().low.delivery_status() != DeliveryStatus::SendPending {
+                return Ok(());
+            }
+
+            backoff.spin();
+        }
+
+        self.report_errors(self.clear_errors());
+        Err("IPI not accepted")
+    }
+
+    /// Perform a raw write to the ICR register.
+    fn write_icr(&self, icr: Icr) {
+        match self.mode() {
+            ApicMode::XApic => unsafe {
+                self.write(ICR_HIGH_REG, icr.high.bits() as u64);
+                self.write(ICR_LOW_REG, icr.low.bits() as u64);
+            },
+            ApicMode::X2Apic => unsafe {
+                self.write(ICR_LOW_REG, icr.bits());
+            },
+        }
+    }
+
+    /// Read the ICR register.
+    fn read_icr(&self) -> Icr {
+        match self.mode() {
+            ApicMode::XApic => unsafe {
+                let low = self.read(ICR_LOW_REG) as u32;
+                let high = self.read(ICR_HIGH_REG) as u32;
+                Icr::from_bits_unchecked(high, low)
+            },
+            ApicMode::X2Apic => unsafe { Icr::from_bits64_unchecked(self.read(ICR_LOW_REG)) },
+        }
+    }
+}
+
+/// Compute the x2APIC logical ID for the given APIC ID.
+///
+/// The upper 16 bits hold the cluster (`apic_id >> 4`), and the lower 16 bits
+/// have a single bit set for the position within the cluster (`apic_id & 0xf`).
+/// See Intel Vol. 3, 10.12.10.2.
+pub const fn x2apic_logical_id(apic_id: u32) -> u32 {
+    (apic_id >> 4) << 16 | 1 << (apic_id & 0xf)
+}
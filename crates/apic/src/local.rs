@@ -3,12 +3,29 @@
 //! Valid registers as defined in the Intel and AMD manuals (Vol. 3, 10.12.1.2
 //! and Vol. 2, 16.3.2 respectively).
 //!
+//! The register constants are *byte* offsets into the xAPIC page. Every
+//! register is 32 bits wide and 16 byte aligned. Use [reg_index] to index the
+//! page as an array of `u32`s.
+//!
 //! An xAPIC register `x` can be converted to its x2APIC equivalent using:
-//! `(x >> 4) + X2APIC_MSR_BASE`.
+//! `(x >> 4) + X2APIC_MSR_BASE` (see [x2apic_msr]).
+
+use core::fmt;
 
 /// Base for x2APIC register access.
 pub const X2APIC_MSR_BASE: u32 = 0x800;
 
+/// Convert a register byte offset to an index in the xAPIC page, viewed as an
+/// array of `u32`s.
+pub const fn reg_index(reg: u32) -> usize {
+    (reg >> 2) as usize
+}
+
+/// Convert a register byte offset to its x2APIC MSR.
+pub const fn x2apic_msr(reg: u32) -> u32 {
+    X2APIC_MSR_BASE + (reg >> 4)
+}
+
 /// Local APIC ID register.
 pub const LOCAL_APIC_ID_REG: u32 = 0x20;
 
@@ -153,6 +170,64 @@ pub const TIMER_DIVIDE_CONF_REG: u32 = 0x3e0;
 /// Self IPI register. Only available for x2APIC.
 pub const X2_SELF_IPI: u32 = 0x83f;
 
+/// The value of the `IA32_APIC_BASE` MSR.
+///
+/// Holds the APIC base address, the global enable and x2APIC mode bits, and
+/// the BSP flag. See Intel Vol. 3, 10.4.4 and 10.12.1.
+#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+pub struct ApicBase {
+    bits: u64,
+}
+
+impl ApicBase {
+    /// Returns true if this is the Boot Strap Processor.
+    pub const fn is_bsp(&self) -> bool {
+        ((self.bits >> 8) & 1) == 1
+    }
+
+    /// Returns true if x2APIC mode ('EXTD') is enabled.
+    pub const fn is_x2apic(&self) -> bool {
+        ((self.bits >> 10) & 1) == 1
+    }
+
+    /// Returns true if the APIC is globally enabled ('EN', or 'AE' on AMD).
+    pub const fn is_enabled(&self) -> bool {
+        ((self.bits >> 11) & 1) == 1
+    }
+
+    /// The physical base address of the xAPIC page.
+    pub const fn address(&self) -> u64 {
+        self.bits & !0xfff
+    }
+
+    /// Set the global enable bit.
+    pub fn enable(&mut self) {
+        self.bits |= 1 << 11;
+    }
+
+    /// Set the x2APIC mode bit. The APIC must already be enabled.
+    pub fn enable_x2apic(&mut self) {
+        debug_assert!(self.is_enabled());
+        self.bits |= 1 << 10;
+    }
+
+    /// Clear both the global enable and x2APIC mode bits.
+    ///
+    /// Both have to be cleared together, EN=0 with EXTD=1 is an invalid state.
+    pub fn disable(&mut self) {
+        self.bits &= !((1 << 11) | (1 << 10));
+    }
+
+    pub const unsafe fn from_bits_unchecked(bits: u64) -> Self {
+        ApicBase { bits }
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.bits
+    }
+}
+
 /// APIC version register.
 ///
 /// The local APIC contains a hardwired version register. This register
@@ -259,6 +334,11 @@ impl ErrorStatus {
         ((self.bits >> 7) & 1) == 1
     }
 
+    /// Returns true if no errors were recorded.
+    pub const fn is_empty(&self) -> bool {
+        (self.bits & 0xff) == 0
+    }
+
     pub const unsafe fn from_bits_unchecked(bits: u32) -> Self {
         ErrorStatus { bits }
     }
@@ -268,6 +348,54 @@ impl ErrorStatus {
     }
 }
 
+/// Spurious interrupt vector register (SVR).
+///
+/// Besides the vector delivered for spurious interrupts, this register holds
+/// the APIC software enable bit. After a reset, or after being globally
+/// enabled, the APIC is software disabled and won't accept any interrupts
+/// until this bit is set. See Intel Vol. 3, 10.9.
+#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+pub struct SpuriousInterrupt {
+    bits: u32,
+}
+
+impl SpuriousInterrupt {
+    pub const fn new(vector: u8, enabled: bool) -> Self {
+        SpuriousInterrupt {
+            bits: vector as u32 | (enabled as u32) << 8,
+        }
+    }
+
+    /// The vector delivered when the APIC generates a spurious interrupt.
+    pub const fn vector(&self) -> u8 {
+        (self.bits & 0xff) as u8
+    }
+
+    pub fn set_vector(&mut self, vector: u8) {
+        self.bits &= !0xff;
+        self.bits |= vector as u32;
+    }
+
+    /// Returns true if the APIC is software enabled.
+    pub const fn is_enabled(&self) -> bool {
+        ((self.bits >> 8) & 1) == 1
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.bits &= !(1 << 8);
+        self.bits |= (enabled as u32) << 8;
+    }
+
+    pub const unsafe fn from_bits_unchecked(bits: u32) -> Self {
+        SpuriousInterrupt { bits }
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.bits
+    }
+}
+
 /// Interrupt delivery mode.
 ///
 /// Specifies the type of interrupt to be sent to the processor. On AMD this
@@ -331,6 +459,37 @@ pub enum DeliveryMode {
     ExtINT = 0b111,
 }
 
+/// The registers that take a [DeliveryMode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LvtContext {
+    Icr,
+    CMCI,
+    LINT0,
+    LINT1,
+    PerfMonCounter,
+    ThermalSensor,
+}
+
+impl DeliveryMode {
+    /// Return whether the delivery mode may be programmed into the given register.
+    ///
+    /// See Intel Vol. 3, 10.5.1 and 10.6.1.
+    pub const fn is_valid_for(&self, context: LvtContext) -> bool {
+        match self {
+            DeliveryMode::Fixed | DeliveryMode::SMI | DeliveryMode::NMI => true,
+            DeliveryMode::LowestPriority | DeliveryMode::StartUp => {
+                matches!(context, LvtContext::Icr)
+            }
+            DeliveryMode::INIT => matches!(
+                context,
+                LvtContext::Icr | LvtContext::LINT0 | LvtContext::LINT1
+            ),
+            DeliveryMode::ExtINT => matches!(context, LvtContext::LINT0 | LvtContext::LINT1),
+            DeliveryMode::_Reserved => false,
+        }
+    }
+}
+
 /// Indicates the interrupt delivery status.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
@@ -380,12 +539,15 @@ pub enum TimerMode {
 
 /// Valid divisors for the divide configuration register (See [DivideConfiguration]).
 ///
-/// The divisor specifies the the value of the CPU core clock divisor.
+/// The divisor specifies the the value of the CPU core clock divisor. It is
+/// encoded in bits 0, 1 and 3 of the register, bit 2 is reserved and must be 0
+/// (see Intel Vol. 3, figure 10-10). The underscores separate bit 3, bit 2 and
+/// bits 1:0.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum Divisor {
     By2 = 0b0_0_00,
-    By4 = 0b0_0_001,
+    By4 = 0b0_0_01,
     By8 = 0b0_0_10,
     By16 = 0b0_0_11,
     By32 = 0b1_0_00,
@@ -411,7 +573,7 @@ impl DivideConfiguration {
     pub const fn divisor(&self) -> Divisor {
         match self.bits & 0b1011 {
             0b0_0_00 => Divisor::By2,
-            0b0_0_001 => Divisor::By4,
+            0b0_0_01 => Divisor::By4,
             0b0_0_10 => Divisor::By8,
             0b0_0_11 => Divisor::By16,
             0b1_0_00 => Divisor::By32,
@@ -423,7 +585,7 @@ impl DivideConfiguration {
     }
 
     pub fn set_divisor(&mut self, divisor: Divisor) {
-        self.bits &= !(0b1111);
+        self.bits &= !0b1011;
         self.bits |= divisor as u32
     }
 
@@ -458,6 +620,13 @@ macro_rules! define_lvt {
             }
 
             pub fn set_delivery_mode(&mut self, mode: DeliveryMode) {
+                debug_assert!(
+                    mode.is_valid_for(LvtContext::$name),
+                    "Invalid delivery mode {:?} for {}",
+                    mode,
+                    stringify!($name)
+                );
+
                 self.bits &= !(0b111 << 8);
                 self.bits |= (mode as u32) << 8;
             }
@@ -487,6 +656,7 @@ macro_rules! define_lvt {
                     0b00 => TimerMode::OneShot,
                     0b01 => TimerMode::Periodic,
                     0b10 => TimerMode::TscDeadline,
+                    0b11 => TimerMode::_Reserved,
                     _ => unreachable!(),
                 }
             }
@@ -710,7 +880,7 @@ pub enum DestinationShorthand {
 /// The lower double-word of the ICR register.
 ///
 /// A write to this word causes the IPI to be sent.
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct IcrLow {
     bits: u32,
@@ -759,6 +929,12 @@ impl IcrLow {
     }
 
     pub fn set_delivery_mode(&mut self, mode: DeliveryMode) {
+        debug_assert!(
+            mode.is_valid_for(LvtContext::Icr),
+            "Invalid delivery mode {:?} for ICR",
+            mode
+        );
+
         self.bits &= !(0b111 << 8);
         self.bits |= (mode as u32) << 8;
     }
@@ -834,6 +1010,20 @@ impl IcrLow {
     }
 }
 
+impl fmt::Debug for IcrLow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IcrLow")
+            .field("vector", &self.vector())
+            .field("delivery_mode", &self.delivery_mode())
+            .field("destination_mode", &self.destination_mode())
+            .field("delivery_status", &self.delivery_status())
+            .field("level", &self.level())
+            .field("trigger_mode", &self.trigger_mode())
+            .field("destination_shorthand", &self.destination_shorthand())
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct IcrHigh {
@@ -918,3 +1108,211 @@ impl Icr {
         (self.high.bits() as u64) << 32 | self.low.bits() as u64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apic_base_disable() {
+        // BSP, x2APIC, enabled, and the default base address.
+        let raw = 0xfee0_0000 | 1 << 11 | 1 << 10 | 1 << 8;
+        let mut base = unsafe { ApicBase::from_bits_unchecked(raw) };
+        assert!(base.is_bsp() && base.is_enabled() && base.is_x2apic());
+
+        base.disable();
+        assert_eq!(base.bits(), raw & !(1 << 11 | 1 << 10));
+        assert!(!base.is_enabled() && !base.is_x2apic());
+        assert!(base.is_bsp());
+        assert_eq!(base.address(), 0xfee0_0000);
+
+        base.enable();
+        base.enable_x2apic();
+        assert_eq!(base.bits(), raw);
+    }
+
+    #[test]
+    fn divisor_round_trip() {
+        let divisors = [
+            (Divisor::By1, 0b1011),
+            (Divisor::By2, 0b0000),
+            (Divisor::By4, 0b0001),
+            (Divisor::By8, 0b0010),
+            (Divisor::By16, 0b0011),
+            (Divisor::By32, 0b1000),
+            (Divisor::By64, 0b1001),
+            (Divisor::By128, 0b1010),
+        ];
+
+        for (divisor, bits) in divisors {
+            let conf = DivideConfiguration::new(divisor);
+            assert_eq!(conf.bits(), bits);
+            assert_eq!(conf.divisor(), divisor);
+        }
+
+        // Bit 2 is reserved and ignored, all reserved bits are preserved.
+        let mut conf = unsafe { DivideConfiguration::from_bits_unchecked(0xffff_fff4) };
+        assert_eq!(conf.divisor(), Divisor::By2);
+        conf.set_divisor(Divisor::By128);
+        assert_eq!(conf.bits(), 0xffff_fffe);
+    }
+
+    #[test]
+    fn timer_mode_reserved() {
+        let timer = unsafe { Timer::from_bits_unchecked(0b11 << 17 | 0x30) };
+        assert_eq!(timer.timer_mode(), TimerMode::_Reserved);
+        assert_eq!(timer.vector(), 0x30);
+    }
+
+    #[test]
+    fn spurious_interrupt() {
+        let mut svr = SpuriousInterrupt::new(0xff, true);
+        assert_eq!(svr.bits(), 0x1ff);
+        assert_eq!(svr.vector(), 0xff);
+        assert!(svr.is_enabled());
+
+        svr.set_enabled(false);
+        svr.set_vector(0x27);
+        assert_eq!(svr.bits(), 0x27);
+
+        // Reserved and focus checking bits are preserved.
+        let mut svr = unsafe { SpuriousInterrupt::from_bits_unchecked(0x1200) };
+        svr.set_vector(0xff);
+        svr.set_enabled(true);
+        assert_eq!(svr.bits(), 0x13ff);
+    }
+
+    #[test]
+    fn register_offsets() {
+        assert_eq!(reg_index(LVT_TIMER_REG), 0xc8);
+        assert_eq!(x2apic_msr(LVT_TIMER_REG), 0x832);
+
+        for reg in [
+            LOCAL_APIC_ID_REG,
+            EOI_REG,
+            ICR_LOW_REG,
+            ICR_HIGH_REG,
+            TIMER_DIVIDE_CONF_REG,
+        ] {
+            assert_eq!(reg % 16, 0);
+            assert!(reg_index(reg) < 0x400);
+        }
+    }
+
+    #[test]
+    fn icr_low_new() {
+        let icr = IcrLow::new(
+            0x20,
+            DeliveryMode::StartUp,
+            DestinationMode::Logical,
+            Level::Assert,
+            TriggerMode::Level,
+            DestinationShorthand::AllExludingSelf,
+        );
+
+        assert_eq!(icr.vector(), 0x20);
+        assert_eq!(icr.delivery_mode(), DeliveryMode::StartUp);
+        assert_eq!(icr.destination_mode(), DestinationMode::Logical);
+        assert_eq!(icr.delivery_status(), DeliveryStatus::Idle);
+        assert_eq!(icr.level(), Level::Assert);
+        assert_eq!(icr.trigger_mode(), TriggerMode::Level);
+        assert_eq!(
+            icr.destination_shorthand(),
+            DestinationShorthand::AllExludingSelf
+        );
+        assert_eq!(icr.bits(), 0x000c_ce20);
+    }
+
+    #[test]
+    fn icr_low_setters() {
+        let mut icr = unsafe { IcrLow::from_bits_unchecked(u32::MAX) };
+
+        icr.set_vector(0x42);
+        assert_eq!(icr.vector(), 0x42);
+
+        for mode in [
+            DeliveryMode::Fixed,
+            DeliveryMode::LowestPriority,
+            DeliveryMode::SMI,
+            DeliveryMode::NMI,
+            DeliveryMode::INIT,
+            DeliveryMode::StartUp,
+        ] {
+            icr.set_delivery_mode(mode);
+            assert_eq!(icr.delivery_mode(), mode);
+        }
+
+        for mode in [DestinationMode::Physical, DestinationMode::Logical] {
+            icr.set_destination_mode(mode);
+            assert_eq!(icr.destination_mode(), mode);
+        }
+
+        for level in [Level::Deassert, Level::Assert] {
+            icr.set_level(level);
+            assert_eq!(icr.level(), level);
+        }
+
+        for mode in [TriggerMode::Edge, TriggerMode::Level] {
+            icr.set_trigger_mode(mode);
+            assert_eq!(icr.trigger_mode(), mode);
+        }
+
+        for shorthand in [
+            DestinationShorthand::NoShorthand,
+            DestinationShorthand::Myself,
+            DestinationShorthand::AllIncludingSelf,
+            DestinationShorthand::AllExludingSelf,
+        ] {
+            icr.set_destination_shorthand(shorthand);
+            assert_eq!(icr.destination_shorthand(), shorthand);
+        }
+
+        // None of the setters should have touched the other fields.
+        assert_eq!(icr.vector(), 0x42);
+        assert_eq!(icr.delivery_mode(), DeliveryMode::StartUp);
+        assert_eq!(icr.delivery_status(), DeliveryStatus::SendPending);
+        assert_eq!(icr.bits() & !0x000c_dfff, !0x000c_dfff);
+    }
+
+    #[test]
+    fn delivery_mode_context() {
+        assert!(DeliveryMode::StartUp.is_valid_for(LvtContext::Icr));
+        assert!(!DeliveryMode::StartUp.is_valid_for(LvtContext::LINT0));
+        assert!(!DeliveryMode::ExtINT.is_valid_for(LvtContext::Icr));
+        assert!(DeliveryMode::ExtINT.is_valid_for(LvtContext::LINT1));
+        assert!(!DeliveryMode::INIT.is_valid_for(LvtContext::ThermalSensor));
+        assert!(!DeliveryMode::_Reserved.is_valid_for(LvtContext::Icr));
+    }
+
+    #[test]
+    #[should_panic]
+    fn lvt_startup() {
+        let mut lint0 = LINT0::new(0x20, true);
+        lint0.set_delivery_mode(DeliveryMode::StartUp);
+    }
+
+    #[test]
+    fn icr_high() {
+        let mut high = IcrHigh::new_xapic_destination(0xab);
+        assert_eq!(high.xapic_destination(), 0xab);
+        assert_eq!(high.bits(), 0xab00_0000);
+
+        high.set_xapic_destination(0x12);
+        assert_eq!(high.xapic_destination(), 0x12);
+
+        let mut high = IcrHigh::new_x2apic_destination(0xdead_beef);
+        assert_eq!(high.x2apic_destination(), 0xdead_beef);
+
+        high.set_x2apic_destination(7);
+        assert_eq!(high.x2apic_destination(), 7);
+    }
+
+    #[test]
+    fn icr_bits() {
+        let icr = unsafe { Icr::from_bits64_unchecked(0x0000_0003_0000_4500) };
+        assert_eq!(icr.high.x2apic_destination(), 3);
+        assert_eq!(icr.low.delivery_mode(), DeliveryMode::INIT);
+        assert_eq!(icr.low.level(), Level::Assert);
+        assert_eq!(icr.bits(), 0x0000_0003_0000_4500);
+    }
+}
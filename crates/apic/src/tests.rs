@@ -0,0 +1,152 @@
+use core::cell::{Cell, RefCell};
+
+use heapless::Vec;
+
+use crate::{
+    local::{
+        ApicBase, ERROR_STATUS_REG, ICR_HIGH_REG, ICR_LOW_REG, LOCAL_APIC_ID_REG,
+        LOGICAL_DEST_FMT_REG, LOGICAL_DEST_REG, TIMER_CURR_COUNT_REG,
+    },
+    ApicAccess, ApicMode,
+};
+
+/// A local APIC backed by plain memory, recording every write.
+struct MockApic {
+    mode: ApicMode,
+    base: Cell<u64>,
+    registers: RefCell<[u64; 64]>,
+    writes: RefCell<Vec<(u32, u64), 16>>,
+}
+
+impl MockApic {
+    fn new(mode: ApicMode) -> Self {
+        MockApic {
+            mode,
+            base: Cell::new(0),
+            registers: RefCell::new([0; 64]),
+            writes: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn writes(&self) -> Vec<(u32, u64), 16> {
+        self.writes.borrow().clone()
+    }
+}
+
+impl ApicAccess for MockApic {
+    fn mode(&self) -> ApicMode {
+        self.mode
+    }
+
+    unsafe fn read(&self, reg: u32) -> u64 {
+        self.registers.borrow()[(reg >> 4) as usize]
+    }
+
+    unsafe fn write(&self, reg: u32, val: u64) {
+        self.registers.borrow_mut()[(reg >> 4) as usize] = val;
+        self.writes.borrow_mut().push((reg, val)).unwrap();
+    }
+
+    fn read_base(&self) -> ApicBase {
+        unsafe { ApicBase::from_bits_unchecked(self.base.get()) }
+    }
+
+    unsafe fn write_base(&self, base: ApicBase) {
+        self.base.set(base.bits());
+    }
+}
+
+#[test]
+fn ipi_startup_xapic() {
+    let apic = MockApic::new(ApicMode::XApic);
+    apic.ipi_startup(3, 8).unwrap();
+
+    // The high half goes first, writing the low half sends the IPI.
+    assert_eq!(
+        apic.writes(),
+        [(ICR_HIGH_REG, 3 << 24), (ICR_LOW_REG, 0x4608)]
+    );
+
+    let icr = apic.read_icr();
+    assert_eq!((icr.high.bits(), icr.low.bits()), (3 << 24, 0x4608));
+}
+
+#[test]
+fn ipi_startup_x2apic() {
+    let apic = MockApic::new(ApicMode::X2Apic);
+    apic.ipi_startup(3, 8).unwrap();
+
+    assert_eq!(apic.writes(), [(ICR_LOW_REG, 3 << 32 | 0x4608)]);
+    assert_eq!(apic.read_icr().bits(), 3 << 32 | 0x4608);
+}
+
+#[test]
+fn id() {
+    let xapic = MockApic::new(ApicMode::XApic);
+    let x2apic = MockApic::new(ApicMode::X2Apic);
+    unsafe {
+        xapic.write(LOCAL_APIC_ID_REG, 5 << 24);
+        x2apic.write(LOCAL_APIC_ID_REG, 0x1234);
+    }
+
+    assert_eq!(xapic.id(), 5);
+    assert_eq!(x2apic.id(), 0x1234);
+}
+
+#[test]
+fn timer_count() {
+    let apic = MockApic::new(ApicMode::X2Apic);
+    unsafe {
+        apic.write(TIMER_CURR_COUNT_REG, 0x1234);
+    }
+
+    assert_eq!(apic.timer_count(), 0x1234);
+}
+
+#[test]
+fn esr() {
+    let apic = MockApic::new(ApicMode::XApic);
+    unsafe {
+        apic.write(ERROR_STATUS_REG, 1 << 7);
+    }
+
+    // The register must be written before it's read, which latches the
+    // errors. The mock doesn't latch, so the write clears them.
+    assert!(apic.esr().is_empty());
+    assert_eq!(
+        apic.writes(),
+        [(ERROR_STATUS_REG, 1 << 7), (ERROR_STATUS_REG, 0)]
+    );
+}
+
+#[test]
+fn logical_id() {
+    let xapic = MockApic::new(ApicMode::XApic);
+    xapic.set_logical_destination(0b100);
+    assert_eq!(xapic.logical_destination(), 0b100);
+    assert_eq!(
+        xapic.writes(),
+        [
+            (LOGICAL_DEST_FMT_REG, 0xffff_ffff),
+            (LOGICAL_DEST_REG, 0b100 << 24)
+        ]
+    );
+
+    let x2apic = MockApic::new(ApicMode::X2Apic);
+    assert!(x2apic.set_logical_id(1).is_err());
+    assert!(x2apic.set_destination_format(true).is_err());
+    assert!(x2apic.writes().is_empty());
+}
+
+#[test]
+fn disable() {
+    // BSP, x2APIC, enabled, and the default base address.
+    let apic = MockApic::new(ApicMode::X2Apic);
+    apic.base.set(0xfee0_0000 | 1 << 11 | 1 << 10 | 1 << 8);
+    assert!(apic.is_bsp());
+
+    apic.disable();
+    assert_eq!(apic.base.get(), 0xfee0_0000 | 1 << 8);
+    assert!(apic.is_bsp());
+    assert!(apic.writes().is_empty());
+}
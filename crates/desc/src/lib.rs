@@ -0,0 +1,11 @@
+//! Descriptor formats shared by the kernel.
+//!
+//! This crate provides the long mode segment, gate and TSS descriptors
+//! ([segment]) and the memory region descriptors built from the firmware
+//! memory map ([memory]). Neither depends on the hardware they describe, so
+//! they can be tested on the host.
+
+#![no_std]
+
+pub mod memory;
+pub mod segment;
@@ -0,0 +1,220 @@
+//! Memory region descriptors.
+
+use core::cmp;
+
+/// A memory region.
+///
+/// Regions can either be physical or virtual, it is up to the user to make clear which
+/// one it is. Regions are ordered by base, then length.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Region {
+    pub base: u64,
+    pub length: usize,
+}
+
+impl Region {
+    /// Calculate the end address of the region.
+    pub fn end(&self) -> u64 {
+        self.base + self.length as u64
+    }
+
+    /// Return true if `addr` lies within the region.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr < self.end()
+    }
+
+    /// Iterate over the base addresses of the `SIZE` frames that fit in the region.
+    ///
+    /// The first frame starts at `align_up::<SIZE>(base)`. Only frames that lie
+    /// entirely within the region are returned, so a region smaller than `SIZE`
+    /// yields nothing.
+    pub fn frames<const SIZE: usize>(&self) -> impl Iterator<Item = u64> {
+        let start = align_up::<SIZE>(self.base);
+        let end = align_down::<SIZE>(self.end());
+
+        (start..end.max(start)).step_by(SIZE)
+    }
+
+    /// Return true if `x` and `y` have any overlap.
+    pub fn are_overlapping(x: &Region, y: &Region) -> bool {
+        (x.base <= y.end()) && (x.end() >= y.base)
+    }
+
+    /// Align the region for the given alignment. This also aligns the length.
+    ///
+    /// Returns None if the alignment can't be done.
+    pub const fn align<const ALIGNMENT: usize>(&self) -> Option<Region> {
+        let base = align_up::<ALIGNMENT>(self.base);
+        let diff = base - self.base;
+
+        if self.length <= (ALIGNMENT + diff as usize) {
+            None
+        } else {
+            Some(Region {
+                base,
+                length: align_down::<ALIGNMENT>(self.length as u64 - diff) as usize,
+            })
+        }
+    }
+
+    /// Remove `other` from the region.
+    ///
+    /// Returns the parts of the region that lie before and after `other`. If
+    /// the regions don't overlap, the region itself is returned as one of the
+    /// two.
+    pub fn exclude(&self, other: &Region) -> (Option<Region>, Option<Region>) {
+        let before = if self.base < other.base {
+            Some(Region {
+                base: self.base,
+                length: (cmp::min(self.end(), other.base) - self.base) as usize,
+            })
+        } else {
+            None
+        };
+
+        let after = if self.end() > other.end() {
+            let base = cmp::max(self.base, other.end());
+            Some(Region {
+                base,
+                length: (self.end() - base) as usize,
+            })
+        } else {
+            None
+        };
+
+        (before, after)
+    }
+
+    /// Merge two regions.
+    ///
+    /// Returns [None] if the regions are not overlapping. Regions have no kind,
+    /// use [MemoryDescriptor::merge] to merge descriptors.
+    pub fn merge(x: &Region, y: &Region) -> Option<Region> {
+        if Region::are_overlapping(x, y) {
+            let base = cmp::min(x.base, y.base);
+            let length = (cmp::max(x.end(), y.end()) - base) as usize;
+            Some(Region { base, length })
+        } else {
+            None
+        }
+    }
+}
+
+/// Memory kind.
+///
+/// Standard E820 memory 'types'. See the [osdev wiki](https://wiki.osdev.org/Detecting_Memory_(x86)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum MemoryKind {
+    Usable = 0,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
+}
+
+/// A memory descriptor.
+///
+/// This is based directly on the E820 memory description as described
+/// [here](https://wiki.osdev.org/Detecting_Memory_(x86)).
+///
+/// Memory descriptors are sorted according to the region they span, and by
+/// kind if the regions are the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MemoryDescriptor {
+    pub region: Region,
+    pub kind: MemoryKind,
+}
+
+impl MemoryDescriptor {
+    /// Return true if the memory region is usable.
+    ///
+    /// This is just a shorthand to check if `self.kind` is [RegionKind::Usable].
+    #[inline]
+    pub fn is_usable(&self) -> bool {
+        self.kind == MemoryKind::Usable
+    }
+
+    /// Merge two descriptors.
+    ///
+    /// Unlike [Region::merge], this returns [None] if the descriptors are of a
+    /// different [MemoryKind], so coalescing descriptors never turns reserved
+    /// memory into usable memory (or the other way around).
+    pub fn merge(x: &MemoryDescriptor, y: &MemoryDescriptor) -> Option<MemoryDescriptor> {
+        if x.kind != y.kind {
+            return None;
+        }
+
+        Region::merge(&x.region, &y.region).map(|region| MemoryDescriptor {
+            region,
+            kind: x.kind,
+        })
+    }
+}
+
+/// Align `addr` down on `ALIGNMENT`, which should be a power of two.
+const fn align_down<const ALIGNMENT: usize>(addr: u64) -> u64 {
+    assert!(ALIGNMENT.is_power_of_two());
+    addr & !((1 << ALIGNMENT.trailing_zeros()) - 1)
+}
+
+/// Align `addr` up on `ALIGNMENT`, which should be a power of two.
+const fn align_up<const ALIGNMENT: usize>(addr: u64) -> u64 {
+    assert!(ALIGNMENT.is_power_of_two());
+    (addr + ALIGNMENT as u64 - 1) & !((1 << ALIGNMENT.trailing_zeros()) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(base: u64, length: usize, kind: MemoryKind) -> MemoryDescriptor {
+        MemoryDescriptor {
+            region: Region { base, length },
+            kind,
+        }
+    }
+
+    #[test]
+    fn merge_same_kind() {
+        let x = desc(0x1000, 0x2000, MemoryKind::Usable);
+        let y = desc(0x2000, 0x3000, MemoryKind::Usable);
+
+        assert_eq!(
+            MemoryDescriptor::merge(&x, &y),
+            Some(desc(0x1000, 0x4000, MemoryKind::Usable))
+        );
+
+        // Adjacent descriptors are merged too.
+        let z = desc(0x5000, 0x1000, MemoryKind::Usable);
+        assert_eq!(
+            MemoryDescriptor::merge(&y, &z),
+            Some(desc(0x2000, 0x4000, MemoryKind::Usable))
+        );
+    }
+
+    #[test]
+    fn merge_mixed_kinds() {
+        let usable = desc(0x1000, 0x2000, MemoryKind::Usable);
+        let reserved = desc(0x2000, 0x3000, MemoryKind::Reserved);
+        let nvs = desc(0x2000, 0x1000, MemoryKind::AcpiNvs);
+
+        assert_eq!(MemoryDescriptor::merge(&usable, &reserved), None);
+        assert_eq!(MemoryDescriptor::merge(&reserved, &nvs), None);
+        assert_eq!(
+            Region::merge(&usable.region, &reserved.region),
+            Some(Region {
+                base: 0x1000,
+                length: 0x4000
+            })
+        );
+    }
+
+    #[test]
+    fn merge_disjoint() {
+        let x = desc(0x1000, 0x1000, MemoryKind::Reserved);
+        let y = desc(0x3000, 0x1000, MemoryKind::Reserved);
+
+        assert_eq!(MemoryDescriptor::merge(&x, &y), None);
+    }
+}
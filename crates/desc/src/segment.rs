@@ -5,6 +5,9 @@
 //!  - AMD64 Architecture Programmer's Manual Vol. 2 Chapters 4 and 8.
 //!  - Intel Software Developer's Manual Vol. 3 Chapters 5, 6, and 7.
 
+// The multi-bit fields are spelled out as `value * (1 << shift)`, zero included.
+#![allow(clippy::erasing_op, clippy::identity_op)]
+
 use core::mem;
 
 use bitflags::bitflags;
@@ -195,7 +198,7 @@ macro_rules! generic_descriptor {
                     | ((typ.bits() as $bits) & 0xf) << 40
                     | ((base as $bits) & 0xffffff) << 16
                     | (limit as $bits) & 0xffff;
-                $descriptor { bits: bits }
+                $descriptor { bits }
             }
 
             pub fn set_limit(&mut self, limit: u32) {
@@ -294,6 +297,36 @@ impl GateDescriptor {
         GateDescriptor { bits }
     }
 
+    /// Create an interrupt gate that can be invoked from ring 3 with `int`.
+    ///
+    /// Interrupts are disabled while the handler runs. Only gates meant to be
+    /// called from userspace (like a legacy `int 0x80` syscall) should use
+    /// this. Exception and IRQ gates must stay DPL 0, else userspace could
+    /// fake exceptions and interrupts by raising them with `int`.
+    pub const fn interrupt_ring3(offset: u64, selector: SegmentSelector, ist: u8) -> Self {
+        Self::new(
+            offset,
+            selector,
+            GateDescriptorType::Interrupt,
+            Access::DPL_3.union(Access::P),
+            ist,
+        )
+    }
+
+    /// Create a trap gate that can be invoked from ring 3 with `int`.
+    ///
+    /// Unlike [interrupt_ring3](GateDescriptor::interrupt_ring3), interrupts
+    /// stay enabled while the handler runs.
+    pub const fn trap_ring3(offset: u64, selector: SegmentSelector, ist: u8) -> Self {
+        Self::new(
+            offset,
+            selector,
+            GateDescriptorType::Trap,
+            Access::DPL_3.union(Access::P),
+            ist,
+        )
+    }
+
     pub fn set_offset(&mut self, offset: u64) {
         self.bits &= !(0xffffffffffff00000000ffff);
         self.bits |= ((offset as u128) & 0xffffffffffff0000) << 32 | (offset as u128) & 0xffff;
@@ -317,7 +350,7 @@ pub const IOPB_BYTES: usize = 8192;
 
 /// 64-bit Task State Segment.
 #[derive(Debug, Clone, Copy)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct Tss {
     pub _reserved0: [u8; 4],
     pub rsp: [u64; 3],
@@ -328,10 +361,28 @@ pub struct Tss {
 }
 
 impl Tss {
+    /// Create a TSS without an I/O permission bitmap.
+    ///
+    /// See [without_io_bitmap](Tss::without_io_bitmap).
     pub const fn new(rsp0: u64) -> Tss {
+        Tss::without_io_bitmap(rsp0)
+    }
+
+    /// Create a TSS without an I/O permission bitmap.
+    ///
+    /// The bitmap base is set to `size_of::<Tss>()`, which lies beyond the TSS
+    /// limit as long as the descriptor limit is `size_of::<Tss>() - 1`. The CPU
+    /// then treats every port as not permitted, so any I/O port access from
+    /// ring 3 faults. A base *within* the limit would make the CPU interpret
+    /// whatever follows the TSS as a bitmap.
+    pub const fn without_io_bitmap(rsp0: u64) -> Tss {
         Tss::new_with_base(rsp0, mem::size_of::<Tss>() as u16)
     }
 
+    /// Create a TSS with the I/O permission bitmap at offset `base`.
+    ///
+    /// The bitmap must directly follow the TSS in memory, and be covered by the
+    /// descriptor limit, including the trailing `0xff` byte.
     pub const fn new_with_base(rsp0: u64, base: u16) -> Tss {
         Tss {
             _reserved0: [0; 4],
@@ -342,4 +393,62 @@ impl Tss {
             io_pmap_base: base,
         }
     }
+
+    /// Set the stack pointer loaded when switching to the given privilege level.
+    ///
+    /// Only rings 0-2 have an entry.
+    pub fn set_rsp(&mut self, ring: usize, stack_top: u64) {
+        debug_assert!(ring < 3, "Invalid ring {}", ring);
+        self.rsp[ring] = stack_top;
+    }
+
+    /// Set the stack pointer for the given interrupt stack table entry.
+    ///
+    /// IST entries are numbered 1-7, matching the index used in the IDT gate
+    /// descriptors. An index of 0 means 'no IST' and has no entry.
+    pub fn set_ist(&mut self, index: usize, stack_top: u64) {
+        debug_assert!((1..=7).contains(&index), "Invalid IST index {}", index);
+        self.ist[index - 1] = stack_top;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tss_stacks() {
+        let mut tss = Tss::new(0x1000);
+        tss.set_rsp(2, 0x2000);
+        tss.set_ist(1, 0x3000);
+        tss.set_ist(7, 0x4000);
+
+        assert_eq!({ tss.rsp }, [0x1000, 0, 0x2000]);
+        assert_eq!({ tss.ist }, [0x3000, 0, 0, 0, 0, 0, 0x4000]);
+    }
+
+    #[test]
+    fn tss_without_io_bitmap() {
+        let tss = Tss::without_io_bitmap(0);
+        assert_eq!(mem::size_of::<Tss>(), 104);
+        assert_eq!({ tss.io_pmap_base }, 104);
+    }
+
+    #[test]
+    fn gate_ring3() {
+        let selector = SegmentSelector::from_raw(0x8);
+
+        let gate = GateDescriptor::trap_ring3(0x1234_5678_9abc_def0, selector, 0);
+        assert_eq!((gate.bits >> 40) & 0xff, 0xef);
+
+        let gate = GateDescriptor::interrupt_ring3(0, selector, 1);
+        assert_eq!((gate.bits >> 40) & 0xff, 0xee);
+        assert_eq!((gate.bits >> 32) & 0b111, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn tss_ist_zero() {
+        Tss::new(0).set_ist(0, 0x1000);
+    }
 }
@@ -1,2 +1,4 @@
 #![no_std]
 #![deny(unsafe_code)]
+
+pub mod sync;
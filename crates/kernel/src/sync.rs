@@ -0,0 +1,121 @@
+//! Synchronisation helpers.
+
+use core::{cell::Cell, hint};
+
+/// Spinning doubles up to `1 << SPIN_LIMIT` pauses per step.
+const SPIN_LIMIT: u32 = 6;
+
+/// After this many steps of [Backoff::snooze], the wait counts as completed
+/// (see [Backoff::is_completed]).
+const SNOOZE_LIMIT: u32 = 10;
+
+/// Exponential backoff for busy-wait loops.
+///
+/// Every call waits longer than the previous one, which cuts down on the
+/// traffic caused by many CPUs polling the same cache line. Like crossbeam's
+/// `Backoff`, it's meant to be created right before a wait loop:
+///
+/// ```ignore
+/// let backoff = Backoff::new();
+/// while !ready() {
+///     backoff.spin();
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Backoff {
+    step: Cell<u32>,
+}
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Self { step: Cell::new(0) }
+    }
+
+    /// Start over with the shortest wait.
+    pub fn reset(&self) {
+        self.step.set(0);
+    }
+
+    /// Wait using `pause` only.
+    ///
+    /// The number of pauses doubles on every call, up to `1 << SPIN_LIMIT`.
+    pub fn spin(&self) {
+        self.pause();
+        if self.step.get() <= SPIN_LIMIT {
+            self.step.set(self.step.get() + 1);
+        }
+    }
+
+    /// Wait for another CPU, sleeping once spinning has gone on for a while.
+    ///
+    /// From then on `sleep` is called instead of spinning. It returns false if
+    /// it couldn't sleep, and this spins after all. How to sleep is up to the
+    /// caller, e.g. halting until the next timer tick.
+    pub fn snooze(&self, sleep: impl FnOnce() -> bool) {
+        if self.step.get() <= SPIN_LIMIT || !sleep() {
+            self.pause();
+        }
+
+        if self.step.get() <= SNOOZE_LIMIT {
+            self.step.set(self.step.get() + 1);
+        }
+    }
+
+    /// Returns true once [snooze](Backoff::snooze) has escalated to sleeping,
+    /// meaning the caller may want to block in some other way.
+    pub fn is_completed(&self) -> bool {
+        self.step.get() > SNOOZE_LIMIT
+    }
+
+    /// Issue `1 << step` pauses, capped at `1 << SPIN_LIMIT`.
+    fn pause(&self) {
+        for _ in 0..1 << self.step.get().min(SPIN_LIMIT) {
+            hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snooze_sleeps_after_spinning() {
+        let backoff = Backoff::new();
+        let slept = Cell::new(0);
+        let sleep = || {
+            slept.set(slept.get() + 1);
+            true
+        };
+
+        for _ in 0..=SPIN_LIMIT {
+            backoff.snooze(sleep);
+        }
+        assert_eq!(slept.get(), 0);
+
+        backoff.snooze(sleep);
+        assert_eq!(slept.get(), 1);
+    }
+
+    #[test]
+    fn snooze_completes() {
+        let backoff = Backoff::new();
+        for _ in 0..=SNOOZE_LIMIT {
+            assert!(!backoff.is_completed());
+            backoff.snooze(|| false);
+        }
+        assert!(backoff.is_completed());
+
+        backoff.reset();
+        assert!(!backoff.is_completed());
+    }
+
+    #[test]
+    fn spin_never_completes() {
+        let backoff = Backoff::new();
+        for _ in 0..2 * SNOOZE_LIMIT {
+            backoff.spin();
+        }
+        assert!(!backoff.is_completed());
+    }
+}